@@ -0,0 +1,144 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+//! Compares the compression algorithms on a batch of log lines, a payload that compresses well.
+//! The compressed size of each is printed once, so speed can be weighed against ratio.
+//!
+//! The same lines are also sent one per payload, the way a stream of small messages would be,
+//! compressing each on its own and with a zstd context for the whole connection.
+
+#![feature(test)]
+
+extern crate tarpc;
+#[cfg(test)]
+extern crate test;
+extern crate tokio_core;
+
+use tarpc::protocol::{Compression, CompressionOptions, PipelineCodec};
+#[cfg(test)]
+use test::Bencher;
+#[cfg(test)]
+use tokio_core::io::Codec;
+
+/// Roughly 16 KiB of log lines.
+fn payload() -> Vec<u8> {
+    lines().concat().into_bytes()
+}
+
+/// 200 log lines of roughly 80 bytes each.
+fn lines() -> Vec<String> {
+    (0..200)
+        .map(|i| {
+            format!("2017-03-01T12:00:{:02}Z INFO request {} served in {} ms by worker {}\n",
+                    i % 60,
+                    1_000_000 + i,
+                    i % 17,
+                    i % 8)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+fn compress_loop(bencher: &mut Bencher, compression: Compression) {
+    let options = CompressionOptions::new(compression);
+    let payload = payload();
+    println!("{:?}: {} bytes compress to {}",
+             compression,
+             payload.len(),
+             options.compress(&payload).unwrap().len());
+    bencher.bytes = payload.len() as u64;
+    bencher.iter(|| options.compress(&payload).unwrap());
+}
+
+#[cfg(test)]
+fn decompress_loop(bencher: &mut Bencher, compression: Compression) {
+    let options = CompressionOptions::new(compression);
+    let payload = payload();
+    let compressed = options.compress(&payload).unwrap();
+    bencher.bytes = payload.len() as u64;
+    bencher.iter(|| options.decompress(&compressed).unwrap());
+}
+
+#[cfg(test)]
+#[bench]
+fn compress_zstd(bencher: &mut Bencher) {
+    compress_loop(bencher, Compression::Zstd);
+}
+
+#[cfg(test)]
+#[bench]
+fn compress_snappy(bencher: &mut Bencher) {
+    compress_loop(bencher, Compression::Snappy);
+}
+
+#[cfg(test)]
+#[bench]
+fn compress_lz4(bencher: &mut Bencher) {
+    compress_loop(bencher, Compression::Lz4);
+}
+
+#[cfg(test)]
+#[bench]
+fn decompress_zstd(bencher: &mut Bencher) {
+    decompress_loop(bencher, Compression::Zstd);
+}
+
+#[cfg(test)]
+#[bench]
+fn decompress_snappy(bencher: &mut Bencher) {
+    decompress_loop(bencher, Compression::Snappy);
+}
+
+#[cfg(test)]
+#[bench]
+fn decompress_lz4(bencher: &mut Bencher) {
+    decompress_loop(bencher, Compression::Lz4);
+}
+
+/// Sends every line as its own payload, compressing each on its own.
+#[cfg(test)]
+#[bench]
+fn compress_lines_zstd(bencher: &mut Bencher) {
+    let options = CompressionOptions::new(Compression::Zstd);
+    let lines = lines();
+    let compressed: usize =
+        lines.iter().map(|line| options.compress(line.as_bytes()).unwrap().len()).sum();
+    println!("Zstd per payload: {} bytes of lines compress to {}",
+             payload().len(),
+             compressed);
+    bencher.bytes = payload().len() as u64;
+    bencher.iter(|| {
+        for line in &lines {
+            options.compress(line.as_bytes()).unwrap();
+        }
+    });
+}
+
+/// Sends every line as its own payload, compressing each with the lines before it.
+#[cfg(test)]
+#[bench]
+fn compress_lines_zstd_stream(bencher: &mut Bencher) {
+    let codec = || -> PipelineCodec<String, String> {
+        PipelineCodec::new(2_000_000).stream_compression(32 << 10, 2 << 20)
+    };
+    let lines = lines();
+    let mut buf = Vec::new();
+    let mut sender = codec();
+    for line in &lines {
+        sender.encode(line.clone(), &mut buf).unwrap();
+    }
+    // Less the 8-byte length of every frame.
+    println!("Zstd with a connection context: {} bytes of lines compress to {}",
+             payload().len(),
+             buf.len() - 8 * lines.len());
+    bencher.bytes = payload().len() as u64;
+    bencher.iter(|| {
+        let mut sender = codec();
+        buf.clear();
+        for line in &lines {
+            sender.encode(line.clone(), &mut buf).unwrap();
+        }
+    });
+}
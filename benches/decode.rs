@@ -0,0 +1,43 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+#![feature(test)]
+
+extern crate tarpc;
+#[cfg(test)]
+extern crate test;
+extern crate tokio_core;
+
+use std::io::Cursor;
+use tarpc::protocol::{BincodeSerializer, PayloadSerializer};
+#[cfg(test)]
+use test::Bencher;
+use tokio_core::io::EasyBuf;
+
+const PAYLOAD_SIZE: usize = 1 << 20;
+
+fn payload() -> EasyBuf {
+    let mut payload = Vec::new();
+    BincodeSerializer.serialize_into(&mut payload, &vec![7u8; PAYLOAD_SIZE]).unwrap();
+    EasyBuf::from(payload)
+}
+
+#[cfg(test)]
+#[bench]
+fn decode_1mb_reader(bencher: &mut Bencher) {
+    let payload = payload();
+    bencher.bytes = PAYLOAD_SIZE as u64;
+    bencher.iter(|| -> Vec<u8> {
+        BincodeSerializer.deserialize_from(&mut Cursor::new(payload.clone())).unwrap()
+    });
+}
+
+#[cfg(test)]
+#[bench]
+fn decode_1mb_slice(bencher: &mut Bencher) {
+    let payload = payload();
+    bencher.bytes = PAYLOAD_SIZE as u64;
+    bencher.iter(|| -> Vec<u8> { BincodeSerializer.deserialize_slice(&payload).unwrap() });
+}
@@ -0,0 +1,55 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+#![feature(test)]
+
+extern crate tarpc;
+#[cfg(test)]
+extern crate test;
+extern crate tokio_core;
+
+use tarpc::protocol::Codec;
+#[cfg(test)]
+use test::Bencher;
+use tokio_core::io::Codec as TokioCodec;
+
+const PAYLOAD_SIZE: usize = 1 << 10;
+const SMALL_PAYLOAD_SIZE: usize = 16;
+
+#[cfg(test)]
+fn encode_loop(bencher: &mut Bencher, mut codec: Codec<Vec<u8>, ()>, size: usize) {
+    let message = vec![7u8; size];
+    let mut buf = Vec::new();
+    bencher.bytes = size as u64;
+    bencher.iter(|| {
+        buf.clear();
+        codec.encode((0, message.clone()), &mut buf).unwrap();
+    });
+}
+
+#[cfg(test)]
+#[bench]
+fn encode_1kb_two_pass(bencher: &mut Bencher) {
+    encode_loop(bencher, Codec::new(2_000_000), PAYLOAD_SIZE);
+}
+
+#[cfg(test)]
+#[bench]
+fn encode_1kb_single_pass(bencher: &mut Bencher) {
+    encode_loop(bencher, Codec::new(2_000_000).single_pass(true), PAYLOAD_SIZE);
+}
+
+#[cfg(test)]
+#[bench]
+fn encode_small_bounded(bencher: &mut Bencher) {
+    encode_loop(bencher, Codec::new(2_000_000), SMALL_PAYLOAD_SIZE);
+}
+
+/// Skips computing the size of each payload, since none can be too big.
+#[cfg(test)]
+#[bench]
+fn encode_small_unbounded(bencher: &mut Bencher) {
+    encode_loop(bencher, Codec::new(u64::max_value()), SMALL_PAYLOAD_SIZE);
+}
@@ -0,0 +1,105 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+//! Measures the throughput of encoding a payload with `Codec` and decoding it again, for
+//! payloads from 16 bytes to 1 MB.
+//!
+//! With the `allocations` feature, the allocations each round trip makes are counted by the
+//! global allocator and printed once per size, before it is timed. Counting them needs
+//! `GlobalAlloc`, so a toolchain from Rust 1.28 on.
+
+#[macro_use]
+extern crate criterion;
+extern crate tarpc;
+extern crate tokio_core;
+
+use criterion::{Bencher, Criterion, ParameterizedBenchmark, Throughput};
+use tarpc::protocol::Codec;
+use tokio_core::io::{Codec as TokioCodec, EasyBuf};
+
+const SIZES: [usize; 4] = [16, 1 << 10, 64 << 10, 1 << 20];
+
+/// Encodes `message` into `frame` and decodes it again, with the same codec for both ends.
+/// Decoding consumes the whole frame, so `frame` is left empty, ready to be reused.
+fn roundtrip(codec: &mut Codec<Vec<u8>, Vec<u8>>, message: &[u8], frame: &mut EasyBuf) -> Vec<u8> {
+    codec.encode((0, message.to_vec()), &mut frame.get_mut()).unwrap();
+    match codec.decode(frame).unwrap() {
+        Some((0, Ok(decoded))) => decoded,
+        _ => panic!("Expected a complete frame"),
+    }
+}
+
+#[cfg(feature = "allocations")]
+mod allocations {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+    use super::{SIZES, roundtrip};
+    use tarpc::protocol::Codec;
+    use tokio_core::io::EasyBuf;
+
+    /// Counts the allocations made through it.
+    struct CountingAlloc;
+
+    static ALLOCATIONS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            System.alloc(layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            System.realloc(ptr, layout, new_size)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOC: CountingAlloc = CountingAlloc;
+
+    /// Prints the allocations a round trip of a payload of each size makes, once the codec and
+    /// frame buffer are warmed up.
+    pub fn count() {
+        for &size in &SIZES {
+            let mut codec = Codec::new(2 << 20);
+            let message = vec![7u8; size];
+            let mut frame = EasyBuf::new();
+            roundtrip(&mut codec, &message, &mut frame);
+            let before = ALLOCATIONS.load(Ordering::Relaxed);
+            roundtrip(&mut codec, &message, &mut frame);
+            let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+            println!("roundtrip/{}: {} allocations per round trip", size, allocations);
+        }
+    }
+}
+
+#[cfg(feature = "allocations")]
+fn count_allocations() {
+    allocations::count();
+}
+
+#[cfg(not(feature = "allocations"))]
+fn count_allocations() {}
+
+fn roundtrip_loop(bencher: &mut Bencher, &size: &usize) {
+    let mut codec = Codec::new(2 << 20);
+    let message = vec![7u8; size];
+    let mut frame = EasyBuf::new();
+    bencher.iter(|| roundtrip(&mut codec, &message, &mut frame));
+}
+
+fn bench_roundtrip(c: &mut Criterion) {
+    count_allocations();
+    let benchmark = ParameterizedBenchmark::new("codec", roundtrip_loop, SIZES.to_vec())
+        .throughput(|&size| Throughput::Bytes(size as u32));
+    c.bench("roundtrip", benchmark);
+}
+
+criterion_group!(benches, bench_roundtrip);
+criterion_main!(benches);
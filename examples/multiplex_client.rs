@@ -0,0 +1,84 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+//! Makes several concurrent calls on one connection with a `protocol::Client`, which matches
+//! each response to its call.
+
+extern crate futures;
+extern crate tarpc;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use futures::{Future, Stream, future};
+use std::io;
+use std::time::Duration;
+use tarpc::bincode;
+use tarpc::protocol::{Client, DecodeError, Proto};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor;
+use tokio_proto::BindServer;
+use tokio_service::Service;
+
+/// Sleeps for the requested number of milliseconds, then echoes it, so that later calls can be
+/// answered before earlier ones.
+struct Sleep {
+    handle: reactor::Handle,
+}
+
+impl Service for Sleep {
+    type Request = Result<u64, DecodeError<bincode::Error>>;
+    type Response = u64;
+    type Error = io::Error;
+    type Future = Box<Future<Item = u64, Error = io::Error>>;
+
+    fn call(&self, millis: Self::Request) -> Self::Future {
+        let millis = match millis {
+            Ok(millis) => millis,
+            Err(e) => return Box::new(future::err(e.into_io())),
+        };
+        match reactor::Timeout::new(Duration::from_millis(millis), &self.handle) {
+            Ok(timeout) => Box::new(timeout.map(move |()| millis)),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+}
+
+fn main() {
+    let mut core = reactor::Core::new().unwrap();
+    let handle = core.handle();
+
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_handle = handle.clone();
+    let server = listener.incoming().for_each(move |(socket, _)| {
+        let proto: Proto<u64, u64> = Proto::new(2 << 20);
+        proto.bind_server(&server_handle, socket, Sleep { handle: server_handle.clone() });
+        Ok(())
+    });
+    handle.spawn(server.map_err(|e| println!("Listener failed: {}", e)));
+
+    let client_handle = handle.clone();
+    let responses = TcpStream::connect(&addr, &handle).and_then(move |socket| {
+        let proto: Proto<u64, u64> = Proto::new(2 << 20);
+        let client = Client::new(&client_handle, socket, &proto);
+        let calls = [300, 100, 200]
+            .iter()
+            .map(|&millis| {
+                client.call(millis).map(|slept| {
+                    println!("Slept for {} ms", slept);
+                    slept
+                })
+            })
+            .collect::<Vec<_>>();
+        // Keep the connection open until every call completes.
+        future::join_all(calls).then(move |responses| {
+            drop(client);
+            responses
+        })
+    });
+    // The calls run concurrently, so they finish shortest first, in about 300 ms in all.
+    println!("Responses, in call order: {:?}", core.run(responses).unwrap());
+}
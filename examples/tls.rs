@@ -0,0 +1,80 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+//! Binds a `Proto` to TLS streams directly, without the `service!` macro. Run with
+//! `cargo run --example tls --features tls`.
+
+extern crate futures;
+extern crate tarpc;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use futures::{Future, Stream, future};
+use std::io;
+use tarpc::bincode;
+use tarpc::native_tls::{Pkcs12, TlsAcceptor};
+use tarpc::protocol::{DecodeError, Proto};
+use tarpc::tls;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor;
+use tokio_proto::{BindClient, BindServer};
+use tokio_service::Service;
+
+struct Hello;
+
+impl Service for Hello {
+    type Request = Result<String, DecodeError<bincode::Error>>;
+    type Response = String;
+    type Error = io::Error;
+    type Future = future::FutureResult<String, io::Error>;
+
+    fn call(&self, name: Self::Request) -> Self::Future {
+        future::result(name.map(|name| format!("Hello, {}!", name)).map_err(DecodeError::into_io))
+    }
+}
+
+fn get_acceptor() -> TlsAcceptor {
+    let buf = include_bytes!("../test/identity.p12");
+    let pkcs12 = Pkcs12::from_der(buf, "mypass").unwrap();
+    TlsAcceptor::builder(pkcs12).unwrap().build().unwrap()
+}
+
+fn main() {
+    let mut core = reactor::Core::new().unwrap();
+    let handle = core.handle();
+
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let acceptor = get_acceptor();
+    let server_handle = handle.clone();
+    let server = listener.incoming().for_each(move |(socket, _)| {
+        let handle = server_handle.clone();
+        // The TLS handshake completes before the proto starts framing.
+        let bind = tls::server::accept(socket, &acceptor).map(move |stream| {
+            let proto: Proto<String, String> = Proto::new(2 << 20);
+            proto.bind_server(&handle, stream, Hello);
+        });
+        server_handle.spawn(bind.map_err(|e| println!("TLS handshake failed: {}", e)));
+        Ok(())
+    });
+    handle.spawn(server.map_err(|e| println!("Listener failed: {}", e)));
+
+    // The test certificate is signed by `test/root-ca.pem`, which must be trusted by the system
+    // for the client to accept it; see the TLS tests for how to trust it per platform instead.
+    let context = tls::client::Context::new("foobar.com").unwrap();
+    let client_handle = handle.clone();
+    let response = TcpStream::connect(&addr, &handle)
+        .and_then(move |socket| tls::client::connect(socket, &context))
+        .and_then(move |stream| {
+            let proto: Proto<String, String> = Proto::new(2 << 20);
+            let client = proto.bind_client(&client_handle, stream);
+            client.call("Mom".to_string())
+        });
+    match core.run(response).unwrap() {
+        Ok(greeting) => println!("{}", greeting),
+        Err(e) => println!("The response couldn't be decoded: {}", e),
+    }
+}
@@ -3,6 +3,7 @@
 // Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
 // This file may not be copied, modified, or distributed except according to those terms.
 
+use protocol::DecodeError;
 use serde::{Deserialize, Serialize};
 use std::{fmt, io};
 use std::error::Error as StdError;
@@ -66,6 +67,16 @@ impl<E> From<io::Error> for Error<E> {
     }
 }
 
+impl<E> From<DecodeError<::bincode::Error>> for Error<E> {
+    fn from(err: DecodeError<::bincode::Error>) -> Self {
+        match err {
+            DecodeError::Deserialize(e) |
+            DecodeError::EmptyPayload(e) => Error::ResponseDeserialize(e),
+            err => Error::Io(err.into_io()),
+        }
+    }
+}
+
 impl<E> From<WireError<E>> for Error<E> {
     fn from(err: WireError<E>) -> Self {
         match err {
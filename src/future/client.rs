@@ -6,11 +6,12 @@
 use {REMOTE, bincode};
 use future::server::Response;
 use futures::{self, Future, future};
-use protocol::Proto;
+use protocol::{DecodeError, Proto};
 use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::io;
 use std::net::SocketAddr;
+use std::time::Duration;
 use stream_type::StreamType;
 use tokio_core::net::TcpStream;
 use tokio_core::reactor;
@@ -30,6 +31,8 @@ cfg_if! {
 pub struct Options {
     /// Max packet size in bytes.
     max_payload_size: u64,
+    /// How long to wait for each response, if at all.
+    response_timeout: Option<Duration>,
     reactor: Option<Reactor>,
     #[cfg(feature = "tls")]
     tls_ctx: Option<Context>,
@@ -40,6 +43,7 @@ impl Default for Options {
     fn default() -> Self {
         Options {
             max_payload_size: 2 << 20,
+            response_timeout: Some(Duration::from_secs(30)),
             reactor: None,
             tls_ctx: None,
         }
@@ -49,6 +53,7 @@ impl Default for Options {
     fn default() -> Self {
         Options {
             max_payload_size: 2 << 20,
+            response_timeout: Some(Duration::from_secs(30)),
             reactor: None,
         }
     }
@@ -61,6 +66,13 @@ impl Options {
         self
     }
 
+    /// Set how long to wait for the response to each request before failing it with an error of
+    /// kind `TimedOut`, or `None` to wait forever. The default is 30 seconds.
+    pub fn response_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.response_timeout = timeout;
+        self
+    }
+
     /// Drive using the given reactor handle. Only used by `FutureClient`s.
     pub fn handle(mut self, handle: reactor::Handle) -> Self {
         self.reactor = Some(Reactor::Handle(handle));
@@ -132,18 +144,26 @@ impl<Req, Resp, E> Client<Req, Resp, E>
           Resp: Deserialize + 'static,
           E: Deserialize + 'static
 {
-    fn bind(handle: &reactor::Handle, tcp: StreamType, max_payload_size: u64) -> Self
+    fn bind(handle: &reactor::Handle,
+            tcp: StreamType,
+            max_payload_size: u64,
+            response_timeout: Option<Duration>)
+            -> Self
         where Req: Serialize + Sync + Send + 'static,
               Resp: Deserialize + Sync + Send + 'static,
               E: Deserialize + Sync + Send + 'static
     {
-        let inner = Proto::new(max_payload_size).bind_client(&handle, tcp);
+        let mut proto: Proto<_, _> = Proto::new(max_payload_size);
+        if let Some(timeout) = response_timeout {
+            proto = proto.response_timeout(handle, timeout);
+        }
+        let inner = proto.bind_client(&handle, tcp);
         Client { inner }
     }
 
     fn map_err(resp: WireResponse<Resp, E>) -> Result<Resp, ::Error<E>> {
         resp.map(|r| r.map_err(::Error::from))
-            .map_err(::Error::ResponseDeserialize)
+            .map_err(::Error::from)
             .and_then(|r| r)
     }
 }
@@ -188,6 +208,7 @@ impl<Req, Resp, E> ClientExt for Client<Req, Resp, E>
         let tls_ctx = options.tls_ctx.take();
 
         let max_payload_size = options.max_payload_size;
+        let response_timeout = options.response_timeout;
 
         let connect = move |handle: &reactor::Handle| {
             let handle2 = handle.clone();
@@ -208,7 +229,7 @@ impl<Req, Resp, E> ClientExt for Client<Req, Resp, E>
                     #[cfg(not(feature = "tls"))]
                     future::ok(StreamType::Tcp(socket))
                 })
-                .map(move |tcp| Client::bind(&handle2, tcp, max_payload_size))
+                .map(move |tcp| Client::bind(&handle2, tcp, max_payload_size, response_timeout))
         };
         let (tx, rx) = futures::oneshot();
         let setup = move |handle: &reactor::Handle| {
@@ -244,4 +265,4 @@ type ResponseFuture<Req, Resp, E> =
                  Result<Resp, ::Error<E>>,
                  fn(Result<Resp, ::Error<E>>) -> Result<Resp, ::Error<E>>>;
 
-type WireResponse<R, E> = Result<Response<R, E>, bincode::Error>;
+type WireResponse<R, E> = Result<Response<R, E>, DecodeError<bincode::Error>>;
@@ -8,12 +8,13 @@ use errors::WireError;
 use futures::{Future, Poll, Stream, future as futures, stream};
 use futures::sync::{mpsc, oneshot};
 use futures::unsync;
-use protocol::Proto;
+use protocol::{ConcurrencyLimit, DecodeError, Drain, Handshake, Limited, Proto};
 use serde::{Deserialize, Serialize};
 use std::cell::Cell;
 use std::io;
 use std::net::SocketAddr;
 use std::rc::Rc;
+use std::sync::Arc;
 use tokio_core::io::Io;
 use tokio_core::net::{Incoming, TcpListener, TcpStream};
 use tokio_core::reactor;
@@ -43,7 +44,7 @@ impl Handle {
                                    handle: &reactor::Handle,
                                    options: Options)
                                    -> io::Result<(Self, Listen<S, Req, Resp, E>)>
-        where S: NewService<Request = Result<Req, bincode::Error>,
+        where S: NewService<Request = Result<Req, DecodeError<bincode::Error>>,
                             Response = Response<Resp, E>,
                             Error = io::Error> + 'static,
               Req: Deserialize + 'static,
@@ -54,6 +55,10 @@ impl Handle {
             listen_with(new_service,
                         addr, handle,
                         options.max_payload_size,
+                        options.max_in_flight,
+                        options.max_concurrent.map(ConcurrencyLimit::new),
+                        options.accept_hook.clone(),
+                        options.handshake_hook.clone(),
                         Acceptor::from(options))?;
         Ok((Handle {
                 addr: addr,
@@ -125,6 +130,46 @@ impl From<Options> for Acceptor {
     }
 }
 
+/// Refuses the connections that `accept_hook` returns an error for, before anything is read from
+/// them.
+struct PeerFilter {
+    accept_hook: Option<AcceptHook>,
+}
+
+impl PeerFilter {
+    fn accepts(&self, peer: &SocketAddr) -> bool {
+        let hook = match self.accept_hook {
+            Some(ref hook) => hook,
+            None => return true,
+        };
+        match hook(peer) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Refusing connection from {}: {}", peer, e);
+                false
+            }
+        }
+    }
+}
+
+impl<'a> FnOnce<(&'a (TcpStream, SocketAddr),)> for PeerFilter {
+    type Output = bool;
+
+    extern "rust-call" fn call_once(self,
+                                    (&(_, ref peer),): (&'a (TcpStream, SocketAddr),))
+                                    -> bool {
+        self.accepts(peer)
+    }
+}
+
+impl<'a> FnMut<(&'a (TcpStream, SocketAddr),)> for PeerFilter {
+    extern "rust-call" fn call_mut(&mut self,
+                                   (&(_, ref peer),): (&'a (TcpStream, SocketAddr),))
+                                   -> bool {
+        self.accepts(peer)
+    }
+}
+
 impl FnOnce<((TcpStream, SocketAddr),)> for Acceptor {
     type Output = Accept;
 
@@ -151,6 +196,10 @@ impl Fn<((TcpStream, SocketAddr),)> for Acceptor {
 pub struct Options {
     /// Max packet size in bytes.
     max_payload_size: u64,
+    max_in_flight: Option<usize>,
+    max_concurrent: Option<usize>,
+    accept_hook: Option<AcceptHook>,
+    handshake_hook: Option<HandshakeHook>,
     #[cfg(feature = "tls")]
     tls_acceptor: Option<TlsAcceptor>,
 }
@@ -160,6 +209,10 @@ impl Default for Options {
     fn default() -> Self {
         Options {
             max_payload_size: 2 << 20,
+            max_in_flight: None,
+            max_concurrent: None,
+            accept_hook: None,
+            handshake_hook: None,
         }
     }
 
@@ -167,6 +220,10 @@ impl Default for Options {
     fn default() -> Self {
         Options {
             max_payload_size: 2 << 20,
+            max_in_flight: None,
+            max_concurrent: None,
+            accept_hook: None,
+            handshake_hook: None,
             tls_acceptor: None,
         }
     }
@@ -179,6 +236,45 @@ impl Options {
         self
     }
 
+    /// Stop reading requests from a client while `requests` of them are awaiting a response,
+    /// resuming as responses are sent. By default there is no limit.
+    pub fn max_in_flight(mut self, requests: usize) -> Self {
+        self.max_in_flight = Some(requests);
+        self
+    }
+
+    /// Handle at most `requests` requests at once, across every connection. Requests over the
+    /// limit wait for one being handled to finish; use `max_in_flight` to bound how many can
+    /// wait on each connection. By default there is no limit.
+    ///
+    /// # Panics
+    ///
+    /// The server panics on `listen` if `requests` is 0.
+    pub fn max_concurrent(mut self, requests: usize) -> Self {
+        self.max_concurrent = Some(requests);
+        self
+    }
+
+    /// Call `hook` with the address of every new connection, before the TLS handshake, if any,
+    /// and before anything is read from it, e.g. to only accept clients on an allowlist. If
+    /// `hook` returns an error, the connection is closed at once, without telling the client why.
+    pub fn on_accept<F>(mut self, hook: F) -> Self
+        where F: Fn(&SocketAddr) -> io::Result<()> + Send + Sync + 'static
+    {
+        self.accept_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Call `hook` with the parameters negotiated for every new connection, e.g. to log the
+    /// protocol version a client speaks. If `hook` returns an error, the client is told why and
+    /// the connection is closed.
+    pub fn on_handshake<F>(mut self, hook: F) -> Self
+        where F: Fn(&Handshake) -> io::Result<()> + Send + Sync + 'static
+    {
+        self.handshake_hook = Some(Arc::new(hook));
+        self
+    }
+
     /// Sets the `TlsAcceptor`
     #[cfg(feature = "tls")]
     pub fn tls(mut self, tls_acceptor: TlsAcceptor) -> Self {
@@ -187,6 +283,10 @@ impl Options {
     }
 }
 
+type AcceptHook = Arc<Fn(&SocketAddr) -> io::Result<()> + Send + Sync>;
+
+type HandshakeHook = Arc<Fn(&Handshake) -> io::Result<()> + Send + Sync>;
+
 /// A message from server to client.
 #[doc(hidden)]
 pub type Response<T, E> = Result<T, WireError<E>>;
@@ -216,8 +316,9 @@ impl Shutdown {
     /// Initiates an orderly server shutdown.
     ///
     /// First, the server enters lameduck mode, in which
-    /// existing connections are honored but no new connections are accepted. Then, once all
-    /// connections are closed, it initates total shutdown.
+    /// existing connections are honored but no new connections are accepted. Each connection
+    /// stops reading new requests, finishes responding to the ones it has already read, and then
+    /// closes. Once all connections are closed, it initates total shutdown.
     ///
     /// This fn will not return until the server is completely shut down.
     pub fn shutdown(&self) -> ShutdownFuture {
@@ -254,7 +355,7 @@ impl ConnectionTracker {
 }
 
 struct ConnectionTrackingService<S> {
-    service: S,
+    service: Limited<S>,
     tracker: ConnectionTracker,
 }
 
@@ -262,7 +363,7 @@ impl<S: Service> Service for ConnectionTrackingService<S> {
     type Request = S::Request;
     type Response = S::Response;
     type Error = S::Error;
-    type Future = S::Future;
+    type Future = <Limited<S> as Service>::Future;
 
     fn call(&self, req: Self::Request) -> Self::Future {
         trace!("Calling service.");
@@ -280,6 +381,7 @@ impl<S> Drop for ConnectionTrackingService<S> {
 struct ConnectionTrackingNewService<S> {
     new_service: S,
     connection_tracker: ConnectionTracker,
+    limit: Option<ConcurrencyLimit>,
 }
 
 impl<S: NewService> NewService for ConnectionTrackingNewService<S> {
@@ -291,7 +393,7 @@ impl<S: NewService> NewService for ConnectionTrackingNewService<S> {
     fn new_service(&self) -> io::Result<Self::Instance> {
         self.connection_tracker.increment();
         Ok(ConnectionTrackingService {
-            service: self.new_service.new_service()?,
+            service: Limited::new(self.new_service.new_service()?, self.limit.clone()),
             tracker: self.connection_tracker.clone(),
         })
     }
@@ -299,6 +401,7 @@ impl<S: NewService> NewService for ConnectionTrackingNewService<S> {
 
 struct ShutdownSetter {
     shutdown: Rc<Cell<Option<oneshot::Sender<()>>>>,
+    drain: Drain,
 }
 
 impl FnOnce<(oneshot::Sender<()>,)> for ShutdownSetter {
@@ -319,6 +422,8 @@ impl Fn<(oneshot::Sender<()>,)> for ShutdownSetter {
     extern "rust-call" fn call(&self, (tx,): (oneshot::Sender<()>,)) {
         debug!("Received shutdown request.");
         self.shutdown.set(Some(tx));
+        // The watcher waits for the connections to close by counting them.
+        let _ = self.drain.start();
     }
 }
 
@@ -459,7 +564,8 @@ impl Future for ShutdownWatcher {
 }
 
 /// Creates a future that completes when a shutdown is signaled and no connections are open.
-fn shutdown_watcher() -> (ConnectionTracker, Shutdown, ShutdownWatcher) {
+/// Signaling a shutdown starts `drain`.
+fn shutdown_watcher(drain: Drain) -> (ConnectionTracker, Shutdown, ShutdownWatcher) {
     let (shutdown_tx, shutdown_rx) = mpsc::unbounded::<oneshot::Sender<()>>();
     let (connection_tx, connection_rx) = unsync::mpsc::unbounded();
     let shutdown = Rc::new(Cell::new(None));
@@ -468,7 +574,10 @@ fn shutdown_watcher() -> (ConnectionTracker, Shutdown, ShutdownWatcher) {
     let connections2 = connections.clone();
 
     let inner = shutdown_rx.take(1)
-        .map(ShutdownSetter { shutdown: shutdown })
+        .map(ShutdownSetter {
+            shutdown: shutdown,
+            drain: drain,
+        })
         .merge(connection_rx.map(ConnectionWatcher { connections: connections }))
         .take_while(ShutdownPredicate {
             shutdown: shutdown2,
@@ -482,7 +591,7 @@ fn shutdown_watcher() -> (ConnectionTracker, Shutdown, ShutdownWatcher) {
      ShutdownWatcher { inner: inner })
 }
 
-type AcceptStream = stream::AndThen<Incoming, Acceptor, Accept>;
+type AcceptStream = stream::AndThen<stream::Filter<Incoming, PeerFilter>, Acceptor, Accept>;
 
 type BindStream<S> = stream::ForEach<AcceptStream,
                                      Bind<ConnectionTrackingNewService<S>>,
@@ -491,7 +600,7 @@ type BindStream<S> = stream::ForEach<AcceptStream,
 /// The future representing a running server.
 #[doc(hidden)]
 pub struct Listen<S, Req, Resp, E>
-    where S: NewService<Request = Result<Req, bincode::Error>,
+    where S: NewService<Request = Result<Req, DecodeError<bincode::Error>>,
                         Response = Response<Resp, E>,
                         Error = io::Error> + 'static,
           Req: Deserialize + 'static,
@@ -505,7 +614,7 @@ pub struct Listen<S, Req, Resp, E>
 }
 
 impl<S, Req, Resp, E> Future for Listen<S, Req, Resp, E>
-    where S: NewService<Request = Result<Req, bincode::Error>,
+    where S: NewService<Request = Result<Req, DecodeError<bincode::Error>>,
                         Response = Response<Resp, E>,
                         Error = io::Error> + 'static,
           Req: Deserialize + 'static,
@@ -525,9 +634,13 @@ fn listen_with<S, Req, Resp, E>(new_service: S,
                                 addr: SocketAddr,
                                 handle: &reactor::Handle,
                                 max_payload_size: u64,
+                                max_in_flight: Option<usize>,
+                                limit: Option<ConcurrencyLimit>,
+                                accept_hook: Option<AcceptHook>,
+                                handshake_hook: Option<HandshakeHook>,
                                 acceptor: Acceptor)
                                 -> io::Result<(SocketAddr, Shutdown, Listen<S, Req, Resp, E>)>
-    where S: NewService<Request = Result<Req, bincode::Error>,
+    where S: NewService<Request = Result<Req, DecodeError<bincode::Error>>,
                         Response = Response<Resp, E>,
                         Error = io::Error> + 'static,
           Req: Deserialize + 'static,
@@ -540,15 +653,21 @@ fn listen_with<S, Req, Resp, E>(new_service: S,
 
     let handle = handle.clone();
 
-    let (connection_tracker, shutdown, shutdown_future) = shutdown_watcher();
+    let drain = Drain::new();
+    let (connection_tracker, shutdown, shutdown_future) = shutdown_watcher(drain.clone());
     let server = listener.incoming()
+        .filter(PeerFilter { accept_hook: accept_hook })
         .and_then(acceptor)
         .for_each(Bind {
             max_payload_size: max_payload_size,
+            max_in_flight: max_in_flight,
+            handshake_hook: handshake_hook,
+            drain: drain,
             handle: handle,
             new_service: ConnectionTrackingNewService {
                 connection_tracker: connection_tracker,
                 new_service: new_service,
+                limit: limit,
             },
         })
         .map_err(log_err as _);
@@ -563,12 +682,15 @@ fn log_err(e: io::Error) {
 
 struct Bind<S> {
     max_payload_size: u64,
+    max_in_flight: Option<usize>,
+    handshake_hook: Option<HandshakeHook>,
+    drain: Drain,
     handle: reactor::Handle,
     new_service: S,
 }
 
 impl<S, Req, Resp, E> Bind<S>
-    where S: NewService<Request = Result<Req, bincode::Error>,
+    where S: NewService<Request = Result<Req, DecodeError<bincode::Error>>,
                         Response = Response<Resp, E>,
                         Error = io::Error> + 'static,
           Req: Deserialize + 'static,
@@ -578,15 +700,22 @@ impl<S, Req, Resp, E> Bind<S>
     fn bind<I>(&self, socket: I) -> io::Result<()>
         where I: Io + 'static
     {
-        Proto::new(self.max_payload_size)
-            .bind_server(&self.handle, socket, self.new_service.new_service()?);
+        let mut proto: Proto<_, _> = Proto::new(self.max_payload_size).drain(self.drain.clone());
+        if let Some(max_in_flight) = self.max_in_flight {
+            proto = proto.max_in_flight(max_in_flight);
+        }
+        if let Some(ref hook) = self.handshake_hook {
+            let hook = hook.clone();
+            proto = proto.on_handshake(move |handshake| hook(handshake));
+        }
+        proto.bind_server(&self.handle, socket, self.new_service.new_service()?);
         Ok(())
     }
 }
 
 impl<I, S, Req, Resp, E> FnOnce<(I,)> for Bind<S>
     where I: Io + 'static,
-          S: NewService<Request = Result<Req, bincode::Error>,
+          S: NewService<Request = Result<Req, DecodeError<bincode::Error>>,
                         Response = Response<Resp, E>,
                         Error = io::Error> + 'static,
           Req: Deserialize + 'static,
@@ -602,7 +731,7 @@ impl<I, S, Req, Resp, E> FnOnce<(I,)> for Bind<S>
 
 impl<I, S, Req, Resp, E> FnMut<(I,)> for Bind<S>
     where I: Io + 'static,
-          S: NewService<Request = Result<Req, bincode::Error>,
+          S: NewService<Request = Result<Req, DecodeError<bincode::Error>>,
                         Response = Response<Resp, E>,
                         Error = io::Error> + 'static,
           Req: Deserialize + 'static,
@@ -616,7 +745,7 @@ impl<I, S, Req, Resp, E> FnMut<(I,)> for Bind<S>
 
 impl<I, S, Req, Resp, E> Fn<(I,)> for Bind<S>
     where I: Io + 'static,
-          S: NewService<Request = Result<Req, bincode::Error>,
+          S: NewService<Request = Result<Req, DecodeError<bincode::Error>>,
                         Response = Response<Resp, E>,
                         Error = io::Error> + 'static,
           Req: Deserialize + 'static,
@@ -119,11 +119,24 @@
 #![plugin(tarpc_plugins)]
 
 extern crate byteorder;
+extern crate crc;
+#[cfg(feature = "hdrhistogram")]
+extern crate hdrhistogram;
 #[macro_use]
 extern crate lazy_static;
 #[macro_use]
 extern crate log;
+#[cfg(feature = "lz4")]
+extern crate lz4;
 extern crate net2;
+#[cfg(feature = "encryption")]
+extern crate ring;
+#[cfg(feature = "snappy")]
+extern crate snap;
+#[cfg(unix)]
+extern crate tokio_uds;
+#[cfg(feature = "zstd")]
+extern crate zstd;
 #[macro_use]
 extern crate serde_derive;
 #[macro_use]
@@ -133,8 +146,17 @@ extern crate cfg_if;
 pub extern crate bincode;
 #[doc(hidden)]
 pub extern crate futures;
+#[cfg(feature = "msgpack")]
+#[doc(hidden)]
+pub extern crate rmp_serde;
 #[doc(hidden)]
 pub extern crate serde;
+#[cfg(feature = "cbor")]
+#[doc(hidden)]
+pub extern crate serde_cbor;
+#[cfg(feature = "json")]
+#[doc(hidden)]
+pub extern crate serde_json;
 #[doc(hidden)]
 pub extern crate tokio_core;
 #[doc(hidden)]
@@ -162,7 +184,7 @@ pub mod future;
 pub mod tls;
 /// Provides implementations of `ClientProto` and `ServerProto` that implement the tarpc protocol.
 /// The tarpc protocol is a length-delimited, bincode-serialized payload.
-mod protocol;
+pub mod protocol;
 /// Provides a few different error types.
 mod errors;
 /// Provides an abstraction over TLS and TCP streams.
@@ -403,7 +403,8 @@ macro_rules! service {
             where tarpc_service_S__: FutureService
         {
             type Request = ::std::result::Result<tarpc_service_Request__,
-                                                 $crate::bincode::Error>;
+                                                 $crate::protocol::DecodeError<
+                                                     $crate::bincode::Error>>;
             type Response = $crate::future::server::Response<tarpc_service_Response__,
                                              tarpc_service_Error__>;
             type Error = ::std::io::Error;
@@ -1323,6 +1324,31 @@ mod functional_test {
             assert_eq!(reactor.run(client.add(1, 2)).unwrap(), 3);
         }
 
+        #[test]
+        fn on_accept() {
+            use future::{client, server};
+            use future::client::ClientExt;
+            use std::io;
+            use util::FirstSocketAddr;
+            use super::{FutureClient, FutureServiceExt};
+
+            let _ = env_logger::init();
+            let mut reactor = reactor::Core::new().unwrap();
+            let options = server::Options::default().on_accept(|peer| {
+                Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                   format!("{} is not on the allowlist", peer)))
+            });
+            let (handle, server) = Server.listen("localhost:0".first_socket_addr(),
+                        &reactor.handle(),
+                        options)
+                .unwrap();
+            reactor.handle().spawn(server);
+
+            let client = FutureClient::connect(handle.addr(),
+                                               client::Options::default().handle(reactor.handle()));
+            assert!(reactor.run(client).is_err());
+        }
+
         #[cfg(feature = "tls")]
         #[test]
         fn tcp_and_tls() {
@@ -0,0 +1,177 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use serde::{Deserialize, Serialize};
+use std::{error, fmt};
+use std::io::{self, Cursor, Write};
+use super::{BincodeSerializer, Format, PayloadSerializer, ProtocolError, ProtocolErrorCode};
+use tokio_core::io::EasyBuf;
+
+/// Wraps a `PayloadSerializer` to start every payload with a one-byte type tag, and to reject
+/// the payloads whose tag isn't allowed before the wrapped serializer reads any of them.
+///
+/// A server hardened against untrusted clients can give every request type its own tag, and
+/// allow only the tags of the types it serves, so that a payload meant for another service, or
+/// crafted to exercise arbitrary decoding, never reaches the deserializer. A `Codec` fails the
+/// connection on a payload with a missing or unknown tag, telling the peer with a protocol error
+/// of code `UnknownType`; other codecs fail only the payload's own message. Both sides must wrap
+/// their serializers, each allowing the tag the other sends.
+///
+/// This is a defense in depth, not a substitute for limits: the tag only says which type the
+/// rest of the payload claims to be.
+#[derive(Clone, Debug)]
+pub struct TypeAllowlist<S = BincodeSerializer> {
+    inner: S,
+    tag: u8,
+    allowed: Vec<u8>,
+}
+
+impl<S> TypeAllowlist<S> {
+    /// Returns a serializer that serializes with `inner`, tagging the payloads it sends with
+    /// `tag`. No tags are allowed until `allow` is called.
+    pub fn new(inner: S, tag: u8) -> Self {
+        TypeAllowlist {
+            inner: inner,
+            tag: tag,
+            allowed: vec![],
+        }
+    }
+
+    /// Accept the payloads tagged `tag`.
+    pub fn allow(mut self, tag: u8) -> Self {
+        if !self.allowed.contains(&tag) {
+            self.allowed.push(tag);
+        }
+        self
+    }
+
+    /// Fails unless `payload` starts with an allowed tag.
+    fn check_tag<E>(&self, payload: &[u8]) -> Result<(), TagError<E>> {
+        match payload.first() {
+            None => Err(TagError::Missing),
+            Some(tag) if !self.allowed.contains(tag) => Err(TagError::NotAllowed(*tag)),
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+impl<S: PayloadSerializer> PayloadSerializer for TypeAllowlist<S> {
+    type Error = TagError<S::Error>;
+
+    fn serialize_into<T: Serialize>(&self, w: &mut Vec<u8>, msg: &T) -> io::Result<()> {
+        w.push(self.tag);
+        self.inner.serialize_into(w, msg)
+    }
+
+    fn serialize_to<W: Write, T: Serialize>(&self, w: &mut W, msg: &T) -> io::Result<()> {
+        w.write_all(&[self.tag])?;
+        self.inner.serialize_to(w, msg)
+    }
+
+    fn serialized_size<T: Serialize>(&self, msg: &T) -> u64 {
+        1 + self.inner.serialized_size(msg)
+    }
+
+    fn deserialize_from<T: Deserialize>(&self, r: &mut Cursor<EasyBuf>) -> Result<T, Self::Error> {
+        let position = r.position();
+        self.check_tag(r.get_ref().as_slice().get(position as usize..).unwrap_or(&[]))?;
+        r.set_position(position + 1);
+        self.inner.deserialize_from(r).map_err(TagError::Inner)
+    }
+
+    fn deserialize_slice<T: Deserialize>(&self, payload: &EasyBuf) -> Result<T, Self::Error> {
+        self.check_tag(payload.as_slice())?;
+        let mut rest = payload.clone();
+        rest.drain_to(1);
+        self.inner.deserialize_slice(&rest).map_err(TagError::Inner)
+    }
+
+    fn check_payload(&self, payload: &EasyBuf) -> io::Result<()> {
+        let message = match self.check_tag::<()>(payload.as_slice()) {
+            Ok(()) => return Ok(()),
+            Err(TagError::NotAllowed(tag)) => {
+                format!("The payload's type tag {} is not allowed", tag)
+            }
+            Err(_) => "The payload has no type tag".to_string(),
+        };
+        Err(ProtocolError::new(ProtocolErrorCode::UnknownType, message).into_io())
+    }
+
+    fn format(&self) -> Option<Format> {
+        self.inner.format()
+    }
+
+    fn with_format(self, format: Format) -> Self {
+        TypeAllowlist { inner: self.inner.with_format(format), ..self }
+    }
+}
+
+/// Why a payload couldn't be deserialized by a `TypeAllowlist`.
+#[derive(Debug)]
+pub enum TagError<E> {
+    /// The payload was empty, so it had no tag.
+    Missing,
+    /// The payload's tag wasn't allowed.
+    NotAllowed(u8),
+    /// The rest of the payload couldn't be deserialized.
+    Inner(E),
+}
+
+impl<E: fmt::Display> fmt::Display for TagError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TagError::Missing => write!(f, "The payload has no type tag"),
+            TagError::NotAllowed(tag) => write!(f, "The payload's type tag {} is not allowed", tag),
+            TagError::Inner(ref e) => fmt::Display::fmt(e, f),
+        }
+    }
+}
+
+impl<E: error::Error> error::Error for TagError<E> {
+    fn description(&self) -> &str {
+        match *self {
+            TagError::Missing => "The payload has no type tag.",
+            TagError::NotAllowed(_) => "The payload's type tag is not allowed.",
+            TagError::Inner(ref e) => e.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            TagError::Inner(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn unknown_tags() {
+    use super::Codec;
+    use tokio_core::io::Codec as TokioCodec;
+
+    let serializer = |tag| TypeAllowlist::new(BincodeSerializer::new(), tag);
+    let mut server: Codec<u32, u32, TypeAllowlist> =
+        Codec::with_serializer(2_000_000, serializer(2).allow(1));
+    let mut client: Codec<u32, u32, TypeAllowlist> =
+        Codec::with_serializer(2_000_000, serializer(1).allow(2));
+    let mut stranger: Codec<u32, u32, TypeAllowlist> =
+        Codec::with_serializer(2_000_000, serializer(3).allow(2));
+
+    let mut buf = Vec::new();
+    client.encode((1, 5), &mut buf).unwrap();
+    let mut buf = EasyBuf::from(buf);
+    let (id, request) = server.decode(&mut buf).unwrap().unwrap();
+    assert_eq!((id, request.unwrap()), (1, 5));
+    let mut buf = Vec::new();
+    server.encode((1, 6), &mut buf).unwrap();
+    let (_, response) = client.decode(&mut EasyBuf::from(buf)).unwrap().unwrap();
+    assert_eq!(response.unwrap(), 6);
+
+    // A payload with another tag fails the connection before it is deserialized.
+    let mut buf = Vec::new();
+    stranger.encode((2, 7), &mut buf).unwrap();
+    let e = server.decode(&mut EasyBuf::from(buf)).unwrap_err();
+    assert_eq!(ProtocolError::from_io(&e).unwrap().code, ProtocolErrorCode::UnknownType);
+}
@@ -0,0 +1,100 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::{fmt, io};
+use std::sync::Arc;
+
+/// A username and password, sent by a client once per connection during the handshake and
+/// checked by the server's authenticator before any request is handled.
+///
+/// Credentials are sent as a SASL PLAIN message ([RFC 4616](https://tools.ietf.org/html/rfc4616)),
+/// which carries the password in the clear: only send them over an encrypted transport, such as
+/// a TLS connection.
+#[derive(Clone, Eq, PartialEq)]
+pub struct Credentials {
+    username: String,
+    password: String,
+}
+
+impl Credentials {
+    /// Returns credentials for `username`, authenticated by `password`.
+    pub fn new<U: Into<String>, P: Into<String>>(username: U, password: P) -> Self {
+        Credentials {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    /// The user the client authenticates as.
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// The password the client authenticates with.
+    pub fn password(&self) -> &str {
+        &self.password
+    }
+
+    /// Encodes the credentials as a SASL PLAIN message with an empty authorization identity:
+    /// a NUL byte, the username, another NUL byte, and the password.
+    pub fn to_plain(&self) -> Vec<u8> {
+        let mut message = Vec::with_capacity(self.username.len() + self.password.len() + 2);
+        message.push(0);
+        message.extend_from_slice(self.username.as_bytes());
+        message.push(0);
+        message.extend_from_slice(self.password.as_bytes());
+        message
+    }
+
+    /// Decodes a SASL PLAIN message. An authorization identity, if present, must be the same as
+    /// the username: a client can't act on behalf of another user.
+    pub fn from_plain(message: &[u8]) -> io::Result<Self> {
+        let fields = message.split(|&b| b == 0).collect::<Vec<_>>();
+        if fields.len() != 3 {
+            return Err(invalid("SASL PLAIN message must have exactly three fields"));
+        }
+        let (authzid, username, password) = (fields[0], fields[1], fields[2]);
+        if username.is_empty() || password.is_empty() {
+            return Err(invalid("SASL PLAIN message is missing its username or password"));
+        }
+        if !authzid.is_empty() && authzid != username {
+            return Err(invalid("SASL PLAIN authorization identity is not the username"));
+        }
+        match (String::from_utf8(username.to_vec()), String::from_utf8(password.to_vec())) {
+            (Ok(username), Ok(password)) => Ok(Credentials::new(username, password)),
+            _ => Err(invalid("SASL PLAIN message is not valid UTF-8")),
+        }
+    }
+}
+
+/// Leaves out the password, so that credentials can be logged.
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Credentials {{ username: {:?}, .. }}", self.username)
+    }
+}
+
+/// Called by a server with the credentials of every new connection. Returning an error closes
+/// the connection.
+pub type Authenticator = Arc<Fn(&Credentials) -> io::Result<()> + Send + Sync>;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[test]
+fn plain() {
+    let credentials = Credentials::new("alice", "hunter2");
+    let message = credentials.to_plain();
+    assert_eq!(&message[..], b"\0alice\0hunter2");
+    assert_eq!(Credentials::from_plain(&message).unwrap(), credentials);
+    assert_eq!(Credentials::from_plain(b"alice\0alice\0hunter2").unwrap(), credentials);
+    assert!(!format!("{:?}", credentials).contains("hunter2"));
+
+    for bad in &[&b"\0alice"[..], b"\0\0hunter2", b"bob\0alice\0hunter2", b"\0a\0b\0c"] {
+        assert_eq!(Credentials::from_plain(bad).err().unwrap().kind(),
+                   io::ErrorKind::InvalidData);
+    }
+}
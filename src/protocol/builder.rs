@@ -0,0 +1,556 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use futures::Future;
+use super::{BincodeSerializer, CodecMetrics, CompressionOptions, Credentials, Drain, Endianness,
+            Handshake, LenWidth, Metadata, PayloadLimits, PayloadSerializer, Proto,
+            ResponseCache, ServerStats};
+#[cfg(feature = "encryption")]
+use super::EncryptionKey;
+use super::frame::FRAGMENT_HEADER_LEN;
+use super::transport::RateLimitOptions;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_core::reactor;
+use tokio_proto::streaming::multiplex::RequestId;
+
+/// Configures a `Proto`, checking that the options make sense together when it's built.
+pub struct ProtoBuilder<Encode, Decode, S = BincodeSerializer> {
+    proto: Proto<Encode, Decode, S>,
+}
+
+impl<Encode, Decode, S> ProtoBuilder<Encode, Decode, S>
+    where S: PayloadSerializer + Default
+{
+    /// Returns a new `ProtoBuilder` with a max payload size of 2,000,000 bytes (2 MB) and no
+    /// compression or checksums.
+    pub fn new() -> Self {
+        ProtoBuilder::with_serializer(S::default())
+    }
+}
+
+impl<Encode, Decode, S> ProtoBuilder<Encode, Decode, S>
+    where S: PayloadSerializer
+{
+    /// Returns a new `ProtoBuilder` whose `Proto` serializes payloads with `serializer`.
+    pub fn with_serializer(serializer: S) -> Self {
+        ProtoBuilder { proto: Proto::with_serializer(2 << 20, serializer) }
+    }
+
+    /// Set the max payload size in bytes, for both sent and received payloads.
+    pub fn max_payload_size(mut self, bytes: u64) -> Self {
+        self.proto.max_outbound = bytes;
+        self.proto.max_inbound = bytes;
+        self
+    }
+
+    /// Set the max size in bytes of sent payloads.
+    pub fn max_outbound(mut self, bytes: u64) -> Self {
+        self.proto.max_outbound = bytes;
+        self
+    }
+
+    /// Set the max size in bytes of received payloads.
+    pub fn max_inbound(mut self, bytes: u64) -> Self {
+        self.proto.max_inbound = bytes;
+        self
+    }
+
+    /// Set whether a received payload that is too big fails only its own request; see
+    /// `Proto::skip_too_big`.
+    pub fn skip_too_big(mut self, skip: bool) -> Self {
+        self.proto.skip_too_big = skip;
+        self
+    }
+
+    /// Set whether a received payload with malformed metadata or compression fails only its
+    /// own request; see `Codec::isolate_payload_errors`.
+    pub fn isolate_payload_errors(mut self, isolate: bool) -> Self {
+        self.proto.isolate_payload_errors = isolate;
+        self
+    }
+
+    /// Set whether payloads are serialized in a single pass; see `Codec::single_pass`.
+    pub fn single_pass(mut self, single_pass: bool) -> Self {
+        self.proto.single_pass = single_pass;
+        self
+    }
+
+    /// Set whether frames are written to the socket as their payloads are serialized; see
+    /// `Codec::write_through`.
+    pub fn write_through(mut self, write_through: bool) -> Self {
+        self.proto.write_through = write_through;
+        self
+    }
+
+    /// Compress payloads. Accepts either a `Compression` algorithm with its default options, or
+    /// `CompressionOptions`.
+    pub fn compression<C: Into<CompressionOptions>>(mut self, compression: C) -> Self {
+        self.proto = self.proto.compression(compression.into());
+        self
+    }
+
+    /// Decompress payloads compressed with another algorithm, or when this side doesn't
+    /// compress; see `Proto::accept_compression`.
+    pub fn accept_compression<C: Into<CompressionOptions>>(mut self, compression: C) -> Self {
+        self.proto = self.proto.accept_compression(compression.into());
+        self
+    }
+
+    /// Set whether payloads are followed by their CRC32.
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.proto = self.proto.checksum(checksum);
+        self
+    }
+
+    /// Checksum only payloads of at least `bytes` bytes; see `Codec::checksum_threshold`.
+    pub fn checksum_threshold(mut self, bytes: u64) -> Self {
+        self.proto = self.proto.checksum_threshold(bytes);
+        self
+    }
+
+    /// Set whether every frame starts with a marker; see `Codec::frame_markers`.
+    pub fn frame_markers(mut self, frame_markers: bool) -> Self {
+        self.proto = self.proto.frame_markers(frame_markers);
+        self
+    }
+
+    /// Check payloads against limits that can be tightened while connections are open; see
+    /// `PayloadLimits`.
+    pub fn payload_limits(mut self, limits: PayloadLimits) -> Self {
+        self.proto = self.proto.payload_limits(limits);
+        self
+    }
+
+    /// Send and receive payloads too big for one frame in fragments; see
+    /// `Codec::fragmentation`.
+    pub fn fragmentation(mut self, max_reassembled_size: u64) -> Self {
+        self.proto = self.proto.fragmentation(max_reassembled_size);
+        self
+    }
+
+    /// Capture the bytes connections receive to the sinks `sinks` returns; see
+    /// `Proto::capture`.
+    #[cfg(feature = "capture")]
+    pub fn capture<F>(mut self, sinks: F) -> Self
+        where F: Fn(u64) -> Option<Box<io::Write + Send>> + Send + Sync + 'static
+    {
+        self.proto = self.proto.capture(sinks);
+        self
+    }
+
+    /// Set the width of the length prefix.
+    pub fn len_width(mut self, width: LenWidth) -> Self {
+        self.proto = self.proto.len_width(width);
+        self
+    }
+
+    /// Set the byte order of the id, length, and checksum fields.
+    pub fn endianness(mut self, endianness: Endianness) -> Self {
+        self.proto = self.proto.endianness(endianness);
+        self
+    }
+
+    /// Set the range of frame format versions this side supports.
+    pub fn supported_versions(mut self, min_version: u32, max_version: u32) -> Self {
+        self.proto.handshake.min_version = min_version;
+        self.proto.handshake.max_version = max_version;
+        self
+    }
+
+    /// Set the identifier of the service's schema; see `Proto::schema`.
+    pub fn schema(mut self, schema: u64) -> Self {
+        self.proto.handshake.schema = schema;
+        self
+    }
+
+    /// Limit the number of requests per connection awaiting a response; see
+    /// `Proto::max_in_flight`.
+    pub fn max_in_flight(mut self, requests: usize) -> Self {
+        self.proto.max_in_flight = Some(requests);
+        self
+    }
+
+    /// Limit the encoded bytes waiting to be written to each connection; see
+    /// `Proto::high_water_mark`.
+    pub fn high_water_mark(mut self, bytes: usize) -> Self {
+        self.proto.high_water_mark = Some(bytes);
+        self
+    }
+
+    /// Limit the frames read from a connection before yielding to the others; see
+    /// `Proto::max_frames_per_poll`.
+    pub fn max_frames_per_poll(mut self, frames: usize) -> Self {
+        self.proto.max_frames_per_poll = Some(frames);
+        self
+    }
+
+    /// Stop reading from a connection while it has a backlog of complete frames; see
+    /// `Proto::max_backlog`.
+    pub fn max_backlog(mut self, frames: usize) -> Self {
+        self.proto.max_backlog = Some(frames);
+        self
+    }
+
+    /// Limit the bytes held for each connection; see `Proto::max_buffered_bytes`.
+    pub fn max_buffered_bytes(mut self, bytes: usize) -> Self {
+        self.proto.max_buffered = Some(bytes);
+        self
+    }
+
+    /// Make room for the rest of a payload before reading it; see
+    /// `Proto::read_whole_payloads`.
+    pub fn read_whole_payloads(mut self, bytes: usize) -> Self {
+        self.proto.read_whole_payloads = Some(bytes);
+        self
+    }
+
+    /// Prioritize requests by `priority`; see `Proto::priority`.
+    pub fn priority<F>(mut self, priority: F) -> Self
+        where F: Fn(&Encode) -> u8 + Send + Sync + 'static
+    {
+        self.proto = self.proto.priority(priority);
+        self
+    }
+
+    /// Send each request with the id `allocate` returns; see `Proto::request_ids`.
+    pub fn request_ids<F>(mut self, allocate: F) -> Self
+        where F: Fn() -> RequestId + Send + Sync + 'static
+    {
+        self.proto = self.proto.request_ids(allocate);
+        self
+    }
+
+    /// Send every request with its sequence number on the connection; see
+    /// `Proto::sequence_numbers`.
+    pub fn sequence_numbers(mut self, sequence_numbers: bool) -> Self {
+        self.proto = self.proto.sequence_numbers(sequence_numbers);
+        self
+    }
+
+    /// Send every message with the metadata `metadata` returns for it; see `Proto::metadata`.
+    pub fn metadata<F>(mut self, metadata: F) -> Self
+        where F: Fn(&Encode) -> Metadata + Send + Sync + 'static
+    {
+        self.proto = self.proto.metadata(metadata);
+        self
+    }
+
+    /// Call `hook` with the metadata of every frame received; see `Proto::on_metadata`.
+    pub fn on_metadata<F>(mut self, hook: F) -> Self
+        where F: Fn(RequestId, &Metadata) + Send + Sync + 'static
+    {
+        self.proto = self.proto.on_metadata(hook);
+        self
+    }
+
+    /// Reject the requests `screen` returns a reason for before deserializing them; see
+    /// `Proto::screen_requests`.
+    pub fn screen_requests<F>(mut self, screen: F) -> Self
+        where F: Fn(RequestId, u64, &Metadata) -> Result<(), String> + Send + Sync + 'static
+    {
+        self.proto = self.proto.screen_requests(screen);
+        self
+    }
+
+    /// Acknowledge every request once `persist` has persisted it; see `Proto::acknowledge`.
+    pub fn acknowledge<F>(mut self, persist: F) -> Self
+        where F: Fn(&Decode) -> Box<Future<Item = (), Error = io::Error>> + Send + Sync + 'static
+    {
+        self.proto = self.proto.acknowledge(persist);
+        self
+    }
+
+    /// Answer retried requests from `cache`; see `Proto::response_cache`.
+    pub fn response_cache(mut self, cache: ResponseCache) -> Self {
+        self.proto = self.proto.response_cache(cache);
+        self
+    }
+
+    /// Encrypt payloads with `key`; see `Proto::encryption`.
+    #[cfg(feature = "encryption")]
+    pub fn encryption(mut self, key: Arc<EncryptionKey>) -> Self {
+        self.proto = self.proto.encryption(key);
+        self
+    }
+
+    /// Set the capacity reserved for each connection's buffers; see `Proto::buffer_capacity`.
+    pub fn buffer_capacity(mut self, bytes: usize) -> Self {
+        self.proto.buffer_capacity = Some(bytes);
+        self
+    }
+
+    /// Limit the rate at which requests are read from each connection; see
+    /// `Proto::rate_limit`.
+    pub fn rate_limit(mut self, handle: &reactor::Handle, per_second: u32, burst: u32) -> Self {
+        self.proto.rate_limit = Some(RateLimitOptions {
+            remote: handle.remote().clone(),
+            per_second: per_second,
+            burst: burst,
+        });
+        self
+    }
+
+    /// Limit the rate at which frames are decoded from each connection; see
+    /// `Proto::max_frames_per_second`.
+    pub fn max_frames_per_second(mut self,
+                                 handle: &reactor::Handle,
+                                 per_second: u32,
+                                 burst: u32)
+                                 -> Self {
+        self.proto.frame_rate = Some(RateLimitOptions {
+            remote: handle.remote().clone(),
+            per_second: per_second,
+            burst: burst,
+        });
+        self
+    }
+
+    /// Send heartbeats from clients; see `Proto::heartbeat`.
+    pub fn heartbeat(mut self,
+                     handle: &reactor::Handle,
+                     interval: Duration,
+                     timeout: Duration)
+                     -> Self {
+        self.proto = self.proto.heartbeat(handle, interval, timeout);
+        self
+    }
+
+    /// Give every request a deadline; see `Proto::request_timeout`.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.proto.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Report the frames of every connection to `metrics`; see `Proto::metrics`.
+    pub fn metrics(mut self, metrics: Arc<CodecMetrics>) -> Self {
+        self.proto.metrics = Some(metrics);
+        self
+    }
+
+    /// Count every connection in `stats`, and answer the stats queries of the clients that
+    /// `authorize` allows; see `Proto::diagnostics`.
+    pub fn diagnostics<F>(mut self, stats: ServerStats, authorize: F) -> Self
+        where F: Fn(Option<&str>) -> bool + Send + Sync + 'static
+    {
+        self.proto = self.proto.diagnostics(stats, authorize);
+        self
+    }
+
+    /// Close connections gracefully once `drain` starts; see `Proto::drain`.
+    pub fn drain(mut self, drain: Drain) -> Self {
+        self.proto.drain = Some(drain);
+        self
+    }
+
+    /// Time out frames that make no progress; see `Proto::idle_timeouts`.
+    pub fn idle_timeouts(mut self,
+                         handle: &reactor::Handle,
+                         read: Duration,
+                         write: Duration)
+                         -> Self {
+        self.proto = self.proto.idle_timeouts(handle, read, write);
+        self
+    }
+
+    /// Close connections that go `timeout` without a frame; see `Proto::reap_idle`.
+    pub fn reap_idle(mut self, handle: &reactor::Handle, timeout: Duration) -> Self {
+        self.proto = self.proto.reap_idle(handle, timeout);
+        self
+    }
+
+    /// Hold frames back to write them together; see `Proto::flush_delay`.
+    pub fn flush_delay(mut self,
+                       handle: &reactor::Handle,
+                       delay: Duration,
+                       threshold: usize)
+                       -> Self {
+        self.proto = self.proto.flush_delay(handle, delay, threshold);
+        self
+    }
+
+    /// Abandon fragmented payloads that take longer than `timeout` to arrive; see
+    /// `Codec::max_reassembly_time`.
+    pub fn max_reassembly_time(mut self, timeout: Duration) -> Self {
+        self.proto = self.proto.max_reassembly_time(timeout);
+        self
+    }
+
+    /// Close connections whose frame headers arrive too slowly; see `Codec::max_header_wait`.
+    pub fn max_header_wait(mut self, wait: Duration) -> Self {
+        self.proto.max_header_wait = Some(wait);
+        self
+    }
+
+    /// Fail requests whose responses take longer than `timeout`; see `Proto::response_timeout`.
+    pub fn response_timeout(mut self, handle: &reactor::Handle, timeout: Duration) -> Self {
+        self.proto = self.proto.response_timeout(handle, timeout);
+        self
+    }
+
+    /// Wait forever for the responses to requests; see `Proto::no_response_timeout`.
+    pub fn no_response_timeout(mut self) -> Self {
+        self.proto = self.proto.no_response_timeout();
+        self
+    }
+
+    /// Call `hook` with the parameters negotiated for every new connection.
+    pub fn on_handshake<F>(mut self, hook: F) -> Self
+        where F: Fn(&Handshake) -> io::Result<()> + Send + Sync + 'static
+    {
+        self.proto.handshake.hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Send `credentials` to the server; see `Proto::credentials`.
+    pub fn credentials(mut self, credentials: Credentials) -> Self {
+        self.proto = self.proto.credentials(credentials);
+        self
+    }
+
+    /// Require clients to send credentials accepted by `authenticator`; see
+    /// `Proto::authenticate`.
+    pub fn authenticate<F>(mut self, authenticator: F) -> Self
+        where F: Fn(&Credentials) -> io::Result<()> + Send + Sync + 'static
+    {
+        self.proto = self.proto.authenticate(authenticator);
+        self
+    }
+
+    /// Returns the configured `Proto`, or an error of kind `InvalidInput` if the options
+    /// contradict each other.
+    pub fn build(self) -> io::Result<Proto<Encode, Decode, S>> {
+        {
+            let proto = &self.proto;
+            let handshake = &proto.handshake;
+            if handshake.min_version > handshake.max_version {
+                return Err(invalid(format!("min_version {} is greater than max_version {}",
+                                           handshake.min_version,
+                                           handshake.max_version)));
+            }
+            if proto.max_in_flight == Some(0) {
+                return Err(invalid("max_in_flight must be at least 1".to_string()));
+            }
+            if proto.high_water_mark == Some(0) {
+                return Err(invalid("The high-water mark must be at least 1 byte".to_string()));
+            }
+            if proto.max_frames_per_poll == Some(0) {
+                return Err(invalid("max_frames_per_poll must be at least 1".to_string()));
+            }
+            if proto.max_backlog == Some(0) {
+                return Err(invalid("max_backlog must be at least 1".to_string()));
+            }
+            if proto.read_whole_payloads == Some(0) {
+                return Err(invalid("read_whole_payloads must be at least 1 byte".to_string()));
+            }
+            if let Some(bytes) = proto.max_buffered {
+                if (bytes as u64) < proto.max_inbound {
+                    return Err(invalid(format!("max_buffered_bytes of {} is less than the max \
+                                                payload size of {} bytes",
+                                               bytes,
+                                               proto.max_inbound)));
+                }
+            }
+            if let Some(ref rate_limit) = proto.rate_limit {
+                if rate_limit.per_second == 0 || rate_limit.burst == 0 {
+                    return Err(invalid("The rate limit and burst must be at least 1"
+                        .to_string()));
+                }
+            }
+            if let Some(ref frame_rate) = proto.frame_rate {
+                if frame_rate.per_second == 0 || frame_rate.burst == 0 {
+                    return Err(invalid("The frame rate and burst must be at least 1"
+                        .to_string()));
+                }
+            }
+            if let Some(ref heartbeat) = proto.heartbeat {
+                if heartbeat.interval == Duration::from_secs(0) ||
+                   heartbeat.timeout == Duration::from_secs(0) {
+                    return Err(invalid("Heartbeat interval and timeout must be nonzero"
+                        .to_string()));
+                }
+            }
+            if let Some(ref timeouts) = proto.idle_timeouts {
+                if timeouts.read == Duration::from_secs(0) ||
+                   timeouts.write == Duration::from_secs(0) {
+                    return Err(invalid("Idle timeouts must be nonzero".to_string()));
+                }
+            }
+            if let Some(ref reaper) = proto.reap_idle {
+                if reaper.timeout == Duration::from_secs(0) {
+                    return Err(invalid("The idle connection timeout must be nonzero"
+                        .to_string()));
+                }
+            }
+            if let Some(ref delay) = proto.flush_delay {
+                if delay.delay == Duration::from_secs(0) || delay.threshold == 0 {
+                    return Err(invalid("The flush delay and its threshold must be nonzero"
+                        .to_string()));
+                }
+            }
+            if proto.max_reassembly_time == Some(Duration::from_secs(0)) {
+                return Err(invalid("The max reassembly time must be nonzero".to_string()));
+            }
+            if let Some(ref timeout) = proto.response_timeout {
+                if timeout.timeout == Duration::from_secs(0) {
+                    return Err(invalid("The response timeout must be nonzero".to_string()));
+                }
+            }
+            if proto.frame.fragments && proto.max_outbound <= FRAGMENT_HEADER_LEN {
+                return Err(invalid(format!("The max payload size of {} bytes leaves no room for \
+                                            fragments",
+                                           proto.max_outbound)));
+            }
+            if let Some(ref compression) = proto.frame.compression {
+                if !compression.compresses(proto.max_outbound) {
+                    return Err(invalid(format!("No payload will be compressed: the compression \
+                                                threshold is at least the max payload size of \
+                                                {} bytes",
+                                               proto.max_outbound)));
+                }
+            }
+        }
+        Ok(self.proto)
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[test]
+#[cfg(feature = "zstd")]
+fn build() {
+    use super::Compression;
+
+    let proto: io::Result<Proto<(), ()>> = ProtoBuilder::new()
+        .max_payload_size(1 << 10)
+        .checksum(true)
+        .compression(Compression::Zstd)
+        .build();
+    assert!(proto.is_ok());
+}
+
+#[test]
+fn build_invalid() {
+    let no_versions: io::Result<Proto<(), ()>> = ProtoBuilder::new()
+        .supported_versions(2, 1)
+        .build();
+    assert_eq!(no_versions.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+#[cfg(feature = "zstd")]
+fn build_invalid_compression() {
+    use super::Compression;
+
+    let threshold_too_big: io::Result<Proto<(), ()>> = ProtoBuilder::new()
+        .max_payload_size(256)
+        .compression(CompressionOptions::new(Compression::Zstd).threshold(1 << 10))
+        .build();
+    assert_eq!(threshold_too_big.err().unwrap().kind(),
+               io::ErrorKind::InvalidInput);
+}
@@ -0,0 +1,83 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::io;
+use std::sync::Arc;
+
+/// Returns the sink to capture the bytes received on connection `connection_id` to, if any.
+pub type CaptureSinks = Arc<Fn(u64) -> Option<Box<io::Write + Send>> + Send + Sync>;
+
+cfg_if! {
+    if #[cfg(feature = "capture")] {
+        use std::cmp;
+        use std::io::Write;
+
+        /// Copies the bytes a `Codec` decodes to a sink, in the order they were received, so
+        /// that they can be replayed through another `Codec`.
+        pub struct Capture {
+            sink: Option<Box<Write + Send>>,
+            /// The bytes at the start of the read buffer that were already copied.
+            copied: usize,
+        }
+
+        impl Default for Capture {
+            fn default() -> Self {
+                Capture::new(None)
+            }
+        }
+
+        impl Capture {
+            /// Returns a `Capture` that copies to `sink`, or that does nothing if it's `None`.
+            pub fn new(sink: Option<Box<Write + Send>>) -> Self {
+                Capture {
+                    sink: sink,
+                    copied: 0,
+                }
+            }
+
+            /// Copies the bytes at the end of `buf` that arrived since `decode` last returned.
+            /// A sink that fails is dropped, so that capturing never fails the connection.
+            pub fn received(&mut self, connection_id: u64, buf: &[u8]) {
+                let failed = match self.sink {
+                    Some(ref mut sink) => {
+                        let start = cmp::min(self.copied, buf.len());
+                        sink.write_all(&buf[start..]).err()
+                    }
+                    None => return,
+                };
+                self.copied = buf.len();
+                if let Some(e) = failed {
+                    warn!("Connection {}: Stopped capturing the bytes received: {}",
+                          connection_id,
+                          e);
+                    self.sink = None;
+                }
+            }
+
+            /// Records that `decode` left `unparsed` bytes of the read buffer, which were
+            /// already copied.
+            pub fn consumed(&mut self, unparsed: usize) {
+                self.copied = unparsed;
+            }
+        }
+    } else {
+        /// Stands in for the capture of the `capture` feature, which isn't enabled; it copies
+        /// nothing.
+        #[derive(Default)]
+        pub struct Capture;
+
+        impl Capture {
+            pub fn new(_sink: Option<Box<io::Write + Send>>) -> Self {
+                Capture
+            }
+
+            #[inline]
+            pub fn received(&mut self, _connection_id: u64, _buf: &[u8]) {}
+
+            #[inline]
+            pub fn consumed(&mut self, _unparsed: usize) {}
+        }
+    }
+}
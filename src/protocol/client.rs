@@ -0,0 +1,388 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use futures::{Async, Future, Poll};
+use futures::sync::oneshot;
+use serde;
+use std::error::Error as StdError;
+use std::{fmt, io};
+use std::time::Duration;
+use super::{AckSlots, BincodeSerializer, DecodeError, PayloadSerializer, PingFuture, Proto,
+            StatsFuture};
+use super::diagnostics::{self, StatsQueries};
+use super::ping::{self, Pings};
+use tokio_core::io::Io;
+use tokio_core::reactor;
+use tokio_proto::BindClient;
+use tokio_proto::multiplex::ClientService;
+use tokio_service::Service;
+
+/// A connection to a server bound with a `Proto`, on which requests can be made concurrently.
+///
+/// Each call is assigned a request id, and resolves when the response with the same id arrives,
+/// in whatever order the server answers. Cloning a `Client` shares the connection.
+pub struct Client<T, Encode, Decode, S = BincodeSerializer>
+    where T: Io + 'static,
+          Encode: serde::Serialize + 'static,
+          Decode: serde::Deserialize + 'static,
+          S: PayloadSerializer + Clone + 'static,
+          S::Error: 'static
+{
+    inner: ClientService<T, Proto<Encode, Decode, S>>,
+    acks: AckSlots,
+    pings: Pings,
+    stats_queries: StatsQueries,
+}
+
+impl<T, Encode, Decode, S> Client<T, Encode, Decode, S>
+    where T: Io + 'static,
+          Encode: serde::Serialize + 'static,
+          Decode: serde::Deserialize + 'static,
+          S: PayloadSerializer + Clone + 'static,
+          S::Error: 'static
+{
+    /// Binds `proto` to `io` on the reactor of `handle`, running its default response timeout
+    /// there; see `Proto::default_timeouts`. The handshake runs in the background; if it fails,
+    /// so do the calls.
+    pub fn new(handle: &reactor::Handle, io: T, proto: &Proto<Encode, Decode, S>) -> Self {
+        let acks = AckSlots::default();
+        let pings = Pings::default();
+        let stats_queries = StatsQueries::default();
+        let mut proto = proto.clone().default_timeouts(handle);
+        proto.ack_slots = Some(acks.clone());
+        proto.pings = Some(pings.clone());
+        proto.stats_queries = Some(stats_queries.clone());
+        Client {
+            inner: proto.bind_client(handle, io),
+            acks: acks,
+            pings: pings,
+            stats_queries: stats_queries,
+        }
+    }
+
+    /// Sends a ping, returning a future of the time the server takes to echo it, on
+    /// connections that negotiate `PING_VERSION` or newer. A ping made right after connecting
+    /// is sent once the handshake completes, so it measures the connection before any
+    /// requests are. It fails if the server predates pings, or the connection closes first.
+    pub fn ping(&self) -> PingFuture {
+        ping::request(&self.pings)
+    }
+
+    /// The round-trip time measured last, by a ping or, if the `Proto` sends heartbeats, by
+    /// the last heartbeat echoed; `None` until one is. A pool can use it to prefer the
+    /// connections to nearby servers.
+    pub fn rtt(&self) -> Option<Duration> {
+        ping::rtt(&self.pings)
+    }
+
+    /// Queries the stats of the server, returning a future of them, on connections that
+    /// negotiate `DIAGNOSTICS_VERSION` or newer; see `Proto::diagnostics`. It fails with an
+    /// error of kind `PermissionDenied` if the server doesn't let the client query them, or
+    /// doesn't answer stats queries, and of kind `Other` if the server predates them, or the
+    /// connection closes first.
+    pub fn server_stats(&self) -> StatsFuture {
+        diagnostics::request(&self.stats_queries)
+    }
+
+    /// Sends `request`, returning a future of the response.
+    pub fn call(&self, request: Encode) -> ResponseFuture<T, Encode, Decode, S> {
+        self.send(request, None)
+    }
+
+    /// Like `call`, but also returns a future that resolves once the server acknowledges the
+    /// request; see `Proto::acknowledge`. It fails if the response arrives, or the connection
+    /// closes, without the request being acknowledged, as it does if the server doesn't
+    /// acknowledge requests.
+    pub fn call_acked(&self,
+                      request: Encode)
+                      -> (AckFuture, ResponseFuture<T, Encode, Decode, S>) {
+        let (tx, rx) = oneshot::channel();
+        let response = self.send(request, Some(tx));
+        (AckFuture { inner: rx }, response)
+    }
+
+    fn send(&self,
+            request: Encode,
+            ack: Option<oneshot::Sender<()>>)
+            -> ResponseFuture<T, Encode, Decode, S> {
+        // Held while the request is queued, so that the slots stay in the order of the requests.
+        let mut acks = self.acks.lock().unwrap();
+        acks.push_back(ack);
+        ResponseFuture { inner: self.inner.call(request) }
+    }
+}
+
+impl<T, Encode, Decode, S> Clone for Client<T, Encode, Decode, S>
+    where T: Io + 'static,
+          Encode: serde::Serialize + 'static,
+          Decode: serde::Deserialize + 'static,
+          S: PayloadSerializer + Clone + 'static,
+          S::Error: 'static
+{
+    fn clone(&self) -> Self {
+        Client {
+            inner: self.inner.clone(),
+            acks: self.acks.clone(),
+            pings: self.pings.clone(),
+            stats_queries: self.stats_queries.clone(),
+        }
+    }
+}
+
+impl<T, Encode, Decode, S> fmt::Debug for Client<T, Encode, Decode, S>
+    where T: Io + 'static,
+          Encode: serde::Serialize + 'static,
+          Decode: serde::Deserialize + 'static,
+          S: PayloadSerializer + Clone + 'static,
+          S::Error: 'static
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Client {{ .. }}")
+    }
+}
+
+/// A future that resolves to the response to a `Client::call`.
+///
+/// A response that can't be decoded fails with the `DecodeError` converted by
+/// `DecodeError::into_io`.
+pub struct ResponseFuture<T, Encode, Decode, S = BincodeSerializer>
+    where T: Io + 'static,
+          Encode: serde::Serialize + 'static,
+          Decode: serde::Deserialize + 'static,
+          S: PayloadSerializer + Clone + 'static,
+          S::Error: 'static
+{
+    inner: <ClientService<T, Proto<Encode, Decode, S>> as Service>::Future,
+}
+
+impl<T, Encode, Decode, S> Future for ResponseFuture<T, Encode, Decode, S>
+    where T: Io + 'static,
+          Encode: serde::Serialize + 'static,
+          Decode: serde::Deserialize + 'static,
+          S: PayloadSerializer + Clone + 'static,
+          S::Error: StdError + Send + Sync + 'static
+{
+    type Item = Decode;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Decode, io::Error> {
+        match self.inner.poll()? {
+            Async::Ready(response) => response.map(Async::Ready).map_err(DecodeError::into_io),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// A future that resolves once the server acknowledges a request sent with `Client::call_acked`.
+pub struct AckFuture {
+    inner: oneshot::Receiver<()>,
+}
+
+impl Future for AckFuture {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll().map_err(|_| {
+            io::Error::new(io::ErrorKind::Other, "The request was not acknowledged")
+        })
+    }
+}
+
+impl fmt::Debug for AckFuture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AckFuture {{ .. }}")
+    }
+}
+
+#[test]
+fn concurrent_calls() {
+    use bincode;
+    use futures::future;
+    use super::in_memory;
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+
+    struct Double;
+
+    impl Service for Double {
+        type Request = Result<u32, DecodeError<bincode::Error>>;
+        type Response = u32;
+        type Error = io::Error;
+        type Future = future::FutureResult<u32, io::Error>;
+
+        fn call(&self, request: Self::Request) -> Self::Future {
+            future::result(request.map(|n| n * 2).map_err(DecodeError::into_io))
+        }
+    }
+
+    let mut core = Core::new().unwrap();
+    let (client_io, server_io) = in_memory();
+    let proto: Proto<u32, u32> = Proto::new(2_000_000);
+    proto.bind_server(&core.handle(), server_io, Double);
+    let client = Client::new(&core.handle(), client_io, &proto);
+
+    let calls = (0..10).map(|n| client.call(n)).collect::<Vec<_>>();
+    let responses = core.run(future::join_all(calls)).unwrap();
+    assert_eq!(responses, (0..10).map(|n| n * 2).collect::<Vec<_>>());
+}
+
+#[test]
+fn acked_calls() {
+    use bincode;
+    use futures::future;
+    use super::in_memory;
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+
+    struct Double;
+
+    impl Service for Double {
+        type Request = Result<u32, DecodeError<bincode::Error>>;
+        type Response = u32;
+        type Error = io::Error;
+        type Future = future::FutureResult<u32, io::Error>;
+
+        fn call(&self, request: Self::Request) -> Self::Future {
+            future::result(request.map(|n| n * 2).map_err(DecodeError::into_io))
+        }
+    }
+
+    let mut core = Core::new().unwrap();
+    let proto: Proto<u32, u32> = Proto::new(2_000_000);
+
+    let (client_io, server_io) = in_memory();
+    proto.clone()
+        .acknowledge(|_| Box::new(future::ok(())))
+        .bind_server(&core.handle(), server_io, Double);
+    let client = Client::new(&core.handle(), client_io, &proto);
+    // Plain calls in between don't throw off which request each acknowledgement is for.
+    let unacked = client.call(1);
+    let (ack, response) = client.call_acked(2);
+    let (acked, response) = core.run(ack.join(response)).unwrap();
+    assert_eq!(acked, ());
+    assert_eq!(response, 4);
+    assert_eq!(core.run(unacked).unwrap(), 2);
+
+    // A server that doesn't acknowledge requests still answers them.
+    let (client_io, server_io) = in_memory();
+    proto.bind_server(&core.handle(), server_io, Double);
+    let client = Client::new(&core.handle(), client_io, &proto);
+    let (ack, response) = client.call_acked(3);
+    assert_eq!(core.run(response).unwrap(), 6);
+    let e = core.run(ack).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::Other);
+}
+
+#[test]
+fn ping() {
+    use bincode;
+    use futures::future;
+    use super::{PING_VERSION, in_memory};
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = Result<u32, DecodeError<bincode::Error>>;
+        type Response = u32;
+        type Error = io::Error;
+        type Future = future::FutureResult<u32, io::Error>;
+
+        fn call(&self, request: Self::Request) -> Self::Future {
+            future::result(request.map_err(DecodeError::into_io))
+        }
+    }
+
+    let mut core = Core::new().unwrap();
+    let proto: Proto<u32, u32> = Proto::new(2_000_000);
+
+    let (client_io, server_io) = in_memory();
+    proto.bind_server(&core.handle(), server_io, Echo);
+    let client = Client::new(&core.handle(), client_io, &proto);
+    assert_eq!(client.rtt(), None);
+    let rtt = core.run(client.ping()).unwrap();
+    assert_eq!(client.rtt(), Some(rtt));
+    assert_eq!(core.run(client.call(5)).unwrap(), 5);
+
+    // A server that predates pings fails them.
+    let (client_io, server_io) = in_memory();
+    proto.clone()
+        .supported_versions(1, PING_VERSION - 1)
+        .bind_server(&core.handle(), server_io, Echo);
+    let client = Client::new(&core.handle(), client_io, &proto);
+    let e = core.run(client.ping()).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::Other);
+    assert_eq!(core.run(client.call(6)).unwrap(), 6);
+}
+
+#[test]
+fn server_stats() {
+    use bincode;
+    use futures::future;
+    use super::{Credentials, DIAGNOSTICS_VERSION, ServerStats, in_memory};
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = Result<u32, DecodeError<bincode::Error>>;
+        type Response = u32;
+        type Error = io::Error;
+        type Future = future::FutureResult<u32, io::Error>;
+
+        fn call(&self, request: Self::Request) -> Self::Future {
+            future::result(request.map_err(DecodeError::into_io))
+        }
+    }
+
+    let mut core = Core::new().unwrap();
+    let proto: Proto<u32, u32> = Proto::new(2_000_000);
+    // The third request on a connection is rejected, so it is never responded to.
+    let server_proto = proto.clone()
+        .authenticate(|_| Ok(()))
+        .screen_requests(|id, _, _| if id == 2 { Err("Third".to_string()) } else { Ok(()) })
+        .diagnostics(ServerStats::new(), |user| user == Some("admin"));
+
+    let (client_io, server_io) = in_memory();
+    server_proto.bind_server(&core.handle(), server_io, Echo);
+    let client = Client::new(&core.handle(),
+                             client_io,
+                             &proto.clone().credentials(Credentials::new("admin", "secret")));
+    assert_eq!(core.run(client.call(5)).unwrap(), 5);
+    assert_eq!(core.run(client.call(6)).unwrap(), 6);
+    assert!(core.run(client.call(7)).is_err());
+    let stats = core.run(client.server_stats()).unwrap();
+    assert_eq!(stats.connections, 1);
+    assert_eq!(stats.requests, 3);
+    assert_eq!(stats.in_flight, 0);
+    assert!(stats.bytes_received > 0 && stats.bytes_sent > 0);
+
+    // Other users aren't told the stats.
+    let (client_io, server_io) = in_memory();
+    server_proto.bind_server(&core.handle(), server_io, Echo);
+    let client = Client::new(&core.handle(),
+                             client_io,
+                             &proto.clone().credentials(Credentials::new("guest", "secret")));
+    let e = core.run(client.server_stats()).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    assert_eq!(core.run(client.call(7)).unwrap(), 7);
+
+    // Nor are the clients of servers without diagnostics, or that predate them.
+    let (client_io, server_io) = in_memory();
+    proto.bind_server(&core.handle(), server_io, Echo);
+    let client = Client::new(&core.handle(), client_io, &proto);
+    let e = core.run(client.server_stats()).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    let (client_io, server_io) = in_memory();
+    proto.clone()
+        .supported_versions(1, DIAGNOSTICS_VERSION - 1)
+        .bind_server(&core.handle(), server_io, Echo);
+    let client = Client::new(&core.handle(), client_io, &proto);
+    let e = core.run(client.server_stats()).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::Other);
+}
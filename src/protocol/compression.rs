@@ -0,0 +1,276 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+#[cfg(feature = "lz4")]
+use byteorder::{ByteOrder, LittleEndian};
+#[cfg(feature = "lz4")]
+use lz4;
+#[cfg(feature = "snappy")]
+use snap;
+use std::io;
+#[cfg(feature = "zstd")]
+use std::io::Read;
+#[cfg(feature = "zstd")]
+use zstd;
+
+/// The zstd compression level used for payloads. Level 3 is zstd's own default.
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+/// A payload compression algorithm. Each is behind the feature of the same name, so that
+/// builds only link the compressors they use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// [Zstandard](http://facebook.github.io/zstd/) compression.
+    #[cfg(feature = "zstd")]
+    Zstd,
+    /// [Snappy](https://google.github.io/snappy/) compression, which compresses less than zstd
+    /// but costs far less CPU.
+    #[cfg(feature = "snappy")]
+    Snappy,
+    /// [LZ4](http://lz4.github.io/lz4/) compression in the block format, which compresses
+    /// about as well as snappy and inflates faster.
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+/// The number that stands for `compression` in the handshake. It doesn't depend on the
+/// features enabled, so peers built with different compressors still understand each other.
+pub fn code(compression: Compression) -> u32 {
+    match compression {
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => 0,
+        #[cfg(feature = "snappy")]
+        Compression::Snappy => 1,
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => 2,
+    }
+}
+
+/// The algorithm that `code` stands for, if this build supports it.
+pub fn from_code(code: u32) -> Option<Compression> {
+    match code {
+        #[cfg(feature = "zstd")]
+        0 => Some(Compression::Zstd),
+        #[cfg(feature = "snappy")]
+        1 => Some(Compression::Snappy),
+        #[cfg(feature = "lz4")]
+        2 => Some(Compression::Lz4),
+        _ => None,
+    }
+}
+
+/// Configures how payloads are compressed.
+///
+/// When compression is enabled, every frame carries a flags byte after its id that records
+/// whether its payload is compressed, so both peers must enable it. Each side compresses with
+/// its own algorithm, which the other must be able to decompress; a `Proto` only compresses
+/// with an algorithm the peer said it decompresses in the handshake.
+#[derive(Clone, Copy, Debug)]
+pub struct CompressionOptions {
+    compression: Compression,
+    threshold: u64,
+    max_decompressed_size: u64,
+}
+
+impl CompressionOptions {
+    /// Returns options that compress payloads with the given algorithm.
+    pub fn new(compression: Compression) -> Self {
+        CompressionOptions {
+            compression: compression,
+            threshold: 512,
+            max_decompressed_size: 2 << 20,
+        }
+    }
+
+    /// Only compress payloads larger than `bytes`; smaller payloads are sent as-is. The default
+    /// is 512 bytes.
+    pub fn threshold(mut self, bytes: u64) -> Self {
+        self.threshold = bytes;
+        self
+    }
+
+    /// Set the max size in bytes that a payload may inflate to when decompressed. This guards
+    /// against decompression bombs. The default is 2,000,000 (2 MB).
+    pub fn max_decompressed_size(mut self, bytes: u64) -> Self {
+        self.max_decompressed_size = bytes;
+        self
+    }
+
+    /// The compression algorithm.
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// True if a payload of `payload_size` bytes is large enough to be compressed.
+    pub fn compresses(&self, payload_size: u64) -> bool {
+        payload_size > self.threshold
+    }
+
+    /// Compresses a serialized payload, failing if it is larger than `max_decompressed_size`,
+    /// since the peer would refuse to inflate it.
+    pub fn compress(&self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        if payload.len() as u64 > self.max_decompressed_size {
+            return Err(too_big_decompressed(self.max_decompressed_size));
+        }
+        match self.compression {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::stream::encode_all(payload, ZSTD_LEVEL),
+            #[cfg(feature = "snappy")]
+            Compression::Snappy => Ok(snap::Encoder::new().compress_vec(payload)?),
+            // The block is prefixed with its decompressed length, for `decompress` to check.
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => lz4::block::compress(payload, None, true),
+        }
+    }
+
+    /// Decompresses a payload, failing if it inflates to more than `max_decompressed_size` bytes.
+    pub fn decompress(&self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        match self.compression {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                // Read at most one byte past the limit; that's enough to know it was exceeded
+                // without inflating the rest.
+                let mut decompressed = Vec::new();
+                zstd::stream::Decoder::new(payload)?
+                    .take(self.max_decompressed_size + 1)
+                    .read_to_end(&mut decompressed)?;
+                self.check_decompressed(decompressed)
+            }
+            #[cfg(feature = "snappy")]
+            Compression::Snappy => {
+                // Snappy records the decompressed length up front, so nothing is inflated if
+                // it's too big.
+                if snap::decompress_len(payload)? as u64 > self.max_decompressed_size {
+                    return Err(too_big_decompressed(self.max_decompressed_size));
+                }
+                self.check_decompressed(snap::Decoder::new().decompress_vec(payload)?)
+            }
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                // Like snappy, check the length prefix before inflating anything.
+                if payload.len() < 4 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              "LZ4 payload is missing its length prefix"));
+                }
+                if LittleEndian::read_u32(payload) as u64 > self.max_decompressed_size {
+                    return Err(too_big_decompressed(self.max_decompressed_size));
+                }
+                self.check_decompressed(lz4::block::decompress(payload, None)?)
+            }
+        }
+    }
+
+    /// Fails if `decompressed` is larger than `max_decompressed_size`.
+    #[cfg(any(feature = "zstd", feature = "snappy", feature = "lz4"))]
+    fn check_decompressed(&self, decompressed: Vec<u8>) -> io::Result<Vec<u8>> {
+        if decompressed.len() as u64 > self.max_decompressed_size {
+            return Err(too_big_decompressed(self.max_decompressed_size));
+        }
+        Ok(decompressed)
+    }
+}
+
+impl From<Compression> for CompressionOptions {
+    fn from(compression: Compression) -> Self {
+        CompressionOptions::new(compression)
+    }
+}
+
+fn too_big_decompressed(max_decompressed_size: u64) -> io::Error {
+    warn!("Payload inflates to more than the max of {} bytes",
+          max_decompressed_size);
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("Maximum decompressed payload size is {} bytes",
+                           max_decompressed_size))
+}
+
+/// A zstd context kept for the whole of a connection, for `PipelineCodec::stream_compression`.
+/// Only built with the `zstd` feature.
+///
+/// Each payload is compressed with the last `window` bytes of the payloads before it as its
+/// dictionary, so that strings repeated across payloads compress as well as strings repeated
+/// within one. Both sides must therefore compress and decompress every payload, in the order
+/// they are sent; a payload skipped or reordered leaves the peer with a different dictionary.
+#[cfg(feature = "zstd")]
+pub struct CompressionContext {
+    window: usize,
+    max_decompressed_size: u64,
+    /// The last `window` bytes of the payloads compressed.
+    sent: Vec<u8>,
+    /// The last `window` bytes of the payloads decompressed.
+    received: Vec<u8>,
+}
+
+#[cfg(feature = "zstd")]
+impl CompressionContext {
+    /// Returns a context that remembers the last `window` bytes sent and received, and fails to
+    /// decompress payloads that inflate to more than `max_decompressed_size` bytes.
+    pub fn new(window: usize, max_decompressed_size: u64) -> Self {
+        CompressionContext {
+            window: window,
+            max_decompressed_size: max_decompressed_size,
+            sent: Vec::with_capacity(window),
+            received: Vec::with_capacity(window),
+        }
+    }
+
+    /// Compresses the next payload sent, failing if it is larger than `max_decompressed_size`,
+    /// since the peer would refuse to inflate it.
+    pub fn compress(&mut self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        if payload.len() as u64 > self.max_decompressed_size {
+            return Err(too_big_decompressed(self.max_decompressed_size));
+        }
+        let compressed = zstd::block::Compressor::with_dict(self.sent.clone())
+            .compress(payload, ZSTD_LEVEL)?;
+        remember(&mut self.sent, payload, self.window);
+        Ok(compressed)
+    }
+
+    /// Decompresses the next payload received, failing if it inflates to more than
+    /// `max_decompressed_size` bytes.
+    pub fn decompress(&mut self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        // The output buffer is allocated up front, and inflating past it fails.
+        let decompressed = zstd::block::Decompressor::with_dict(self.received.clone())
+            .decompress(payload, self.max_decompressed_size as usize)
+            .map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData,
+                               format!("Payload failed to decompress, or inflates to more than \
+                                        {} bytes: {}",
+                                       self.max_decompressed_size,
+                                       e))
+            })?;
+        remember(&mut self.received, &decompressed, self.window);
+        Ok(decompressed)
+    }
+}
+
+/// Stands in for the zstd context in builds without the `zstd` feature, which have no stream
+/// compression: there is no value of it, so a codec never has one.
+#[cfg(not(feature = "zstd"))]
+pub enum CompressionContext {}
+
+#[cfg(not(feature = "zstd"))]
+impl CompressionContext {
+    /// Never called, since there is no context.
+    pub fn compress(&mut self, _payload: &[u8]) -> io::Result<Vec<u8>> {
+        match *self {}
+    }
+
+    /// Never called, since there is no context.
+    pub fn decompress(&mut self, _payload: &[u8]) -> io::Result<Vec<u8>> {
+        match *self {}
+    }
+}
+
+/// Appends `payload` to `history`, then drops the oldest bytes past `window`.
+#[cfg(feature = "zstd")]
+fn remember(history: &mut Vec<u8>, payload: &[u8], window: usize) {
+    history.extend_from_slice(payload);
+    if history.len() > window {
+        let excess = history.len() - window;
+        history.drain(..excess);
+    }
+}
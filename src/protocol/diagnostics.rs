@@ -0,0 +1,271 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use bincode::{self, Bounded, Infinite};
+use futures::{Async, Future, Poll};
+use futures::sync::oneshot;
+use futures::task::{self, Task};
+use std::{fmt, io, mem};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use super::CodecMetrics;
+use tokio_proto::streaming::multiplex::RequestId;
+
+/// Decides whether a client may query the stats of a server, by the user it authenticated as,
+/// if any; see `Proto::diagnostics`.
+pub type DiagnosticsAuthorizer = Arc<Fn(Option<&str>) -> bool + Send + Sync>;
+
+/// A server's counts, as of when a client queried them with `Client::server_stats`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Stats {
+    /// The connections open, including the one the stats were queried on.
+    pub connections: u64,
+    /// The requests decoded that haven't been responded to or rejected yet.
+    pub in_flight: u64,
+    /// The requests decoded since the server started.
+    pub requests: u64,
+    /// The payload bytes received, before decompression.
+    pub bytes_received: u64,
+    /// The payload bytes sent, after compression.
+    pub bytes_sent: u64,
+    /// The milliseconds since the server started.
+    pub uptime_millis: u64,
+}
+
+struct Counters {
+    started: Instant,
+    connections: AtomicUsize,
+    requests: AtomicUsize,
+    /// The requests responded to, or that won't be, such as those rejected.
+    finished: AtomicUsize,
+    bytes_received: AtomicUsize,
+    bytes_sent: AtomicUsize,
+}
+
+/// Counts the connections, requests, and bytes of a server, to answer the stats queries of its
+/// clients; see `Proto::diagnostics`. The requests and bytes are counted by the `CodecMetrics`
+/// hooks, which the codec of every connection calls, besides those of `Proto::metrics`. Clones
+/// share the counts.
+#[derive(Clone)]
+pub struct ServerStats {
+    counters: Arc<Counters>,
+}
+
+impl ServerStats {
+    /// Returns counts of 0, with the uptime starting now.
+    pub fn new() -> Self {
+        ServerStats {
+            counters: Arc::new(Counters {
+                started: Instant::now(),
+                connections: AtomicUsize::new(0),
+                requests: AtomicUsize::new(0),
+                finished: AtomicUsize::new(0),
+                bytes_received: AtomicUsize::new(0),
+                bytes_sent: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Returns the counts as they are now.
+    pub fn snapshot(&self) -> Stats {
+        let counters = &self.counters;
+        let requests = counters.requests.load(Ordering::SeqCst) as u64;
+        let finished = counters.finished.load(Ordering::SeqCst) as u64;
+        let uptime = counters.started.elapsed();
+        Stats {
+            connections: counters.connections.load(Ordering::SeqCst) as u64,
+            in_flight: requests.saturating_sub(finished),
+            requests: requests,
+            bytes_received: counters.bytes_received.load(Ordering::SeqCst) as u64,
+            bytes_sent: counters.bytes_sent.load(Ordering::SeqCst) as u64,
+            uptime_millis: uptime.as_secs() * 1_000 + (uptime.subsec_nanos() / 1_000_000) as u64,
+        }
+    }
+
+    /// Counts a connection as open until the returned handle is dropped. Its client may query
+    /// the counts if `authorized` is true.
+    pub fn connection(&self, authorized: bool) -> StatsHandle {
+        self.counters.connections.fetch_add(1, Ordering::SeqCst);
+        StatsHandle {
+            stats: self.clone(),
+            authorized: authorized,
+        }
+    }
+}
+
+impl Default for ServerStats {
+    fn default() -> Self {
+        ServerStats::new()
+    }
+}
+
+impl CodecMetrics for ServerStats {
+    fn on_encode(&self, _: RequestId, payload_size: u64) {
+        self.counters.finished.fetch_add(1, Ordering::SeqCst);
+        self.counters.bytes_sent.fetch_add(payload_size as usize, Ordering::SeqCst);
+    }
+
+    fn on_decode(&self, _: RequestId, payload_size: u64) {
+        self.counters.requests.fetch_add(1, Ordering::SeqCst);
+        self.counters.bytes_received.fetch_add(payload_size as usize, Ordering::SeqCst);
+    }
+}
+
+/// The server codec's end of `ServerStats`, which counts its connection as open while it lives.
+pub struct StatsHandle {
+    stats: ServerStats,
+    authorized: bool,
+}
+
+impl StatsHandle {
+    /// The counts the connection adds to.
+    pub fn stats(&self) -> &ServerStats {
+        &self.stats
+    }
+
+    /// Counts a request decoded on the connection as no longer in flight, though no response
+    /// to it was sent, because it was rejected or its response failed to encode.
+    pub fn unanswered(&self) {
+        self.stats.counters.finished.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// The payload of the answer to a stats query: the counts, if the client may query them.
+    pub fn answer(&self) -> Vec<u8> {
+        if self.authorized {
+            encode_answer(&Ok(self.stats.snapshot()))
+        } else {
+            encode_answer(&Err("The client is not authorized to query the server's stats"
+                .to_string()))
+        }
+    }
+}
+
+impl Drop for StatsHandle {
+    fn drop(&mut self) {
+        self.stats.counters.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Serializes an answer to a stats query: the counts, or why the server didn't give them.
+pub fn encode_answer(answer: &Result<Stats, String>) -> Vec<u8> {
+    bincode::serialize(answer, Infinite).expect("Stats always serialize")
+}
+
+/// What a `Client` and the codec of its connection share about stats queries.
+#[derive(Default)]
+pub struct QueryState {
+    /// The senders of the `StatsFuture`s whose query hasn't been sent yet.
+    requested: Vec<oneshot::Sender<Result<Stats, String>>>,
+    /// The task of the connection's transport, woken to send a query.
+    task: Option<Task>,
+    /// Set once the connection has closed, after which no queries are sent.
+    closed: bool,
+}
+
+/// Shared by a `Client` and the codec of its connection.
+pub type StatsQueries = Arc<Mutex<QueryState>>;
+
+/// Asks the connection of `queries` to query the server's stats, returning a future of them.
+pub fn request(queries: &StatsQueries) -> StatsFuture {
+    let (tx, rx) = oneshot::channel();
+    let mut state = queries.lock().unwrap();
+    if state.closed {
+        return StatsFuture { inner: rx };
+    }
+    state.requested.push(tx);
+    if let Some(task) = state.task.take() {
+        task.unpark();
+    }
+    StatsFuture { inner: rx }
+}
+
+/// The client codec's end of `StatsQueries`. Dropping it fails the queries never answered.
+pub struct QueryHandle {
+    queries: StatsQueries,
+    /// The senders of the `StatsFuture`s whose query was sent, in the order they were, which is
+    /// the order the server answers them in.
+    awaiting: VecDeque<oneshot::Sender<Result<Stats, String>>>,
+}
+
+impl QueryHandle {
+    /// Returns the codec's end of `queries`.
+    pub fn new(queries: StatsQueries) -> Self {
+        QueryHandle {
+            queries: queries,
+            awaiting: VecDeque::new(),
+        }
+    }
+
+    /// Returns the number of queries the client asked for since the last call, registering the
+    /// current task to be woken when it asks for more. If `supported` is false, because the
+    /// server predates stats queries, the queries asked for fail instead.
+    pub fn poll_requested(&mut self, supported: bool) -> usize {
+        let mut state = self.queries.lock().unwrap();
+        state.task = Some(task::park());
+        let requested = mem::replace(&mut state.requested, vec![]);
+        if !supported {
+            return 0;
+        }
+        let n = requested.len();
+        self.awaiting.extend(requested);
+        n
+    }
+
+    /// Completes the oldest query awaiting an answer with `payload`, the server's answer.
+    pub fn answer(&mut self, payload: &[u8]) -> io::Result<()> {
+        let answer = bincode::deserialize_from(&mut &payload[..], Bounded(payload.len() as u64))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        match self.awaiting.pop_front() {
+            // The client may have stopped waiting.
+            Some(query) => {
+                let _ = query.send(answer);
+            }
+            None => warn!("Discarding stats that no query is waiting for."),
+        }
+        Ok(())
+    }
+}
+
+impl Drop for QueryHandle {
+    fn drop(&mut self) {
+        let mut state = self.queries.lock().unwrap();
+        state.requested.clear();
+        state.task = None;
+        state.closed = true;
+    }
+}
+
+/// A future of the stats of a server, queried with `Client::server_stats`.
+pub struct StatsFuture {
+    inner: oneshot::Receiver<Result<Stats, String>>,
+}
+
+impl Future for StatsFuture {
+    type Item = Stats;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Stats, io::Error> {
+        match self.inner.poll() {
+            Ok(Async::Ready(Ok(stats))) => Ok(Async::Ready(stats)),
+            Ok(Async::Ready(Err(reason))) => {
+                Err(io::Error::new(io::ErrorKind::PermissionDenied, reason))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_) => {
+                Err(io::Error::new(io::ErrorKind::Other,
+                                   "The stats query was not answered: the connection closed, or \
+                                    the server predates stats queries"))
+            }
+        }
+    }
+}
+
+impl fmt::Debug for StatsFuture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StatsFuture {{ .. }}")
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use futures::{Async, Future, Poll};
+use futures::task::{self, Task};
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct State {
+    draining: bool,
+    /// Why the transports are draining, as told to their clients.
+    reason: String,
+    next_id: usize,
+    /// The tasks of the open transports, by transport id.
+    transports: HashMap<usize, Option<Task>>,
+    /// The tasks waiting for every transport to close.
+    waiters: Vec<Task>,
+}
+
+/// Gracefully closes the server transports it is given to.
+///
+/// Once `start` is called, each transport says goodbye to its client, stops reading requests,
+/// finishes sending the responses to the requests it has already read, and then closes.
+#[derive(Clone, Default)]
+pub struct Drain {
+    state: Arc<Mutex<State>>,
+}
+
+impl Drain {
+    /// Returns a new `Drain` that hasn't started.
+    pub fn new() -> Self {
+        Drain::default()
+    }
+
+    /// Starts draining every transport, including the ones registered later. Returns a future
+    /// that resolves once they have all closed.
+    pub fn start(&self) -> DrainFuture {
+        self.start_with_reason("")
+    }
+
+    /// Like `start`, but tells clients `reason` in the goodbye.
+    pub fn start_with_reason(&self, reason: &str) -> DrainFuture {
+        let transports = {
+            let mut state = self.state.lock().unwrap();
+            state.draining = true;
+            state.reason = reason.to_string();
+            state.transports.values_mut().filter_map(Option::take).collect::<Vec<_>>()
+        };
+        debug!("Draining {} transports.", transports.len());
+        for transport in transports {
+            transport.unpark();
+        }
+        DrainFuture { state: self.state.clone() }
+    }
+
+    /// True once `start` has been called.
+    pub fn is_draining(&self) -> bool {
+        self.state.lock().unwrap().draining
+    }
+
+    /// Registers a new transport, which stays open until the returned registration is dropped.
+    pub fn register(&self) -> Registration {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.transports.insert(id, None);
+        Registration {
+            drain: self.clone(),
+            id: id,
+        }
+    }
+}
+
+/// A transport's membership in a `Drain`.
+pub struct Registration {
+    drain: Drain,
+    id: usize,
+}
+
+impl Registration {
+    /// True once the transport should drain.
+    pub fn is_draining(&self) -> bool {
+        self.drain.is_draining()
+    }
+
+    /// Why the transport is draining.
+    pub fn reason(&self) -> String {
+        self.drain.state.lock().unwrap().reason.clone()
+    }
+
+    /// True if the transport should drain. Otherwise, arranges for the current task to be woken
+    /// when draining starts.
+    pub fn poll_draining(&self) -> bool {
+        let mut state = self.drain.state.lock().unwrap();
+        if state.draining {
+            return true;
+        }
+        state.transports.insert(self.id, Some(task::park()));
+        false
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let waiters = {
+            let mut state = self.drain.state.lock().unwrap();
+            state.transports.remove(&self.id);
+            if state.transports.is_empty() {
+                mem::replace(&mut state.waiters, vec![])
+            } else {
+                vec![]
+            }
+        };
+        for waiter in waiters {
+            waiter.unpark();
+        }
+    }
+}
+
+/// A future that resolves once every transport of a `Drain` has closed.
+pub struct DrainFuture {
+    state: Arc<Mutex<State>>,
+}
+
+impl Future for DrainFuture {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        let mut state = self.state.lock().unwrap();
+        if state.transports.is_empty() {
+            return Ok(Async::Ready(()));
+        }
+        state.waiters.push(task::park());
+        Ok(Async::NotReady)
+    }
+}
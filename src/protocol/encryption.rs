@@ -0,0 +1,112 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::io;
+use tokio_core::io::EasyBuf;
+use tokio_proto::streaming::multiplex::RequestId;
+
+cfg_if! {
+    if #[cfg(feature = "encryption")] {
+        use byteorder::{BigEndian, WriteBytesExt};
+        use ring::aead::{self, CHACHA20_POLY1305, OpeningKey, SealingKey};
+        use ring::rand::{SecureRandom, SystemRandom};
+        use std::fmt;
+
+        /// A 256-bit ChaCha20-Poly1305 key shared by both ends of a connection.
+        pub struct EncryptionKey {
+            sealing: SealingKey,
+            opening: OpeningKey,
+            rng: SystemRandom,
+        }
+
+        impl EncryptionKey {
+            /// Returns a key made of the 32 bytes of `key`.
+            pub fn new(key: &[u8; 32]) -> Self {
+                EncryptionKey {
+                    sealing: SealingKey::new(&CHACHA20_POLY1305, key)
+                        .expect("A ChaCha20-Poly1305 key is 32 bytes"),
+                    opening: OpeningKey::new(&CHACHA20_POLY1305, key)
+                        .expect("A ChaCha20-Poly1305 key is 32 bytes"),
+                    rng: SystemRandom::new(),
+                }
+            }
+
+            /// The number of bytes encryption adds to a payload: its nonce and its tag.
+            pub fn overhead(&self) -> u64 {
+                (CHACHA20_POLY1305.nonce_len() + CHACHA20_POLY1305.tag_len()) as u64
+            }
+
+            /// Encrypts the payload of frame `id`, returning its random nonce followed by the
+            /// ciphertext and tag.
+            pub fn seal(&self, id: RequestId, payload: &[u8]) -> io::Result<Vec<u8>> {
+                let nonce_len = CHACHA20_POLY1305.nonce_len();
+                let tag_len = CHACHA20_POLY1305.tag_len();
+                let mut sealed = vec![0; nonce_len];
+                self.rng
+                    .fill(&mut sealed)
+                    .map_err(|_| io::Error::new(io::ErrorKind::Other, "Couldn't make a nonce"))?;
+                sealed.extend_from_slice(payload);
+                sealed.extend(::std::iter::repeat(0).take(tag_len));
+                let ad = associated_data(id, sealed.len());
+                let (nonce, in_out) = sealed.split_at_mut(nonce_len);
+                aead::seal_in_place(&self.sealing, nonce, &ad, in_out, tag_len)
+                    .map_err(|_| io::Error::new(io::ErrorKind::Other, "Couldn't encrypt"))?;
+                Ok(sealed)
+            }
+
+            /// Decrypts a payload made by `seal` for frame `id`. Returns `None` if it was
+            /// altered, or sealed with a different key or for a different frame.
+            pub fn open(&self, id: RequestId, mut payload: EasyBuf) -> Option<EasyBuf> {
+                let nonce_len = CHACHA20_POLY1305.nonce_len();
+                if payload.len() < self.overhead() as usize {
+                    return None;
+                }
+                let ad = associated_data(id, payload.len());
+                let nonce = payload.drain_to(nonce_len).as_slice().to_vec();
+                let len = {
+                    let mut in_out = payload.get_mut();
+                    match aead::open_in_place(&self.opening, &nonce, &ad, 0, &mut in_out[..]) {
+                        Ok(plaintext) => plaintext.len(),
+                        Err(_) => return None,
+                    }
+                };
+                Some(payload.drain_to(len))
+            }
+        }
+
+        impl fmt::Debug for EncryptionKey {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("EncryptionKey")
+            }
+        }
+
+        /// The header fields a payload's tag covers: the frame's id and the payload's length.
+        fn associated_data(id: RequestId, len: usize) -> Vec<u8> {
+            let mut ad = Vec::with_capacity(16);
+            ad.write_u64::<BigEndian>(id).unwrap();
+            ad.write_u64::<BigEndian>(len as u64).unwrap();
+            ad
+        }
+    } else {
+        /// Stands in for the key of the `encryption` feature, which isn't enabled; there are no
+        /// values of it.
+        #[derive(Debug)]
+        pub enum EncryptionKey {}
+
+        impl EncryptionKey {
+            pub fn overhead(&self) -> u64 {
+                match *self {}
+            }
+
+            pub fn seal(&self, _id: RequestId, _payload: &[u8]) -> io::Result<Vec<u8>> {
+                match *self {}
+            }
+
+            pub fn open(&self, _id: RequestId, _payload: EasyBuf) -> Option<EasyBuf> {
+                match *self {}
+            }
+        }
+    }
+}
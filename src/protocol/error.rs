@@ -0,0 +1,354 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::{fmt, io};
+use std::error::Error as StdError;
+use std::time::Duration;
+use tokio_proto::streaming::multiplex::RequestId;
+
+/// Why a single frame couldn't be decoded.
+///
+/// These errors affect one request only: the frame was well-delimited, so the connection can
+/// keep being used, and a server can reply to the request with an error. Problems that leave
+/// the stream unreadable are instead returned as an `io::Error` from `Codec::decode`, which
+/// closes the connection.
+///
+/// Frames are delimited by their length alone, so however a payload fails to decode, the next
+/// frame is read from where the length says it starts: several bad payloads in a row each
+/// fail their own request, in the order they were received. Only a bad header, such as
+/// unknown flags or a missing frame marker, loses the frame boundaries.
+#[derive(Debug)]
+pub enum DecodeError<E> {
+    /// The frame's payload was larger than the max payload size. Its bytes were skipped.
+    PayloadTooLarge {
+        /// The length of the payload.
+        len: u64,
+        /// The max payload size.
+        max: u64,
+    },
+    /// The payload didn't match the checksum sent with it.
+    ChecksumMismatch {
+        /// The checksum sent with the payload.
+        expected: u32,
+        /// The checksum of the payload as received.
+        actual: u32,
+    },
+    /// The request's deadline passed before it was received, so the client no longer waits for
+    /// a response. Its payload wasn't deserialized.
+    DeadlineExceeded {
+        /// The deadline, in milliseconds since the unix epoch.
+        deadline: u64,
+    },
+    /// No response to the request arrived within the response timeout, so the client stopped
+    /// waiting for it. Only returned by clients.
+    TimedOut {
+        /// The response timeout.
+        timeout: Duration,
+    },
+    /// The rest of the fragments of the payload didn't arrive within the max reassembly time,
+    /// so those received were discarded, as will be any that arrive later.
+    ReassemblyTimedOut {
+        /// The max reassembly time.
+        timeout: Duration,
+    },
+    /// The server said it was closing the connection before the request was sent, so the
+    /// request wasn't sent. Only returned by clients.
+    Closing {
+        /// The reason the server gave.
+        reason: String,
+    },
+    /// The server rejected the request without handling it, such as for reusing the id of a
+    /// request still in flight. Returned by clients, and by a server's codec for the requests
+    /// its `screen_requests` rejects.
+    Rejected {
+        /// The reason the server gave.
+        reason: String,
+    },
+    /// The request failed to encode, such as for being larger than the max payload size, so it
+    /// wasn't sent. Only returned by clients.
+    Unsent(io::Error),
+    /// The payload wasn't encrypted with the codec's key, or was altered on the way. It wasn't
+    /// deserialized.
+    Unauthenticated,
+    /// The payload's metadata or compression was malformed, and the codec isolates payload
+    /// errors; see `Codec::isolate_payload_errors`. It wasn't deserialized.
+    MalformedPayload(io::Error),
+    /// The payload couldn't be deserialized.
+    Deserialize(E),
+    /// The payload was empty, but the type it was deserialized as can't be read from zero bytes.
+    /// Unit-like types, such as `()`, deserialize from an empty payload with bincode.
+    EmptyPayload(E),
+}
+
+impl<E: fmt::Display> fmt::Display for DecodeError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::PayloadTooLarge { len, max } => {
+                write!(f,
+                       "Maximum payload size is {} bytes but got a payload of {}",
+                       max,
+                       len)
+            }
+            DecodeError::ChecksumMismatch { expected, actual } => {
+                write!(f,
+                       "Checksum mismatch: expected {:#x}, got {:#x}",
+                       expected,
+                       actual)
+            }
+            DecodeError::DeadlineExceeded { deadline } => {
+                write!(f,
+                       "The request's deadline, {} ms after the unix epoch, has passed",
+                       deadline)
+            }
+            DecodeError::TimedOut { timeout } => write!(f, "No response within {:?}", timeout),
+            DecodeError::ReassemblyTimedOut { timeout } => {
+                write!(f, "The payload's fragments didn't all arrive within {:?}", timeout)
+            }
+            DecodeError::Closing { ref reason } => {
+                write!(f, "The server is closing the connection: {}", reason)
+            }
+            DecodeError::Rejected { ref reason } => {
+                write!(f, "The server rejected the request: {}", reason)
+            }
+            DecodeError::Unsent(ref e) => write!(f, "The request failed to encode: {}", e),
+            DecodeError::Unauthenticated => write!(f, "The payload failed authentication"),
+            DecodeError::MalformedPayload(ref e) => write!(f, "The payload was malformed: {}", e),
+            DecodeError::Deserialize(ref e) => fmt::Display::fmt(e, f),
+            DecodeError::EmptyPayload(ref e) => {
+                write!(f, "The payload was empty, but its type needs bytes: {}", e)
+            }
+        }
+    }
+}
+
+impl<E: StdError> StdError for DecodeError<E> {
+    fn description(&self) -> &str {
+        match *self {
+            DecodeError::PayloadTooLarge { .. } => "The payload was too large.",
+            DecodeError::ChecksumMismatch { .. } => "The payload didn't match its checksum.",
+            DecodeError::DeadlineExceeded { .. } => "The request's deadline has passed.",
+            DecodeError::TimedOut { .. } => "The response timed out.",
+            DecodeError::ReassemblyTimedOut { .. } => "The payload's fragments timed out.",
+            DecodeError::Closing { .. } => "The server is closing the connection.",
+            DecodeError::Rejected { .. } => "The server rejected the request.",
+            DecodeError::Unsent(_) => "The request failed to encode.",
+            DecodeError::Unauthenticated => "The payload failed authentication.",
+            DecodeError::MalformedPayload(_) => "The payload was malformed.",
+            DecodeError::Deserialize(ref e) => e.description(),
+            DecodeError::EmptyPayload(_) => "The payload was empty, but its type needs bytes.",
+        }
+    }
+
+    fn cause(&self) -> Option<&StdError> {
+        match *self {
+            DecodeError::PayloadTooLarge { .. } |
+            DecodeError::ChecksumMismatch { .. } |
+            DecodeError::DeadlineExceeded { .. } |
+            DecodeError::TimedOut { .. } |
+            DecodeError::ReassemblyTimedOut { .. } |
+            DecodeError::Closing { .. } |
+            DecodeError::Rejected { .. } |
+            DecodeError::Unauthenticated => None,
+            DecodeError::Unsent(ref e) |
+            DecodeError::MalformedPayload(ref e) => Some(e),
+            DecodeError::Deserialize(ref e) => e.cause(),
+            DecodeError::EmptyPayload(ref e) => Some(e),
+        }
+    }
+}
+
+/// A payload larger than the max payload size, which a codec refused to send or to receive.
+///
+/// The `io::Error` a codec returns for it wraps this error, so that the failure can be traced to
+/// a request: get it back with `get_ref` and `downcast_ref`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PayloadTooLargeError {
+    /// The id of the frame, if it has one.
+    pub id: Option<RequestId>,
+    /// The length of the payload.
+    pub len: u64,
+    /// The max payload size.
+    pub max: u64,
+}
+
+impl PayloadTooLargeError {
+    /// Converts the error into an `io::Error` of kind `InvalidData`.
+    pub fn into_io(self) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, self)
+    }
+}
+
+impl fmt::Display for PayloadTooLargeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "Maximum payload size is {} bytes but got a payload of {}",
+               self.max,
+               self.len)?;
+        match self.id {
+            Some(id) => write!(f, " for request id {}", id),
+            None => Ok(()),
+        }
+    }
+}
+
+impl StdError for PayloadTooLargeError {
+    fn description(&self) -> &str {
+        "The payload was too large."
+    }
+}
+
+/// No id could be found to send a request with: every id the client's allocator returned
+/// belonged to a request still waiting for its response, or was reserved for control frames.
+///
+/// The `io::Error` a codec returns for it wraps this error; get it back with `get_ref` and
+/// `downcast_ref`. The request isn't sent, but the connection can keep being used, and an id
+/// frees up as soon as a response arrives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IdSpaceExhaustedError {
+    /// The number of requests waiting for their response.
+    pub in_flight: usize,
+    /// The number of ids the allocator returned before giving up.
+    pub attempts: usize,
+}
+
+impl IdSpaceExhaustedError {
+    /// Converts the error into an `io::Error` of kind `Other`.
+    pub fn into_io(self) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, self)
+    }
+}
+
+impl fmt::Display for IdSpaceExhaustedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "No free request id after {} attempts with {} requests in flight",
+               self.attempts,
+               self.in_flight)
+    }
+}
+
+impl StdError for IdSpaceExhaustedError {
+    fn description(&self) -> &str {
+        "The request id space was exhausted."
+    }
+}
+
+/// Why a side closed the connection, for the errors that have a code the other side can act on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ProtocolErrorCode {
+    /// A frame was larger than the side that closed the connection accepts.
+    PayloadTooLarge = 1,
+    /// The two sides have no protocol version in common.
+    VersionMismatch = 2,
+    /// The client sent no credentials, or ones the server didn't accept.
+    AuthFailed = 3,
+    /// The client connected or sent requests more often than the server allows.
+    RateLimited = 4,
+    /// A payload's type tag was missing, or not one the side that closed the connection
+    /// accepts; see `TypeAllowlist`.
+    UnknownType = 5,
+}
+
+/// The reason a peer gave for closing the connection, in the handshake or in a protocol error
+/// frame sent just before closing, so that the other side can tell what went wrong rather than
+/// seeing the connection reset.
+///
+/// The `io::Error` the connection fails with wraps this error: get it back with
+/// `ProtocolError::from_io`. A server's handshake hook or authenticator can refuse a client with
+/// a code by failing with the `io::Error` of `into_io`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProtocolError {
+    /// What kind of error it was.
+    pub code: ProtocolErrorCode,
+    /// A description of the error, for people.
+    pub message: String,
+}
+
+impl ProtocolError {
+    /// Returns the error with `code`, described by `message`.
+    pub fn new<M: Into<String>>(code: ProtocolErrorCode, message: M) -> Self {
+        ProtocolError {
+            code: code,
+            message: message.into(),
+        }
+    }
+
+    /// Converts the error into an `io::Error` of kind `PermissionDenied` for `AuthFailed`,
+    /// `Other` for `RateLimited`, and `InvalidData` otherwise.
+    pub fn into_io(self) -> io::Error {
+        let kind = match self.code {
+            ProtocolErrorCode::AuthFailed => io::ErrorKind::PermissionDenied,
+            ProtocolErrorCode::RateLimited => io::ErrorKind::Other,
+            ProtocolErrorCode::PayloadTooLarge |
+            ProtocolErrorCode::VersionMismatch |
+            ProtocolErrorCode::UnknownType => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, self)
+    }
+
+    /// The `ProtocolError` that `e` wraps, if any.
+    pub fn from_io(e: &io::Error) -> Option<&ProtocolError> {
+        e.get_ref().and_then(|e| e.downcast_ref())
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl StdError for ProtocolError {
+    fn description(&self) -> &str {
+        match self.code {
+            ProtocolErrorCode::PayloadTooLarge => "The payload was too large.",
+            ProtocolErrorCode::VersionMismatch => "There is no common protocol version.",
+            ProtocolErrorCode::AuthFailed => "Authentication failed.",
+            ProtocolErrorCode::RateLimited => "The rate limit was exceeded.",
+            ProtocolErrorCode::UnknownType => "The payload's type is not allowed.",
+        }
+    }
+}
+
+/// The code sent as `code` in a protocol error frame, if it is one.
+pub fn protocol_error_code(code: u8) -> Option<ProtocolErrorCode> {
+    match code {
+        1 => Some(ProtocolErrorCode::PayloadTooLarge),
+        2 => Some(ProtocolErrorCode::VersionMismatch),
+        3 => Some(ProtocolErrorCode::AuthFailed),
+        4 => Some(ProtocolErrorCode::RateLimited),
+        5 => Some(ProtocolErrorCode::UnknownType),
+        _ => None,
+    }
+}
+
+/// The `ProtocolError` to tell the peer about when the connection fails with `e`, if it has a
+/// code: either the one it wraps, or `PayloadTooLarge` for a `PayloadTooLargeError`.
+pub fn protocol_error(e: &io::Error) -> Option<ProtocolError> {
+    if let Some(error) = ProtocolError::from_io(e) {
+        return Some(error.clone());
+    }
+    e.get_ref()
+        .and_then(|e| e.downcast_ref::<PayloadTooLargeError>())
+        .map(|e| ProtocolError::new(ProtocolErrorCode::PayloadTooLarge, e.to_string()))
+}
+
+impl<E> DecodeError<E> {
+    /// Converts the error into an `io::Error` of kind `TimedOut` for an exceeded deadline, a
+    /// response timeout, or a reassembly timeout, `ConnectionAborted` for a closing server, and
+    /// `InvalidData` otherwise.
+    pub fn into_io(self) -> io::Error
+        where E: StdError + Send + Sync + 'static
+    {
+        let kind = match self {
+            DecodeError::DeadlineExceeded { .. } |
+            DecodeError::TimedOut { .. } |
+            DecodeError::ReassemblyTimedOut { .. } => io::ErrorKind::TimedOut,
+            DecodeError::Closing { .. } => io::ErrorKind::ConnectionAborted,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, self)
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use bincode;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use super::{Codec, DecodeError};
+use super::frame::FrameOptions;
+use tokio_core::io::Io;
+use tokio_proto::streaming::multiplex::RequestId;
+
+/// A corruption of the bytes read from a `FaultyIo`. Offsets count the bytes of the underlying
+/// connection, before any faults are applied.
+#[derive(Clone, Debug)]
+pub enum Fault {
+    /// Drops `len` bytes, starting at `offset`.
+    Drop { offset: usize, len: usize },
+    /// Flips bit `bit` of the byte at `offset`.
+    FlipBit { offset: usize, bit: u8 },
+    /// Inserts `bytes` before the byte at `offset`.
+    Inject { offset: usize, bytes: Vec<u8> },
+}
+
+impl Fault {
+    /// Inserts, before the byte at `offset`, the header of a frame with id `id` that claims a
+    /// payload of `len` bytes, without sending the payload.
+    pub fn header(offset: usize, frame: &FrameOptions, id: RequestId, len: u64) -> Self {
+        let mut bytes = vec![];
+        frame.write_header(&mut bytes, id, 0, 0, 0, len);
+        Fault::Inject {
+            offset: offset,
+            bytes: bytes,
+        }
+    }
+}
+
+/// Wraps a connection, applying faults to the bytes read from it. Writes pass through
+/// unchanged.
+pub struct FaultyIo<T> {
+    inner: T,
+    faults: Vec<Fault>,
+    /// The number of bytes read from `inner` so far.
+    offset: usize,
+    /// Bytes that faults have been applied to, waiting to be read.
+    pending: VecDeque<u8>,
+}
+
+impl<T> FaultyIo<T> {
+    /// Returns a connection that reads from `inner`, applying `faults`.
+    pub fn new(inner: T, faults: Vec<Fault>) -> Self {
+        FaultyIo {
+            inner: inner,
+            faults: faults,
+            offset: 0,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Applies the faults to `byte`, the next byte of `inner`, and queues the result.
+    fn push(&mut self, byte: u8) {
+        let offset = self.offset;
+        self.offset += 1;
+        let mut byte = Some(byte);
+        for fault in &self.faults {
+            match *fault {
+                Fault::Inject { offset: at, ref bytes } if at == offset => {
+                    self.pending.extend(bytes);
+                }
+                Fault::Drop { offset: at, len } if at <= offset && offset < at + len => {
+                    byte = None;
+                }
+                Fault::FlipBit { offset: at, bit } if at == offset => {
+                    byte = byte.map(|byte| byte ^ (1 << bit));
+                }
+                _ => {}
+            }
+        }
+        self.pending.extend(byte);
+    }
+}
+
+impl<T: Read> Read for FaultyIo<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pending.is_empty() {
+            let mut chunk = vec![0; buf.len()];
+            let n = self.inner.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            for &byte in &chunk[..n] {
+                self.push(byte);
+            }
+        }
+        let mut n = 0;
+        while n < buf.len() {
+            match self.pending.pop_front() {
+                Some(byte) => buf[n] = byte,
+                None => break,
+            }
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for FaultyIo<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Io> Io for FaultyIo<T> {}
+
+/// A request decoded by a transport.
+type Request = (RequestId, Result<Vec<u8>, DecodeError<bincode::Error>>);
+
+/// Encodes a request `(id, vec![id])` with checksums for each of `ids`, and returns what
+/// `codec` decodes from them after they pass through a `FaultyIo` with `faults`.
+fn decode_with(codec: Codec<Vec<u8>, Vec<u8>>,
+               ids: &[RequestId],
+               faults: Vec<Fault>)
+               -> io::Result<Vec<Request>> {
+    use futures::{Future, Stream};
+    use super::handshake::MockIo;
+    use super::transport::Transport;
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut sender: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000).checksum(true);
+    let mut vec = Vec::new();
+    for &id in ids {
+        sender.encode((id, vec![id as u8]), &mut vec).unwrap();
+    }
+    Transport::new(FaultyIo::new(MockIo::new(vec), faults), codec).collect().wait()
+}
+
+// Each request frame is an 8-byte id, an 8-byte length, a 9-byte payload, and a 4-byte checksum.
+const FRAME_LEN: usize = 29;
+
+#[test]
+fn flipped_bit() {
+    let codec = Codec::new(2_000_000).checksum(true);
+    let faults = vec![Fault::FlipBit {
+                          offset: 16,
+                          bit: 3,
+                      }];
+    let requests = decode_with(codec, &[1, 2], faults).unwrap();
+    assert_eq!(requests.len(), 2);
+    match requests[0] {
+        (1, Err(DecodeError::ChecksumMismatch { .. })) => {}
+        ref bad => panic!("Expected a ChecksumMismatch, but got {:?}", bad),
+    }
+    assert_eq!(requests[1].0, 2);
+    assert_eq!(*requests[1].1.as_ref().unwrap(), vec![2]);
+}
+
+#[test]
+fn truncated_payload() {
+    let codec = Codec::new(2_000_000).checksum(true);
+    let faults = vec![Fault::Drop {
+                          offset: FRAME_LEN + 20,
+                          len: 9,
+                      }];
+    let err = decode_with(codec, &[1, 2], faults).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+}
+
+#[test]
+fn oversized_len() {
+    let oversized = || vec![Fault::header(0, &FrameOptions::default(), 7, 1 << 30)];
+
+    let codec = Codec::new(1024).checksum(true);
+    let err = decode_with(codec, &[1], oversized()).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    // When skipped, the claimed payload swallows the rest of the stream.
+    let codec = Codec::new(1024).checksum(true).skip_too_big(true);
+    let requests = decode_with(codec, &[1], oversized()).unwrap();
+    assert_eq!(requests.len(), 1);
+    match requests[0] {
+        (7, Err(DecodeError::PayloadTooLarge { len, max: 1024 })) if len == 1 << 30 => {}
+        ref bad => panic!("Expected PayloadTooLarge, but got {:?}", bad),
+    }
+}
@@ -0,0 +1,918 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian, WriteBytesExt};
+use bincode::{self, Infinite};
+use crc::crc32;
+use super::{CompressionOptions, DecodeError, Metadata};
+use std::{cmp, mem, u32, u64, usize};
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_core::io::EasyBuf;
+use tokio_proto::streaming::multiplex::RequestId;
+
+/// Set on frames whose payload is compressed.
+pub const FLAG_COMPRESSED: u8 = 0b0000_0001;
+
+/// Set on a message that is followed by a stream of items with the same id.
+pub const FLAG_STREAM_START: u8 = 0b0000_0010;
+
+/// Set on a frame that holds an item of a stream.
+pub const FLAG_STREAM_ITEM: u8 = 0b0000_0100;
+
+/// Set on the frame with an empty payload that ends a stream.
+pub const FLAG_STREAM_END: u8 = 0b0000_1000;
+
+/// Set on a frame whose payload is an error message for the id, in place of a message or item.
+pub const FLAG_ERROR: u8 = 0b0001_0000;
+
+/// Set on a frame whose payload is followed by its CRC32, when only large payloads are.
+pub const FLAG_CHECKSUM: u8 = 0b0010_0000;
+
+/// Set on a frame whose payload starts with a block of metadata; see
+/// `FrameOptions::write_metadata`.
+pub const FLAG_METADATA: u8 = 0b0100_0000;
+
+/// Set on a frame that holds one fragment of a payload too big for a single frame. Its payload
+/// starts with the fragment's index and the number of fragments, as 4-byte integers, followed
+/// by that fragment of the whole payload. The fragments of a payload are sent in order, and
+/// carry the same flags.
+pub const FLAG_FRAGMENT: u8 = 0b1000_0000;
+
+/// The bytes at the start of the payload of a frame with `FLAG_FRAGMENT`.
+pub const FRAGMENT_HEADER_LEN: u64 = 8;
+
+/// The id of heartbeat frames, which have an empty payload and are handled by the transport
+/// rather than passed on. tokio-proto assigns request ids sequentially from 0, so it never uses
+/// this one.
+pub const HEARTBEAT_ID: RequestId = u64::MAX;
+
+/// The id of goodbye frames, which a server sends when it starts closing the connection
+/// gracefully. The payload is the reason, in UTF-8, and may be empty. Like heartbeats, they are
+/// handled by the transport.
+pub const GOODBYE_ID: RequestId = u64::MAX - 1;
+
+/// The id of rejection frames, which a server sends in place of a response to a request it
+/// didn't pass on to the service, such as one reusing the id of a request still in flight. The
+/// payload is the id of the request, followed by the reason in UTF-8.
+pub const REJECTED_ID: RequestId = u64::MAX - 2;
+
+/// The id of acknowledgement frames, which a server sends once it has durably accepted a
+/// request, ahead of its response. The payload is the id of the request.
+pub const ACKED_ID: RequestId = u64::MAX - 3;
+
+/// The id of ping frames, which a client sends to measure the round-trip time to its server,
+/// and the server echoes. The payload is a timestamp of the client's, which only it
+/// interprets.
+pub const PING_ID: RequestId = u64::MAX - 4;
+
+/// The id of protocol error frames, which a side sends just before closing the connection
+/// because of an error that has a `ProtocolErrorCode`. The payload is the code, in one byte,
+/// followed by a description of the error in UTF-8.
+pub const PROTOCOL_ERROR_ID: RequestId = u64::MAX - 5;
+
+/// The id of diagnostics frames, which a client sends with an empty payload to query the stats
+/// of its server, and the server answers with a bincode-serialized `Result<Stats, String>`.
+pub const DIAGNOSTICS_ID: RequestId = u64::MAX - 6;
+
+/// The lowest id reserved for control frames, which requests are never sent with.
+pub const MIN_CONTROL_ID: RequestId = DIAGNOSTICS_ID;
+
+/// Starts every frame when frame markers are enabled, so that a reader that lost track of the
+/// frame boundaries can find the next one.
+pub const FRAME_MARKER: &'static [u8; 4] = b"TRPF";
+
+/// The width of the length prefix of a frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LenWidth {
+    /// A 4-byte length prefix. Payloads may be at most `u32::MAX` bytes.
+    U32,
+    /// An 8-byte length prefix. This is the default.
+    U64,
+    /// A LEB128 varint length prefix of 1 to 10 bytes: 7 bits per byte, least significant group
+    /// first, with the high bit set on every byte but the last. Payloads under 128 bytes need a
+    /// single byte.
+    Varint,
+}
+
+impl Default for LenWidth {
+    fn default() -> Self {
+        LenWidth::U64
+    }
+}
+
+impl LenWidth {
+    /// The number of bytes in a fixed-width length prefix.
+    fn size(&self) -> usize {
+        match *self {
+            LenWidth::U32 => mem::size_of::<u32>(),
+            LenWidth::U64 => mem::size_of::<u64>(),
+            LenWidth::Varint => unreachable!("varint length prefixes have no fixed size"),
+        }
+    }
+
+    /// The largest length the prefix can hold.
+    pub fn max_len(&self) -> u64 {
+        match *self {
+            LenWidth::U32 => u32::MAX as u64,
+            LenWidth::U64 | LenWidth::Varint => u64::MAX,
+        }
+    }
+}
+
+/// The byte order of the integer fields of a frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Endianness {
+    /// Most significant byte first. This is the default.
+    Big,
+    /// Least significant byte first.
+    Little,
+}
+
+impl Default for Endianness {
+    fn default() -> Self {
+        Endianness::Big
+    }
+}
+
+impl Endianness {
+    fn write_u32(&self, buf: &mut Vec<u8>, n: u32) {
+        match *self {
+            Endianness::Big => buf.write_u32::<BigEndian>(n).unwrap(),
+            Endianness::Little => buf.write_u32::<LittleEndian>(n).unwrap(),
+        }
+    }
+
+    fn write_u64(&self, buf: &mut Vec<u8>, n: u64) {
+        match *self {
+            Endianness::Big => buf.write_u64::<BigEndian>(n).unwrap(),
+            Endianness::Little => buf.write_u64::<LittleEndian>(n).unwrap(),
+        }
+    }
+
+    fn read_u32(&self, buf: &[u8]) -> u32 {
+        match *self {
+            Endianness::Big => BigEndian::read_u32(buf),
+            Endianness::Little => LittleEndian::read_u32(buf),
+        }
+    }
+
+    fn read_u64(&self, buf: &[u8]) -> u64 {
+        match *self {
+            Endianness::Big => BigEndian::read_u64(buf),
+            Endianness::Little => LittleEndian::read_u64(buf),
+        }
+    }
+}
+
+/// Options that change the layout of a frame on the wire. Both peers must agree on them.
+#[derive(Clone, Debug, Default)]
+pub struct FrameOptions {
+    /// How the payloads sent are compressed, if they are.
+    pub compression: Option<CompressionOptions>,
+    /// How received payloads that carry `FLAG_COMPRESSED` are decompressed, which may differ
+    /// from how the payloads sent are compressed.
+    pub decompression: Option<CompressionOptions>,
+    /// If true, every payload is followed by its CRC32.
+    pub checksum: bool,
+    /// If set, and `checksum` isn't, payloads of at least this many bytes are followed by their
+    /// CRC32, and their frames carry `FLAG_CHECKSUM`.
+    pub checksum_threshold: Option<u64>,
+    /// If true, every frame starts with `FRAME_MARKER`.
+    pub frame_markers: bool,
+    pub len_width: LenWidth,
+    /// The byte order of the id, the length, and the checksum.
+    pub endianness: Endianness,
+    /// If true, every frame carries a priority byte between the flags and the deadline.
+    pub priorities: bool,
+    /// If true, frames may start their payload with a block of metadata, and carry
+    /// `FLAG_METADATA` if they do.
+    pub metadata: bool,
+    /// If true, every frame carries an 8-byte deadline between the flags and the length.
+    pub deadlines: bool,
+    /// If true, the flags byte records whether a frame is a message, an item of a stream, the
+    /// end of a stream, or an error.
+    pub streams: bool,
+    /// If true, payloads too big for one frame are split across frames with `FLAG_FRAGMENT`.
+    pub fragments: bool,
+}
+
+impl FrameOptions {
+    /// True if frames carry a flags byte between the id and the length.
+    pub fn has_flags(&self) -> bool {
+        self.compression.is_some() || self.decompression.is_some() || self.streams ||
+        self.checksum_threshold.is_some() || self.metadata || self.fragments
+    }
+
+    /// The flags a frame received with these options may carry. A frame with another flag is
+    /// from a peer that framed it with options the connection didn't negotiate, so the rest of
+    /// its header can't be trusted either.
+    fn accepted_flags(&self) -> u8 {
+        // A compressed payload that can't be decompressed fails only its own request.
+        let mut flags = FLAG_COMPRESSED;
+        if self.streams {
+            flags |= FLAG_STREAM_START | FLAG_STREAM_ITEM | FLAG_STREAM_END | FLAG_ERROR;
+        }
+        if self.checksum_threshold.is_some() {
+            flags |= FLAG_CHECKSUM;
+        }
+        if self.metadata {
+            flags |= FLAG_METADATA;
+        }
+        if self.fragments {
+            flags |= FLAG_FRAGMENT;
+        }
+        flags
+    }
+
+    /// True if a payload of `len` bytes is sent with a checksum.
+    fn checksums(&self, len: u64) -> bool {
+        self.checksum || self.checksum_threshold.map_or(false, |threshold| len >= threshold)
+    }
+
+    /// The flag that records whether a payload of `len` bytes is sent with a checksum, if
+    /// that varies from frame to frame.
+    fn checksum_flag(&self, len: u64) -> u8 {
+        if !self.checksum && self.checksums(len) {
+            FLAG_CHECKSUM
+        } else {
+            0
+        }
+    }
+
+    /// Appends a frame header to `buf`. `priority` is left out unless priorities are enabled.
+    /// `deadline` is in milliseconds since the unix epoch, or 0 for none; it is left out unless
+    /// deadlines are enabled.
+    pub fn write_header(&self,
+                        buf: &mut Vec<u8>,
+                        id: RequestId,
+                        flags: u8,
+                        priority: u8,
+                        deadline: u64,
+                        len: u64) {
+        if self.frame_markers {
+            buf.extend_from_slice(FRAME_MARKER);
+        }
+        self.endianness.write_u64(buf, id);
+        trace!("Encoded request id = {} as {:?}", id, buf);
+        if self.has_flags() {
+            buf.push(flags | self.checksum_flag(len));
+        }
+        if self.priorities {
+            buf.push(priority);
+        }
+        if self.deadlines {
+            self.endianness.write_u64(buf, deadline);
+        }
+        match self.len_width {
+            LenWidth::U32 => self.endianness.write_u32(buf, len as u32),
+            LenWidth::U64 => self.endianness.write_u64(buf, len),
+            LenWidth::Varint => write_varint(buf, len),
+        }
+    }
+
+    /// Appends the block of metadata that starts the payload of a frame with `FLAG_METADATA`:
+    /// the length of the serialized metadata, as a u32, followed by the metadata serialized with
+    /// bincode.
+    pub fn write_metadata(&self, buf: &mut Vec<u8>, metadata: &Metadata) -> io::Result<()> {
+        let len = bincode::serialized_size(metadata);
+        if len > u32::MAX as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("Frame metadata of {} bytes is too large", len)));
+        }
+        self.endianness.write_u32(buf, len as u32);
+        bincode::serialize_into(buf, metadata, Infinite)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    /// The number of bytes `write_metadata` appends for `metadata`.
+    pub fn metadata_len(&self, metadata: &Metadata) -> u64 {
+        (mem::size_of::<u32>() as u64) + bincode::serialized_size(metadata)
+    }
+
+    /// Splits the block written by `write_metadata` off the front of `payload`.
+    pub fn split_metadata(&self, payload: &mut EasyBuf) -> io::Result<Metadata> {
+        let prefix = mem::size_of::<u32>();
+        if payload.len() < prefix {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "Frame metadata is missing its length"));
+        }
+        let len = self.endianness.read_u32(&payload.as_slice()[..prefix]) as usize;
+        if payload.len() - prefix < len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("Frame metadata of {} bytes is longer than its \
+                                               payload",
+                                              len)));
+        }
+        payload.drain_to(prefix);
+        let block = payload.drain_to(len);
+        bincode::deserialize(block.as_slice())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Appends the index of a fragment and the number of fragments, which start the payload of
+    /// a frame with `FLAG_FRAGMENT`.
+    pub fn write_fragment_header(&self, buf: &mut Vec<u8>, index: u32, total: u32) {
+        self.endianness.write_u32(buf, index);
+        self.endianness.write_u32(buf, total);
+    }
+
+    /// Splits the header written by `write_fragment_header` off the front of `payload`,
+    /// returning the index and the number of fragments.
+    pub fn split_fragment_header(&self, payload: &mut EasyBuf) -> io::Result<(u32, u32)> {
+        if (payload.len() as u64) < FRAGMENT_HEADER_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "Fragment is missing its index"));
+        }
+        let header = payload.drain_to(FRAGMENT_HEADER_LEN as usize);
+        let header = header.as_slice();
+        Ok((self.endianness.read_u32(&header[..4]), self.endianness.read_u32(&header[4..])))
+    }
+
+    /// Appends `id` to `buf`, in the byte order of the ids in frame headers.
+    pub fn write_id(&self, buf: &mut Vec<u8>, id: RequestId) {
+        self.endianness.write_u64(buf, id);
+    }
+
+    /// Reads an id written by `write_id` from the start of `buf`.
+    pub fn read_id(&self, buf: &[u8]) -> RequestId {
+        self.endianness.read_u64(buf)
+    }
+
+    /// The number of bytes `write_header` and `write_trailer` add around a payload of `len`
+    /// bytes.
+    pub fn frame_len(&self, len: u64) -> u64 {
+        let mut header = self.marker_len() + mem::size_of::<RequestId>();
+        if self.has_flags() {
+            header += 1;
+        }
+        if self.priorities {
+            header += 1;
+        }
+        if self.deadlines {
+            header += mem::size_of::<u64>();
+        }
+        header += match self.len_width {
+            LenWidth::Varint => varint_len(len),
+            width => width.size(),
+        };
+        let trailer = if self.checksums(len) {
+            mem::size_of::<u32>()
+        } else {
+            0
+        };
+        (header + trailer) as u64
+    }
+
+    /// Overwrites the length in the header of the frame whose payload starts at `payload_start`
+    /// in `buf`. The header must have been written with a fixed-width length.
+    pub fn patch_len(&self, buf: &mut Vec<u8>, payload_start: usize, len: u64) {
+        let mut encoded = Vec::with_capacity(mem::size_of::<u64>());
+        match self.len_width {
+            LenWidth::U32 => self.endianness.write_u32(&mut encoded, len as u32),
+            LenWidth::U64 => self.endianness.write_u64(&mut encoded, len),
+            LenWidth::Varint => panic!("Varint lengths can't be patched"),
+        }
+        let len_start = payload_start - encoded.len();
+        buf[len_start..payload_start].copy_from_slice(&encoded);
+        if self.checksum_threshold.is_some() {
+            // Whether the payload is checksummed depends on the length, too.
+            let mut before_len = if self.deadlines {
+                mem::size_of::<u64>()
+            } else {
+                0
+            };
+            if self.priorities {
+                before_len += 1;
+            }
+            let flags = &mut buf[len_start - before_len - 1];
+            *flags = *flags & !FLAG_CHECKSUM | self.checksum_flag(len);
+        }
+    }
+
+    /// The state that follows the flags of frame `id`, or its id if it has no flags.
+    fn after_flags(&self, id: RequestId, flags: u8) -> CodecState {
+        if self.priorities {
+            CodecState::Priority {
+                id: id,
+                flags: flags,
+            }
+        } else {
+            self.after_priority(id, flags, 0)
+        }
+    }
+
+    /// The state that follows the priority of frame `id`, or its flags if it has no priority.
+    fn after_priority(&self, id: RequestId, flags: u8, priority: u8) -> CodecState {
+        if self.deadlines {
+            CodecState::Deadline {
+                id: id,
+                flags: flags,
+                priority: priority,
+            }
+        } else {
+            CodecState::Len {
+                id: id,
+                flags: flags,
+                priority: priority,
+                deadline: 0,
+            }
+        }
+    }
+
+    /// The number of bytes in front of the id.
+    fn marker_len(&self) -> usize {
+        if self.frame_markers {
+            FRAME_MARKER.len()
+        } else {
+            0
+        }
+    }
+
+    /// True if the payload of a frame with `flags` is followed by its checksum.
+    fn has_checksum(&self, flags: u8) -> bool {
+        self.checksum || flags & FLAG_CHECKSUM != 0
+    }
+
+    /// The number of bytes that follow the payload of a frame with `flags`.
+    fn trailer_len(&self, flags: u8) -> usize {
+        if self.has_checksum(flags) {
+            mem::size_of::<u32>()
+        } else {
+            0
+        }
+    }
+
+    /// The number of bytes a payload of `len` bytes takes in the read buffer with its trailer,
+    /// or `None` if that is more than can be addressed.
+    fn buffered_len(&self, len: u64, flags: u8) -> Option<usize> {
+        if len > usize::MAX as u64 {
+            return None;
+        }
+        (len as usize).checked_add(self.trailer_len(flags))
+    }
+
+    /// Appends the frame trailer for the payload that starts at `payload_start` in `buf`.
+    pub fn write_trailer(&self, buf: &mut Vec<u8>, payload_start: usize) {
+        if self.checksums((buf.len() - payload_start) as u64) {
+            let checksum = crc32::checksum_ieee(&buf[payload_start..]);
+            self.endianness.write_u32(buf, checksum);
+        }
+    }
+
+    /// Returns a writer that passes a payload of `len` bytes through to `w`, computing its
+    /// checksum on the way if its frame carries one. Where `write_trailer` needs the whole
+    /// payload in a buffer, `PayloadWriter::finish` writes the trailer after it was streamed.
+    pub fn payload_writer<'a, W: io::Write>(&self, w: &'a mut W, len: u64) -> PayloadWriter<'a, W> {
+        PayloadWriter {
+            inner: w,
+            checksum: if self.checksums(len) { Some(0) } else { None },
+        }
+    }
+}
+
+/// Writes a payload to the writer it wraps; see `FrameOptions::payload_writer`.
+pub struct PayloadWriter<'a, W: 'a> {
+    inner: &'a mut W,
+    /// The CRC32 of the bytes written so far, if the frame carries a checksum.
+    checksum: Option<u32>,
+}
+
+impl<'a, W: io::Write> PayloadWriter<'a, W> {
+    /// Writes the frame trailer for the payload written, with the layout in `options`.
+    pub fn finish(self, options: &FrameOptions) -> io::Result<()> {
+        match self.checksum {
+            Some(checksum) => {
+                let mut trailer = Vec::with_capacity(mem::size_of::<u32>());
+                options.endianness.write_u32(&mut trailer, checksum);
+                self.inner.write_all(&trailer)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a, W: io::Write> io::Write for PayloadWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(ref mut checksum) = self.checksum {
+            *checksum = crc32::update(*checksum, &crc32::IEEE_TABLE, &buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Appends `n` to `buf` as a LEB128 varint.
+fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push((n as u8 & 0x7f) | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+/// The number of bytes `write_varint` uses for `n`.
+fn varint_len(mut n: u64) -> usize {
+    let mut len = 1;
+    while n >= 0x80 {
+        len += 1;
+        n >>= 7;
+    }
+    len
+}
+
+/// Returns `time` in milliseconds since the unix epoch, as carried by frame deadlines.
+pub fn unix_millis(time: SystemTime) -> u64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    since_epoch.as_secs() * 1000 + since_epoch.subsec_nanos() as u64 / 1_000_000
+}
+
+/// What a `Codec` is waiting for, as returned by `Codec::state`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DecodeProgress {
+    /// Waiting for the id of the next frame.
+    WaitingForId,
+    /// Discarding bytes up to the next frame marker.
+    Resyncing,
+    /// Waiting for the rest of the header of frame `id`, up to and including its length.
+    WaitingForLen { id: u64 },
+    /// Waiting for `needed` more bytes of the payload of frame `id`, and its trailer.
+    WaitingForPayload { id: u64, needed: u64 },
+    /// Discarding the `remaining` bytes of a frame that was rejected.
+    Skipping { remaining: u64 },
+}
+
+/// A complete frame whose payload has not been deserialized yet.
+pub struct Frame {
+    pub flags: u8,
+    /// The priority of the frame, as set by the sender, or 0 if frames carry none.
+    pub priority: u8,
+    /// When the sender stops waiting for a response, in milliseconds since the unix epoch.
+    pub deadline: Option<u64>,
+    pub payload: EasyBuf,
+}
+
+#[derive(Clone, Copy)]
+pub enum CodecState {
+    Id,
+    /// Discarding bytes up to the next frame marker.
+    Resync,
+    Flags { id: u64 },
+    Priority { id: u64, flags: u8 },
+    Deadline { id: u64, flags: u8, priority: u8 },
+    Len {
+        id: u64,
+        flags: u8,
+        priority: u8,
+        deadline: u64,
+    },
+    /// Reading a varint length prefix, of which `len` holds the bits read so far and `shift` is
+    /// the position of the next group of bits.
+    VarLen {
+        id: u64,
+        flags: u8,
+        priority: u8,
+        deadline: u64,
+        len: u64,
+        shift: u32,
+    },
+    Payload {
+        id: u64,
+        flags: u8,
+        priority: u8,
+        deadline: u64,
+        len: u64,
+    },
+    /// Discarding the rest of a frame that was rejected.
+    Skip { remaining: u64 },
+}
+
+impl CodecState {
+    /// Advances the framing state machine as far as the bytes in `buf` allow. Returns the id
+    /// and contents of the next complete frame, or `None` if more bytes are needed.
+    ///
+    /// A frame whose payload is too large is rejected as soon as its length is known; the rest
+    /// of it is skipped as it arrives.
+    pub fn decode<E>(&mut self,
+                     options: &FrameOptions,
+                     max_payload_size: u64,
+                     buf: &mut EasyBuf)
+                     -> io::Result<Option<(RequestId, Result<Frame, DecodeError<E>>)>> {
+        self.decode_screened(options, max_payload_size, buf, |_, _, _| None)
+    }
+
+    /// Like `decode`, but calls `screen` with the id, flags, and payload length of every frame
+    /// once its header is parsed. If it returns a reason, the frame is rejected with
+    /// `DecodeError::Rejected`, and its payload is skipped as it arrives, like one that is too
+    /// large.
+    pub fn decode_screened<E, F>(&mut self,
+                                 options: &FrameOptions,
+                                 max_payload_size: u64,
+                                 buf: &mut EasyBuf,
+                                 mut screen: F)
+                                 -> io::Result<Option<(RequestId, Result<Frame, DecodeError<E>>)>>
+        where F: FnMut(RequestId, u8, u64) -> Option<String>
+    {
+        use self::CodecState::*;
+        trace!("Codec::decode: {:?}", buf.as_slice());
+
+        loop {
+            match *self {
+                Id if buf.len() < options.marker_len() + mem::size_of::<u64>() => {
+                    trace!("--> Buf len is {}; waiting for {} to parse id.",
+                           buf.len(),
+                           options.marker_len() + mem::size_of::<u64>());
+                    return Ok(None);
+                }
+                Id => {
+                    if options.frame_markers {
+                        let marker = buf.drain_to(FRAME_MARKER.len());
+                        if marker.as_slice() != FRAME_MARKER {
+                            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                      format!("Expected a frame marker, but \
+                                                               got {:?}",
+                                                              marker.as_slice())));
+                        }
+                    }
+                    let id_buf = buf.drain_to(mem::size_of::<u64>());
+                    let id = options.endianness.read_u64(id_buf.as_slice());
+                    trace!("--> Parsed id = {} from {:?}", id, id_buf.as_slice());
+                    *self = if options.has_flags() {
+                        Flags { id: id }
+                    } else {
+                        options.after_flags(id, 0)
+                    };
+                }
+                Resync => {
+                    let found = buf.as_slice()
+                        .windows(FRAME_MARKER.len())
+                        .position(|window| window == FRAME_MARKER);
+                    match found {
+                        Some(start) => {
+                            trace!("--> Found a frame marker after {} bytes.", start);
+                            buf.drain_to(start);
+                            *self = Id;
+                        }
+                        None => {
+                            // The end of the buffer may be the start of a marker.
+                            let discard = buf.len().saturating_sub(FRAME_MARKER.len() - 1);
+                            trace!("--> Discarded {} bytes while looking for a frame marker.",
+                                   discard);
+                            buf.drain_to(discard);
+                            return Ok(None);
+                        }
+                    }
+                }
+                Flags { .. } if buf.len() < mem::size_of::<u8>() => {
+                    trace!("--> Buf len is {}; waiting for 1 to parse flags.", buf.len());
+                    return Ok(None);
+                }
+                Flags { id } => {
+                    let flags = buf.drain_to(mem::size_of::<u8>()).as_slice()[0];
+                    trace!("--> Parsed flags = {:#b}", flags);
+                    let unexpected = flags & !options.accepted_flags();
+                    if unexpected != 0 {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  format!("Frame flags {:#b} weren't negotiated \
+                                                           for the connection",
+                                                          unexpected)));
+                    }
+                    *self = options.after_flags(id, flags);
+                }
+                Priority { .. } if buf.len() < mem::size_of::<u8>() => {
+                    trace!("--> Buf len is {}; waiting for 1 to parse priority.", buf.len());
+                    return Ok(None);
+                }
+                Priority { id, flags } => {
+                    let priority = buf.drain_to(mem::size_of::<u8>()).as_slice()[0];
+                    trace!("--> Parsed priority = {}", priority);
+                    *self = options.after_priority(id, flags, priority);
+                }
+                Deadline { .. } if buf.len() < mem::size_of::<u64>() => {
+                    trace!("--> Buf len is {}; waiting for 8 to parse deadline.", buf.len());
+                    return Ok(None);
+                }
+                Deadline { id, flags, priority } => {
+                    let deadline_buf = buf.drain_to(mem::size_of::<u64>());
+                    let deadline = options.endianness.read_u64(deadline_buf.as_slice());
+                    trace!("--> Parsed deadline = {}", deadline);
+                    *self = Len {
+                        id: id,
+                        flags: flags,
+                        priority: priority,
+                        deadline: deadline,
+                    };
+                }
+                Len { id, flags, priority, deadline } if options.len_width == LenWidth::Varint => {
+                    *self = VarLen {
+                        id: id,
+                        flags: flags,
+                        priority: priority,
+                        deadline: deadline,
+                        len: 0,
+                        shift: 0,
+                    };
+                }
+                Len { .. } if buf.len() < options.len_width.size() => {
+                    trace!("--> Buf len is {}; waiting for {} to parse packet length.",
+                           buf.len(),
+                           options.len_width.size());
+                    return Ok(None);
+                }
+                Len { id, flags, priority, deadline } => {
+                    let len_buf = buf.drain_to(options.len_width.size());
+                    let len = match options.len_width {
+                        LenWidth::U32 => options.endianness.read_u32(len_buf.as_slice()) as u64,
+                        LenWidth::U64 => options.endianness.read_u64(len_buf.as_slice()),
+                        LenWidth::Varint => unreachable!(),
+                    };
+                    if let Some(rejected) = self.start_payload(options,
+                                                               max_payload_size,
+                                                               &mut screen,
+                                                               id,
+                                                               flags,
+                                                               priority,
+                                                               deadline,
+                                                               len) {
+                        return Ok(Some(rejected));
+                    }
+                }
+                VarLen { .. } if buf.len() == 0 => {
+                    trace!("--> Buf is empty; waiting for the next byte of the packet length.");
+                    return Ok(None);
+                }
+                VarLen { id, flags, priority, deadline, len, shift } => {
+                    let byte = buf.drain_to(mem::size_of::<u8>()).as_slice()[0];
+                    let group = (byte & 0x7f) as u64;
+                    if shift > 63 || (shift == 63 && group > 1) {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  "Varint length prefix overflows a u64"));
+                    }
+                    let len = len | group << shift;
+                    if byte & 0x80 != 0 {
+                        *self = VarLen {
+                            id: id,
+                            flags: flags,
+                            priority: priority,
+                            deadline: deadline,
+                            len: len,
+                            shift: shift + 7,
+                        };
+                    } else if let Some(rejected) = self.start_payload(options,
+                                                                      max_payload_size,
+                                                                      &mut screen,
+                                                                      id,
+                                                                      flags,
+                                                                      priority,
+                                                                      deadline,
+                                                                      len) {
+                        return Ok(Some(rejected));
+                    }
+                }
+                Payload { len, flags, .. } if options.buffered_len(len, flags).is_none() => {
+                    // Only an unbounded max payload size lets a length this large through.
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("A payload of {} bytes can't be buffered",
+                                                      len)));
+                }
+                Payload { len, flags, .. } if buf.len() <
+                                              options.buffered_len(len, flags).unwrap() => {
+                    trace!("--> Buf len is {}; waiting for {} to parse payload.",
+                           buf.len(),
+                           options.buffered_len(len, flags).unwrap());
+                    return Ok(None);
+                }
+                Skip { remaining } => {
+                    let skipped = cmp::min(remaining, buf.len() as u64);
+                    buf.drain_to(skipped as usize);
+                    if skipped < remaining {
+                        trace!("--> Skipped {} bytes; {} left to skip.",
+                               skipped,
+                               remaining - skipped);
+                        *self = Skip { remaining: remaining - skipped };
+                        return Ok(None);
+                    }
+                    *self = Id;
+                }
+                Payload { id, flags, priority, deadline, len } => {
+                    let payload = buf.drain_to(len as usize);
+                    // Reset the state machine because, either way, we're done processing this
+                    // message.
+                    *self = Id;
+
+                    if options.has_checksum(flags) {
+                        let checksum_buf = buf.drain_to(mem::size_of::<u32>());
+                        let expected = options.endianness.read_u32(checksum_buf.as_slice());
+                        let actual = crc32::checksum_ieee(payload.as_slice());
+                        if actual != expected {
+                            warn!("Checksum mismatch for request id = {}: expected {:#x}, got \
+                                   {:#x}",
+                                  id,
+                                  expected,
+                                  actual);
+                            return Ok(Some((id,
+                                            Err(DecodeError::ChecksumMismatch {
+                                                expected: expected,
+                                                actual: actual,
+                                            }))));
+                        }
+                    }
+
+                    return Ok(Some((id,
+                                    Ok(Frame {
+                                        flags: flags,
+                                        priority: priority,
+                                        deadline: if deadline == 0 { None } else { Some(deadline) },
+                                        payload: payload,
+                                    }))));
+                }
+            }
+        }
+    }
+
+    /// True if a frame header is partly parsed, given that `buffered` bytes are waiting to be
+    /// parsed.
+    pub fn in_header(&self, buffered: usize) -> bool {
+        match *self {
+            CodecState::Id => buffered > 0,
+            CodecState::Flags { .. } |
+            CodecState::Priority { .. } |
+            CodecState::Deadline { .. } |
+            CodecState::Len { .. } |
+            CodecState::VarLen { .. } => true,
+            CodecState::Resync |
+            CodecState::Payload { .. } |
+            CodecState::Skip { .. } => false,
+        }
+    }
+
+    /// Returns what the state machine is waiting for, given that `buffered` bytes are waiting to
+    /// be parsed.
+    pub fn progress(&self, options: &FrameOptions, buffered: usize) -> DecodeProgress {
+        match *self {
+            CodecState::Id => DecodeProgress::WaitingForId,
+            CodecState::Resync => DecodeProgress::Resyncing,
+            CodecState::Flags { id } |
+            CodecState::Priority { id, .. } |
+            CodecState::Deadline { id, .. } |
+            CodecState::Len { id, .. } |
+            CodecState::VarLen { id, .. } => DecodeProgress::WaitingForLen { id: id },
+            CodecState::Payload { id, flags, len, .. } => {
+                let total = len.saturating_add(options.trailer_len(flags) as u64);
+                DecodeProgress::WaitingForPayload {
+                    id: id,
+                    needed: total.saturating_sub(buffered as u64),
+                }
+            }
+            CodecState::Skip { remaining } => DecodeProgress::Skipping { remaining: remaining },
+        }
+    }
+
+    /// Moves on to the payload of a frame whose length, `len`, was just parsed. If the payload
+    /// is too large, or `screen` rejects the frame, moves on to skipping it instead, and
+    /// returns the rejection.
+    fn start_payload<E, F>(&mut self,
+                           options: &FrameOptions,
+                           max_payload_size: u64,
+                           screen: &mut F,
+                           id: RequestId,
+                           flags: u8,
+                           priority: u8,
+                           deadline: u64,
+                           len: u64)
+                           -> Option<(RequestId, Result<Frame, DecodeError<E>>)>
+        where F: FnMut(RequestId, u8, u64) -> Option<String>
+    {
+        trace!("--> Parsed payload length = {}", len);
+        let rejected = if len > max_payload_size {
+            warn!("Rejecting too-big packet of size {} for request id = {} (max is {})",
+                  len,
+                  id,
+                  max_payload_size);
+            DecodeError::PayloadTooLarge {
+                len: len,
+                max: max_payload_size,
+            }
+        } else if let Some(reason) = screen(id, flags, len) {
+            DecodeError::Rejected { reason: reason }
+        } else {
+            *self = CodecState::Payload {
+                id: id,
+                flags: flags,
+                priority: priority,
+                deadline: deadline,
+                len: len,
+            };
+            return None;
+        };
+        let remaining = len.saturating_add(options.trailer_len(flags) as u64);
+        *self = CodecState::Skip { remaining: remaining };
+        Some((id, Err(rejected)))
+    }
+}
@@ -0,0 +1,884 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use bincode::{self, Infinite};
+use byteorder::{BigEndian, ByteOrder};
+use futures::{Future, future};
+use serde::{Deserialize, Serialize};
+use std::{cmp, fmt, io, u16, u64};
+use std::sync::Arc;
+use super::{Compression, Credentials, Format, ProtocolError, ProtocolErrorCode};
+use super::auth::Authenticator;
+use super::compression;
+use super::serializer::{format_code, format_from_code};
+use tokio_core::io::{Io, read_exact, write_all};
+
+/// Identifies the tarpc protocol.
+const MAGIC: &'static [u8] = b"TRPC";
+
+/// The version of the preamble itself.
+const PREAMBLE_VERSION: u8 = 1;
+
+/// `MAGIC` followed by `PREAMBLE_VERSION`. Written once by the client at the start of every
+/// connection, before any frames.
+const PREAMBLE: &'static [u8; 5] = b"TRPC\x01";
+
+/// The newest version of the frame format.
+pub const PROTOCOL_VERSION: u32 = 10;
+
+/// The oldest version of the frame format still supported.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// The first version of the frame format in which frames carry a deadline.
+pub const DEADLINE_VERSION: u32 = 2;
+
+/// The first version of the frame format in which servers say goodbye before closing.
+pub const GOODBYE_VERSION: u32 = 3;
+
+/// The first version of the frame format in which frames carry a priority.
+pub const PRIORITY_VERSION: u32 = 4;
+
+/// The first version of the frame format in which servers reject requests that reuse the id of
+/// a request in flight.
+pub const REJECTION_VERSION: u32 = 5;
+
+/// The first version of the frame format in which frames can carry metadata.
+pub const METADATA_VERSION: u32 = 6;
+
+/// The first version of the frame format in which servers acknowledge requests they have
+/// accepted.
+pub const ACK_VERSION: u32 = 7;
+
+/// The first version of the frame format in which servers echo the pings of clients.
+pub const PING_VERSION: u32 = 8;
+
+/// The first version of the frame format in which a side that closes the connection because of
+/// a `ProtocolError` tells the other why, and in which servers refuse clients with its code.
+pub const PROTOCOL_ERROR_VERSION: u32 = 9;
+
+/// The first version of the frame format in which servers answer the stats queries of clients.
+pub const DIAGNOSTICS_VERSION: u32 = 10;
+
+/// The parameters agreed on by the client and server when a connection is established.
+#[derive(Clone, Debug)]
+pub struct Handshake {
+    version: u32,
+    max_outbound: u64,
+    max_inbound: u64,
+    format: Option<Format>,
+    compression: Option<Compression>,
+    peer_compression: Option<Compression>,
+    schema: u64,
+    username: Option<String>,
+    /// The versions the client supports, as told to a server.
+    peer_versions: Option<(u32, u32)>,
+}
+
+impl Handshake {
+    /// The version of the frame format used on the connection.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// The largest payload this side may send: the smaller of its own max outbound payload size
+    /// and the peer's max inbound payload size.
+    pub fn max_outbound(&self) -> u64 {
+        self.max_outbound
+    }
+
+    /// The largest payload this side accepts.
+    pub fn max_inbound(&self) -> u64 {
+        self.max_inbound
+    }
+
+    /// The payload format chosen for the connection, if its serializer is a `Format`.
+    pub fn format(&self) -> Option<Format> {
+        self.format
+    }
+
+    /// The algorithm this side compresses the payloads it sends with, if any: its own, if the
+    /// peer can decompress it.
+    pub fn compression(&self) -> Option<Compression> {
+        self.compression
+    }
+
+    /// The algorithm the peer compresses the payloads it sends with, if any, which this side
+    /// decompresses. It needn't be the same as `compression`: each direction is negotiated on
+    /// its own.
+    pub fn peer_compression(&self) -> Option<Compression> {
+        self.peer_compression
+    }
+
+    /// The schema both sides agreed on.
+    pub fn schema(&self) -> u64 {
+        self.schema
+    }
+
+    /// The user the client authenticated as, if the server requires credentials or the client
+    /// sent them.
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_ref().map(|username| &username[..])
+    }
+
+    /// The oldest and newest versions the client supports, which a server learns in the
+    /// handshake; `None` for a client. During a rollout, this shows how many clients could
+    /// speak a newer version than the one negotiated.
+    pub fn peer_versions(&self) -> Option<(u32, u32)> {
+        self.peer_versions
+    }
+}
+
+/// Called with the outcome of every handshake, once per connection. Returning an error closes
+/// the connection; a client is told the code of an error that wraps a `ProtocolError`.
+pub type HandshakeHook = Arc<Fn(&Handshake) -> io::Result<()> + Send + Sync>;
+
+/// What one side of the connection brings to the handshake.
+#[derive(Clone)]
+pub struct HandshakeOptions {
+    pub min_version: u32,
+    pub max_version: u32,
+    /// The algorithm this side compresses payloads with, if the peer can decompress it.
+    pub compression: Option<Compression>,
+    /// The algorithms this side can decompress besides `compression`, which the peer is told.
+    pub decompresses: Vec<Compression>,
+    /// If true, the handshake fails unless both sides compress payloads with `compression`.
+    pub require_compression: bool,
+    /// Identifies the requests and responses sent on the connection, such as a hash of the
+    /// service definition. Must be the same on both sides.
+    pub schema: u64,
+    /// The largest payload this side will send.
+    pub max_outbound: u64,
+    /// The largest payload this side accepts, which the peer is told.
+    pub max_inbound: u64,
+    /// The payload format this side uses, if it can switch formats per connection.
+    pub format: Option<Format>,
+    /// The formats a server accepts besides its own.
+    pub accepted_formats: Vec<Format>,
+    /// The credentials a client sends.
+    pub credentials: Option<Credentials>,
+    /// Checks the credentials of clients. A server with an authenticator rejects clients that
+    /// send none.
+    pub authenticator: Option<Authenticator>,
+    pub hook: Option<HandshakeHook>,
+}
+
+impl Default for HandshakeOptions {
+    fn default() -> Self {
+        HandshakeOptions {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            compression: None,
+            decompresses: vec![],
+            require_compression: false,
+            schema: 0,
+            max_outbound: u64::MAX,
+            max_inbound: u64::MAX,
+            format: None,
+            accepted_formats: vec![],
+            credentials: None,
+            authenticator: None,
+            hook: None,
+        }
+    }
+}
+
+impl fmt::Debug for HandshakeOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "HandshakeOptions {{ min_version: {}, max_version: {}, compression: {:?}, \
+                decompresses: {:?}, schema: {:#x}, max_outbound: {}, max_inbound: {}, format: \
+                {:?}, accepted_formats: {:?}, credentials: {:?}, .. }}",
+               self.min_version,
+               self.max_version,
+               self.compression,
+               self.decompresses,
+               self.schema,
+               self.max_outbound,
+               self.max_inbound,
+               self.format,
+               self.accepted_formats,
+               self.credentials)
+    }
+}
+
+impl HandshakeOptions {
+    fn run_hook(&self, handshake: &Handshake) -> io::Result<()> {
+        match self.hook {
+            Some(ref hook) => hook(handshake),
+            None => Ok(()),
+        }
+    }
+
+    /// The algorithms this side can decompress: `compression`, and `decompresses`.
+    fn all_decompresses(&self) -> Vec<Compression> {
+        let mut decompresses = self.decompresses.clone();
+        if let Some(compression) = self.compression {
+            if !decompresses.contains(&compression) {
+                decompresses.push(compression);
+            }
+        }
+        decompresses
+    }
+
+    /// Fails unless `client` and `server`, the algorithms negotiated for the payloads each sends,
+    /// are both `compression`, if `require_compression` is set.
+    fn check_required(&self,
+                      client: Option<Compression>,
+                      server: Option<Compression>)
+                      -> io::Result<()> {
+        if !self.require_compression || (client == self.compression && server == self.compression) {
+            return Ok(());
+        }
+        Err(io::Error::new(io::ErrorKind::InvalidData,
+                           format!("Compression mismatch: this side requires {:?}, but the \
+                                    client compresses with {:?} and the server with {:?}",
+                                   self.compression,
+                                   client,
+                                   server)))
+    }
+}
+
+/// The algorithm a side that compresses with `compression` may use, given that its peer can
+/// decompress `decompresses`: its own, if the peer can decompress it, and none otherwise.
+fn usable(compression: Option<Compression>, decompresses: &[Compression]) -> Option<Compression> {
+    match compression {
+        Some(compression) if decompresses.contains(&compression) => Some(compression),
+        _ => None,
+    }
+}
+
+/// The algorithm that `code`, chosen by the server, stands for. Fails if this build lacks it,
+/// since the server only chooses among the algorithms the client sent.
+fn known_compression(code: Option<u32>) -> io::Result<Option<Compression>> {
+    match code {
+        None => Ok(None),
+        Some(code) => {
+            compression::from_code(code).map(Some).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData,
+                               format!("Server chose unsupported compression code {}", code))
+            })
+        }
+    }
+}
+
+/// Sent by the client after the preamble. Algorithms and formats are sent as their codes, so
+/// that a peer built without some of them can still read the message.
+#[derive(Debug, Deserialize, Serialize)]
+struct ClientHello {
+    min_version: u32,
+    max_version: u32,
+    compression: Option<u32>,
+    /// The algorithms the client can decompress.
+    decompresses: Vec<u32>,
+    schema: u64,
+    max_inbound: u64,
+    format: Option<u32>,
+    /// The client's credentials, as a SASL PLAIN message.
+    credentials: Option<Vec<u8>>,
+}
+
+/// The server's response to a `ClientHello`.
+#[derive(Debug, Deserialize, Serialize)]
+enum ServerHello {
+    /// Carries the algorithms the client and the server compress the payloads they send with.
+    Accept {
+        version: u32,
+        max_inbound: u64,
+        client_compression: Option<u32>,
+        server_compression: Option<u32>,
+    },
+    Reject { reason: String },
+    /// The client sent no credentials or the wrong ones.
+    Unauthorized { reason: String },
+    /// Sent instead of the others for a `ProtocolError`, to clients of `PROTOCOL_ERROR_VERSION`
+    /// or newer.
+    Refused {
+        code: ProtocolErrorCode,
+        reason: String,
+    },
+}
+
+/// Writes a `u16`-length-prefixed, bincode-serialized handshake message.
+fn write_message<T, M>(io: T, message: &M) -> Box<Future<Item = T, Error = io::Error>>
+    where T: Io + 'static,
+          M: Serialize
+{
+    let payload_size = bincode::serialized_size(message);
+    if payload_size > u16::MAX as u64 {
+        return Box::new(future::err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                   "Handshake message is too large")));
+    }
+    let mut buf = vec![0; 2];
+    BigEndian::write_u16(&mut buf, payload_size as u16);
+    if let Err(e) = bincode::serialize_into(&mut buf, message, Infinite) {
+        return Box::new(future::err(io::Error::new(io::ErrorKind::InvalidInput, e)));
+    }
+    Box::new(write_all(io, buf).map(|(io, _)| io))
+}
+
+/// Reads a message written by `write_message`.
+fn read_message<T, M>(io: T) -> Box<Future<Item = (T, M), Error = io::Error>>
+    where T: Io + 'static,
+          M: Deserialize + 'static
+{
+    Box::new(read_exact(io, [0; 2])
+        .and_then(|(io, len)| read_exact(io, vec![0; BigEndian::read_u16(&len) as usize]))
+        .and_then(|(io, buf)| {
+            bincode::deserialize_from(&mut &buf[..], Infinite)
+                .map(|message| (io, message))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }))
+}
+
+/// Picks the newest version supported by both sides, the payload size limits, the payload
+/// format, and how each side compresses the payloads it sends, and checks that they agree on
+/// the schema. Then
+/// checks the client's credentials, failing with an error of kind `PermissionDenied` if they
+/// aren't accepted.
+fn negotiate(ours: &HandshakeOptions, theirs: &ClientHello) -> io::Result<Handshake> {
+    if ours.schema != theirs.schema {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("Schema mismatch: client has {:#x}, server has {:#x}",
+                                          theirs.schema,
+                                          ours.schema)));
+    }
+    // The algorithms this build lacks can't be used, as if the client didn't have them.
+    let decompresses = theirs.decompresses
+        .iter()
+        .filter_map(|&code| compression::from_code(code))
+        .collect::<Vec<_>>();
+    let client_compression = usable(theirs.compression.and_then(compression::from_code),
+                                    &ours.all_decompresses());
+    let server_compression = usable(ours.compression, &decompresses);
+    ours.check_required(client_compression, server_compression)?;
+    let format = match theirs.format.map(format_from_code) {
+        None if ours.format.is_none() => None,
+        Some(Some(format)) if Some(format) == ours.format ||
+                              ours.accepted_formats.contains(&format) => Some(format),
+        _ => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("Format mismatch: client uses format code {:?}, \
+                                               server uses {:?} and accepts {:?}",
+                                              theirs.format,
+                                              ours.format,
+                                              ours.accepted_formats)));
+        }
+    };
+    let version = cmp::min(ours.max_version, theirs.max_version);
+    if version < ours.min_version || version < theirs.min_version {
+        let message = format!("No common protocol version: client supports {}-{}, server \
+                               supports {}-{}",
+                              theirs.min_version,
+                              theirs.max_version,
+                              ours.min_version,
+                              ours.max_version);
+        return Err(ProtocolError::new(ProtocolErrorCode::VersionMismatch, message).into_io());
+    }
+    Ok(Handshake {
+        version: version,
+        max_outbound: cmp::min(ours.max_outbound, theirs.max_inbound),
+        max_inbound: ours.max_inbound,
+        format: format,
+        compression: server_compression,
+        peer_compression: client_compression,
+        schema: ours.schema,
+        username: authenticate(ours, theirs)?,
+        peer_versions: Some((theirs.min_version, theirs.max_version)),
+    })
+}
+
+/// Checks the client's credentials with the server's authenticator, if it has one, returning
+/// the username they name.
+fn authenticate(ours: &HandshakeOptions, theirs: &ClientHello) -> io::Result<Option<String>> {
+    let credentials = match theirs.credentials {
+        Some(ref message) => {
+            Some(Credentials::from_plain(message).map_err(|e| {
+                    auth_failed(format!("Malformed credentials: {}", e))
+                })?)
+        }
+        None => None,
+    };
+    let authenticator = match ours.authenticator {
+        Some(ref authenticator) => authenticator,
+        None => return Ok(credentials.map(|credentials| credentials.username().to_string())),
+    };
+    let credentials = match credentials {
+        Some(credentials) => credentials,
+        None => {
+            return Err(auth_failed("The server requires credentials"));
+        }
+    };
+    if let Err(e) = authenticator(&credentials) {
+        return Err(auth_failed(format!("Authentication failed for user {:?}: {}",
+                                       credentials.username(),
+                                       e)));
+    }
+    Ok(Some(credentials.username().to_string()))
+}
+
+fn auth_failed<M: Into<String>>(message: M) -> io::Error {
+    ProtocolError::new(ProtocolErrorCode::AuthFailed, message).into_io()
+}
+
+/// Writes the preamble to a newly-connected server and negotiates the connection's parameters.
+pub fn client<T>(io: T, options: HandshakeOptions) -> Box<Future<Item = (T, Handshake),
+                                                               Error = io::Error>>
+    where T: Io + 'static
+{
+    let hello = ClientHello {
+        min_version: options.min_version,
+        max_version: options.max_version,
+        compression: options.compression.map(compression::code),
+        decompresses: options.all_decompresses().into_iter().map(compression::code).collect(),
+        schema: options.schema,
+        max_inbound: options.max_inbound,
+        format: options.format.map(format_code),
+        credentials: options.credentials.as_ref().map(Credentials::to_plain),
+    };
+    Box::new(write_all(io, PREAMBLE)
+        .and_then(move |(io, _)| {
+            trace!("Wrote preamble.");
+            write_message(io, &hello)
+        })
+        .and_then(read_message)
+        .and_then(move |(io, hello)| match hello {
+            ServerHello::Accept { version,
+                                  max_inbound,
+                                  client_compression,
+                                  server_compression } => {
+                if version < options.min_version || version > options.max_version {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("Server chose unsupported protocol \
+                                                       version {}",
+                                                      version)));
+                }
+                let client_compression = known_compression(client_compression)?;
+                let server_compression = known_compression(server_compression)?;
+                let decompresses = options.all_decompresses();
+                if (client_compression.is_some() && client_compression != options.compression) ||
+                   server_compression.map_or(false, |server| !decompresses.contains(&server)) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("Server chose unsupported compression: \
+                                                       {:?} for requests and {:?} for \
+                                                       responses",
+                                                      client_compression,
+                                                      server_compression)));
+                }
+                options.check_required(client_compression, server_compression)?;
+                let handshake = Handshake {
+                    version: version,
+                    max_outbound: cmp::min(options.max_outbound, max_inbound),
+                    max_inbound: options.max_inbound,
+                    format: options.format,
+                    compression: client_compression,
+                    peer_compression: server_compression,
+                    schema: options.schema,
+                    username: options.credentials
+                        .as_ref()
+                        .map(|credentials| credentials.username().to_string()),
+                    peer_versions: None,
+                };
+                debug!("Negotiated {:?}", handshake);
+                options.run_hook(&handshake)?;
+                Ok((io, handshake))
+            }
+            ServerHello::Reject { reason } => {
+                warn!("Server rejected the connection: {}", reason);
+                Err(io::Error::new(io::ErrorKind::ConnectionRefused,
+                                   format!("Server rejected the connection: {}", reason)))
+            }
+            ServerHello::Unauthorized { reason } => {
+                warn!("Server rejected the credentials: {}", reason);
+                Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                   format!("Server rejected the credentials: {}", reason)))
+            }
+            ServerHello::Refused { code, reason } => {
+                warn!("Server refused the connection ({:?}): {}", code, reason);
+                let kind = if code == ProtocolErrorCode::AuthFailed {
+                    io::ErrorKind::PermissionDenied
+                } else {
+                    io::ErrorKind::ConnectionRefused
+                };
+                Err(io::Error::new(kind, ProtocolError::new(code, reason)))
+            }
+        }))
+}
+
+fn check_preamble(preamble: [u8; 5]) -> io::Result<()> {
+    if preamble[..4] != MAGIC[..] {
+        warn!("Rejecting connection with unknown preamble {:?}", preamble);
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  "Peer is not speaking the tarpc protocol"));
+    }
+    if preamble[4] != PREAMBLE_VERSION {
+        warn!("Rejecting connection with preamble version {}", preamble[4]);
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("Unsupported tarpc preamble version {}; expected {}",
+                                          preamble[4],
+                                          PREAMBLE_VERSION)));
+    }
+    trace!("Read preamble.");
+    Ok(())
+}
+
+/// Reads the preamble from a newly-accepted client, failing if the client isn't speaking the
+/// tarpc protocol, and negotiates the connection's parameters.
+///
+/// If negotiation fails, the client is told why before the connection is closed.
+pub fn server<T>(io: T, options: HandshakeOptions) -> Box<Future<Item = (T, Handshake),
+                                                               Error = io::Error>>
+    where T: Io + 'static
+{
+    Box::new(read_exact(io, [0; 5])
+        .and_then(|(io, preamble)| check_preamble(preamble).map(|()| io))
+        .and_then(read_message)
+        .and_then(move |(io, hello): (T, ClientHello)| {
+            let handshake = negotiate(&options, &hello)
+                .and_then(|handshake| options.run_hook(&handshake).map(|()| handshake));
+            match handshake {
+                Ok(handshake) => {
+                    debug!("Negotiated {:?}", handshake);
+                    let accept = ServerHello::Accept {
+                        version: handshake.version,
+                        max_inbound: handshake.max_inbound,
+                        client_compression: handshake.peer_compression.map(compression::code),
+                        server_compression: handshake.compression.map(compression::code),
+                    };
+                    future::Either::A(write_message(io, &accept).map(move |io| (io, handshake)))
+                }
+                Err(e) => {
+                    warn!("Rejecting connection: {}", e);
+                    let refused = match ProtocolError::from_io(&e) {
+                        Some(error) if hello.max_version >= PROTOCOL_ERROR_VERSION => Some(error),
+                        _ => None,
+                    };
+                    let reject = if let Some(error) = refused {
+                        ServerHello::Refused {
+                            code: error.code,
+                            reason: error.message.clone(),
+                        }
+                    } else if e.kind() == io::ErrorKind::PermissionDenied {
+                        ServerHello::Unauthorized { reason: e.to_string() }
+                    } else {
+                        ServerHello::Reject { reason: e.to_string() }
+                    };
+                    future::Either::B(write_message(io, &reject).then(move |_| Err(e)))
+                }
+            }
+        }))
+}
+
+#[cfg(test)]
+use std::cell::RefCell;
+#[cfg(test)]
+use std::rc::Rc;
+
+#[cfg(test)]
+/// A fake connection that reads from a fixed buffer and records what's written to it. The record
+/// is shared so that it outlives a handshake that fails and drops the connection.
+pub struct MockIo {
+    pub read: io::Cursor<Vec<u8>>,
+    pub written: Rc<RefCell<Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl MockIo {
+    pub fn new(read: Vec<u8>) -> Self {
+        MockIo {
+            read: io::Cursor::new(read),
+            written: Rc::new(RefCell::new(vec![])),
+        }
+    }
+}
+
+#[cfg(test)]
+impl io::Read for MockIo {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::Read::read(&mut self.read, buf)
+    }
+}
+
+#[cfg(test)]
+impl io::Write for MockIo {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.written.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Io for MockIo {}
+
+#[cfg(test)]
+fn options(min_version: u32, max_version: u32) -> HandshakeOptions {
+    HandshakeOptions {
+        min_version: min_version,
+        max_version: max_version,
+        compression: None,
+        decompresses: vec![],
+        require_compression: false,
+        schema: 0,
+        max_outbound: u64::MAX,
+        max_inbound: u64::MAX,
+        format: None,
+        accepted_formats: vec![],
+        credentials: None,
+        authenticator: None,
+        hook: None,
+    }
+}
+
+/// Plays the client's messages to the server and the server's replies back to the client, and
+/// returns what each side concluded.
+#[cfg(test)]
+fn handshake(client_options: HandshakeOptions,
+             server_options: HandshakeOptions)
+             -> (io::Result<Handshake>, io::Result<Handshake>) {
+    // The first client errors at EOF while waiting for the server's reply, but by then it has
+    // written everything the server needs.
+    let first_client = MockIo::new(vec![]);
+    let client_written = first_client.written.clone();
+    assert!(client(first_client, client_options.clone()).wait().is_err());
+
+    let server_io = MockIo::new(client_written.borrow().clone());
+    let server_written = server_io.written.clone();
+    let server_result = server(server_io, server_options).map(|(_, handshake)| handshake).wait();
+
+    let client_io = MockIo::new(server_written.borrow().clone());
+    let client_result = client(client_io, client_options).map(|(_, handshake)| handshake).wait();
+    (client_result, server_result)
+}
+
+#[test]
+fn preamble() {
+    let mock = MockIo::new(vec![]);
+    let written = mock.written.clone();
+    assert!(client(mock, HandshakeOptions::default()).wait().is_err());
+    assert!(written.borrow().starts_with(b"TRPC\x01"));
+}
+
+#[test]
+fn bad_preamble() {
+    let mock = MockIo::new(b"GET / HTTP/1.1\r\n".to_vec());
+    assert_eq!(server(mock, HandshakeOptions::default()).wait().err().unwrap().kind(),
+               io::ErrorKind::InvalidData);
+    assert_eq!(check_preamble(*b"TRPC\x02").err().unwrap().kind(),
+               io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn negotiate_version() {
+    let (client, server) = handshake(options(1, 3), options(2, 5));
+    assert_eq!(client.unwrap().version(), 3);
+    assert_eq!(server.unwrap().version(), 3);
+
+    let (client, server) = handshake(options(1, 1), options(2, 5));
+    assert_eq!(client.err().unwrap().kind(), io::ErrorKind::ConnectionRefused);
+    assert_eq!(server.err().unwrap().kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn hook_rejects() {
+    let mut server_options = options(1, 2);
+    server_options.hook = Some(Arc::new(|handshake: &Handshake| if handshake.version() < 2 {
+        Err(io::Error::new(io::ErrorKind::Other, "Please upgrade"))
+    } else {
+        Ok(())
+    }));
+    let (client, server) = handshake(options(1, 1), server_options);
+    let err = client.err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    assert!(err.to_string().contains("Please upgrade"), "{}", err);
+    assert_eq!(server.err().unwrap().kind(), io::ErrorKind::Other);
+}
+
+#[test]
+#[cfg(feature = "snappy")]
+fn hook_observes() {
+    use std::sync::Mutex;
+
+    let seen = Arc::new(Mutex::new(vec![]));
+    let mut server_options = options(1, 4);
+    server_options.compression = Some(Compression::Snappy);
+    server_options.schema = 0x7a;
+    let recorded = seen.clone();
+    server_options.hook = Some(Arc::new(move |handshake: &Handshake| {
+        recorded.lock().unwrap().push(handshake.clone());
+        Ok(())
+    }));
+    let mut client_options = options(2, 3);
+    client_options.compression = Some(Compression::Snappy);
+    client_options.schema = 0x7a;
+    let (client, server) = handshake(client_options, server_options);
+    assert_eq!(client.unwrap().peer_versions(), None);
+    server.unwrap();
+
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 1);
+    assert_eq!(seen[0].version(), 3);
+    assert_eq!(seen[0].peer_versions(), Some((2, 3)));
+    assert_eq!(seen[0].compression(), Some(Compression::Snappy));
+    assert_eq!(seen[0].schema(), 0x7a);
+    assert_eq!(seen[0].username(), None);
+}
+
+#[test]
+#[cfg(all(feature = "zstd", feature = "snappy"))]
+fn compression_mismatch() {
+    let mut client_options = options(1, 1);
+    client_options.compression = Some(Compression::Snappy);
+    let mut server_options = options(1, 1);
+    server_options.compression = Some(Compression::Snappy);
+    let (client, server) = handshake(client_options.clone(), server_options.clone());
+    assert!(client.is_ok());
+    assert!(server.is_ok());
+
+    assert_eq!(client.unwrap().peer_compression(), Some(Compression::Snappy));
+    assert_eq!(server.unwrap().compression(), Some(Compression::Snappy));
+
+    // Neither side can decompress what the other compresses with, so both send payloads as-is.
+    server_options.compression = Some(Compression::Zstd);
+    let (client, server) = handshake(client_options.clone(), server_options.clone());
+    let (client, server) = (client.unwrap(), server.unwrap());
+    assert_eq!(client.compression(), None);
+    assert_eq!(client.peer_compression(), None);
+    assert_eq!(server.compression(), None);
+    assert_eq!(server.peer_compression(), None);
+
+    // Unless compression is required.
+    server_options.require_compression = true;
+    let (client, server) = handshake(client_options, server_options);
+    assert_eq!(client.err().unwrap().kind(), io::ErrorKind::ConnectionRefused);
+    assert_eq!(server.err().unwrap().kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+#[cfg(all(feature = "zstd", feature = "snappy"))]
+fn asymmetric_compression() {
+    // The client sends requests as-is, but accepts compressed responses.
+    let mut client_options = options(1, 1);
+    client_options.decompresses = vec![Compression::Zstd];
+    let mut server_options = options(1, 1);
+    server_options.compression = Some(Compression::Zstd);
+    let (client, server) = handshake(client_options.clone(), server_options.clone());
+    let (client, server) = (client.unwrap(), server.unwrap());
+    assert_eq!(client.compression(), None);
+    assert_eq!(client.peer_compression(), Some(Compression::Zstd));
+    assert_eq!(server.compression(), Some(Compression::Zstd));
+    assert_eq!(server.peer_compression(), None);
+
+    // Each side compresses with its own algorithm, if the other decompresses it.
+    client_options.compression = Some(Compression::Snappy);
+    server_options.decompresses = vec![Compression::Snappy];
+    let (client, server) = handshake(client_options, server_options);
+    let (client, server) = (client.unwrap(), server.unwrap());
+    assert_eq!(client.compression(), Some(Compression::Snappy));
+    assert_eq!(client.peer_compression(), Some(Compression::Zstd));
+    assert_eq!(server.compression(), Some(Compression::Zstd));
+    assert_eq!(server.peer_compression(), Some(Compression::Snappy));
+}
+
+#[test]
+fn schema_mismatch() {
+    let mut client_options = options(1, 1);
+    client_options.schema = 0xfeed;
+    let mut server_options = options(1, 1);
+    server_options.schema = 0xfeed;
+    let (client, server) = handshake(client_options.clone(), server_options.clone());
+    assert!(client.is_ok());
+    assert!(server.is_ok());
+
+    server_options.schema = 0xbeef;
+    let (client, server) = handshake(client_options, server_options);
+    let err = client.err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    assert!(err.to_string().contains("Schema mismatch"), "{}", err);
+    assert_eq!(server.err().unwrap().kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn payload_limits() {
+    let mut client_options = options(1, 1);
+    client_options.max_outbound = 1 << 20;
+    client_options.max_inbound = 1 << 10;
+    let mut server_options = options(1, 1);
+    server_options.max_outbound = 1 << 20;
+    server_options.max_inbound = 1 << 16;
+    let (client, server) = handshake(client_options, server_options);
+    let (client, server) = (client.unwrap(), server.unwrap());
+    assert_eq!(client.max_outbound(), 1 << 16);
+    assert_eq!(client.max_inbound(), 1 << 10);
+    assert_eq!(server.max_outbound(), 1 << 10);
+    assert_eq!(server.max_inbound(), 1 << 16);
+}
+
+#[test]
+#[cfg(all(feature = "json", feature = "msgpack", feature = "cbor"))]
+fn negotiate_format() {
+    let mut client_options = options(1, 1);
+    client_options.format = Some(Format::Json);
+    let mut server_options = options(1, 1);
+    server_options.format = Some(Format::Bincode);
+    server_options.accepted_formats = vec![Format::Json, Format::MsgPack];
+    let (client, server) = handshake(client_options.clone(), server_options.clone());
+    assert_eq!(client.unwrap().format(), Some(Format::Json));
+    assert_eq!(server.unwrap().format(), Some(Format::Json));
+
+    client_options.format = Some(Format::Cbor);
+    let (client, server) = handshake(client_options, server_options);
+    assert_eq!(client.err().unwrap().kind(), io::ErrorKind::ConnectionRefused);
+    assert_eq!(server.err().unwrap().kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn authenticate_credentials() {
+    let mut server_options = options(1, 1);
+    server_options.authenticator = Some(Arc::new(|credentials: &Credentials| {
+        if credentials.password() == "hunter2" {
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::Other, "Wrong password"))
+        }
+    }));
+    let mut client_options = options(1, 1);
+    client_options.credentials = Some(Credentials::new("alice", "hunter2"));
+    let (client, server) = handshake(client_options.clone(), server_options.clone());
+    assert_eq!(client.unwrap().username(), Some("alice"));
+    assert_eq!(server.unwrap().username(), Some("alice"));
+
+    client_options.credentials = Some(Credentials::new("alice", "letmein"));
+    let (client, server) = handshake(client_options, server_options.clone());
+    let err = client.err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    assert!(err.to_string().contains("Wrong password"), "{}", err);
+    assert_eq!(server.err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+
+    let (client, server) = handshake(options(1, 1), server_options);
+    assert_eq!(client.err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+    assert_eq!(server.err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+}
+
+#[test]
+fn refused_with_code() {
+    let (client, server) = handshake(options(PROTOCOL_ERROR_VERSION, PROTOCOL_ERROR_VERSION),
+                                     options(1, PROTOCOL_ERROR_VERSION - 1));
+    let err = client.err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    assert_eq!(ProtocolError::from_io(&err).unwrap().code,
+               ProtocolErrorCode::VersionMismatch);
+    assert_eq!(server.err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+    let mut server_options = options(1, PROTOCOL_ERROR_VERSION);
+    server_options.authenticator = Some(Arc::new(|_: &Credentials| Ok(())));
+    let (client, server) = handshake(options(1, PROTOCOL_ERROR_VERSION), server_options);
+    let err = client.err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    assert_eq!(ProtocolError::from_io(&err).unwrap().code, ProtocolErrorCode::AuthFailed);
+    assert_eq!(server.err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+}
@@ -0,0 +1,153 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The metadata key under which a client sends the idempotency key of a request; see
+/// `ResponseCache`.
+pub const IDEMPOTENCY_KEY: &'static str = "idempotency-key";
+
+struct Entry {
+    /// The serialized response.
+    payload: Vec<u8>,
+    inserted: Instant,
+    /// When the entry was last used, by `State::clock`.
+    used: u64,
+}
+
+struct State {
+    capacity: usize,
+    ttl: Duration,
+    entries: HashMap<String, Entry>,
+    /// The keys of `entries`, by when they were last used.
+    lru: BTreeMap<u64, String>,
+    /// Counts the uses of entries, ordering `lru`.
+    clock: u64,
+}
+
+impl State {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.used);
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+/// Recent responses of a server, by the idempotency keys of the requests they answered, so that
+/// a request retried with the same key, such as by a client that reconnected, gets the response
+/// it already got instead of being handled again.
+///
+/// A client opts a request in by sending its key in the frame's metadata under
+/// `IDEMPOTENCY_KEY`, for example with `Proto::metadata`; requests without one are handled as
+/// usual. Keys must be unique across the server's clients. A duplicate that arrives while the
+/// first request is still being handled is handled again, since there is no response to give it
+/// yet.
+///
+/// At most `capacity` responses are kept, evicting the least recently used, each for `ttl`
+/// after it was sent. Clones share the responses, so that a retry finds its response on a new
+/// connection.
+#[derive(Clone)]
+pub struct ResponseCache {
+    state: Arc<Mutex<State>>,
+}
+
+impl ResponseCache {
+    /// Returns an empty cache of up to `capacity` responses, each kept for `ttl`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is 0.
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        assert!(capacity > 0, "The response cache must hold at least 1 response");
+        ResponseCache {
+            state: Arc::new(Mutex::new(State {
+                capacity: capacity,
+                ttl: ttl,
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                clock: 0,
+            })),
+        }
+    }
+
+    /// The number of responses cached, including those that expired but weren't evicted yet.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    /// True if no responses are cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The serialized response to the request with idempotency key `key`, if it's cached and
+    /// hasn't expired.
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let expired = match state.entries.get(key) {
+            Some(entry) => entry.inserted.elapsed() > state.ttl,
+            None => return None,
+        };
+        if expired {
+            state.remove(key);
+            return None;
+        }
+        let now = state.tick();
+        let entry = state.entries.get_mut(key).unwrap();
+        let used = mem::replace(&mut entry.used, now);
+        state.lru.remove(&used);
+        state.lru.insert(now, key.to_string());
+        Some(entry.payload.clone())
+    }
+
+    /// Caches `payload`, a serialized response, for the request with idempotency key `key`,
+    /// evicting the least recently used response if the cache is full.
+    pub fn insert(&self, key: String, payload: Vec<u8>) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        state.remove(&key);
+        while state.entries.len() >= state.capacity {
+            let oldest = *state.lru.keys().next().unwrap();
+            let evicted = state.lru.remove(&oldest).unwrap();
+            state.entries.remove(&evicted);
+        }
+        let now = state.tick();
+        state.lru.insert(now, key.clone());
+        state.entries.insert(key,
+                             Entry {
+                                 payload: payload,
+                                 inserted: Instant::now(),
+                                 used: now,
+                             });
+    }
+}
+
+#[test]
+fn evicts_least_recently_used() {
+    let cache = ResponseCache::new(2, Duration::from_secs(60));
+    cache.insert("a".to_string(), vec![1]);
+    cache.insert("b".to_string(), vec![2]);
+    assert_eq!(cache.get("a"), Some(vec![1]));
+    cache.insert("c".to_string(), vec![3]);
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.get("b"), None);
+    assert_eq!(cache.get("a"), Some(vec![1]));
+    assert_eq!(cache.get("c"), Some(vec![3]));
+
+    let cache = ResponseCache::new(2, Duration::from_secs(0));
+    cache.insert("a".to_string(), vec![1]);
+    ::std::thread::sleep(Duration::from_millis(1));
+    assert_eq!(cache.get("a"), None);
+    assert!(cache.is_empty());
+}
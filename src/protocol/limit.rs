@@ -0,0 +1,270 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use futures::{Async, Future, Poll};
+use futures::task::{self, Task};
+use std::{mem, u64};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use tokio_service::Service;
+
+struct State {
+    max: usize,
+    active: usize,
+    /// The tasks of requests waiting for a slot.
+    waiters: Vec<Task>,
+}
+
+/// Limits how many requests are being handled at once, across every service it wraps.
+///
+/// Requests over the limit wait for a request being handled to finish. The waiting requests are
+/// still in flight, so a server's `Proto::max_in_flight` bounds how many can queue up on each
+/// connection: once it is reached, the connection stops reading requests until one finishes.
+#[derive(Clone)]
+pub struct ConcurrencyLimit {
+    state: Arc<Mutex<State>>,
+}
+
+impl ConcurrencyLimit {
+    /// Returns a limit of `max` requests handled at once.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is 0.
+    pub fn new(max: usize) -> Self {
+        assert!(max > 0, "The concurrency limit must be at least 1");
+        ConcurrencyLimit {
+            state: Arc::new(Mutex::new(State {
+                max: max,
+                active: 0,
+                waiters: vec![],
+            })),
+        }
+    }
+
+    /// The number of requests being handled.
+    pub fn active(&self) -> usize {
+        self.state.lock().unwrap().active
+    }
+
+    /// Takes a slot if one is free. Otherwise, arranges for the current task to be woken when
+    /// one is released.
+    fn poll_acquire(&self) -> Option<Permit> {
+        let mut state = self.state.lock().unwrap();
+        if state.active < state.max {
+            state.active += 1;
+            return Some(Permit { limit: self.clone() });
+        }
+        state.waiters.push(task::park());
+        None
+    }
+
+    /// Frees a slot, waking every waiting request to compete for it. A task that polled more
+    /// than once is waiting more than once, so waking just one could wake a request that is no
+    /// longer waiting.
+    fn release(&self) {
+        let waiters = {
+            let mut state = self.state.lock().unwrap();
+            state.active -= 1;
+            mem::replace(&mut state.waiters, vec![])
+        };
+        for waiter in waiters {
+            waiter.unpark();
+        }
+    }
+}
+
+/// Limits on the size of payloads that can be changed while connections are open, such as to
+/// tighten them during an incident without closing every connection. Each `Codec` given a clone,
+/// by `Codec::payload_limits` or `Proto::payload_limits`, checks the payloads it sends and
+/// receives against them from the next frame on. A frame whose length was read before they
+/// changed is still received under the old limit, so no frame fails halfway.
+///
+/// They only ever tighten a codec's own limits: a connection never sends or accepts payloads
+/// larger than it agreed to in its handshake.
+#[derive(Clone, Debug)]
+pub struct PayloadLimits {
+    /// The largest payloads sent and received.
+    limits: Arc<Mutex<(u64, u64)>>,
+}
+
+impl PayloadLimits {
+    /// Returns limits that don't restrict anything until they are set.
+    pub fn new() -> Self {
+        PayloadLimits { limits: Arc::new(Mutex::new((u64::MAX, u64::MAX))) }
+    }
+
+    /// Limits the payloads sent and received to `max_payload_size` bytes.
+    pub fn set(&self, max_payload_size: u64) {
+        self.set_limits(max_payload_size, max_payload_size);
+    }
+
+    /// Limits the payloads sent to `max_outbound` bytes and those received to `max_inbound`
+    /// bytes. Both change at once.
+    pub fn set_limits(&self, max_outbound: u64, max_inbound: u64) {
+        *self.limits.lock().unwrap() = (max_outbound, max_inbound);
+    }
+
+    /// Lifts the limits, leaving each codec with its own.
+    pub fn clear(&self) {
+        self.set(u64::MAX);
+    }
+
+    /// The largest payload sent.
+    pub fn max_outbound(&self) -> u64 {
+        self.limits.lock().unwrap().0
+    }
+
+    /// The largest payload received.
+    pub fn max_inbound(&self) -> u64 {
+        self.limits.lock().unwrap().1
+    }
+}
+
+impl Default for PayloadLimits {
+    fn default() -> Self {
+        PayloadLimits::new()
+    }
+}
+
+/// A slot taken from a `ConcurrencyLimit`, released when dropped.
+struct Permit {
+    limit: ConcurrencyLimit,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limit.release();
+    }
+}
+
+/// A service whose calls wait for a slot in a `ConcurrencyLimit`, if it has one.
+pub struct Limited<S> {
+    service: Rc<S>,
+    limit: Option<ConcurrencyLimit>,
+}
+
+impl<S> Limited<S> {
+    /// Wraps `service`, limiting its calls by `limit` if set.
+    pub fn new(service: S, limit: Option<ConcurrencyLimit>) -> Self {
+        Limited {
+            service: Rc::new(service),
+            limit: limit,
+        }
+    }
+}
+
+impl<S: Service> Service for Limited<S> {
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = LimitedFuture<S>;
+
+    fn call(&self, request: S::Request) -> LimitedFuture<S> {
+        let state = match self.limit {
+            Some(ref limit) => {
+                CallState::Waiting {
+                    service: self.service.clone(),
+                    limit: limit.clone(),
+                    request: Some(request),
+                }
+            }
+            None => CallState::Running(self.service.call(request), None),
+        };
+        LimitedFuture { state: state }
+    }
+}
+
+enum CallState<S: Service> {
+    Waiting {
+        service: Rc<S>,
+        limit: ConcurrencyLimit,
+        request: Option<S::Request>,
+    },
+    Running(S::Future, Option<Permit>),
+}
+
+/// The response to a call on a `Limited` service.
+pub struct LimitedFuture<S: Service> {
+    state: CallState<S>,
+}
+
+impl<S: Service> Future for LimitedFuture<S> {
+    type Item = S::Response;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<S::Response, S::Error> {
+        let running = match self.state {
+            CallState::Running(ref mut future, ref mut permit) => {
+                let poll = future.poll();
+                if let Ok(Async::NotReady) = poll {
+                } else {
+                    // Free the slot as soon as the call is done, not when the future is dropped.
+                    permit.take();
+                }
+                return poll;
+            }
+            CallState::Waiting { ref service, ref limit, ref mut request } => {
+                match limit.poll_acquire() {
+                    Some(permit) => {
+                        let request = request.take().expect("polled after the call started");
+                        CallState::Running(service.call(request), Some(permit))
+                    }
+                    None => return Ok(Async::NotReady),
+                }
+            }
+        };
+        self.state = running;
+        self.poll()
+    }
+}
+
+#[test]
+fn concurrency_limit() {
+    use futures::future;
+    use futures::sync::oneshot;
+    use std::cell::RefCell;
+
+    /// Responds to each call when the test completes it.
+    struct Deferred {
+        calls: Rc<RefCell<Vec<oneshot::Sender<u32>>>>,
+    }
+
+    impl Service for Deferred {
+        type Request = u32;
+        type Response = u32;
+        type Error = oneshot::Canceled;
+        type Future = oneshot::Receiver<u32>;
+
+        fn call(&self, _: u32) -> Self::Future {
+            let (tx, rx) = oneshot::channel();
+            self.calls.borrow_mut().push(tx);
+            rx
+        }
+    }
+
+    let calls = Rc::new(RefCell::new(vec![]));
+    let limit = ConcurrencyLimit::new(2);
+    let service = Limited::new(Deferred { calls: calls.clone() }, Some(limit.clone()));
+    future::lazy(|| {
+            let mut futures = (0..3).map(|n| service.call(n)).collect::<Vec<_>>();
+            for future in &mut futures {
+                assert!(future.poll().unwrap().is_not_ready());
+            }
+            // The third call waits for a slot.
+            assert_eq!(limit.active(), 2);
+            assert_eq!(calls.borrow().len(), 2);
+
+            calls.borrow_mut().remove(0).complete(7);
+            assert_eq!(futures[0].poll().unwrap(), Async::Ready(7));
+            assert_eq!(limit.active(), 1);
+            assert!(futures[2].poll().unwrap().is_not_ready());
+            assert_eq!(limit.active(), 2);
+            assert_eq!(calls.borrow().len(), 2);
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap();
+}
@@ -0,0 +1,182 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use serde;
+use serde_json::{self, Value};
+use std::io;
+use std::marker::PhantomData;
+use super::DecodeError;
+use tokio_core::io::{Codec, EasyBuf, Framed, Io};
+use tokio_proto::multiplex::ServerProto;
+use tokio_proto::streaming::multiplex::RequestId;
+
+/// A tokio `Codec` that frames each message as a line of JSON, so that a service can be poked at
+/// by hand with `telnet` or `nc`. It is meant for debugging only: it is slower and larger than
+/// a `Codec`, has no heartbeats, deadlines, or checksums, and skips lines it can't make sense
+/// of rather than failing.
+///
+/// Each frame is an object holding the request id and the message, such as
+/// `{"id":0,"body":[1,2]}`, followed by a newline. The JSON on a line is never broken across
+/// lines, since newlines inside strings are escaped. Lines ending in `\r\n`, as telnet sends
+/// them, and blank lines are accepted. A line whose id is readable but whose body isn't is
+/// decoded as `DecodeError::Deserialize` for that id.
+pub struct LineCodec<Encode, Decode> {
+    max_line: u64,
+    _phantom_data: PhantomData<(Encode, Decode)>,
+}
+
+/// The envelope a `LineCodec` writes a message in.
+#[derive(Serialize)]
+struct Envelope<'a, T: 'a> {
+    id: RequestId,
+    body: &'a T,
+}
+
+impl<Encode, Decode> LineCodec<Encode, Decode> {
+    /// Returns a new `LineCodec` that fails once more than `max_line` bytes arrive without a
+    /// newline.
+    pub fn new(max_line: u64) -> Self {
+        LineCodec {
+            max_line: max_line,
+            _phantom_data: PhantomData,
+        }
+    }
+}
+
+impl<Encode, Decode> Codec for LineCodec<Encode, Decode>
+    where Encode: serde::Serialize,
+          Decode: serde::Deserialize
+{
+    type Out = (RequestId, Encode);
+    type In = (RequestId, Result<Decode, DecodeError<serde_json::Error>>);
+
+    fn encode(&mut self, (id, message): Self::Out, buf: &mut Vec<u8>) -> io::Result<()> {
+        let frame_start = buf.len();
+        let envelope = Envelope {
+            id: id,
+            body: &message,
+        };
+        if let Err(e) = serde_json::to_writer(&mut *buf, &envelope) {
+            buf.truncate(frame_start);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+        }
+        buf.push(b'\n');
+        Ok(())
+    }
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Self::In>> {
+        loop {
+            let newline = match buf.as_slice().iter().position(|&b| b == b'\n') {
+                Some(newline) => newline,
+                None if buf.len() as u64 > self.max_line => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("Line longer than {} bytes", self.max_line)))
+                }
+                None => return Ok(None),
+            };
+            let line = buf.drain_to(newline + 1);
+            let line = trim(line.as_slice());
+            if line.is_empty() {
+                continue;
+            }
+            let mut envelope = match serde_json::from_slice(line) {
+                Ok(Value::Object(envelope)) => envelope,
+                _ => {
+                    warn!("Ignoring a line that isn't a JSON object: {:?}",
+                          String::from_utf8_lossy(line));
+                    continue;
+                }
+            };
+            let id = match envelope.remove("id").and_then(|id| id.as_u64()) {
+                Some(id) => id,
+                None => {
+                    warn!("Ignoring a line without a request id: {:?}",
+                          String::from_utf8_lossy(line));
+                    continue;
+                }
+            };
+            let body = envelope.remove("body").unwrap_or(Value::Null);
+            trace!("--> Decoded line for id = {}: {:?}", id, body);
+            return Ok(Some((id, serde_json::from_value(body).map_err(DecodeError::Deserialize))));
+        }
+    }
+}
+
+/// `line` without its line ending and surrounding whitespace.
+fn trim(line: &[u8]) -> &[u8] {
+    let is_space = |b: &u8| (*b as char).is_whitespace();
+    let start = line.iter().position(|b| !is_space(b)).unwrap_or(line.len());
+    let end = line.iter().rposition(|b| !is_space(b)).map_or(start, |end| end + 1);
+    &line[start..end]
+}
+
+/// Implements the `multiplex::ServerProto` trait using a `LineCodec`, with no handshake, so
+/// that a client can type requests as soon as it connects. For debugging only; see `LineCodec`.
+pub struct LineProto<Encode, Decode> {
+    max_line: u64,
+    _phantom_data: PhantomData<(Encode, Decode)>,
+}
+
+impl<Encode, Decode> LineProto<Encode, Decode> {
+    /// Returns a new `LineProto` whose codecs fail once more than `max_line` bytes arrive
+    /// without a newline.
+    pub fn new(max_line: u64) -> Self {
+        LineProto {
+            max_line: max_line,
+            _phantom_data: PhantomData,
+        }
+    }
+}
+
+impl<Encode, Decode> Clone for LineProto<Encode, Decode> {
+    fn clone(&self) -> Self {
+        LineProto::new(self.max_line)
+    }
+}
+
+impl<T, Encode, Decode> ServerProto<T> for LineProto<Encode, Decode>
+    where T: Io + 'static,
+          Encode: serde::Serialize + 'static,
+          Decode: serde::Deserialize + 'static
+{
+    type Request = Result<Decode, DecodeError<serde_json::Error>>;
+    type Response = Encode;
+    type Transport = Framed<T, LineCodec<Encode, Decode>>;
+    type BindTransport = io::Result<Self::Transport>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(io.framed(LineCodec::new(self.max_line)))
+    }
+}
+
+#[test]
+fn round_trip() {
+    let mut codec: LineCodec<Vec<u8>, Vec<u8>> = LineCodec::new(1024);
+    let mut vec = Vec::new();
+    codec.encode((3, vec![1, 2]), &mut vec).unwrap();
+    assert_eq!(vec, b"{\"id\":3,\"body\":[1,2]}\n".to_vec());
+
+    // As typed into telnet, with a blank line, a typo, and a body of the wrong type.
+    vec.extend_from_slice(b"\r\n{\"id\": 4, \"body\": [5]}\r\n");
+    vec.extend_from_slice(b"{oops\r\n{\"id\": 6, \"body\": \"x\"}\r\n");
+    let mut buf = EasyBuf::from(vec);
+    match codec.decode(&mut buf) {
+        Ok(Some((3, Ok(ref v)))) if *v == vec![1, 2] => {}
+        bad => panic!("Expected Some((3, Ok([1, 2]))), but got {:?}", bad),
+    }
+    match codec.decode(&mut buf) {
+        Ok(Some((4, Ok(ref v)))) if *v == vec![5] => {}
+        bad => panic!("Expected Some((4, Ok([5]))), but got {:?}", bad),
+    }
+    match codec.decode(&mut buf) {
+        Ok(Some((6, Err(DecodeError::Deserialize(_))))) => {}
+        bad => panic!("Expected Some((6, Err(Deserialize))), but got {:?}", bad),
+    }
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+
+    let mut buf = EasyBuf::from(vec![b'1'; 1025]);
+    assert_eq!(codec.decode(&mut buf).err().unwrap().kind(),
+               io::ErrorKind::InvalidData);
+}
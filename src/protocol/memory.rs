@@ -0,0 +1,136 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use futures::task::{self, Task};
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use tokio_core::io::Io;
+
+/// One direction of an in-memory connection.
+#[derive(Default)]
+struct Pipe {
+    buf: VecDeque<u8>,
+    /// The task waiting for bytes to read, if any.
+    reader: Option<Task>,
+    /// True once either end of the connection is dropped.
+    closed: bool,
+}
+
+impl Pipe {
+    fn close(&mut self) {
+        self.closed = true;
+        if let Some(reader) = self.reader.take() {
+            reader.unpark();
+        }
+    }
+}
+
+/// One end of an in-memory connection created by `in_memory`.
+///
+/// Reads that find no bytes available return `WouldBlock` and wake the reading task when the
+/// other end writes, so a `MemoryIo` can be driven by any event loop.
+pub struct MemoryIo {
+    read: Arc<Mutex<Pipe>>,
+    write: Arc<Mutex<Pipe>>,
+}
+
+/// Returns both ends of a connection that lives entirely in memory. Bytes written to one end
+/// can be read from the other.
+///
+/// This is useful for testing services without binding a socket: pass one end to
+/// `BindServer::bind_server` and the other to `BindClient::bind_client`.
+pub fn in_memory() -> (MemoryIo, MemoryIo) {
+    let a_to_b = Arc::new(Mutex::new(Pipe::default()));
+    let b_to_a = Arc::new(Mutex::new(Pipe::default()));
+    (MemoryIo {
+         read: b_to_a.clone(),
+         write: a_to_b.clone(),
+     },
+     MemoryIo {
+         read: a_to_b,
+         write: b_to_a,
+     })
+}
+
+impl Read for MemoryIo {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut pipe = self.read.lock().unwrap();
+        if pipe.buf.is_empty() {
+            if pipe.closed {
+                return Ok(0);
+            }
+            pipe.reader = Some(task::park());
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let len = cmp::min(buf.len(), pipe.buf.len());
+        for (dst, src) in buf.iter_mut().zip(pipe.buf.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for MemoryIo {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut pipe = self.write.lock().unwrap();
+        if pipe.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        pipe.buf.extend(buf);
+        if let Some(reader) = pipe.reader.take() {
+            reader.unpark();
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Io for MemoryIo {}
+
+impl Drop for MemoryIo {
+    fn drop(&mut self) {
+        self.read.lock().unwrap().close();
+        self.write.lock().unwrap().close();
+    }
+}
+
+#[test]
+fn round_trip() {
+    use bincode;
+    use futures::future;
+    use super::{DecodeError, Proto};
+    use tokio_core::reactor::Core;
+    use tokio_proto::{BindClient, BindServer};
+    use tokio_service::Service;
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = Result<String, DecodeError<bincode::Error>>;
+        type Response = String;
+        type Error = io::Error;
+        type Future = future::FutureResult<String, io::Error>;
+
+        fn call(&self, request: Self::Request) -> Self::Future {
+            future::result(request.map_err(DecodeError::into_io))
+        }
+    }
+
+    let mut core = Core::new().unwrap();
+    let (client_io, server_io) = in_memory();
+
+    let proto: Proto<String, String> = Proto::new(2_000_000);
+    proto.bind_server(&core.handle(), server_io, Echo);
+    let proto: Proto<String, String> = Proto::new(2_000_000);
+    let client = proto.bind_client(&core.handle(), client_io);
+
+    let response = core.run(client.call("hello".to_string())).unwrap();
+    assert_eq!(response.unwrap(), "hello");
+}
@@ -0,0 +1,151 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::time::Duration;
+use tokio_proto::streaming::multiplex::RequestId;
+
+/// Receives counts of the frames a `Codec` encodes and decodes, and of the connections a server
+/// closes for being idle, e.g. to export them as metrics.
+///
+/// Every method does nothing by default, so implementations only need to override the events
+/// they're interested in. Methods are called on the event loop, so they should be cheap.
+pub trait CodecMetrics: Send + Sync {
+    /// Called after a frame is encoded. `payload_size` is the number of payload bytes sent,
+    /// after compression.
+    fn on_encode(&self, id: RequestId, payload_size: u64) {
+        let _ = (id, payload_size);
+    }
+
+    /// Called after a frame is decoded. `payload_size` is the number of payload bytes received,
+    /// before decompression.
+    fn on_decode(&self, id: RequestId, payload_size: u64) {
+        let _ = (id, payload_size);
+    }
+
+    /// Called when a payload of `len` bytes, sent or received, is rejected for being larger than
+    /// `max` bytes.
+    fn on_reject(&self, len: u64, max: u64) {
+        let _ = (len, max);
+    }
+
+    /// Called when a server rejects request `id` for reusing the id of a request still in
+    /// flight on the same connection, which a correct client never does.
+    fn on_duplicate_id(&self, id: RequestId) {
+        let _ = id;
+    }
+
+    /// Called when a server closes a connection that sent and received no frames within
+    /// `timeout`; see `Proto::reap_idle`.
+    fn on_reap(&self, timeout: Duration) {
+        let _ = timeout;
+    }
+
+    /// Called when the payload of frame `id` is abandoned because only `received` of its
+    /// `total` fragments arrived within the max reassembly time; see
+    /// `Codec::max_reassembly_time`.
+    fn on_abandoned_reassembly(&self, id: RequestId, received: u32, total: u32) {
+        let _ = (id, received, total);
+    }
+
+    /// Called when a server decodes request `id` with sequence number `received` where it
+    /// expected `expected`, the number after the last one it decoded: a smaller number means
+    /// the requests arrived out of the order they were sent in, and a larger one that some
+    /// were lost. See `Proto::sequence_numbers`.
+    fn on_sequence_gap(&self, id: RequestId, expected: u64, received: u64) {
+        let _ = (id, expected, received);
+    }
+
+    /// Called after each read of a connection with a max backlog, with the number of complete
+    /// frames buffered but not yet passed on; see `Proto::max_backlog`.
+    fn on_backlog(&self, frames: usize) {
+        let _ = frames;
+    }
+}
+
+cfg_if! {
+    if #[cfg(feature = "hdrhistogram")] {
+        use hdrhistogram::Histogram;
+        use std::sync::Mutex;
+
+        /// A `CodecMetrics` that records the size of every payload sent and received in a
+        /// histogram, to show how sizes are distributed, e.g. whether the max payload size or the
+        /// buffer capacities need tuning. Sizes are recorded to three significant figures.
+        pub struct PayloadHistograms {
+            sent: Mutex<Histogram<u64>>,
+            received: Mutex<Histogram<u64>>,
+        }
+
+        /// A snapshot of the histograms of a `PayloadHistograms`.
+        #[derive(Clone)]
+        pub struct PayloadSizes {
+            /// The sizes of the payloads encoded, after compression.
+            pub sent: Histogram<u64>,
+            /// The sizes of the payloads decoded, before decompression.
+            pub received: Histogram<u64>,
+        }
+
+        impl PayloadHistograms {
+            /// Returns empty histograms.
+            pub fn new() -> Self {
+                let histogram = Histogram::new(3).expect("3 significant figures is valid");
+                PayloadHistograms {
+                    sent: Mutex::new(histogram.clone()),
+                    received: Mutex::new(histogram),
+                }
+            }
+
+            /// Returns a copy of the histograms as they are now, such as to read the median and
+            /// 99th percentile sizes with `value_at_quantile`.
+            pub fn snapshot(&self) -> PayloadSizes {
+                PayloadSizes {
+                    sent: self.sent.lock().unwrap().clone(),
+                    received: self.received.lock().unwrap().clone(),
+                }
+            }
+
+            /// Returns the histograms as they are now and empties them, so that each snapshot
+            /// covers one reporting interval.
+            pub fn take(&self) -> PayloadSizes {
+                let sizes = self.snapshot();
+                self.sent.lock().unwrap().reset();
+                self.received.lock().unwrap().reset();
+                sizes
+            }
+        }
+
+        impl Default for PayloadHistograms {
+            fn default() -> Self {
+                PayloadHistograms::new()
+            }
+        }
+
+        impl CodecMetrics for PayloadHistograms {
+            fn on_encode(&self, _: RequestId, payload_size: u64) {
+                self.sent.lock().unwrap().saturating_record(payload_size);
+            }
+
+            fn on_decode(&self, _: RequestId, payload_size: u64) {
+                self.received.lock().unwrap().saturating_record(payload_size);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "hdrhistogram")]
+#[test]
+fn payload_histograms() {
+    let histograms = PayloadHistograms::new();
+    for size in 1..101 {
+        histograms.on_encode(size, size);
+    }
+    histograms.on_decode(0, 1000);
+
+    let sizes = histograms.take();
+    assert_eq!(sizes.sent.len(), 100);
+    assert_eq!(sizes.sent.value_at_quantile(0.5), 50);
+    assert_eq!(sizes.sent.value_at_quantile(0.99), 99);
+    assert_eq!(sizes.received.max(), 1000);
+    assert_eq!(histograms.snapshot().sent.len(), 0);
+}
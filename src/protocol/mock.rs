@@ -0,0 +1,243 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+use serde;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use super::{BincodeSerializer, Codec, MemoryIo, PayloadSerializer, ProtocolError, Proto,
+            in_memory};
+use super::transport::Transport;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_proto::multiplex::ServerProto;
+use tokio_proto::streaming::multiplex::RequestId;
+
+/// Decides whether a rule of a `MockServer` applies to a request.
+type Matcher<Request> = Arc<Fn(&Request) -> bool + Send + Sync>;
+
+/// What a `MockServer` does with a request that matches a rule.
+#[derive(Clone, Debug)]
+pub enum MockReply<Response> {
+    /// Responds with the response right away.
+    Respond(Response),
+    /// Responds with the response once the duration is up.
+    Delay(Duration, Response),
+    /// Never responds, as if the request was lost.
+    Drop,
+    /// Closes the connection, without sending the responses not yet written.
+    Disconnect,
+    /// Closes the connection after sending a protocol error frame, so that the client fails with
+    /// the error.
+    Fail(ProtocolError),
+}
+
+struct Rule<Request, Response> {
+    matcher: Matcher<Request>,
+    reply: MockReply<Response>,
+    /// If true, the rule is removed once it applies.
+    once: bool,
+}
+
+/// A server that answers requests by following a script, for testing how a client handles
+/// slow responses, lost requests, and broken connections.
+///
+/// Each request is checked against the rules in the order they were added, and the first that
+/// matches decides the reply. A request that matches none is never answered. The script is
+/// shared by every connection to the server, so a rule added with `once` applies to only one
+/// request across all of them: a client that reconnects and retries finds the script where its
+/// last connection left it, which makes retries deterministic.
+///
+/// Connections are made with `connect`, in memory, and frames are read and written with the
+/// same handshake, `Codec`, and transport as a `Proto` server, so what the client sees on the
+/// wire matches production.
+pub struct MockServer<Request, Response, S = BincodeSerializer> {
+    proto: Proto<Response, Request, S>,
+    rules: Arc<Mutex<Vec<Rule<Request, Response>>>>,
+    received: Arc<AtomicUsize>,
+}
+
+impl<Request, Response, S> MockServer<Request, Response, S>
+    where Request: serde::Deserialize + 'static,
+          Response: serde::Serialize + Clone + 'static,
+          S: PayloadSerializer + Clone + 'static,
+          S::Error: 'static
+{
+    /// Returns a server with no rules, whose connections are set up by `proto`.
+    pub fn new(proto: Proto<Response, Request, S>) -> Self {
+        MockServer {
+            proto: proto,
+            rules: Arc::new(Mutex::new(vec![])),
+            received: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Replies to every request that `matcher` matches, unless an earlier rule matches it, with
+    /// `reply`.
+    pub fn on<F>(self, matcher: F, reply: MockReply<Response>) -> Self
+        where F: Fn(&Request) -> bool + Send + Sync + 'static
+    {
+        self.rule(Arc::new(matcher), reply, false)
+    }
+
+    /// Replies to the next request that `matcher` matches, unless an earlier rule matches it,
+    /// with `reply`, and then removes the rule.
+    pub fn once<F>(self, matcher: F, reply: MockReply<Response>) -> Self
+        where F: Fn(&Request) -> bool + Send + Sync + 'static
+    {
+        self.rule(Arc::new(matcher), reply, true)
+    }
+
+    fn rule(self, matcher: Matcher<Request>, reply: MockReply<Response>, once: bool) -> Self {
+        self.rules.lock().unwrap().push(Rule {
+            matcher: matcher,
+            reply: reply,
+            once: once,
+        });
+        self
+    }
+
+    /// The number of requests the server has decoded, over all its connections.
+    pub fn received(&self) -> usize {
+        self.received.load(Ordering::SeqCst)
+    }
+
+    /// Returns the client end of a new connection to the server, whose server end is served on
+    /// the reactor of `handle`, for example to bind a `Client` to.
+    pub fn connect(&self, handle: &Handle) -> MemoryIo {
+        let (client_io, server_io) = in_memory();
+        let rules = self.rules.clone();
+        let received = self.received.clone();
+        let handle2 = handle.clone();
+        let transport = <Proto<Response, Request, S> as ServerProto<MemoryIo>>::bind_transport(
+            &self.proto, server_io);
+        handle.spawn(transport.and_then(move |transport| {
+                Connection {
+                    transport: transport,
+                    rules: rules,
+                    received: received,
+                    handle: handle2,
+                    delayed: vec![],
+                    outbound: VecDeque::new(),
+                }
+            })
+            .map_err(|e| debug!("Mock server connection failed: {}", e)));
+        client_io
+    }
+}
+
+/// The server end of a connection to a `MockServer`.
+struct Connection<Request, Response, S> {
+    transport: Transport<MemoryIo, Codec<Response, Request, S>>,
+    rules: Arc<Mutex<Vec<Rule<Request, Response>>>>,
+    received: Arc<AtomicUsize>,
+    handle: Handle,
+    /// Responses waiting for their delay to be up.
+    delayed: Vec<(Timeout, RequestId, Response)>,
+    /// Responses the transport hasn't accepted yet.
+    outbound: VecDeque<(RequestId, Response)>,
+}
+
+impl<Request, Response, S> Connection<Request, Response, S>
+    where Response: Clone
+{
+    /// The reply to `request`, by the first rule that matches it, if any.
+    fn reply(&self, request: &Request) -> Option<MockReply<Response>> {
+        let mut rules = self.rules.lock().unwrap();
+        let i = match rules.iter().position(|rule| (rule.matcher)(request)) {
+            Some(i) => i,
+            None => return None,
+        };
+        if rules[i].once {
+            Some(rules.remove(i).reply)
+        } else {
+            Some(rules[i].reply.clone())
+        }
+    }
+}
+
+impl<Request, Response, S> Future for Connection<Request, Response, S>
+    where Request: serde::Deserialize,
+          Response: serde::Serialize + Clone,
+          S: PayloadSerializer
+{
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        loop {
+            let (id, request) = match self.transport.poll()? {
+                Async::Ready(Some((id, Ok(request)))) => (id, request),
+                Async::Ready(Some((id, Err(_)))) => {
+                    debug!("Mock server: Not answering request id = {}, which failed to decode.",
+                           id);
+                    continue;
+                }
+                Async::Ready(None) => return Ok(Async::Ready(())),
+                Async::NotReady => break,
+            };
+            self.received.fetch_add(1, Ordering::SeqCst);
+            let reply = self.reply(&request);
+            match reply {
+                Some(MockReply::Respond(response)) => self.outbound.push_back((id, response)),
+                Some(MockReply::Delay(delay, response)) => {
+                    let timeout = Timeout::new(delay, &self.handle)?;
+                    self.delayed.push((timeout, id, response));
+                }
+                Some(MockReply::Drop) => {}
+                Some(MockReply::Disconnect) => return Ok(Async::Ready(())),
+                Some(MockReply::Fail(error)) => return Err(self.transport.close_with(error)),
+                None => warn!("Mock server: No rule matches request id = {}", id),
+            }
+        }
+        let mut i = 0;
+        while i < self.delayed.len() {
+            if self.delayed[i].0.poll()?.is_ready() {
+                let (_, id, response) = self.delayed.remove(i);
+                self.outbound.push_back((id, response));
+            } else {
+                i += 1;
+            }
+        }
+        while let Some(message) = self.outbound.pop_front() {
+            if let AsyncSink::NotReady(message) = self.transport.start_send(message)? {
+                self.outbound.push_front(message);
+                break;
+            }
+        }
+        self.transport.poll_complete()?;
+        Ok(Async::NotReady)
+    }
+}
+
+#[test]
+fn scripted_replies() {
+    use super::{Client, ProtocolErrorCode};
+    use std::time::Instant;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let error = ProtocolError::new(ProtocolErrorCode::RateLimited, "Scripted");
+    let server = MockServer::new(Proto::new(2_000_000))
+        .once(|n: &u32| *n == 1, MockReply::Disconnect)
+        .on(|n: &u32| *n == 1, MockReply::Delay(Duration::from_millis(50), 10))
+        .on(|n: &u32| *n == 2, MockReply::Fail(error))
+        .on(|_: &u32| true, MockReply::Respond(0));
+    let proto: Proto<u32, u32> = Proto::new(2_000_000);
+
+    // The first try is cut off, and the retry on a new connection is answered late.
+    let client = Client::new(&handle, server.connect(&handle), &proto);
+    assert!(core.run(client.call(1)).is_err());
+    let client = Client::new(&handle, server.connect(&handle), &proto);
+    let start = Instant::now();
+    assert_eq!(core.run(client.call(1)).unwrap().unwrap(), 10);
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    assert_eq!(core.run(client.call(3)).unwrap().unwrap(), 0);
+    assert!(core.run(client.call(2)).is_err());
+    assert_eq!(server.received(), 4);
+}
@@ -0,0 +1,4781 @@
+// Copyright 2016 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use {serde, tokio_core};
+use futures::Future;
+use futures::sync::oneshot;
+use self::capture::{Capture, CaptureSinks};
+use self::encryption::EncryptionKey as Cipher;
+use self::diagnostics::{DiagnosticsAuthorizer, QueryHandle, StatsHandle, StatsQueries};
+use self::frame::{ACKED_ID, CodecState, DIAGNOSTICS_ID, FLAG_COMPRESSED, FLAG_FRAGMENT,
+                  FLAG_METADATA, FRAGMENT_HEADER_LEN, Frame, FrameOptions, GOODBYE_ID,
+                  HEARTBEAT_ID, MIN_CONTROL_ID, PING_ID, PROTOCOL_ERROR_ID, REJECTED_ID,
+                  unix_millis};
+use self::handshake::HandshakeOptions;
+use self::ping::{PingHandle, Pings};
+use self::spans::RequestSpans;
+use self::transport::{FlushDelayOptions, HeartbeatOptions, IdleReaperOptions, IdleTimeoutOptions,
+                      RateLimitOptions, RateLimiter, ResponseTimeoutOptions, Transport};
+use std::{cmp, mem, u32, u64, usize};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use tokio_core::io::{EasyBuf, Io};
+use tokio_core::reactor;
+use tokio_proto::multiplex::{ClientProto, ServerProto};
+use tokio_proto::streaming::multiplex::RequestId;
+
+pub use self::allowlist::{TagError, TypeAllowlist};
+pub use self::auth::Credentials;
+pub use self::builder::ProtoBuilder;
+pub use self::client::{AckFuture, Client, ResponseFuture};
+pub use self::compression::{Compression, CompressionOptions};
+pub use self::diagnostics::{ServerStats, Stats, StatsFuture};
+pub use self::drain::{Drain, DrainFuture};
+#[cfg(feature = "encryption")]
+pub use self::encryption::EncryptionKey;
+pub use self::error::{DecodeError, IdSpaceExhaustedError, PayloadTooLargeError, ProtocolError,
+                      ProtocolErrorCode};
+pub use self::frame::{DecodeProgress, Endianness, LenWidth};
+pub use self::handshake::{ACK_VERSION, DEADLINE_VERSION, GOODBYE_VERSION, Handshake,
+                          DIAGNOSTICS_VERSION, MIN_PROTOCOL_VERSION, METADATA_VERSION,
+                          PING_VERSION, PRIORITY_VERSION, PROTOCOL_ERROR_VERSION, PROTOCOL_VERSION,
+                          REJECTION_VERSION};
+pub use self::idempotency::{IDEMPOTENCY_KEY, ResponseCache};
+pub use self::limit::{ConcurrencyLimit, Limited, LimitedFuture, PayloadLimits};
+#[cfg(feature = "json")]
+pub use self::line::{LineCodec, LineProto};
+pub use self::memory::{MemoryIo, in_memory};
+pub use self::metrics::CodecMetrics;
+pub use self::mock::{MockReply, MockServer};
+#[cfg(feature = "hdrhistogram")]
+pub use self::metrics::{PayloadHistograms, PayloadSizes};
+pub use self::peer::{PeerClient, PeerCodec, PeerMessage, PeerProto, PeerResponse, RESPONSE_BIT};
+pub use self::ping::PingFuture;
+pub use self::pipeline::{PipelineCodec, PipelineProto};
+pub use self::pool::{ClientPool, PoolFuture};
+pub use self::raw::RawCodec;
+pub use self::serializer::{BincodeSerializer, Format, FormatError, PayloadSerializer};
+#[cfg(feature = "cbor")]
+pub use self::serializer::CborSerializer;
+#[cfg(feature = "json")]
+pub use self::serializer::JsonSerializer;
+#[cfg(feature = "msgpack")]
+pub use self::serializer::MsgPackSerializer;
+pub use self::streaming::{ResponseStream, StreamingClient, StreamingCodec, StreamingProto};
+#[cfg(unix)]
+pub use self::unix::{connect_unix, serve_unix};
+
+/// Type tags that payloads must carry to be deserialized.
+mod allowlist;
+/// Credentials checked during the handshake.
+mod auth;
+/// A validating builder for `Proto`.
+mod builder;
+/// Copies of the bytes connections receive, for replaying them.
+mod capture;
+/// A client that matches responses to calls.
+mod client;
+/// Payload compression.
+mod compression;
+/// Stats queries answered by servers.
+mod diagnostics;
+/// Graceful closing of server transports.
+mod drain;
+/// Optional AEAD encryption of payloads.
+mod encryption;
+/// Errors that affect a single frame.
+mod error;
+/// Connections that corrupt the bytes read from them, for testing error handling.
+#[cfg(test)]
+mod faulty;
+/// The frame layout and the state machine that parses it.
+mod frame;
+/// The exchange that starts every connection, before any frames are sent.
+mod handshake;
+/// Cached responses for requests retried with an idempotency key.
+mod idempotency;
+/// Limits on the requests being handled at once and on the size of payloads.
+mod limit;
+/// Newline-delimited JSON framing, for debugging by hand.
+#[cfg(feature = "json")]
+mod line;
+/// Connections that don't leave the process, for testing.
+mod memory;
+/// Hooks for counting the frames a `Codec` handles.
+mod metrics;
+/// Scripted servers, for testing clients.
+mod mock;
+/// Framing for payloads that are already serialized.
+mod raw;
+/// Connections on which both sides make requests and answer them.
+mod peer;
+/// Round-trip time measurements with ping frames.
+mod ping;
+/// Framing for pipelined protocols, whose frames carry no request id.
+mod pipeline;
+/// Clients that spread calls over several connections.
+mod pool;
+/// Pluggable payload serialization formats.
+mod serializer;
+/// Per-request spans, logged through `log`.
+mod spans;
+/// Responses made of a stream of frames.
+mod streaming;
+/// Frames connections, and handles the frames that aren't passed on to the service.
+mod transport;
+/// Serving and connecting over Unix domain sockets.
+#[cfg(unix)]
+mod unix;
+
+/// A tokio `Codec` that frames payloads serialized by `S`.
+///
+/// `Encode` is the type that `Codec` encodes. `Decode` is the type it decodes.
+pub struct Codec<Encode, Decode, S = BincodeSerializer> {
+    /// Identifies the connection in log messages.
+    connection_id: u64,
+    /// The largest payload `encode` will send.
+    max_outbound: u64,
+    /// The largest payload `decode` will accept.
+    max_inbound: u64,
+    /// Limits shared with other codecs that can tighten `max_outbound` and `max_inbound` while
+    /// the connection is open.
+    payload_limits: Option<PayloadLimits>,
+    /// The largest payload sent or received in fragments, if frames can carry fragments.
+    max_reassembled: u64,
+    /// The payloads whose fragments are arriving, by the id of their frames.
+    reassembling: HashMap<RequestId, Reassembly>,
+    /// How long the fragments of a payload may take to arrive, from its first.
+    max_reassembly_time: Option<Duration>,
+    /// If false, a received payload that is too big closes the connection.
+    skip_too_big: bool,
+    /// If false, a received payload with malformed metadata or compression closes the
+    /// connection.
+    isolate_payload_errors: bool,
+    /// If true, payloads are serialized before their size is known.
+    single_pass: bool,
+    /// The size of the last payload encoded in a single pass, used to reserve space.
+    size_estimate: u64,
+    /// If true, a `Transport` writes frames to its socket as their payloads are serialized.
+    write_through: bool,
+    /// How long after a request is encoded its deadline falls, if it has one.
+    request_timeout: Option<Duration>,
+    /// How long the header of a frame may take to arrive.
+    max_header_wait: Option<Duration>,
+    /// When the first bytes of the header being read arrived.
+    header_started: Option<Instant>,
+    /// The size of the last payload decoded, as it was read.
+    decoded_size: u64,
+    /// The bytes left unparsed by the last call to `decode`.
+    buffered: usize,
+    /// Copies the bytes `decode` is given, if capturing.
+    capture: Capture,
+    /// Limits the frames decoded per second, if set.
+    frame_rate: Option<RateLimiter>,
+    /// Set while `decode` leaves frames undecoded because `frame_rate` ran out.
+    throttled: bool,
+    frame: FrameOptions,
+    serializer: S,
+    state: CodecState,
+    version: u32,
+    /// Heartbeat frames decoded since the transport last checked.
+    heartbeats: u64,
+    /// The reason given by a goodbye frame decoded since the transport last checked.
+    goodbye: Option<String>,
+    /// Set once a protocol error frame is decoded, so that the error isn't sent back.
+    refused: bool,
+    /// Sends the pings a `Client` asks for, and measures their round-trip times. Only set on
+    /// clients.
+    pings: Option<PingHandle>,
+    /// The timestamps of the pings decoded since the transport last checked, to be echoed.
+    pongs: Vec<u64>,
+    /// Sends the stats queries a `Client` asks for, and completes them with the answers. Only
+    /// set on clients.
+    stats_queries: Option<QueryHandle>,
+    /// Counts the connection in the server's stats, and answers the queries of its client.
+    /// Only set on servers with diagnostics.
+    stats: Option<StatsHandle>,
+    /// The stats queries decoded since the transport last checked, to be answered.
+    queries: usize,
+    /// Gives each request encoded its priority.
+    request_priority: Option<Prioritizer<Encode>>,
+    /// If true, each message encoded takes the priority of the decoded frame with the same id,
+    /// as a response takes the priority of its request.
+    inherit_priorities: bool,
+    /// The priorities of the frames decoded but not yet answered, if not 0.
+    priorities: HashMap<RequestId, u8>,
+    /// Gives each message encoded its metadata.
+    request_metadata: Option<MetadataSource<Encode>>,
+    /// Called with the metadata of each frame decoded that carries any.
+    metadata_hook: Option<MetadataHook>,
+    /// Decides from its id, length, and metadata whether to reject each request decoded
+    /// before it is deserialized. Only set on servers.
+    request_screen: Option<RequestScreen>,
+    /// The request decoded last and the reason it was rejected, if `request_screen` rejected
+    /// it.
+    screened: Option<(RequestId, String)>,
+    /// True if the frame decoded last carried no metadata and was screened by its header alone,
+    /// before its payload arrived.
+    header_screened: bool,
+    /// Encrypts and authenticates every payload, if set.
+    cipher: Option<Arc<Cipher>>,
+    /// Persists each request decoded, which is acknowledged once it is. Only set on servers.
+    persist: Option<Persister<Decode>>,
+    /// Answers requests retried with the idempotency key of one already answered. Only set on
+    /// servers.
+    response_cache: Option<ResponseCache>,
+    /// The idempotency keys of the requests decoded but not yet answered, whose responses are
+    /// to be cached.
+    idempotency_keys: HashMap<RequestId, String>,
+    /// The cached response to the request decoded last, if it was a retry.
+    cached_response: Option<(RequestId, Vec<u8>)>,
+    /// The serialized payload of the response encoded last, if it is to be cached.
+    response_payload: Option<Vec<u8>>,
+    /// The senders of the `AckFuture`s of a `Client`, in the order its requests are encoded.
+    ack_slots: Option<AckSlots>,
+    /// The senders of the `AckFuture`s of the requests encoded but neither acknowledged nor
+    /// answered yet.
+    awaiting_acks: HashMap<RequestId, oneshot::Sender<()>>,
+    /// Gives each request encoded the id it is sent with, if set. Only set on clients.
+    request_ids: Option<IdAllocator>,
+    /// The sequence number of the next request encoded, if requests carry one. Only set on
+    /// clients.
+    sequence: Option<u64>,
+    /// The sequence number expected on the next request decoded that carries one.
+    expected_sequence: u64,
+    /// The ids tokio-proto gave the requests sent with an id from `request_ids` but not answered
+    /// yet, by the id they were sent with.
+    wire_ids: HashMap<RequestId, RequestId>,
+    spans: RequestSpans,
+    metrics: Option<Arc<CodecMetrics>>,
+    _phantom_data: PhantomData<(Encode, Decode)>,
+}
+
+/// The fragments of a payload received so far; see `Codec::fragmentation`.
+struct Reassembly {
+    /// The header fields of the first fragment, which become those of the whole frame.
+    flags: u8,
+    priority: u8,
+    deadline: Option<u64>,
+    /// The number of fragments the payload was split into.
+    total: u32,
+    /// The number of fragments received.
+    received: u32,
+    /// When the first fragment arrived.
+    started: Instant,
+    /// The payload so far, or `None` if it grew too big and the rest is being discarded.
+    payload: Option<Vec<u8>>,
+}
+
+/// Returns the priority of a request.
+type Prioritizer<Encode> = Arc<Fn(&Encode) -> u8 + Send + Sync>;
+
+/// Key-value metadata sent in a frame alongside its payload, such as a trace id or the tenant a
+/// request is made for.
+pub type Metadata = HashMap<String, String>;
+
+/// The metadata key under which a client sends the sequence number of a request; see
+/// `Proto::sequence_numbers`.
+pub const SEQUENCE_KEY: &'static str = "sequence";
+
+/// Returns the metadata to send with a message.
+type MetadataSource<Encode> = Arc<Fn(&Encode) -> Metadata + Send + Sync>;
+
+/// Called with the id and metadata of a frame decoded.
+type MetadataHook = Arc<Fn(RequestId, &Metadata) + Send + Sync>;
+
+/// Called with the id, payload length, and metadata of a request decoded; returns the reason to
+/// reject it for, if any.
+type RequestScreen = Arc<Fn(RequestId, u64, &Metadata) -> Result<(), String> + Send + Sync>;
+
+/// Starts persisting a request, returning a future that resolves once it has been.
+type Persister<Decode> = Arc<Fn(&Decode) -> Box<Future<Item = (), Error = io::Error>> + Send +
+                             Sync>;
+
+/// One entry per request a `Client` sends, holding the sender of its `AckFuture` if it has one.
+/// tokio-proto encodes requests in the order they are made, which is how the codec learns the
+/// id of each.
+type AckSlots = Arc<Mutex<VecDeque<Option<oneshot::Sender<()>>>>>;
+
+/// Returns the id to send the next request with.
+type IdAllocator = Arc<Fn() -> RequestId + Send + Sync>;
+
+/// A `Codec` that serializes payloads as JSON.
+#[cfg(feature = "json")]
+pub type JsonCodec<Encode, Decode> = Codec<Encode, Decode, JsonSerializer>;
+
+/// A `Codec` that serializes payloads as MessagePack.
+#[cfg(feature = "msgpack")]
+pub type MsgPackCodec<Encode, Decode> = Codec<Encode, Decode, MsgPackSerializer>;
+
+/// A `Codec` that serializes payloads as CBOR.
+#[cfg(feature = "cbor")]
+pub type CborCodec<Encode, Decode> = Codec<Encode, Decode, CborSerializer>;
+
+/// A `Codec` that serializes payloads in a `Format` chosen when the connection is established.
+pub type NegotiatedCodec<Encode, Decode> = Codec<Encode, Decode, Format>;
+
+impl<Encode, Decode, S> Codec<Encode, Decode, S>
+    where S: PayloadSerializer + Default
+{
+    /// Returns a new `Codec` that rejects payloads larger than `max_payload_size` bytes.
+    pub fn new(max_payload_size: u64) -> Self {
+        Codec::with_limits(max_payload_size, max_payload_size)
+    }
+
+    /// Returns a new `Codec` that refuses to send payloads larger than `max_outbound` bytes and
+    /// rejects received payloads larger than `max_inbound` bytes.
+    pub fn with_limits(max_outbound: u64, max_inbound: u64) -> Self {
+        Codec::with_frame_options(max_outbound, max_inbound, FrameOptions::default(), S::default())
+    }
+
+    /// Returns a new `Codec` with no max payload size, for links between trusted peers.
+    /// Payloads are serialized in a single pass, with their length filled in afterwards, and
+    /// received payloads are never rejected for their length; see `single_pass`.
+    ///
+    /// A peer can then make the codec buffer as many bytes as it likes by sending a large
+    /// length, which a transport reads until it runs out of memory. Only use it where both
+    /// sides are under your control; otherwise, set a max payload size above the largest
+    /// payload expected and enable `single_pass` instead.
+    pub fn unbounded() -> Self {
+        Codec::new(u64::MAX)
+    }
+
+    /// Returns a new `Codec` that follows every payload with its CRC32, and verifies the CRC32
+    /// of every payload it decodes. The peer must use a checksumming codec too.
+    pub fn with_checksum(max_payload_size: u64) -> Self {
+        Codec::new(max_payload_size).checksum(true)
+    }
+
+    /// Returns a new `Codec` that prefixes payloads with their length as a varint, which takes
+    /// a single byte for payloads under 128 bytes. The peer must use varint lengths too.
+    pub fn with_varint_len(max_payload_size: u64) -> Self {
+        Codec::new(max_payload_size).len_width(LenWidth::Varint)
+    }
+}
+
+impl<Encode, Decode, S> Codec<Encode, Decode, S>
+    where S: PayloadSerializer
+{
+    /// Returns a new `Codec` that uses `serializer` for payloads, rejecting payloads larger than
+    /// `max_payload_size` bytes.
+    pub fn with_serializer(max_payload_size: u64, serializer: S) -> Self {
+        Codec::with_frame_options(max_payload_size,
+                                  max_payload_size,
+                                  FrameOptions::default(),
+                                  serializer)
+    }
+
+    fn with_frame_options(max_outbound: u64,
+                          max_inbound: u64,
+                          frame: FrameOptions,
+                          serializer: S)
+                          -> Self {
+        Codec {
+            connection_id: 0,
+            max_outbound: max_outbound,
+            max_inbound: max_inbound,
+            payload_limits: None,
+            max_reassembled: 0,
+            reassembling: HashMap::new(),
+            max_reassembly_time: None,
+            skip_too_big: false,
+            isolate_payload_errors: false,
+            single_pass: false,
+            size_estimate: 0,
+            write_through: false,
+            request_timeout: None,
+            max_header_wait: None,
+            header_started: None,
+            decoded_size: 0,
+            buffered: 0,
+            capture: Capture::default(),
+            frame_rate: None,
+            throttled: false,
+            frame: frame,
+            serializer: serializer,
+            state: CodecState::Id,
+            version: PROTOCOL_VERSION,
+            heartbeats: 0,
+            goodbye: None,
+            refused: false,
+            request_priority: None,
+            inherit_priorities: false,
+            priorities: HashMap::new(),
+            request_metadata: None,
+            metadata_hook: None,
+            request_screen: None,
+            screened: None,
+            header_screened: false,
+            cipher: None,
+            persist: None,
+            response_cache: None,
+            idempotency_keys: HashMap::new(),
+            cached_response: None,
+            response_payload: None,
+            ack_slots: None,
+            awaiting_acks: HashMap::new(),
+            pings: None,
+            pongs: vec![],
+            stats_queries: None,
+            stats: None,
+            queries: 0,
+            request_ids: None,
+            sequence: None,
+            expected_sequence: 0,
+            wire_ids: HashMap::new(),
+            spans: RequestSpans::default(),
+            metrics: None,
+            _phantom_data: PhantomData,
+        }
+    }
+
+    /// Compress payloads according to `options`, and decompress received payloads the same
+    /// way. This adds a flags byte to every frame, so the peer must enable compression too.
+    ///
+    /// The payload size limits apply to the payload as it is sent on the wire, i.e. after
+    /// compression.
+    pub fn compression(mut self, options: CompressionOptions) -> Self {
+        self.frame.compression = Some(options);
+        self.frame.decompression = Some(options);
+        self
+    }
+
+    /// Decompress received payloads according to `options`, whatever this side compresses the
+    /// payloads it sends with, if anything. Whether a payload is compressed is recorded in the
+    /// flags of its own frame, so each direction of a connection can be compressed, or not, on
+    /// its own: a side can send uncompressed requests and receive compressed responses. Like
+    /// `compression`, this adds a flags byte to every frame, so the peer must enable
+    /// compression or decompression too.
+    pub fn decompression(mut self, options: CompressionOptions) -> Self {
+        self.frame.decompression = Some(options);
+        self
+    }
+
+    /// Set whether payloads are followed by their CRC32. The peer must use the same setting.
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.frame.checksum = checksum;
+        self
+    }
+
+    /// Follow only payloads of at least `bytes` bytes with their CRC32, flagging the frames that
+    /// have one so that the peer knows which to verify. Small payloads, such as most control
+    /// frames, are cheap to resend and aren't worth checksumming. Has no effect if `checksum`
+    /// is set. The peer must set a threshold too, though not necessarily the same one.
+    pub fn checksum_threshold(mut self, bytes: u64) -> Self {
+        self.frame.checksum_threshold = Some(bytes);
+        self
+    }
+
+    /// Set whether every frame starts with a 4-byte marker, which lets `resync` find the next
+    /// frame after the stream was corrupted. The peer must use the same setting.
+    pub fn frame_markers(mut self, frame_markers: bool) -> Self {
+        self.frame.frame_markers = frame_markers;
+        self
+    }
+
+    /// Check payloads against `limits` as well, which can be tightened while the connection is
+    /// open; see `PayloadLimits`.
+    pub fn payload_limits(mut self, limits: PayloadLimits) -> Self {
+        self.payload_limits = Some(limits);
+        self
+    }
+
+    /// Copy the bytes `decode` is given to `sink` as they arrive, so that the stream a
+    /// connection received can be replayed through another `Codec` with `replay`. Capturing
+    /// stops, with a warning, if writing to `sink` fails; it never fails the connection.
+    #[cfg(feature = "capture")]
+    pub fn capture<W: io::Write + Send + 'static>(mut self, sink: W) -> Self {
+        self.capture = Capture::new(Some(Box::new(sink)));
+        self
+    }
+
+    /// Split a payload larger than the max payload size across as many frames as it takes,
+    /// rather than failing to send it, and put such payloads back together as they are
+    /// received. The max payload size then bounds each frame, and `max_reassembled_size` bounds
+    /// the whole payload, sent or received; a payload received in fragments that grows larger
+    /// is rejected like any other that is too big. Each fragment spends 8 bytes of its frame on
+    /// its index and the number of fragments. This adds a flags byte to every frame, so the
+    /// peer must enable fragmentation too.
+    ///
+    /// The fragments received are held until the last one arrives, apart from the bytes a
+    /// transport buffers.
+    pub fn fragmentation(mut self, max_reassembled_size: u64) -> Self {
+        self.frame.fragments = true;
+        self.max_reassembled = max_reassembled_size;
+        self
+    }
+
+    /// Abandon a payload received in fragments if its last fragment hasn't arrived `timeout`
+    /// after its first, so that a peer can't pin reassembly buffers by never finishing a
+    /// payload. The fragments received are discarded, as are those that arrive later, and the
+    /// frame is decoded as `DecodeError::ReassemblyTimedOut`. Like the max header wait, the
+    /// time is checked as frames are decoded; a peer that stops sending altogether is left to
+    /// the idle timeouts. Has no effect unless fragmentation is enabled.
+    pub fn max_reassembly_time(mut self, timeout: Duration) -> Self {
+        self.max_reassembly_time = Some(timeout);
+        self
+    }
+
+    /// Set the width of the length prefix. The default is `LenWidth::U64`; `LenWidth::U32` saves
+    /// 4 bytes per frame, but limits payloads to `u32::MAX` bytes regardless of the configured
+    /// limits, and `LenWidth::Varint` takes as few bytes as the length needs. The peer must use
+    /// the same width.
+    pub fn len_width(mut self, width: LenWidth) -> Self {
+        self.frame.len_width = width;
+        self
+    }
+
+    /// Set the byte order of the id, length, and checksum fields. The default is
+    /// `Endianness::Big`; `Endianness::Little` is for talking to peers that expect it. The peer
+    /// must use the same byte order.
+    pub fn endianness(mut self, endianness: Endianness) -> Self {
+        self.frame.endianness = endianness;
+        self
+    }
+
+    /// Set whether every frame carries a deadline after its id, which costs 8 bytes per frame.
+    /// The peer must use the same setting. A `Proto` enables deadlines on the connections that
+    /// negotiate `DEADLINE_VERSION` or newer.
+    pub fn deadlines(mut self, deadlines: bool) -> Self {
+        self.frame.deadlines = deadlines;
+        self
+    }
+
+    /// Set whether every frame carries a priority byte after its flags. The peer must use the
+    /// same setting. A `Proto` enables priorities on the connections that negotiate
+    /// `PRIORITY_VERSION` or newer.
+    pub fn priorities(mut self, priorities: bool) -> Self {
+        self.frame.priorities = priorities;
+        self
+    }
+
+    /// Send every request with the priority `priority` returns for it, if frames carry
+    /// priorities. A server's transport sends the responses to higher-priority requests ahead
+    /// of the ones waiting to be written; requests of the same priority, like those of the
+    /// default priority, 0, are answered in order.
+    pub fn priority<F>(mut self, priority: F) -> Self
+        where F: Fn(&Encode) -> u8 + Send + Sync + 'static
+    {
+        self.request_priority = Some(Arc::new(priority));
+        self
+    }
+
+    /// Send each request with the id `allocate` returns, rather than the one tokio-proto gave
+    /// it, so that tests can predict the ids on the wire; a sequential counter starting at a
+    /// known value is typical. Responses are decoded with tokio-proto's ids again.
+    ///
+    /// An id of a request still waiting for its response, or one of the largest six ids, which
+    /// heartbeats and other control frames use, is skipped, and `allocate` is called again. A
+    /// counter that wraps therefore never reuses an active id. If `allocate` returns no free id
+    /// in more attempts than there are ids in use, the request fails with an
+    /// `IdSpaceExhaustedError`. Only for a client's codec.
+    ///
+    /// The id of a request that times out is free again, and a frame with an id that no request
+    /// in flight was sent with is discarded, so a response arriving after its request timed out
+    /// is only mistaken for another request's if `allocate` returned its id again since: an
+    /// allocator should cycle through the ids, as a counter does, rather than reuse them soon.
+    pub fn request_ids<F>(mut self, allocate: F) -> Self
+        where F: Fn() -> RequestId + Send + Sync + 'static
+    {
+        self.request_ids = Some(Arc::new(allocate));
+        self
+    }
+
+    /// Set whether to send every request with its sequence number on the connection, counting
+    /// from 0, in its metadata under `SEQUENCE_KEY`, so that the server can check that requests
+    /// arrive in the order they were sent and that none are lost. A codec that decodes a
+    /// sequence number other than the one after the last it decoded logs it and reports it to
+    /// `CodecMetrics::on_sequence_gap`; the request is decoded as usual. Has no effect unless
+    /// frames can carry metadata. Only for a client's codec.
+    pub fn sequence_numbers(mut self, sequence_numbers: bool) -> Self {
+        self.sequence = if sequence_numbers { Some(0) } else { None };
+        self
+    }
+
+    /// Set whether frames can start their payload with a block of metadata. This adds a flags
+    /// byte to every frame, so the peer must use the same setting. A `Proto` enables metadata on
+    /// the connections that negotiate `METADATA_VERSION` or newer.
+    pub fn frame_metadata(mut self, metadata: bool) -> Self {
+        self.frame.metadata = metadata;
+        self
+    }
+
+    /// Send every message with the metadata `metadata` returns for it, if frames can carry
+    /// metadata and it returns any. The metadata is serialized with bincode, regardless of the
+    /// payload's format, and counts towards the max payload size.
+    pub fn metadata<F>(mut self, metadata: F) -> Self
+        where F: Fn(&Encode) -> Metadata + Send + Sync + 'static
+    {
+        self.request_metadata = Some(Arc::new(metadata));
+        self
+    }
+
+    /// Call `hook` with the id and metadata of every frame decoded that carries metadata,
+    /// before its payload is deserialized.
+    pub fn on_metadata<F>(mut self, hook: F) -> Self
+        where F: Fn(RequestId, &Metadata) + Send + Sync + 'static
+    {
+        self.metadata_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Call `screen` with the id, payload length, and metadata of every request decoded, empty
+    /// if the frame carries none, before its payload is decompressed or deserialized. If it
+    /// returns a reason, the request is decoded as `DecodeError::Rejected`
+    /// and deserialization is skipped; a server's `Transport` tells the client why instead of
+    /// passing the request on, on connections that negotiate `REJECTION_VERSION`, and drops it
+    /// on older ones. Only for a server's codec.
+    ///
+    /// A request without metadata is screened as soon as its frame header is parsed, and if it
+    /// is rejected, its payload is skipped as it arrives rather than buffered. The metadata is
+    /// carried in the payload, though, so a request with metadata is only screened once its
+    /// whole payload has been buffered, and decrypted if the connection is encrypted; a
+    /// fragmented payload is screened once it is reassembled. Either way, exactly the
+    /// payload's bytes are consumed, so the stream stays in sync.
+    pub fn screen_requests<F>(mut self, screen: F) -> Self
+        where F: Fn(RequestId, u64, &Metadata) -> Result<(), String> + Send + Sync + 'static
+    {
+        self.request_screen = Some(Arc::new(screen));
+        self
+    }
+
+    /// Encrypt every payload with `key`, authenticating it along with the frame's id and the
+    /// payload's length. Each payload carries its random nonce and a tag, which add 28 bytes
+    /// to it. The peer must use the same key. A payload that isn't authenticated is decoded as
+    /// `DecodeError::Unauthenticated`.
+    ///
+    /// Heartbeats, goodbyes, rejections, and acknowledgements aren't encrypted.
+    #[cfg(feature = "encryption")]
+    pub fn encryption(mut self, key: Arc<EncryptionKey>) -> Self {
+        self.cipher = Some(key);
+        self
+    }
+
+    /// Give every request encoded a deadline `timeout` from now. A peer that decodes the request
+    /// after its deadline returns `DecodeError::DeadlineExceeded` instead of the request. Has no
+    /// effect unless deadlines are enabled.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail `decode` with an error of kind `TimedOut` if the header of a frame, up to its
+    /// length, takes longer than `wait` to arrive. This closes connections to peers that send
+    /// headers a byte at a time, which idle timeouts don't catch since every byte is progress.
+    /// The wait is checked as bytes arrive, so a peer that stops sending altogether is left to
+    /// the idle timeouts.
+    pub fn max_header_wait(mut self, wait: Duration) -> Self {
+        self.max_header_wait = Some(wait);
+        self
+    }
+
+    /// Set whether a received payload larger than the max payload size fails only its own
+    /// request. If true, the payload is skipped and `decode` returns a
+    /// `DecodeError::PayloadTooLarge` for the request; if false, the default, `decode`
+    /// returns an `io::Error`, closing the connection.
+    pub fn skip_too_big(mut self, skip: bool) -> Self {
+        self.skip_too_big = skip;
+        self
+    }
+
+    /// Set whether a received payload whose metadata is malformed or won't decompress fails
+    /// only its own request. If true, `decode` returns a `DecodeError::MalformedPayload` for
+    /// the request and goes on to the next frame, which starts where the frame's length says;
+    /// if false, the default, `decode` returns an `io::Error`, closing the connection. Either
+    /// way, a payload that doesn't deserialize fails only its own request.
+    pub fn isolate_payload_errors(mut self, isolate: bool) -> Self {
+        self.isolate_payload_errors = isolate;
+        self
+    }
+
+    /// Set whether payloads are serialized in a single pass. By default, `encode` computes the
+    /// size of a payload before serializing it, so that a payload that is too big is rejected
+    /// without being serialized. In a single pass, the payload is serialized first and its size
+    /// checked afterwards, saving a traversal of every message that fits; this pays off when
+    /// payloads are rarely too big, e.g. when the max payload size is effectively unbounded.
+    /// Space for each payload is reserved based on the size of the last one.
+    ///
+    /// Payloads are always serialized in a single pass if the max payload size is `u64::MAX`,
+    /// since none of them can be too big. Has no effect with `LenWidth::Varint`, whose width
+    /// depends on the size.
+    pub fn single_pass(mut self, single_pass: bool) -> Self {
+        self.single_pass = single_pass;
+        self
+    }
+
+    /// Set whether a `Transport` writes each frame to its socket as the payload is serialized,
+    /// with `encode_to`, rather than serializing the whole frame into its write buffer first.
+    /// Whatever the socket won't take yet is still buffered, but a large payload going to a
+    /// socket that keeps up is never held in memory in full. `single_pass` is ignored, and
+    /// frames sent by priority are always buffered.
+    pub fn write_through(mut self, write_through: bool) -> Self {
+        self.write_through = write_through;
+        self
+    }
+
+    /// Report every frame encoded, decoded, or rejected for its size to `metrics`.
+    pub fn metrics(mut self, metrics: Arc<CodecMetrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Recovers from a corrupt stream, e.g. after `decode` returned an error or a frame failed to
+    /// deserialize, by discarding bytes until the start of the next frame. If frame markers
+    /// aren't enabled, the frame boundaries can't be found again, so this returns an error of
+    /// kind `InvalidData` and the connection should be closed.
+    ///
+    /// A frame whose length was corrupted may have consumed the start of the frames after it,
+    /// which are lost; and since payloads aren't escaped, a payload that contains the marker
+    /// can be mistaken for the start of a frame. Enable checksums to catch both.
+    pub fn resync(&mut self) -> io::Result<()> {
+        if !self.frame.frame_markers {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "Can't find the next frame without frame markers"));
+        }
+        debug!("Connection {}: Resynchronizing: discarding bytes up to the next frame marker.",
+               self.connection_id);
+        self.state = CodecState::Resync;
+        Ok(())
+    }
+
+    /// The id that log messages give the connection. For a `Codec` created by a `Proto`, this is
+    /// unique among the connections the process has bound, counting from 1; otherwise it is 0.
+    pub fn connection_id(&self) -> u64 {
+        self.connection_id
+    }
+
+    /// The version of the frame format. For a `Codec` created by a `Proto`, this is the version
+    /// negotiated with the peer; otherwise it is `PROTOCOL_VERSION`.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// What `decode` is waiting for, as of its last call. Useful for logging why it keeps
+    /// returning `Ok(None)`.
+    pub fn state(&self) -> DecodeProgress {
+        self.state.progress(&self.frame, self.buffered)
+    }
+
+    /// How many more bytes `decode` needs before it can finish the frame it's reading, if it
+    /// knows: the rest of a payload and its trailer, or of a frame being skipped. This is only a
+    /// hint for sizing reads; it's `None` while a header is being read, and a frame may still be
+    /// rejected or skipped once its bytes arrive.
+    pub fn bytes_needed(&self) -> Option<u64> {
+        match self.state() {
+            DecodeProgress::WaitingForPayload { needed, .. } => Some(needed),
+            DecodeProgress::Skipping { remaining } => Some(remaining),
+            _ => None,
+        }
+    }
+
+    /// The largest payload that can be sent, taking the width of the length prefix and the
+    /// `PayloadLimits` as they are now into account. For a `Codec` created by a `Proto`, this is
+    /// also no more than the peer accepts.
+    pub fn max_outbound(&self) -> u64 {
+        let max = match self.payload_limits {
+            Some(ref limits) => cmp::min(self.max_outbound, limits.max_outbound()),
+            None => self.max_outbound,
+        };
+        cmp::min(max, self.frame.len_width.max_len())
+    }
+
+    /// The largest payload that `decode` accepts, taking the `PayloadLimits` as they are now
+    /// into account.
+    pub fn max_inbound(&self) -> u64 {
+        match self.payload_limits {
+            Some(ref limits) => cmp::min(self.max_inbound, limits.max_inbound()),
+            None => self.max_inbound,
+        }
+    }
+
+    /// Returns the error for the outbound payload of frame `id`, of `payload_size` bytes, which
+    /// is too big.
+    fn too_big(&self, id: RequestId, payload_size: u64) -> io::Error {
+        if let Some(ref metrics) = self.metrics {
+            metrics.on_reject(payload_size, self.max_outbound());
+        }
+        warn!("Connection {}: Not sending too-big packet of size {} for request id = {} (max is \
+               {})",
+              self.connection_id,
+              payload_size,
+              id,
+              self.max_outbound());
+        too_big_error(Some(id), payload_size, self.max_outbound())
+    }
+
+    /// The deadline to write in the header of the next frame encoded, or 0 for none.
+    fn next_deadline(&self) -> u64 {
+        match self.request_timeout {
+            Some(timeout) if self.frame.deadlines => unix_millis(SystemTime::now() + timeout),
+            _ => 0,
+        }
+    }
+
+    /// The priority of the frame to encode for `message`, with id `id`.
+    fn priority(&self, id: RequestId, message: &Encode) -> u8 {
+        if !self.frame.priorities {
+            return 0;
+        }
+        if self.inherit_priorities {
+            return self.priorities.get(&id).cloned().unwrap_or(0);
+        }
+        self.request_priority.as_ref().map_or(0, |priority| priority(message))
+    }
+
+    /// The metadata to send with `message`, if frames can carry metadata and there is any.
+    fn outbound_metadata(&self, message: &Encode) -> Option<Metadata> {
+        if !self.frame.metadata {
+            return None;
+        }
+        let mut metadata = match self.request_metadata {
+            Some(ref metadata) => metadata(message),
+            None => Metadata::new(),
+        };
+        if let Some(sequence) = self.sequence {
+            metadata.insert(SEQUENCE_KEY.to_string(), sequence.to_string());
+        }
+        if metadata.is_empty() {
+            None
+        } else {
+            Some(metadata)
+        }
+    }
+
+    /// Moves on to the next sequence number once a request carrying one was sent.
+    fn sent_sequence(&mut self, sent: &io::Result<()>) {
+        if sent.is_ok() {
+            if let Some(ref mut sequence) = self.sequence {
+                *sequence += 1;
+            }
+        }
+    }
+
+    /// Checks the sequence number of request `id`, if it carries one, against the one expected.
+    fn check_sequence(&mut self, id: RequestId, metadata: &Metadata) {
+        let received: u64 = match metadata.get(SEQUENCE_KEY).and_then(|n| n.parse().ok()) {
+            Some(received) => received,
+            None => return,
+        };
+        if received != self.expected_sequence {
+            warn!("Connection {}: Request id = {} has sequence number {}, but {} was expected",
+                  self.connection_id,
+                  id,
+                  received,
+                  self.expected_sequence);
+            if let Some(ref metrics) = self.metrics {
+                metrics.on_sequence_gap(id, self.expected_sequence, received);
+            }
+        }
+        self.expected_sequence = received.wrapping_add(1);
+    }
+
+    /// Appends a heartbeat frame to `buf`.
+    fn encode_heartbeat(&self, buf: &mut Vec<u8>) {
+        self.frame.write_header(buf, HEARTBEAT_ID, 0, 0, 0, 0);
+        let payload_start = buf.len();
+        self.frame.write_trailer(buf, payload_start);
+    }
+
+    /// Appends a ping frame carrying `timestamp` to `buf`, which is how a server echoes a ping
+    /// too.
+    fn encode_ping(&self, timestamp: u64, buf: &mut Vec<u8>) {
+        self.frame.write_header(buf, PING_ID, 0, 0, 0, mem::size_of::<u64>() as u64);
+        let payload_start = buf.len();
+        self.frame.write_id(buf, timestamp);
+        self.frame.write_trailer(buf, payload_start);
+    }
+
+    /// Appends a ping frame to `buf` if the client asked for one, returning true if it did.
+    /// Registers the current task to be woken when the client asks. Pings fail instead if the
+    /// negotiated version predates them.
+    fn poll_ping(&mut self, buf: &mut Vec<u8>) -> bool {
+        let supported = self.version >= PING_VERSION;
+        let timestamp = match self.pings {
+            Some(ref mut pings) => pings.poll_requested(supported),
+            None => return false,
+        };
+        match timestamp {
+            Some(timestamp) => {
+                self.encode_ping(timestamp, buf);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Appends a diagnostics frame carrying `payload` to `buf`: a stats query if it's empty,
+    /// and otherwise an answer.
+    fn encode_diagnostics(&self, payload: &[u8], buf: &mut Vec<u8>) {
+        self.frame.write_header(buf, DIAGNOSTICS_ID, 0, 0, 0, payload.len() as u64);
+        let payload_start = buf.len();
+        buf.extend_from_slice(payload);
+        self.frame.write_trailer(buf, payload_start);
+    }
+
+    /// Appends a stats query to `buf` for each the client asked for, returning true if it
+    /// asked for any. Registers the current task to be woken when the client asks. Queries
+    /// fail instead if the negotiated version predates them.
+    fn poll_stats_queries(&mut self, buf: &mut Vec<u8>) -> bool {
+        let supported = self.version >= DIAGNOSTICS_VERSION;
+        let queries = match self.stats_queries {
+            Some(ref mut queries) => queries.poll_requested(supported),
+            None => return false,
+        };
+        for _ in 0..queries {
+            self.encode_diagnostics(&[], buf);
+        }
+        queries > 0
+    }
+
+    /// Appends the answer to a stats query to `buf`: the server's stats, if the client may
+    /// query them.
+    fn encode_stats(&self, buf: &mut Vec<u8>) {
+        let answer = match self.stats {
+            Some(ref stats) => stats.answer(),
+            None => {
+                diagnostics::encode_answer(&Err("The server does not answer stats queries"
+                    .to_string()))
+            }
+        };
+        self.encode_diagnostics(&answer, buf);
+    }
+
+    /// Records `rtt` as the round-trip time of the connection, if the client measures it.
+    fn record_rtt(&self, rtt: Duration) {
+        if let Some(ref pings) = self.pings {
+            pings.record(rtt);
+        }
+    }
+
+    /// Appends a goodbye frame carrying `reason` to `buf`. Returns false, appending nothing, if
+    /// the negotiated version predates goodbye frames.
+    fn encode_goodbye(&self, reason: &str, buf: &mut Vec<u8>) -> bool {
+        if self.version < GOODBYE_VERSION {
+            return false;
+        }
+        self.frame.write_header(buf, GOODBYE_ID, 0, 0, 0, reason.len() as u64);
+        let payload_start = buf.len();
+        buf.extend_from_slice(reason.as_bytes());
+        self.frame.write_trailer(buf, payload_start);
+        true
+    }
+
+    /// Appends a protocol error frame carrying `error` to `buf`. Returns false, appending
+    /// nothing, if the negotiated version predates protocol error frames or the peer already
+    /// sent one.
+    fn encode_protocol_error(&self, error: &ProtocolError, buf: &mut Vec<u8>) -> bool {
+        if self.version < PROTOCOL_ERROR_VERSION || self.refused {
+            return false;
+        }
+        let len = 1 + error.message.len() as u64;
+        self.frame.write_header(buf, PROTOCOL_ERROR_ID, 0, 0, 0, len);
+        let payload_start = buf.len();
+        buf.push(error.code as u8);
+        buf.extend_from_slice(error.message.as_bytes());
+        self.frame.write_trailer(buf, payload_start);
+        true
+    }
+
+    /// Appends a frame to `buf` rejecting request `id` for `reason`. Returns false, appending
+    /// nothing, if the negotiated version predates rejection frames.
+    fn encode_rejection(&self, id: RequestId, reason: &str, buf: &mut Vec<u8>) -> bool {
+        if self.version < REJECTION_VERSION {
+            return false;
+        }
+        // The reason is cut short to fit the max payload size, so that the client can read it.
+        let room = cmp::min(self.max_outbound(), usize::MAX as u64) as usize;
+        let room = room.saturating_sub(mem::size_of::<RequestId>());
+        let reason = &reason.as_bytes()[..cmp::min(reason.len(), room)];
+        let mut payload = Vec::with_capacity(mem::size_of::<RequestId>() + reason.len());
+        self.frame.write_id(&mut payload, id);
+        payload.extend_from_slice(reason);
+        self.frame.write_header(buf, REJECTED_ID, 0, 0, 0, payload.len() as u64);
+        let payload_start = buf.len();
+        buf.extend_from_slice(&payload);
+        self.frame.write_trailer(buf, payload_start);
+        true
+    }
+
+    /// Appends a frame to `buf` acknowledging request `id`. Returns false, appending nothing, if
+    /// the negotiated version predates acknowledgement frames.
+    fn encode_ack(&self, id: RequestId, buf: &mut Vec<u8>) -> bool {
+        if self.version < ACK_VERSION {
+            return false;
+        }
+        self.frame.write_header(buf, ACKED_ID, 0, 0, 0, mem::size_of::<RequestId>() as u64);
+        let payload_start = buf.len();
+        self.frame.write_id(buf, id);
+        self.frame.write_trailer(buf, payload_start);
+        true
+    }
+
+    /// Keeps the sender of the `AckFuture` of request `id`, if the client gave it one.
+    fn await_ack(&mut self, id: RequestId) {
+        let ack = match self.ack_slots {
+            Some(ref slots) => slots.lock().unwrap().pop_front(),
+            None => return,
+        };
+        if let Some(Some(ack)) = ack {
+            self.awaiting_acks.insert(id, ack);
+        }
+    }
+
+    /// The id to send request `id` with, remembering it if it was allocated. Allocated ids that
+    /// are in flight or reserved are skipped; a sequential allocator finds a free one within
+    /// one attempt more than there are ids in use.
+    fn wire_id(&mut self, id: RequestId) -> io::Result<RequestId> {
+        let allocate = match self.request_ids {
+            Some(ref allocate) => allocate.clone(),
+            None => return Ok(id),
+        };
+        let in_flight = self.wire_ids.len();
+        let attempts = in_flight + (RequestId::max_value() - MIN_CONTROL_ID) as usize + 2;
+        for _ in 0..attempts {
+            let wire_id = allocate();
+            if wire_id >= MIN_CONTROL_ID || self.wire_ids.contains_key(&wire_id) {
+                continue;
+            }
+            trace!("Connection {}: Sending request id = {} as id = {}",
+                   self.connection_id, id, wire_id);
+            self.wire_ids.insert(wire_id, id);
+            return Ok(wire_id);
+        }
+        Err(IdSpaceExhaustedError {
+                in_flight: in_flight,
+                attempts: attempts,
+            }
+            .into_io())
+    }
+
+    /// Frees `wire_id` again if the frame sent with it, whose result is `encoded`, failed to
+    /// encode.
+    fn sent_with(&mut self, wire_id: RequestId, encoded: io::Result<u64>) -> io::Result<u64> {
+        if encoded.is_err() {
+            self.wire_ids.remove(&wire_id);
+        }
+        encoded
+    }
+
+    /// Frees the id request `id` was sent with, once the client stopped waiting for its
+    /// response; the response is discarded if it arrives after all. Returns false if the
+    /// request was sent with its own id, in which case the caller must discard the response.
+    fn abandon_request(&mut self, id: RequestId) -> bool {
+        let wire_id = match self.wire_ids.iter().find(|&(_, &sent)| sent == id) {
+            Some((&wire_id, _)) => wire_id,
+            None => return false,
+        };
+        self.wire_ids.remove(&wire_id);
+        true
+    }
+
+    /// The id of the request that frames with id `wire_id` belong to, or `None` if requests
+    /// are sent with allocated ids and none in flight was sent with `wire_id`.
+    fn request_id(&self, wire_id: RequestId) -> Option<RequestId> {
+        match self.request_ids {
+            Some(_) => self.wire_ids.get(&wire_id).cloned(),
+            None => Some(wire_id),
+        }
+    }
+
+    /// Fails if the header being parsed, with `buffered` bytes waiting, has taken longer than
+    /// `max_header_wait` to arrive.
+    fn check_header_wait(&mut self, buffered: usize) -> io::Result<()> {
+        let max_wait = match self.max_header_wait {
+            Some(max_wait) => max_wait,
+            None => return Ok(()),
+        };
+        if !self.state.in_header(buffered) {
+            self.header_started = None;
+            return Ok(());
+        }
+        let now = Instant::now();
+        let started = match self.header_started {
+            Some(started) => started,
+            None => {
+                self.header_started = Some(now);
+                now
+            }
+        };
+        if now - started > max_wait {
+            warn!("Connection {}: Frame header not received within {:?}; closing the connection.",
+                  self.connection_id,
+                  max_wait);
+            return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                      format!("Frame header not received within {:?}",
+                                              max_wait)));
+        }
+        Ok(())
+    }
+
+    /// Decodes the next frame that isn't a heartbeat, goodbye, acknowledgement, ping, or
+    /// diagnostics frame, counting the heartbeats, keeping the goodbye, completing the
+    /// `AckFuture`s, timing or keeping the pings, and answering or counting the stats queries
+    /// along the way. A rejection frame is decoded as `DecodeError::Rejected`
+    /// for the request it rejects.
+    fn decode_frame(&mut self,
+                    buf: &mut EasyBuf)
+                    -> io::Result<Option<(RequestId, Result<Frame, DecodeError<S::Error>>)>> {
+        if let Some((id, timeout)) = self.abandon_reassembly() {
+            return Ok(Some((id, Err(DecodeError::ReassemblyTimedOut { timeout: timeout }))));
+        }
+        loop {
+            if let Some(ref mut limiter) = self.frame_rate {
+                self.throttled = !limiter.poll_ready()?;
+                if self.throttled {
+                    return Ok(None);
+                }
+            }
+            let max_inbound = self.max_inbound();
+            // Whether the frame whose header was parsed, if any, was screened by its header, and
+            // the length of its payload.
+            let mut header = None;
+            let decoded = {
+                let screen = self.request_screen.as_ref();
+                self.state.decode_screened(&self.frame, max_inbound, buf, |id, flags, len| {
+                    // The metadata is in the payload, so a frame with metadata is screened once
+                    // its payload is buffered, as is a fragment, once it is reassembled.
+                    let by_header = id < MIN_CONTROL_ID &&
+                                    flags & (FLAG_METADATA | FLAG_FRAGMENT) == 0;
+                    header = Some((by_header, len));
+                    match screen {
+                        Some(screen) if by_header => screen(id, len, &Metadata::new()).err(),
+                        _ => None,
+                    }
+                })?
+            };
+            if let Some((by_header, _)) = header {
+                self.header_screened = by_header;
+            }
+            if let (Some(_), Some(limiter)) = (decoded.as_ref(), self.frame_rate.as_mut()) {
+                limiter.take();
+            }
+            let decoded = match (header, decoded) {
+                (Some((true, len)), Some((id, Err(DecodeError::Rejected { reason })))) => {
+                    self.count_decoded(id, len);
+                    return Ok(Some((id, Err(self.screened_out(id, reason)))));
+                }
+                (_, decoded) => decoded,
+            };
+            match decoded {
+                Some((HEARTBEAT_ID, _)) => {
+                    trace!("--> Connection {}: Decoded heartbeat.", self.connection_id);
+                    self.heartbeats += 1;
+                }
+                Some((GOODBYE_ID, frame)) => {
+                    let reason = frame.map(|frame| {
+                            String::from_utf8_lossy(frame.payload.as_slice()).into_owned()
+                        })
+                        .unwrap_or_default();
+                    debug!("--> Connection {}: Decoded goodbye: {:?}",
+                           self.connection_id, reason);
+                    self.goodbye = Some(reason);
+                }
+                Some((REJECTED_ID, Ok(frame))) => {
+                    let payload = frame.payload.as_slice();
+                    if payload.len() < mem::size_of::<RequestId>() {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  "Rejection frame is missing its request id"));
+                    }
+                    let (id, reason) = payload.split_at(mem::size_of::<RequestId>());
+                    let id = self.frame.read_id(id);
+                    let reason = String::from_utf8_lossy(reason).into_owned();
+                    debug!("--> Connection {}: Decoded rejection of request id = {}: {:?}",
+                           self.connection_id, id, reason);
+                    return Ok(Some((id, Err(DecodeError::Rejected { reason: reason }))));
+                }
+                Some((PROTOCOL_ERROR_ID, frame)) => {
+                    self.refused = true;
+                    let payload: &[u8] = match frame {
+                        Ok(ref frame) => frame.payload.as_slice(),
+                        Err(_) => &[],
+                    };
+                    let (code, message) = match payload.split_first() {
+                        Some((&code, message)) => (error::protocol_error_code(code), message),
+                        None => (None, payload),
+                    };
+                    let message = String::from_utf8_lossy(message).into_owned();
+                    debug!("--> Connection {}: Decoded protocol error {:?}: {:?}",
+                           self.connection_id, code, message);
+                    return Err(match code {
+                        Some(code) => ProtocolError::new(code, message).into_io(),
+                        None => {
+                            io::Error::new(io::ErrorKind::Other,
+                                           format!("The peer closed the connection: {}", message))
+                        }
+                    });
+                }
+                Some((REJECTED_ID, Err(_))) => {
+                    warn!("Connection {}: Discarding a rejection frame that failed to decode.",
+                          self.connection_id);
+                }
+                Some((ACKED_ID, Ok(frame))) => {
+                    if frame.payload.len() != mem::size_of::<RequestId>() {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  "Acknowledgement frame is not a request id"));
+                    }
+                    let id = self.frame.read_id(frame.payload.as_slice());
+                    let id = match self.request_id(id) {
+                        Some(id) => id,
+                        None => {
+                            debug!("--> Connection {}: Discarding the acknowledgement of id = \
+                                    {}, which no request in flight was sent with.",
+                                   self.connection_id, id);
+                            continue;
+                        }
+                    };
+                    debug!("--> Connection {}: Decoded acknowledgement of request id = {}",
+                           self.connection_id, id);
+                    if let Some(ack) = self.awaiting_acks.remove(&id) {
+                        // The client may have stopped waiting.
+                        let _ = ack.send(());
+                    }
+                }
+                Some((ACKED_ID, Err(_))) => {
+                    warn!("Connection {}: Discarding an acknowledgement frame that failed to \
+                           decode.",
+                          self.connection_id);
+                }
+                Some((PING_ID, Ok(frame))) => {
+                    if frame.payload.len() != mem::size_of::<u64>() {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  "Ping frame is not a timestamp"));
+                    }
+                    let timestamp = self.frame.read_id(frame.payload.as_slice());
+                    trace!("--> Connection {}: Decoded ping.", self.connection_id);
+                    match self.pings {
+                        Some(ref mut pings) => pings.pong(timestamp),
+                        None => self.pongs.push(timestamp),
+                    }
+                }
+                Some((PING_ID, Err(_))) => {
+                    warn!("Connection {}: Discarding a ping frame that failed to decode.",
+                          self.connection_id);
+                }
+                Some((DIAGNOSTICS_ID, Ok(frame))) => {
+                    trace!("--> Connection {}: Decoded diagnostics frame.", self.connection_id);
+                    match self.stats_queries {
+                        Some(ref mut queries) => queries.answer(frame.payload.as_slice())?,
+                        None if frame.payload.is_empty() => self.queries += 1,
+                        None => {
+                            warn!("Connection {}: Discarding stats that weren't queried.",
+                                  self.connection_id);
+                        }
+                    }
+                }
+                Some((DIAGNOSTICS_ID, Err(_))) => {
+                    warn!("Connection {}: Discarding a diagnostics frame that failed to decode.",
+                          self.connection_id);
+                }
+                Some((id, Ok(frame))) => {
+                    if frame.flags & FLAG_FRAGMENT == 0 {
+                        return Ok(Some((id, Ok(frame))));
+                    }
+                    if let Some(reassembled) = self.reassemble(id, frame)? {
+                        return Ok(Some((id, reassembled)));
+                    }
+                }
+                decoded => return Ok(decoded),
+            }
+        }
+    }
+
+    /// Discards the fragments of a payload whose fragments have been arriving for longer than
+    /// the max reassembly time, if there is one, returning the id of its frame along with the
+    /// max time. Its later fragments are skipped.
+    fn abandon_reassembly(&mut self) -> Option<(RequestId, Duration)> {
+        let timeout = match self.max_reassembly_time {
+            Some(timeout) => timeout,
+            None => return None,
+        };
+        let expired = self.reassembling
+            .iter_mut()
+            .find(|&(_, ref reassembly)| {
+                reassembly.payload.is_some() && reassembly.started.elapsed() > timeout
+            });
+        let (&id, reassembly) = match expired {
+            Some(expired) => expired,
+            None => return None,
+        };
+        warn!("Connection {}: Abandoning the fragmented payload of request id = {}, of which {} \
+               of {} fragments arrived within {:?}",
+              self.connection_id,
+              id,
+              reassembly.received,
+              reassembly.total,
+              timeout);
+        reassembly.payload = None;
+        if let Some(ref metrics) = self.metrics {
+            metrics.on_abandoned_reassembly(id, reassembly.received, reassembly.total);
+        }
+        Some((id, timeout))
+    }
+
+    /// Adds `frame`, a fragment of the payload of frame `id`, to the fragments received before
+    /// it. Returns the whole frame once its last fragment arrives, or the error rejecting it
+    /// once it grows too big.
+    fn reassemble(&mut self,
+                  id: RequestId,
+                  mut frame: Frame)
+                  -> io::Result<Option<Result<Frame, DecodeError<S::Error>>>> {
+        let (index, total) = self.frame.split_fragment_header(&mut frame.payload)?;
+        trace!("--> Connection {}: Decoded fragment {} of {} for id = {}",
+               self.connection_id, index, total, id);
+        if index == 0 {
+            let reassembly = Reassembly {
+                flags: frame.flags & !FLAG_FRAGMENT,
+                priority: frame.priority,
+                deadline: frame.deadline,
+                total: total,
+                received: 0,
+                started: Instant::now(),
+                payload: Some(Vec::new()),
+            };
+            if self.reassembling.insert(id, reassembly).is_some() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Request id = {} started a payload before \
+                                                   its last was complete",
+                                                  id)));
+            }
+        }
+        let mut reassembly = match self.reassembling.remove(&id) {
+            Some(reassembly) => reassembly,
+            None => return Err(unexpected_fragment(id, index, total)),
+        };
+        if index != reassembly.received || total != reassembly.total {
+            return Err(unexpected_fragment(id, index, total));
+        }
+        reassembly.received += 1;
+        let mut rejected = None;
+        if let Some(mut payload) = reassembly.payload.take() {
+            let len = (payload.len() + frame.payload.len()) as u64;
+            if len > self.max_reassembled {
+                warn!("Connection {}: Discarding the rest of the fragmented payload of request \
+                       id = {}, which is larger than {} bytes",
+                      self.connection_id,
+                      id,
+                      self.max_reassembled);
+                rejected = Some(DecodeError::PayloadTooLarge {
+                    len: len,
+                    max: self.max_reassembled,
+                });
+            } else {
+                payload.extend_from_slice(frame.payload.as_slice());
+                reassembly.payload = Some(payload);
+            }
+        }
+        if reassembly.received < reassembly.total {
+            self.reassembling.insert(id, reassembly);
+            return Ok(rejected.map(Err));
+        }
+        if rejected.is_some() {
+            return Ok(rejected.map(Err));
+        }
+        let Reassembly { flags, priority, deadline, payload, .. } = reassembly;
+        Ok(payload.map(|payload| {
+            Ok(Frame {
+                flags: flags,
+                priority: priority,
+                deadline: deadline,
+                payload: EasyBuf::from(payload),
+            })
+        }))
+    }
+
+    /// Open a span for every request decoded, closing it when the response is encoded. Does
+    /// nothing unless the `spans` feature is enabled.
+    fn trace_requests(mut self) -> Self {
+        self.spans = RequestSpans::enabled();
+        self
+    }
+
+    /// Decode no more frames than `limiter` allows, leaving the rest in the buffer.
+    fn frame_rate(mut self, limiter: RateLimiter) -> Self {
+        self.frame_rate = Some(limiter);
+        self
+    }
+
+    /// Send every response with the priority of the request it answers.
+    fn inherit_priorities(mut self) -> Self {
+        self.inherit_priorities = true;
+        self
+    }
+
+    /// True if `decode` has consumed part of a frame, and is waiting for the rest.
+    fn mid_frame(&self) -> bool {
+        match self.state {
+            CodecState::Id => false,
+            _ => true,
+        }
+    }
+
+    /// The number of complete frames in `buf` that `decode` hasn't decoded yet, found by
+    /// parsing copies of the buffer and the state. A frame that `decode` would fail on ends the
+    /// count.
+    fn complete_frames(&self, buf: &EasyBuf) -> usize {
+        let mut state = self.state;
+        let mut buf = buf.clone();
+        let max_inbound = self.max_inbound();
+        let mut frames = 0;
+        while let Ok(Some(_)) = state.decode::<()>(&self.frame, max_inbound, &mut buf) {
+            frames += 1;
+        }
+        frames
+    }
+
+    /// Returns the error for a stream that ended where `decode` last left off, or `None` if it
+    /// ended between frames.
+    fn truncated(&self) -> Option<io::Error> {
+        let progress = self.state();
+        match progress {
+            DecodeProgress::WaitingForId |
+            DecodeProgress::Resyncing if self.buffered == 0 => None,
+            _ => {
+                Some(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                    format!("Connection closed mid-frame: {:?}, with {} bytes \
+                                             buffered",
+                                            progress,
+                                            self.buffered)))
+            }
+        }
+    }
+
+    /// Returns the number of heartbeat frames decoded since the last call.
+    fn take_heartbeats(&mut self) -> u64 {
+        let heartbeats = self.heartbeats;
+        self.heartbeats = 0;
+        heartbeats
+    }
+
+    /// Returns the reason given by a goodbye frame decoded since the last call, if any.
+    fn take_goodbye(&mut self) -> Option<String> {
+        self.goodbye.take()
+    }
+
+    /// Returns the timestamps of the pings decoded since the last call, to be echoed.
+    fn take_pongs(&mut self) -> Vec<u64> {
+        mem::replace(&mut self.pongs, vec![])
+    }
+
+    /// Returns the number of stats queries decoded since the last call, to be answered.
+    fn take_queries(&mut self) -> usize {
+        mem::replace(&mut self.queries, 0)
+    }
+
+    /// Decodes request `id`, whose payload is malformed for reason `e`, as
+    /// `DecodeError::MalformedPayload` if payload errors are isolated, or fails with `e`.
+    fn malformed(&mut self,
+                 id: RequestId,
+                 e: io::Error)
+                 -> io::Result<Option<(RequestId, Result<Decode, DecodeError<S::Error>>)>> {
+        if !self.isolate_payload_errors {
+            return Err(e);
+        }
+        warn!("Connection {}: Payload of request id = {} is malformed: {}",
+              self.connection_id,
+              id,
+              e);
+        let e = DecodeError::MalformedPayload(e);
+        self.spans.rejected(id, &e);
+        Ok(Some((id, Err(e))))
+    }
+
+    /// Counts request `id`, with a payload of `payload_size` bytes, as decoded, in the metrics
+    /// and stats, and opens its span.
+    fn count_decoded(&mut self, id: RequestId, payload_size: u64) {
+        self.decoded_size = payload_size;
+        if let Some(ref metrics) = self.metrics {
+            metrics.on_decode(id, payload_size);
+        }
+        if let Some(ref stats) = self.stats {
+            stats.stats().on_decode(id, payload_size);
+        }
+        self.spans.open(id, payload_size);
+    }
+
+    /// Keeps the reason `screen_requests` rejected request `id` for, for `take_screened`, and
+    /// returns the rejection.
+    fn screened_out(&mut self, id: RequestId, reason: String) -> DecodeError<S::Error> {
+        debug!("Connection {}: Rejecting request id = {} before deserializing it: {}",
+               self.connection_id, id, reason);
+        self.screened = Some((id, reason.clone()));
+        DecodeError::Rejected { reason: reason }
+    }
+
+    /// Returns the reason request `id` was rejected for by the codec's `screen_requests`, if it
+    /// was decoded last and rejected, to be sent instead of handling it.
+    fn take_screened(&mut self, id: RequestId) -> Option<String> {
+        self.screened
+            .take()
+            .and_then(|(screened, reason)| if screened == id { Some(reason) } else { None })
+    }
+
+    /// Returns the cached response to request `id`, if it was decoded last and is a retry, to
+    /// be sent instead of handling the request again.
+    fn take_cached_response(&mut self, id: RequestId) -> Option<Vec<u8>> {
+        self.cached_response
+            .take()
+            .and_then(|(cached, response)| if cached == id { Some(response) } else { None })
+    }
+}
+
+fn too_big(id: Option<RequestId>, payload_size: u64, max_payload_size: u64) -> io::Error {
+    match id {
+        Some(id) => {
+            warn!("Not sending too-big packet of size {} for request id = {} (max is {})",
+                  payload_size, id, max_payload_size)
+        }
+        None => {
+            warn!("Not sending too-big packet of size {} (max is {})",
+                  payload_size, max_payload_size)
+        }
+    }
+    too_big_error(id, payload_size, max_payload_size)
+}
+
+fn too_big_error(id: Option<RequestId>, payload_size: u64, max_payload_size: u64) -> io::Error {
+    PayloadTooLargeError {
+            id: id,
+            len: payload_size,
+            max: max_payload_size,
+        }
+        .into_io()
+}
+
+/// Returns the error for a fragment that doesn't follow the fragments received before it.
+fn unexpected_fragment(id: RequestId, index: u32, total: u32) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("Unexpected fragment {} of {} for request id = {}", index, total, id))
+}
+
+impl<Encode, Decode, S> tokio_core::io::Codec for Codec<Encode, Decode, S>
+    where Encode: serde::Serialize,
+          Decode: serde::Deserialize,
+          S: PayloadSerializer
+{
+    type Out = (RequestId, Encode);
+    type In = (RequestId, Result<Decode, DecodeError<S::Error>>);
+
+    fn encode(&mut self, (id, message): Self::Out, buf: &mut Vec<u8>) -> io::Result<()> {
+        self.await_ack(id);
+        let priority = self.priority(id, &message);
+        self.priorities.remove(&id);
+        let encoded = self.wire_id(id).and_then(|wire_id| {
+            let encoded = if self.idempotency_keys.contains_key(&id) {
+                self.encode_cached(wire_id, priority, &message, buf)
+            } else {
+                self.encode_frame(wire_id, priority, &message, buf)
+            };
+            self.sent_with(wire_id, encoded)
+        });
+        self.cache_response(id, &encoded);
+        let sent = self.encoded(id, encoded);
+        self.sent_sequence(&sent);
+        sent
+    }
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> Result<Option<Self::In>, io::Error> {
+        self.capture.received(self.connection_id, buf.as_slice());
+        let mut decoded = self.decode_frame(buf)?;
+        loop {
+            let unknown = match decoded {
+                Some((id, _)) if self.request_id(id).is_none() => id,
+                _ => break,
+            };
+            debug!("--> Connection {}: Discarding frame id = {}, which no request in flight was \
+                    sent with.",
+                   self.connection_id, unknown);
+            decoded = self.decode_frame(buf)?;
+        }
+        self.buffered = buf.len();
+        self.capture.consumed(buf.len());
+        // A payload is sealed for the id it was sent with.
+        let mut wire_id = 0;
+        let decoded = decoded.map(|(id, frame)| {
+            wire_id = id;
+            (self.wire_ids.remove(&id).unwrap_or(id), frame)
+        });
+        if let Some(&(id, _)) = decoded.as_ref() {
+            self.header_started = None;
+            // A request answered without being acknowledged never will be.
+            self.awaiting_acks.remove(&id);
+        }
+        let (id, frame) = match decoded {
+            Some((id, Ok(frame))) => (id, frame),
+            Some((id, Err(e))) => {
+                if let DecodeError::PayloadTooLarge { len, max } = e {
+                    if let Some(ref metrics) = self.metrics {
+                        metrics.on_reject(len, max);
+                    }
+                    if !self.skip_too_big {
+                        // The connection is closing, so the payload isn't skipped.
+                        self.state = CodecState::Id;
+                        return Err(too_big_error(Some(id), len, max));
+                    }
+                }
+                self.spans.rejected(id, &e);
+                return Ok(Some((id, Err(e))));
+            }
+            // The frame being read isn't stalled, just held back.
+            None if self.throttled => return Ok(None),
+            None => {
+                self.check_header_wait(buf.len())?;
+                return Ok(None);
+            }
+        };
+        if self.inherit_priorities && frame.priority != 0 {
+            self.priorities.insert(id, frame.priority);
+        }
+        if let Some(deadline) = frame.deadline {
+            if unix_millis(SystemTime::now()) > deadline {
+                debug!("Connection {}: Request id = {} arrived after its deadline, {}.",
+                       self.connection_id, id, deadline);
+                let e = DecodeError::DeadlineExceeded { deadline: deadline };
+                self.spans.rejected(id, &e);
+                return Ok(Some((id, Err(e))));
+            }
+        }
+        let payload_size = frame.payload.len() as u64;
+        self.count_decoded(id, payload_size);
+        let mut payload = frame.payload;
+        if let Some(ref cipher) = self.cipher {
+            payload = match cipher.open(wire_id, payload) {
+                Some(payload) => payload,
+                None => {
+                    warn!("Connection {}: Payload of request id = {} failed authentication.",
+                          self.connection_id,
+                          id);
+                    let e = DecodeError::Unauthenticated;
+                    self.spans.rejected(id, &e);
+                    return Ok(Some((id, Err(e))));
+                }
+            };
+        }
+        let metadata = if frame.flags & FLAG_METADATA != 0 {
+            let metadata = match self.frame.split_metadata(&mut payload) {
+                Ok(metadata) => metadata,
+                Err(e) => return self.malformed(id, e),
+            };
+            trace!("--> Connection {}: Decoded metadata for id = {}: {:?}",
+                   self.connection_id, id, metadata);
+            if let Some(ref hook) = self.metadata_hook {
+                hook(id, &metadata);
+            }
+            self.check_sequence(id, &metadata);
+            metadata
+        } else {
+            Metadata::new()
+        };
+        let screened = match self.request_screen {
+            Some(ref screen) if !self.header_screened => {
+                screen(id, payload_size, &metadata).err()
+            }
+            _ => None,
+        };
+        if let Some(reason) = screened {
+            let e = self.screened_out(id, reason);
+            self.spans.rejected(id, &e);
+            return Ok(Some((id, Err(e))));
+        }
+        if let (Some(cache), Some(key)) = (self.response_cache.as_ref(),
+                                           metadata.get(IDEMPOTENCY_KEY)) {
+            match cache.get(key) {
+                Some(response) => self.cached_response = Some((id, response)),
+                None => {
+                    self.idempotency_keys.insert(id, key.clone());
+                }
+            }
+        }
+        let payload = if frame.flags & FLAG_COMPRESSED != 0 {
+            let compression = match self.frame.decompression {
+                Some(compression) => compression,
+                None => {
+                    let e = io::Error::new(io::ErrorKind::InvalidData,
+                                           "Compressed frame, but decompression is not enabled");
+                    return self.malformed(id, e);
+                }
+            };
+            match compression.decompress(payload.as_slice()) {
+                Ok(payload) => EasyBuf::from(payload),
+                Err(e) => return self.malformed(id, e),
+            }
+        } else {
+            payload
+        };
+        if let Err(e) = self.serializer.check_payload(&payload) {
+            warn!("Connection {}: Refusing the payload of request id = {}: {}",
+                  self.connection_id, id, e);
+            return Err(e);
+        }
+        let message = match self.serializer.deserialize_slice(&payload) {
+            Ok(message) => Ok(message),
+            Err(e) if payload.is_empty() => Err(DecodeError::EmptyPayload(e)),
+            Err(e) => Err(DecodeError::Deserialize(e)),
+        };
+        Ok(Some((id, message)))
+    }
+
+    /// Fails with an error of kind `UnexpectedEof` if the stream ends partway through a frame,
+    /// saying how far into the frame it got.
+    fn decode_eof(&mut self, buf: &mut EasyBuf) -> io::Result<Self::In> {
+        match self.decode(buf)? {
+            Some(frame) => Ok(frame),
+            None => {
+                Err(self.truncated().unwrap_or_else(|| {
+                    io::Error::new(io::ErrorKind::Other, "bytes remaining on stream")
+                }))
+            }
+        }
+    }
+}
+
+impl<Encode, Decode, S> Codec<Encode, Decode, S>
+    where Encode: serde::Serialize,
+          Decode: serde::Deserialize,
+          S: PayloadSerializer
+{
+    /// Returns an iterator over the frames decoded from `bytes`, as if they were everything read
+    /// from a connection. It ends after the last complete frame, with an error of kind
+    /// `UnexpectedEof` first if `bytes` end partway through a frame, or with the error that
+    /// stops `decode`. Meant for tests, which can feed a codec a byte slice and collect what it
+    /// makes of them.
+    pub fn decode_all(&mut self, bytes: &[u8]) -> DecodeAll<Encode, Decode, S> {
+        DecodeAll {
+            codec: self,
+            buf: EasyBuf::from(bytes.to_vec()),
+            done: false,
+        }
+    }
+
+    /// Returns an iterator over the frames decoded from everything `r` reads, like
+    /// `decode_all`, so that a stream captured by `Codec::capture` can be replayed. The codec
+    /// must frame payloads the way the one that captured the stream did, including the options
+    /// its connection negotiated.
+    pub fn replay<R: io::Read>(&mut self, mut r: R) -> io::Result<DecodeAll<Encode, Decode, S>> {
+        let mut bytes = Vec::new();
+        r.read_to_end(&mut bytes)?;
+        Ok(DecodeAll {
+            codec: self,
+            buf: EasyBuf::from(bytes),
+            done: false,
+        })
+    }
+}
+
+/// An iterator over the frames a `Codec` decodes from a byte slice; see `Codec::decode_all`.
+pub struct DecodeAll<'a, Encode: 'a, Decode: 'a, S: 'a> {
+    codec: &'a mut Codec<Encode, Decode, S>,
+    buf: EasyBuf,
+    done: bool,
+}
+
+impl<'a, Encode, Decode, S> Iterator for DecodeAll<'a, Encode, Decode, S>
+    where Encode: serde::Serialize,
+          Decode: serde::Deserialize,
+          S: PayloadSerializer
+{
+    type Item = io::Result<(RequestId, Result<Decode, DecodeError<S::Error>>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        use tokio_core::io::Codec as TokioCodec;
+
+        if self.done {
+            return None;
+        }
+        match self.codec.decode(&mut self.buf) {
+            Ok(Some(frame)) => Some(Ok(frame)),
+            Ok(None) => {
+                self.done = true;
+                self.codec.truncated().map(Err)
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<Encode, Decode, S> Codec<Encode, Decode, S>
+    where Encode: serde::Serialize,
+          S: PayloadSerializer
+{
+    /// Returns the number of bytes `encode` would write for `message`, header and trailer
+    /// included, without serializing it. Callers can check a payload against `max_outbound`, or
+    /// budget a batch of requests, before sending anything.
+    ///
+    /// A payload that would be compressed is counted at its uncompressed size, since only a
+    /// payload that shrinks is sent compressed; its actual frame may be smaller.
+    pub fn encoded_len(&self, message: &Encode) -> u64 {
+        let metadata_len = self.outbound_metadata(message)
+            .map_or(0, |metadata| self.frame.metadata_len(&metadata));
+        let overhead = self.cipher.as_ref().map_or(0, |cipher| cipher.overhead());
+        let payload_size = self.serializer.serialized_size(message) + metadata_len + overhead;
+        payload_size + self.frame.frame_len(payload_size)
+    }
+
+    /// Writes a frame holding `message` to `w` as the payload is serialized, rather than
+    /// building the whole frame in a buffer like `encode`. The size of the payload is computed
+    /// first, so a payload that is too big is rejected before anything is written. A compressed
+    /// payload is still built in memory, since its size isn't known until it is compressed.
+    ///
+    /// If serialization fails partway, part of the frame has been written, and the rest of the
+    /// stream can't be framed: the connection should be closed.
+    pub fn encode_to<W: io::Write>(&mut self,
+                                   (id, message): (RequestId, Encode),
+                                   w: &mut W)
+                                   -> io::Result<()> {
+        self.await_ack(id);
+        let priority = self.priority(id, &message);
+        self.priorities.remove(&id);
+        let encoded = self.wire_id(id).and_then(|wire_id| {
+            let encoded = if self.idempotency_keys.contains_key(&id) {
+                let mut buf = Vec::new();
+                self.encode_cached(wire_id, priority, &message, &mut buf)
+                    .and_then(|payload_size| w.write_all(&buf).map(|()| payload_size))
+            } else {
+                self.stream_frame(wire_id, priority, &message, w)
+            };
+            self.sent_with(wire_id, encoded)
+        });
+        self.cache_response(id, &encoded);
+        let sent = self.encoded(id, encoded);
+        self.sent_sequence(&sent);
+        sent
+    }
+
+    /// Appends a frame with id `id` holding `payload`, which was serialized elsewhere, so that
+    /// a relay can forward a payload it decoded with a `RawCodec` without serializing it again.
+    /// `payload` must already be in this codec's format. It is checked against `max_outbound`
+    /// and compressed and encrypted the way `encode` would do it, but can't carry metadata, and
+    /// its priority is the one inherited from its request, if any.
+    pub fn encode_payload(&mut self,
+                          id: RequestId,
+                          payload: &[u8],
+                          buf: &mut Vec<u8>)
+                          -> io::Result<()> {
+        self.await_ack(id);
+        let priority = if self.frame.priorities {
+            self.priorities.get(&id).cloned().unwrap_or(0)
+        } else {
+            0
+        };
+        self.priorities.remove(&id);
+        let wire_id = match self.wire_id(id) {
+            Ok(wire_id) => wire_id,
+            Err(e) => return self.encoded(id, Err(e)),
+        };
+        let encoded = if self.frame.compression.is_some() || self.cipher.is_some() ||
+                         self.frame.fragments {
+            let compression = self.frame.compression.as_ref();
+            self.encode_serialized(wire_id, priority, payload.to_vec(), compression, None, buf)
+        } else {
+            self.encode_raw(wire_id, priority, payload, buf)
+        };
+        let encoded = self.sent_with(wire_id, encoded);
+        self.encoded(id, encoded)
+    }
+
+    /// Reports the outcome of encoding the frame `id`, whose payload size `encoded` holds.
+    fn encoded(&mut self, id: RequestId, encoded: io::Result<u64>) -> io::Result<()> {
+        match encoded {
+            Ok(payload_size) => {
+                if let Some(ref metrics) = self.metrics {
+                    metrics.on_encode(id, payload_size);
+                }
+                if let Some(ref stats) = self.stats {
+                    stats.stats().on_encode(id, payload_size);
+                }
+                self.spans.close(id, payload_size);
+                Ok(())
+            }
+            Err(e) => {
+                self.unanswered();
+                self.spans.encode_failed(id, &e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Counts a request decoded as no longer in flight in the server's stats, though no
+    /// response to it was sent.
+    fn unanswered(&self) {
+        if let Some(ref stats) = self.stats {
+            stats.unanswered();
+        }
+    }
+
+    /// Caches the payload of the response to request `id` that `encode_cached` kept, if the
+    /// request had an idempotency key and the response was sent.
+    fn cache_response(&mut self, id: RequestId, encoded: &io::Result<u64>) {
+        let payload = self.response_payload.take();
+        let key = match self.idempotency_keys.remove(&id) {
+            Some(key) => key,
+            None => return,
+        };
+        match (self.response_cache.as_ref(), payload) {
+            (Some(cache), Some(payload)) if encoded.is_ok() => cache.insert(key, payload),
+            _ => {}
+        }
+    }
+
+    /// Like `encode_frame`, but for a response that is to be cached: its payload is serialized
+    /// into a buffer of its own, a copy of which is kept for `cache_response` before it is
+    /// compressed or encrypted, so that it isn't serialized twice.
+    fn encode_cached(&mut self,
+                     id: RequestId,
+                     priority: u8,
+                     message: &Encode,
+                     buf: &mut Vec<u8>)
+                     -> io::Result<u64> {
+        let metadata = self.outbound_metadata(message);
+        let mut payload = Vec::new();
+        self.serializer.serialize_into(&mut payload, message)?;
+        self.response_payload = Some(payload.clone());
+        let compression = self.frame.compression.as_ref();
+        self.encode_serialized(id, priority, payload, compression, metadata.as_ref(), buf)
+    }
+
+    /// Like `encode_frame`, but writes the frame to `w` as its payload is serialized.
+    fn stream_frame<W: io::Write>(&mut self,
+                                  id: RequestId,
+                                  priority: u8,
+                                  message: &Encode,
+                                  w: &mut W)
+                                  -> io::Result<u64> {
+        let metadata = self.outbound_metadata(message);
+        if metadata.is_some() || self.cipher.is_some() || self.frame.fragments {
+            let mut buf = Vec::new();
+            let payload_size =
+                self.encode_buffered(id, priority, message, metadata.as_ref(), &mut buf)?;
+            w.write_all(&buf)?;
+            return Ok(payload_size);
+        }
+        let payload_size = self.serializer.serialized_size(message);
+        if let Some(compression) = self.frame.compression {
+            if compression.compresses(payload_size) {
+                let mut buf = Vec::new();
+                let payload_size = self.encode_compressed(id,
+                                                          priority,
+                                                          message,
+                                                          payload_size,
+                                                          &compression,
+                                                          &mut buf)?;
+                w.write_all(&buf)?;
+                return Ok(payload_size);
+            }
+        }
+        if payload_size > self.max_outbound() {
+            return Err(self.too_big(id, payload_size));
+        }
+        let mut header = Vec::new();
+        self.frame.write_header(&mut header, id, 0, priority, self.next_deadline(), payload_size);
+        w.write_all(&header)?;
+        let mut payload = self.frame.payload_writer(w, payload_size);
+        self.serializer.serialize_to(&mut payload, message)?;
+        payload.finish(&self.frame)?;
+        trace!("Connection {}: Streamed frame id = {} with a payload of {} bytes",
+               self.connection_id, id, payload_size);
+        Ok(payload_size)
+    }
+
+    /// Appends a frame holding `message` to `buf`, returning the size of its payload.
+    fn encode_frame(&mut self,
+                    id: RequestId,
+                    priority: u8,
+                    message: &Encode,
+                    buf: &mut Vec<u8>)
+                    -> io::Result<u64> {
+        let metadata = self.outbound_metadata(message);
+        if metadata.is_some() || self.cipher.is_some() || self.frame.fragments {
+            return self.encode_buffered(id, priority, message, metadata.as_ref(), buf);
+        }
+        // Nothing is too big for an unbounded max payload size, so its size isn't worth computing.
+        let single_pass = self.single_pass || self.max_outbound() == u64::MAX;
+        if single_pass && self.frame.len_width != LenWidth::Varint {
+            return self.encode_single_pass(id, priority, message, buf);
+        }
+        let payload_size = self.serializer.serialized_size(message);
+        if let Some(compression) = self.frame.compression {
+            if compression.compresses(payload_size) {
+                return self.encode_compressed(id,
+                                              priority,
+                                              message,
+                                              payload_size,
+                                              &compression,
+                                              buf);
+            }
+        }
+        if payload_size > self.max_outbound() {
+            return Err(self.too_big(id, payload_size));
+        }
+        // `buf` may already hold frames that haven't been flushed yet, so nothing may be left
+        // behind when this frame fails to encode.
+        let frame_start = buf.len();
+        self.frame.write_header(buf, id, 0, priority, self.next_deadline(), payload_size);
+        let payload_start = buf.len();
+        if let Err(e) = self.serializer.serialize_into(buf, message) {
+            buf.truncate(frame_start);
+            return Err(e);
+        }
+        self.frame.write_trailer(buf, payload_start);
+        trace!("Connection {}: Encoded buffer: {:?}", self.connection_id, buf);
+        Ok(payload_size)
+    }
+
+    /// Like `encode_frame`, but serializes `message` before checking its size, patching the
+    /// length into the header afterwards.
+    fn encode_single_pass(&mut self,
+                          id: RequestId,
+                          priority: u8,
+                          message: &Encode,
+                          buf: &mut Vec<u8>)
+                          -> io::Result<u64> {
+        if let Some(compression) = self.frame.compression {
+            let mut payload = Vec::with_capacity(self.size_estimate as usize);
+            self.serializer.serialize_into(&mut payload, message)?;
+            self.size_estimate = payload.len() as u64;
+            return self.encode_serialized(id, priority, payload, Some(&compression), None, buf);
+        }
+        let frame_start = buf.len();
+        buf.reserve(self.size_estimate as usize);
+        self.frame.write_header(buf, id, 0, priority, self.next_deadline(), 0);
+        let payload_start = buf.len();
+        if let Err(e) = self.serializer.serialize_into(buf, message) {
+            buf.truncate(frame_start);
+            return Err(e);
+        }
+        let payload_size = (buf.len() - payload_start) as u64;
+        self.size_estimate = payload_size;
+        if payload_size > self.max_outbound() {
+            buf.truncate(frame_start);
+            return Err(self.too_big(id, payload_size));
+        }
+        self.frame.patch_len(buf, payload_start, payload_size);
+        self.frame.write_trailer(buf, payload_start);
+        trace!("Connection {}: Encoded buffer: {:?}", self.connection_id, buf);
+        Ok(payload_size)
+    }
+
+    fn encode_compressed(&self,
+                         id: RequestId,
+                         priority: u8,
+                         message: &Encode,
+                         payload_size: u64,
+                         compression: &CompressionOptions,
+                         buf: &mut Vec<u8>)
+                         -> io::Result<u64> {
+        let mut payload = Vec::with_capacity(payload_size as usize);
+        self.serializer.serialize_into(&mut payload, message)?;
+        self.encode_serialized(id, priority, payload, Some(compression), None, buf)
+    }
+
+    /// Appends a frame holding `message`, serialized into a buffer of its own so that the
+    /// payload can be started by a block of `metadata` or encrypted.
+    fn encode_buffered(&self,
+                       id: RequestId,
+                       priority: u8,
+                       message: &Encode,
+                       metadata: Option<&Metadata>,
+                       buf: &mut Vec<u8>)
+                       -> io::Result<u64> {
+        let mut payload = Vec::new();
+        self.serializer.serialize_into(&mut payload, message)?;
+        let compression = self.frame.compression.as_ref();
+        self.encode_serialized(id, priority, payload, compression, metadata, buf)
+    }
+
+    /// Appends frames holding `payload`, which is too big for one, in fragments that fit,
+    /// returning its size. `flags` are those of the whole payload.
+    fn encode_fragments(&self,
+                        id: RequestId,
+                        flags: u8,
+                        priority: u8,
+                        payload: &[u8],
+                        buf: &mut Vec<u8>)
+                        -> io::Result<u64> {
+        let payload_size = payload.len() as u64;
+        if payload_size > self.max_reassembled {
+            warn!("Connection {}: Not sending too-big packet of size {} for request id = {} \
+                   (max is {} in fragments)",
+                  self.connection_id,
+                  payload_size,
+                  id,
+                  self.max_reassembled);
+            if let Some(ref metrics) = self.metrics {
+                metrics.on_reject(payload_size, self.max_reassembled);
+            }
+            return Err(too_big_error(Some(id), payload_size, self.max_reassembled));
+        }
+        if self.max_outbound() <= FRAGMENT_HEADER_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("The max payload size of {} bytes leaves no room \
+                                               for fragments",
+                                              self.max_outbound())));
+        }
+        let fragment_size = cmp::min(self.max_outbound() - FRAGMENT_HEADER_LEN,
+                                     usize::MAX as u64) as usize;
+        let total = (payload.len() + fragment_size - 1) / fragment_size;
+        if total as u64 > u32::MAX as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("A payload of {} bytes takes more than {} \
+                                               fragments",
+                                              payload_size,
+                                              u32::MAX)));
+        }
+        let deadline = self.next_deadline();
+        for (index, fragment) in payload.chunks(fragment_size).enumerate() {
+            let len = FRAGMENT_HEADER_LEN + fragment.len() as u64;
+            self.frame.write_header(buf, id, flags | FLAG_FRAGMENT, priority, deadline, len);
+            let payload_start = buf.len();
+            self.frame.write_fragment_header(buf, index as u32, total as u32);
+            buf.extend_from_slice(fragment);
+            self.frame.write_trailer(buf, payload_start);
+        }
+        trace!("Connection {}: Encoded a payload of {} bytes in {} fragments",
+               self.connection_id, payload_size, total);
+        Ok(payload_size)
+    }
+
+    /// Appends a frame holding `payload` as is, returning its size.
+    fn encode_raw(&self,
+                  id: RequestId,
+                  priority: u8,
+                  payload: &[u8],
+                  buf: &mut Vec<u8>)
+                  -> io::Result<u64> {
+        let payload_size = payload.len() as u64;
+        if payload_size > self.max_outbound() {
+            return Err(self.too_big(id, payload_size));
+        }
+        self.frame.write_header(buf, id, 0, priority, self.next_deadline(), payload_size);
+        let payload_start = buf.len();
+        buf.extend_from_slice(payload);
+        self.frame.write_trailer(buf, payload_start);
+        trace!("Connection {}: Encoded buffer: {:?}", self.connection_id, buf);
+        Ok(payload_size)
+    }
+
+    /// Appends a frame holding `payload`, compressing it first if that's worthwhile, starting
+    /// it with a block of `metadata` if set, and then encrypting it if the codec encrypts.
+    fn encode_serialized(&self,
+                         id: RequestId,
+                         priority: u8,
+                         payload: Vec<u8>,
+                         compression: Option<&CompressionOptions>,
+                         metadata: Option<&Metadata>,
+                         buf: &mut Vec<u8>)
+                         -> io::Result<u64> {
+        let (flags, payload) = match compression {
+            Some(compression) if compression.compresses(payload.len() as u64) => {
+                let compressed = compression.compress(&payload)?;
+                trace!("Connection {}: Compressed payload of {} bytes to {}",
+                       self.connection_id, payload.len(), compressed.len());
+                // Not every payload shrinks; there's no point making the peer inflate those.
+                if compressed.len() < payload.len() {
+                    (FLAG_COMPRESSED, compressed)
+                } else {
+                    (0, payload)
+                }
+            }
+            _ => (0, payload),
+        };
+        let (flags, payload) = match metadata {
+            Some(metadata) => {
+                let mut block = Vec::with_capacity(self.frame.metadata_len(metadata) as usize +
+                                                   payload.len());
+                self.frame.write_metadata(&mut block, metadata)?;
+                block.extend_from_slice(&payload);
+                (flags | FLAG_METADATA, block)
+            }
+            None => (flags, payload),
+        };
+        let payload = match self.cipher {
+            Some(ref cipher) => cipher.seal(id, &payload)?,
+            None => payload,
+        };
+        let payload_size = payload.len() as u64;
+        if payload_size > self.max_outbound() {
+            if self.frame.fragments {
+                return self.encode_fragments(id, flags, priority, &payload, buf);
+            }
+            return Err(self.too_big(id, payload_size));
+        }
+        self.frame.write_header(buf, id, flags, priority, self.next_deadline(), payload_size);
+        let payload_start = buf.len();
+        buf.extend_from_slice(&payload);
+        self.frame.write_trailer(buf, payload_start);
+        trace!("Connection {}: Encoded buffer: {:?}", self.connection_id, buf);
+        Ok(payload_size)
+    }
+}
+
+/// The number of connections a `Proto` has created a `Codec` for.
+static CONNECTIONS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Returns the id of a new connection.
+fn next_connection_id() -> u64 {
+    CONNECTIONS.fetch_add(1, Ordering::Relaxed) as u64 + 1
+}
+
+/// The most a `Proto` reserves for each connection's read and write buffers by default.
+const MAX_DEFAULT_BUFFER_CAPACITY: u64 = 64 * 1024;
+
+/// By default, a connection holds at most this many max-size payloads' worth of bytes read from
+/// it; see `Proto::max_buffered_bytes`.
+const DEFAULT_BUFFERED_PAYLOADS: u64 = 4;
+
+/// Room for the headers and trailers of the frames a connection holds by default.
+const DEFAULT_BUFFERED_FRAMING: u64 = 1024;
+
+/// How many seconds a client waits for each response by default; see
+/// `Proto::response_timeout`.
+const DEFAULT_RESPONSE_TIMEOUT_SECS: u64 = 30;
+
+/// Implements the `multiplex::ServerProto` and `multiplex::ClientProto` traits using a `Codec`
+/// that serializes payloads with `S`.
+pub struct Proto<Encode, Decode, S = BincodeSerializer> {
+    max_outbound: u64,
+    max_inbound: u64,
+    payload_limits: Option<PayloadLimits>,
+    max_reassembled: u64,
+    max_reassembly_time: Option<Duration>,
+    skip_too_big: bool,
+    isolate_payload_errors: bool,
+    single_pass: bool,
+    write_through: bool,
+    request_timeout: Option<Duration>,
+    max_header_wait: Option<Duration>,
+    frame: FrameOptions,
+    /// How payloads compressed with each algorithm this side decompresses are decompressed.
+    decompression: Vec<CompressionOptions>,
+    handshake: HandshakeOptions,
+    max_in_flight: Option<usize>,
+    high_water_mark: Option<usize>,
+    max_frames_per_poll: Option<usize>,
+    max_backlog: Option<usize>,
+    max_buffered: Option<usize>,
+    read_whole_payloads: Option<usize>,
+    buffer_capacity: Option<usize>,
+    priority: Option<Prioritizer<Encode>>,
+    metadata: Option<MetadataSource<Encode>>,
+    metadata_hook: Option<MetadataHook>,
+    request_screen: Option<RequestScreen>,
+    encryption: Option<Arc<Cipher>>,
+    persist: Option<Persister<Decode>>,
+    response_cache: Option<ResponseCache>,
+    ack_slots: Option<AckSlots>,
+    pings: Option<Pings>,
+    stats_queries: Option<StatsQueries>,
+    diagnostics: Option<(ServerStats, DiagnosticsAuthorizer)>,
+    request_ids: Option<IdAllocator>,
+    sequence_numbers: bool,
+    rate_limit: Option<RateLimitOptions>,
+    frame_rate: Option<RateLimitOptions>,
+    heartbeat: Option<HeartbeatOptions>,
+    idle_timeouts: Option<IdleTimeoutOptions>,
+    reap_idle: Option<IdleReaperOptions>,
+    flush_delay: Option<FlushDelayOptions>,
+    response_timeout: Option<ResponseTimeoutOptions>,
+    default_response_timeout: Option<Duration>,
+    metrics: Option<Arc<CodecMetrics>>,
+    capture: Option<CaptureSinks>,
+    drain: Option<Drain>,
+    serializer: S,
+    _phantom_data: PhantomData<(Encode, Decode)>,
+}
+
+/// A `Proto` that serializes payloads as MessagePack.
+#[cfg(feature = "msgpack")]
+pub type MsgPackProto<Encode, Decode> = Proto<Encode, Decode, MsgPackSerializer>;
+
+/// A `Proto` that serializes payloads as CBOR.
+#[cfg(feature = "cbor")]
+pub type CborProto<Encode, Decode> = Proto<Encode, Decode, CborSerializer>;
+
+/// A `Proto` whose connections each negotiate their payload `Format` in the handshake.
+pub type NegotiatedProto<Encode, Decode> = Proto<Encode, Decode, Format>;
+
+impl<Encode, Decode, S> Proto<Encode, Decode, S>
+    where S: PayloadSerializer + Default
+{
+    /// Returns a new `Proto`.
+    pub fn new(max_payload_size: u64) -> Self {
+        Proto::with_serializer(max_payload_size, S::default())
+    }
+
+    /// Returns a `ProtoBuilder`, which checks that its options are consistent.
+    pub fn builder() -> ProtoBuilder<Encode, Decode, S> {
+        ProtoBuilder::new()
+    }
+
+    /// Returns a new `Proto` whose transports refuse to send payloads larger than
+    /// `max_outbound` bytes and reject received payloads larger than `max_inbound` bytes.
+    pub fn with_limits(max_outbound: u64, max_inbound: u64) -> Self {
+        let mut proto = Proto::with_serializer(max_outbound, S::default());
+        proto.max_inbound = max_inbound;
+        proto
+    }
+}
+
+impl<Encode, Decode, S> Proto<Encode, Decode, S>
+    where S: PayloadSerializer
+{
+    /// Returns a new `Proto` whose transports serialize payloads with `serializer`.
+    pub fn with_serializer(max_payload_size: u64, serializer: S) -> Self {
+        Proto {
+            max_outbound: max_payload_size,
+            max_inbound: max_payload_size,
+            payload_limits: None,
+            max_reassembled: 0,
+            max_reassembly_time: None,
+            skip_too_big: false,
+            isolate_payload_errors: false,
+            single_pass: false,
+            write_through: false,
+            request_timeout: None,
+            max_header_wait: None,
+            frame: FrameOptions::default(),
+            decompression: vec![],
+            handshake: HandshakeOptions::default(),
+            max_in_flight: None,
+            high_water_mark: None,
+            max_frames_per_poll: None,
+            max_backlog: None,
+            max_buffered: None,
+            read_whole_payloads: None,
+            buffer_capacity: None,
+            priority: None,
+            metadata: None,
+            metadata_hook: None,
+            request_screen: None,
+            encryption: None,
+            persist: None,
+            response_cache: None,
+            ack_slots: None,
+            pings: None,
+            stats_queries: None,
+            diagnostics: None,
+            request_ids: None,
+            sequence_numbers: false,
+            rate_limit: None,
+            frame_rate: None,
+            heartbeat: None,
+            idle_timeouts: None,
+            reap_idle: None,
+            flush_delay: None,
+            response_timeout: None,
+            default_response_timeout: Some(Duration::from_secs(DEFAULT_RESPONSE_TIMEOUT_SECS)),
+            metrics: None,
+            capture: None,
+            drain: None,
+            serializer: serializer,
+            _phantom_data: PhantomData,
+        }
+    }
+
+    /// Set the range of frame format versions this side supports. When a connection is
+    /// established, the client and server agree on the newest version they both support; if
+    /// there is none, the connection fails. The default is `MIN_PROTOCOL_VERSION` through
+    /// `PROTOCOL_VERSION`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `min_version > max_version`.
+    pub fn supported_versions(mut self, min_version: u32, max_version: u32) -> Self {
+        assert!(min_version <= max_version,
+                "min_version {} is greater than max_version {}",
+                min_version,
+                max_version);
+        self.handshake.min_version = min_version;
+        self.handshake.max_version = max_version;
+        self
+    }
+
+    /// Set the identifier of the requests and responses sent on connections, such as a hash of
+    /// the service definition. The handshake rejects connections whose client and server have
+    /// different schemas, rather than letting requests deserialize into nonsense. The default
+    /// is 0.
+    pub fn schema(mut self, schema: u64) -> Self {
+        self.handshake.schema = schema;
+        self
+    }
+
+    /// Stop reading requests from a connection while `requests` of its requests are awaiting a
+    /// response, so that a single client can't make the server buffer unboundedly many. Reading
+    /// resumes as responses are sent. Only applies to servers; by default there is no limit.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `requests` is 0.
+    pub fn max_in_flight(mut self, requests: usize) -> Self {
+        assert!(requests > 0, "max_in_flight must be at least 1");
+        self.max_in_flight = Some(requests);
+        self
+    }
+
+    /// Stop accepting messages to send on a connection while more than `bytes` encoded bytes are
+    /// waiting to be written to it, so that a peer that reads slowly applies backpressure to the
+    /// service instead of making this side buffer unboundedly many. The default is 8 KiB.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` is 0.
+    pub fn high_water_mark(mut self, bytes: usize) -> Self {
+        assert!(bytes > 0, "The high-water mark must be at least 1 byte");
+        self.high_water_mark = Some(bytes);
+        self
+    }
+
+    /// Read at most `frames` frames from a connection before letting the reactor run the other
+    /// connections, so that one peer sending a large batch at once doesn't delay the requests of
+    /// the others. The rest of the batch is read on the connection's next turn. By default a
+    /// connection reads until it runs out of frames.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `frames` is 0.
+    pub fn max_frames_per_poll(mut self, frames: usize) -> Self {
+        assert!(frames > 0, "max_frames_per_poll must be at least 1");
+        self.max_frames_per_poll = Some(frames);
+        self
+    }
+
+    /// Stop reading from a connection once its read buffer holds `frames` complete frames not
+    /// yet passed on, leaving the rest of a burst in the socket until they are. Reads are then
+    /// made in chunks of 4 KiB, and the frames buffered after each are counted by parsing
+    /// their headers, and reported to `CodecMetrics::on_backlog`. This is backpressure between
+    /// parsing frames and dispatching them: without it, a single read can buffer as many frames
+    /// as the socket holds. By default the backlog isn't counted or limited.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `frames` is 0.
+    pub fn max_backlog(mut self, frames: usize) -> Self {
+        assert!(frames > 0, "max_backlog must be at least 1");
+        self.max_backlog = Some(frames);
+        self
+    }
+
+    /// Hold at most `bytes` bytes read from each connection: those not yet decoded, plus, on a
+    /// server, the payloads of the requests awaiting a response. A server stops reading once it
+    /// holds that many, until it responds to a request. The max payload size bounds a single
+    /// frame, but not the memory a client can tie up with many large requests at once. By
+    /// default, a connection holds at most four payloads of the max size it accepts, and
+    /// connections that accept payloads of any size hold any number of bytes.
+    pub fn max_buffered_bytes(mut self, bytes: usize) -> Self {
+        self.max_buffered = Some(bytes);
+        self
+    }
+
+    /// Once a connection has read the header of a frame, make room in its read buffer for the
+    /// rest of the payload, up to `bytes` of it, so that a large payload is read with a few big
+    /// reads rather than many small ones as the buffer grows. This saves system calls on
+    /// connections carrying large payloads, at the cost of allocating the room before the
+    /// payload arrives; `bytes` bounds what a peer that claims a large payload and never sends
+    /// it can make a connection allocate. It's best-effort: it only sizes the reads, and the
+    /// payload is read as it arrives either way. By default, the read buffer grows as bytes are
+    /// read.
+    pub fn read_whole_payloads(mut self, bytes: usize) -> Self {
+        self.read_whole_payloads = Some(bytes);
+        self
+    }
+
+    /// Send every request with the priority `priority` returns for it, on connections that
+    /// negotiate `PRIORITY_VERSION` or newer. A server sends the responses to higher-priority
+    /// requests ahead of the others waiting to be written, so that a connection busy with bulk
+    /// requests stays responsive to interactive ones. By default, every request has priority 0,
+    /// and responses are sent in the order they are ready. Only applies to clients.
+    pub fn priority<F>(mut self, priority: F) -> Self
+        where F: Fn(&Encode) -> u8 + Send + Sync + 'static
+    {
+        self.priority = Some(Arc::new(priority));
+        self
+    }
+
+    /// Send each request with the id `allocate` returns, rather than the one tokio-proto gives
+    /// it; see `Codec::request_ids`. Every connection calls the same `allocate`. Meant for
+    /// tests that check the ids on the wire. Only applies to clients.
+    pub fn request_ids<F>(mut self, allocate: F) -> Self
+        where F: Fn() -> RequestId + Send + Sync + 'static
+    {
+        self.request_ids = Some(Arc::new(allocate));
+        self
+    }
+
+    /// Send every request with its sequence number on the connection, so that servers can
+    /// check that requests arrive in the order they were sent; see `Codec::sequence_numbers`.
+    /// Servers check the sequence numbers they receive whether or not they set this. Meant for
+    /// debugging: the numbers are only sent on connections that negotiate `METADATA_VERSION`,
+    /// cost a few bytes of metadata per request, and never change how requests are dispatched.
+    /// Only applies to clients.
+    pub fn sequence_numbers(mut self, sequence_numbers: bool) -> Self {
+        self.sequence_numbers = sequence_numbers;
+        self
+    }
+
+    /// Send every message with the metadata `metadata` returns for it, on connections that
+    /// negotiate `METADATA_VERSION` or newer. Metadata travels in the frame rather than the
+    /// message, so it suits cross-cutting values like trace ids that the service's types
+    /// shouldn't have to carry. Messages for which it returns an empty map are sent without
+    /// metadata.
+    pub fn metadata<F>(mut self, metadata: F) -> Self
+        where F: Fn(&Encode) -> Metadata + Send + Sync + 'static
+    {
+        self.metadata = Some(Arc::new(metadata));
+        self
+    }
+
+    /// Call `hook` with the id and metadata of every frame received that carries metadata,
+    /// before its payload is deserialized.
+    pub fn on_metadata<F>(mut self, hook: F) -> Self
+        where F: Fn(RequestId, &Metadata) + Send + Sync + 'static
+    {
+        self.metadata_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Call `screen` with the id, payload length, and metadata of every request a server
+    /// receives, before its payload is deserialized, rejecting the requests it returns a reason
+    /// for without passing them on to the service; see `Codec::screen_requests`. Lets a gateway
+    /// turn requests away by their metadata without paying to deserialize them.
+    pub fn screen_requests<F>(mut self, screen: F) -> Self
+        where F: Fn(RequestId, u64, &Metadata) -> Result<(), String> + Send + Sync + 'static
+    {
+        self.request_screen = Some(Arc::new(screen));
+        self
+    }
+
+    /// Call `persist` with every request received, and once the future it returns resolves,
+    /// acknowledge the request to the client, on connections that negotiate `ACK_VERSION` or
+    /// newer. The acknowledgement is separate from the response, so a client that sees it knows
+    /// the request was accepted, and needn't retry it after reconnecting, even if the response
+    /// never arrives. A request whose future fails isn't acknowledged. Only applies to servers;
+    /// clients wait for acknowledgements with `Client::call_acked`.
+    pub fn acknowledge<F>(mut self, persist: F) -> Self
+        where F: Fn(&Decode) -> Box<Future<Item = (), Error = io::Error>> + Send + Sync + 'static
+    {
+        self.persist = Some(Arc::new(persist));
+        self
+    }
+
+    /// Answer a request retried with the idempotency key of a request already answered with
+    /// the response cached in `cache`, rather than handling it again; see `ResponseCache`.
+    /// Only applies to servers, on connections that negotiate `METADATA_VERSION` or newer.
+    pub fn response_cache(mut self, cache: ResponseCache) -> Self {
+        self.response_cache = Some(cache);
+        self
+    }
+
+    /// Encrypt the payloads of every connection with `key`; see `Codec::encryption`. This keeps
+    /// payloads confidential over transports without TLS, such as a shared message bus. The key
+    /// isn't negotiated in the handshake, so both sides must be configured with it.
+    #[cfg(feature = "encryption")]
+    pub fn encryption(mut self, key: Arc<EncryptionKey>) -> Self {
+        self.encryption = Some(key);
+        self
+    }
+
+    /// Reserve `bytes` for each connection's read and write buffers when it is established,
+    /// rather than growing them as payloads arrive. By default, each buffer reserves room for a
+    /// payload of the negotiated max size in that direction, up to 64 KiB.
+    pub fn buffer_capacity(mut self, bytes: usize) -> Self {
+        self.buffer_capacity = Some(bytes);
+        self
+    }
+
+    /// Read at most `per_second` requests per second from each connection on average, and at
+    /// most `burst` at once. A client that sends faster has its requests left unread until the
+    /// limit allows them, so it is slowed down rather than failed; responses are sent as usual.
+    /// The timers run on the reactor of `handle`, which must be the one the server is bound on.
+    /// Only applies to servers; by default there is no limit.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `per_second` or `burst` is 0.
+    pub fn rate_limit(mut self, handle: &reactor::Handle, per_second: u32, burst: u32) -> Self {
+        assert!(per_second > 0 && burst > 0,
+                "The rate limit and burst must be at least 1");
+        self.rate_limit = Some(RateLimitOptions {
+            remote: handle.remote().clone(),
+            per_second: per_second,
+            burst: burst,
+        });
+        self
+    }
+
+    /// Decode at most `per_second` frames per second from each connection on average, and at
+    /// most `burst` at once, counting every frame, heartbeats included, before its payload is
+    /// deserialized. A peer that sends a storm of small frames has them left unread until the
+    /// limit allows them, rather than keeping the deserializer busy. Unlike `rate_limit`, this
+    /// applies to clients as well as servers. The timers run on the reactor of `handle`, which
+    /// must be the one the connections are bound on. By default there is no limit.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `per_second` or `burst` is 0.
+    pub fn max_frames_per_second(mut self,
+                                 handle: &reactor::Handle,
+                                 per_second: u32,
+                                 burst: u32)
+                                 -> Self {
+        assert!(per_second > 0 && burst > 0,
+                "The frame rate and burst must be at least 1");
+        self.frame_rate = Some(RateLimitOptions {
+            remote: handle.remote().clone(),
+            per_second: per_second,
+            burst: burst,
+        });
+        self
+    }
+
+    /// Send a heartbeat every `interval`, closing the connection if the server doesn't echo it
+    /// within `timeout`. This detects dead connections even when they're idle. Heartbeats run
+    /// on the reactor of `handle`, which must be the one the client is bound on. Only applies to
+    /// clients; servers always echo heartbeats.
+    pub fn heartbeat(mut self,
+                     handle: &reactor::Handle,
+                     interval: Duration,
+                     timeout: Duration)
+                     -> Self {
+        self.heartbeat = Some(HeartbeatOptions {
+            remote: handle.remote().clone(),
+            interval: interval,
+            timeout: timeout,
+        });
+        self
+    }
+
+    /// Close connections gracefully once `drain` starts: each stops reading requests, sends the
+    /// responses to the requests it already read, and then closes. Only applies to servers.
+    pub fn drain(mut self, drain: Drain) -> Self {
+        self.drain = Some(drain);
+        self
+    }
+
+    /// Close a connection with an error of kind `TimedOut` if a frame it started to receive
+    /// makes no progress within `read`, or if the peer accepts none of the frames being sent
+    /// within `write`. Unlike heartbeats, this doesn't close connections that are merely idle. The
+    /// timers run on the reactor of `handle`, which must be the one the transports are bound on.
+    pub fn idle_timeouts(mut self,
+                         handle: &reactor::Handle,
+                         read: Duration,
+                         write: Duration)
+                         -> Self {
+        self.idle_timeouts = Some(IdleTimeoutOptions {
+            remote: handle.remote().clone(),
+            read: read,
+            write: write,
+        });
+        self
+    }
+
+    /// Close a connection once it has gone `timeout` without a frame being sent or received,
+    /// such as one whose client went away without closing it, and with no requests in flight.
+    /// Heartbeats count as frames, so clients that send them are never reaped. Each connection
+    /// closed is reported to `CodecMetrics::on_reap`. The timers run on the reactor of `handle`,
+    /// which must be the one the server is bound on. Only applies to servers.
+    pub fn reap_idle(mut self, handle: &reactor::Handle, timeout: Duration) -> Self {
+        self.reap_idle = Some(IdleReaperOptions {
+            remote: handle.remote().clone(),
+            timeout: timeout,
+        });
+        self
+    }
+
+    /// Hold the frames sent on a connection back for up to `delay` before writing them, unless
+    /// `threshold` bytes or more are buffered, so that small frames sent in quick succession go
+    /// out in fewer writes. A delay of around 100 microseconds trades that much latency for
+    /// fewer syscalls. `threshold` should be at most the high-water mark, or sending stalls until
+    /// the delay is up. The timers run on the reactor of `handle`, which must be the one the
+    /// connection is bound on. By default, frames are written as soon as they are sent.
+    pub fn flush_delay(mut self,
+                       handle: &reactor::Handle,
+                       delay: Duration,
+                       threshold: usize)
+                       -> Self {
+        self.flush_delay = Some(FlushDelayOptions {
+            remote: handle.remote().clone(),
+            delay: delay,
+            threshold: threshold,
+        });
+        self
+    }
+
+    /// Stop waiting for the response to a request after `timeout`, failing the request with
+    /// `DecodeError::TimedOut` and discarding its response if it arrives later. The timers run on
+    /// the reactor of `handle`, which must be the one the client is bound on. Only applies to
+    /// clients. By default they wait 30 seconds, once bound by a `Client` or a `Proto` that
+    /// `default_timeouts` was called on; `no_response_timeout` makes them wait forever.
+    pub fn response_timeout(mut self, handle: &reactor::Handle, timeout: Duration) -> Self {
+        self.response_timeout = Some(ResponseTimeoutOptions {
+            remote: handle.remote().clone(),
+            timeout: timeout,
+        });
+        self
+    }
+
+    /// Wait forever for the responses to requests, instead of the default 30 seconds.
+    pub fn no_response_timeout(mut self) -> Self {
+        self.response_timeout = None;
+        self.default_response_timeout = None;
+        self
+    }
+
+    /// Run the default response timeout on the reactor of `handle`, unless `response_timeout`
+    /// or `no_response_timeout` was called. The timers need a reactor, which binding a client
+    /// with `BindClient::bind_client` doesn't give a `Proto`, so a client bound that way waits
+    /// forever unless `default_timeouts` is called with the handle it's bound on first;
+    /// `Client::new` calls it.
+    pub fn default_timeouts(mut self, handle: &reactor::Handle) -> Self {
+        if let (None, Some(timeout)) = (self.response_timeout.as_ref(),
+                                         self.default_response_timeout) {
+            self = self.response_timeout(handle, timeout);
+        }
+        self
+    }
+
+    /// Call `hook` with the parameters negotiated for every new connection, once the handshake
+    /// succeeds: the version, format, compression, schema, and the user the client
+    /// authenticated as. It runs once per connection, so it suits logging and metering what
+    /// clients connect with, such as the versions they speak during a rollout. If `hook`
+    /// returns an error, the connection is closed; a server tells the client why before
+    /// closing.
+    pub fn on_handshake<F>(mut self, hook: F) -> Self
+        where F: Fn(&Handshake) -> io::Result<()> + Send + Sync + 'static
+    {
+        self.handshake.hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Send `credentials` to the server during the handshake. Only applies to clients.
+    ///
+    /// The password is sent in the clear, so only send credentials over an encrypted transport:
+    /// bind the `Proto` to a TLS stream rather than a plain TCP one.
+    pub fn credentials(mut self, credentials: Credentials) -> Self {
+        self.handshake.credentials = Some(credentials);
+        self
+    }
+
+    /// Require clients to send credentials, checked by `authenticator` during the handshake
+    /// before any of their requests are read. A client without credentials, or whose
+    /// credentials `authenticator` rejects, fails to connect with an error of kind
+    /// `PermissionDenied`. Only applies to servers.
+    pub fn authenticate<F>(mut self, authenticator: F) -> Self
+        where F: Fn(&Credentials) -> io::Result<()> + Send + Sync + 'static
+    {
+        self.handshake.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Give every request a deadline `timeout` after it is sent, so that a server that receives
+    /// it too late fails it with `DecodeError::DeadlineExceeded` rather than running it. Only
+    /// applies to clients, on connections that negotiate `DEADLINE_VERSION` or newer.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Close connections whose frame headers take longer than `wait` to arrive; see
+    /// `Codec::max_header_wait`.
+    pub fn max_header_wait(mut self, wait: Duration) -> Self {
+        self.max_header_wait = Some(wait);
+        self
+    }
+
+    /// Set whether a received payload larger than the max payload size fails only its own
+    /// request, rather than the whole connection. The default is false.
+    pub fn skip_too_big(mut self, skip: bool) -> Self {
+        self.skip_too_big = skip;
+        self
+    }
+
+    /// Set whether a received payload with malformed metadata or compression fails only its
+    /// own request, rather than the whole connection; see `Codec::isolate_payload_errors`. The
+    /// default is false.
+    pub fn isolate_payload_errors(mut self, isolate: bool) -> Self {
+        self.isolate_payload_errors = isolate;
+        self
+    }
+
+    /// Set whether payloads are serialized in a single pass; see `Codec::single_pass`.
+    pub fn single_pass(mut self, single_pass: bool) -> Self {
+        self.single_pass = single_pass;
+        self
+    }
+
+    /// Set whether frames are written to the socket as their payloads are serialized; see
+    /// `Codec::write_through`.
+    pub fn write_through(mut self, write_through: bool) -> Self {
+        self.write_through = write_through;
+        self
+    }
+
+    /// Report every frame that the transports encode, decode, or reject for its size to
+    /// `metrics`, which is shared by all connections.
+    pub fn metrics(mut self, metrics: Arc<CodecMetrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Count the connections, requests, and bytes of every connection in `stats`, and answer
+    /// the stats queries of the clients that `authorize` allows, by the user the client
+    /// authenticated as, if any; see `Client::server_stats`. Queries are control frames,
+    /// answered by the transport without reaching the service, on connections that negotiate
+    /// `DIAGNOSTICS_VERSION` or newer, so operators can ask a live server how busy it is
+    /// without a separate admin port. Clients that aren't allowed, and the clients of servers
+    /// without diagnostics, are told so instead. Only applies to servers.
+    pub fn diagnostics<F>(mut self, stats: ServerStats, authorize: F) -> Self
+        where F: Fn(Option<&str>) -> bool + Send + Sync + 'static
+    {
+        self.diagnostics = Some((stats, Arc::new(authorize)));
+        self
+    }
+
+    /// Compress the payloads this side sends according to `options`, on connections whose peer
+    /// can decompress them; on the others, they are sent uncompressed. This side also
+    /// decompresses received payloads compressed with the same algorithm, as with
+    /// `accept_compression`. The handshake tells each side which algorithms the other
+    /// decompresses, so neither ever sends a payload the other can't read.
+    pub fn compression(self, options: CompressionOptions) -> Self {
+        let mut proto = self.accept_compression(options);
+        proto.frame.compression = Some(options);
+        proto.handshake.compression = Some(options.compression());
+        proto
+    }
+
+    /// Decompress received payloads compressed with `options.compression()`, inflating each to
+    /// at most `options.max_decompressed_size`, whether this side compresses the payloads it
+    /// sends or not. Each frame records whether its own payload is compressed, so the two
+    /// directions of a connection are independent: a client that sends its requests
+    /// uncompressed, to save CPU, can accept the large responses a server compresses. Can be
+    /// called once for each algorithm; the threshold of `options` isn't used.
+    pub fn accept_compression(mut self, options: CompressionOptions) -> Self {
+        self.decompression.retain(|accepted| accepted.compression() != options.compression());
+        self.decompression.push(options);
+        if !self.handshake.decompresses.contains(&options.compression()) {
+            self.handshake.decompresses.push(options.compression());
+        }
+        self
+    }
+
+    /// Set whether payloads are followed by their CRC32. Both the client and the server must use
+    /// the same setting.
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.frame.checksum = checksum;
+        self
+    }
+
+    /// Follow only payloads of at least `bytes` bytes with their CRC32; see
+    /// `Codec::checksum_threshold`.
+    pub fn checksum_threshold(mut self, bytes: u64) -> Self {
+        self.frame.checksum_threshold = Some(bytes);
+        self
+    }
+
+    /// Set whether every frame starts with a 4-byte marker; see `Codec::frame_markers`. Both the
+    /// client and the server must use the same setting.
+    pub fn frame_markers(mut self, frame_markers: bool) -> Self {
+        self.frame.frame_markers = frame_markers;
+        self
+    }
+
+    /// Capture the bytes each connection receives after its handshake to the sink `sinks`
+    /// returns for the connection's id, if any; see `Codec::capture`. A captured stream can be
+    /// replayed through a `Codec` with the options the connection negotiated.
+    #[cfg(feature = "capture")]
+    pub fn capture<F>(mut self, sinks: F) -> Self
+        where F: Fn(u64) -> Option<Box<io::Write + Send>> + Send + Sync + 'static
+    {
+        self.capture = Some(Arc::new(sinks));
+        self
+    }
+
+    /// Check the payloads of every connection against `limits` as well, which can be tightened
+    /// while the connections are open; see `PayloadLimits`. Only tightens the limits agreed to
+    /// in each handshake, which are still those this `Proto` was created with.
+    pub fn payload_limits(mut self, limits: PayloadLimits) -> Self {
+        self.payload_limits = Some(limits);
+        self
+    }
+
+    /// Send and receive payloads larger than the max payload size, up to `max_reassembled_size`
+    /// bytes, in fragments; see `Codec::fragmentation`. Both the client and the server must
+    /// enable fragmentation.
+    pub fn fragmentation(mut self, max_reassembled_size: u64) -> Self {
+        self.frame.fragments = true;
+        self.max_reassembled = max_reassembled_size;
+        self
+    }
+
+    /// Abandon payloads received in fragments whose last fragment takes longer than `timeout`
+    /// to arrive after their first; see `Codec::max_reassembly_time`.
+    pub fn max_reassembly_time(mut self, timeout: Duration) -> Self {
+        self.max_reassembly_time = Some(timeout);
+        self
+    }
+
+    /// Set the width of the length prefix. The default is `LenWidth::U64`. Both the client and
+    /// the server must use the same width.
+    pub fn len_width(mut self, width: LenWidth) -> Self {
+        self.frame.len_width = width;
+        self
+    }
+
+    /// Set the byte order of the id, length, and checksum fields. The default is
+    /// `Endianness::Big`. Both the client and the server must use the same byte order.
+    pub fn endianness(mut self, endianness: Endianness) -> Self {
+        self.frame.endianness = endianness;
+        self
+    }
+}
+
+impl<Encode, Decode> Proto<Encode, Decode, Format> {
+    /// Accept clients that propose any of `formats`, as well as the format this `Proto` was
+    /// created with. Only applies to servers; clients always use their own format.
+    pub fn accept_formats(mut self, formats: Vec<Format>) -> Self {
+        self.handshake.accepted_formats = formats;
+        self
+    }
+}
+
+impl<Encode, Decode, S> Clone for Proto<Encode, Decode, S>
+    where S: Clone
+{
+    fn clone(&self) -> Self {
+        Proto {
+            max_outbound: self.max_outbound,
+            max_inbound: self.max_inbound,
+            payload_limits: self.payload_limits.clone(),
+            max_reassembled: self.max_reassembled,
+            max_reassembly_time: self.max_reassembly_time,
+            skip_too_big: self.skip_too_big,
+            isolate_payload_errors: self.isolate_payload_errors,
+            single_pass: self.single_pass,
+            write_through: self.write_through,
+            request_timeout: self.request_timeout,
+            max_header_wait: self.max_header_wait,
+            frame: self.frame.clone(),
+            decompression: self.decompression.clone(),
+            handshake: self.handshake.clone(),
+            max_in_flight: self.max_in_flight,
+            high_water_mark: self.high_water_mark,
+            max_frames_per_poll: self.max_frames_per_poll,
+            max_backlog: self.max_backlog,
+            max_buffered: self.max_buffered,
+            read_whole_payloads: self.read_whole_payloads,
+            buffer_capacity: self.buffer_capacity,
+            priority: self.priority.clone(),
+            metadata: self.metadata.clone(),
+            metadata_hook: self.metadata_hook.clone(),
+            request_screen: self.request_screen.clone(),
+            encryption: self.encryption.clone(),
+            persist: self.persist.clone(),
+            response_cache: self.response_cache.clone(),
+            ack_slots: self.ack_slots.clone(),
+            pings: self.pings.clone(),
+            stats_queries: self.stats_queries.clone(),
+            diagnostics: self.diagnostics.clone(),
+            request_ids: self.request_ids.clone(),
+            sequence_numbers: self.sequence_numbers,
+            rate_limit: self.rate_limit.clone(),
+            frame_rate: self.frame_rate.clone(),
+            heartbeat: self.heartbeat.clone(),
+            idle_timeouts: self.idle_timeouts.clone(),
+            reap_idle: self.reap_idle.clone(),
+            flush_delay: self.flush_delay.clone(),
+            response_timeout: self.response_timeout.clone(),
+            default_response_timeout: self.default_response_timeout,
+            metrics: self.metrics.clone(),
+            capture: self.capture.clone(),
+            drain: self.drain.clone(),
+            serializer: self.serializer.clone(),
+            _phantom_data: PhantomData,
+        }
+    }
+}
+
+impl<Encode, Decode, S> Proto<Encode, Decode, S>
+    where S: PayloadSerializer + Clone
+{
+    /// Starts the idle timeouts of `transport`, if configured.
+    fn start_idle_timeouts<T>(&self,
+                              transport: Transport<T, Codec<Encode, Decode, S>>)
+                              -> io::Result<Transport<T, Codec<Encode, Decode, S>>> {
+        match self.idle_timeouts {
+            Some(ref timeouts) => Ok(transport.idle_timeouts(timeouts.start()?)),
+            None => Ok(transport),
+        }
+    }
+
+    /// Starts the frame rate limit of `codec`, if configured.
+    fn start_frame_rate(&self,
+                        codec: Codec<Encode, Decode, S>)
+                        -> io::Result<Codec<Encode, Decode, S>> {
+        match self.frame_rate {
+            Some(ref frame_rate) => Ok(codec.frame_rate(frame_rate.start()?)),
+            None => Ok(codec),
+        }
+    }
+
+    /// The options this side brings to the handshake, including its payload size limits.
+    fn handshake_options(&self) -> HandshakeOptions {
+        let mut options = self.handshake.clone();
+        options.max_outbound = self.max_outbound;
+        options.max_inbound = self.max_inbound;
+        options.format = self.serializer.format();
+        options
+    }
+
+    /// The capacities of the read and write buffers of a connection that negotiated `handshake`.
+    fn buffer_capacities(&self, handshake: &Handshake) -> (usize, usize) {
+        match self.buffer_capacity {
+            Some(bytes) => (bytes, bytes),
+            None => {
+                (cmp::min(handshake.max_inbound(), MAX_DEFAULT_BUFFER_CAPACITY) as usize,
+                 cmp::min(handshake.max_outbound(), MAX_DEFAULT_BUFFER_CAPACITY) as usize)
+            }
+        }
+    }
+
+    /// The most bytes a connection that negotiated `handshake` holds; see `max_buffered_bytes`.
+    fn max_buffered(&self, handshake: &Handshake) -> Option<usize> {
+        self.max_buffered.or_else(|| {
+            let bytes = handshake.max_inbound()
+                .checked_mul(DEFAULT_BUFFERED_PAYLOADS)
+                .and_then(|bytes| bytes.checked_add(DEFAULT_BUFFERED_FRAMING));
+            match bytes {
+                Some(bytes) if bytes <= usize::MAX as u64 => Some(bytes as usize),
+                _ => None,
+            }
+        })
+    }
+
+    /// Returns a `Codec` for a connection that negotiated `handshake`.
+    fn codec(&self, handshake: &Handshake) -> Codec<Encode, Decode, S> {
+        let serializer = match handshake.format() {
+            Some(format) => self.serializer.clone().with_format(format),
+            None => self.serializer.clone(),
+        };
+        let mut codec = Codec::with_frame_options(handshake.max_outbound(),
+                                                  handshake.max_inbound(),
+                                                  self.frame.clone(),
+                                                  serializer);
+        codec.connection_id = next_connection_id();
+        let sink = self.capture.as_ref().and_then(|sinks| sinks(codec.connection_id));
+        codec.capture = Capture::new(sink);
+        codec.version = handshake.version();
+        codec.frame.deadlines = handshake.version() >= DEADLINE_VERSION;
+        codec.frame.priorities = handshake.version() >= PRIORITY_VERSION;
+        codec.frame.compression = handshake.compression().and(self.frame.compression);
+        codec.frame.decompression = handshake.peer_compression().and_then(|peer| {
+            self.decompression.iter().cloned().find(|options| options.compression() == peer)
+        });
+        codec.request_priority = self.priority.clone();
+        codec.frame.metadata = handshake.version() >= METADATA_VERSION;
+        codec.request_metadata = self.metadata.clone();
+        codec.metadata_hook = self.metadata_hook.clone();
+        codec.cipher = self.encryption.clone();
+        codec.ack_slots = self.ack_slots.clone();
+        codec.pings = self.pings.clone().map(PingHandle::new);
+        codec.stats_queries = self.stats_queries.clone().map(QueryHandle::new);
+        codec.request_timeout = self.request_timeout;
+        codec.max_header_wait = self.max_header_wait;
+        codec.payload_limits = self.payload_limits.clone();
+        codec.max_reassembled = self.max_reassembled;
+        codec.max_reassembly_time = self.max_reassembly_time;
+        codec.skip_too_big = self.skip_too_big;
+        codec.isolate_payload_errors = self.isolate_payload_errors;
+        codec.single_pass = self.single_pass;
+        codec.write_through = self.write_through;
+        codec.metrics = self.metrics.clone();
+        codec
+    }
+}
+
+impl<T, Encode, Decode, S> ServerProto<T> for Proto<Encode, Decode, S>
+    where T: Io + 'static,
+          Encode: serde::Serialize + 'static,
+          Decode: serde::Deserialize + 'static,
+          S: PayloadSerializer + Clone + 'static,
+          S::Error: 'static
+{
+    type Response = Encode;
+    type Request = Result<Decode, DecodeError<S::Error>>;
+    type Transport = Transport<T, Codec<Encode, Decode, S>>;
+    type BindTransport = Box<Future<Item = Self::Transport, Error = io::Error>>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        let proto = self.clone();
+        Box::new(handshake::server(io, self.handshake_options()).and_then(move |(io, handshake)| {
+            let mut codec = proto.codec(&handshake).trace_requests().inherit_priorities();
+            codec.persist = proto.persist.clone();
+            codec.response_cache = proto.response_cache.clone();
+            codec.request_screen = proto.request_screen.clone();
+            if let Some((ref stats, ref authorize)) = proto.diagnostics {
+                codec.stats = Some(stats.connection(authorize(handshake.username())));
+            }
+            let codec = proto.start_frame_rate(codec)?;
+            let (read, write) = proto.buffer_capacities(&handshake);
+            let mut transport = Transport::with_capacity(io, codec, read, write)
+                .high_water_mark(proto.high_water_mark)
+                .max_frames_per_poll(proto.max_frames_per_poll)
+                .max_backlog(proto.max_backlog)
+                .max_buffered_bytes(proto.max_buffered(&handshake))
+                .read_whole_payloads(proto.read_whole_payloads)
+                .max_in_flight(proto.max_in_flight)
+                .reject_duplicate_ids(true)
+                .drain(proto.drain.clone());
+            if let Some(ref rate_limit) = proto.rate_limit {
+                transport = transport.rate_limit(rate_limit.start()?);
+            }
+            if let Some(ref reaper) = proto.reap_idle {
+                transport = transport.reap_idle(reaper.start()?);
+            }
+            if let Some(ref delay) = proto.flush_delay {
+                transport = transport.flush_delay(delay.start()?);
+            }
+            proto.start_idle_timeouts(transport)
+        }))
+    }
+}
+
+impl<T, Encode, Decode, S> ClientProto<T> for Proto<Encode, Decode, S>
+    where T: Io + 'static,
+          Encode: serde::Serialize + 'static,
+          Decode: serde::Deserialize + 'static,
+          S: PayloadSerializer + Clone + 'static,
+          S::Error: 'static
+{
+    type Response = Result<Decode, DecodeError<S::Error>>;
+    type Request = Encode;
+    type Transport = Transport<T, Codec<Encode, Decode, S>>;
+    type BindTransport = Box<Future<Item = Self::Transport, Error = io::Error>>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        let proto = self.clone();
+        Box::new(handshake::client(io, self.handshake_options()).and_then(move |(io, handshake)| {
+            let (read, write) = proto.buffer_capacities(&handshake);
+            let mut codec = proto.codec(&handshake);
+            codec.request_ids = proto.request_ids.clone();
+            let codec = codec.sequence_numbers(proto.sequence_numbers);
+            let codec = proto.start_frame_rate(codec)?;
+            let transport = Transport::with_capacity(io, codec, read, write)
+                .client()
+                .high_water_mark(proto.high_water_mark)
+                .max_frames_per_poll(proto.max_frames_per_poll)
+                .max_backlog(proto.max_backlog)
+                .max_buffered_bytes(proto.max_buffered(&handshake))
+                .read_whole_payloads(proto.read_whole_payloads);
+            let mut transport = proto.start_idle_timeouts(transport)?;
+            if let Some(ref timeout) = proto.response_timeout {
+                transport = transport.response_timeouts(timeout.start()?);
+            }
+            if let Some(ref delay) = proto.flush_delay {
+                transport = transport.flush_delay(delay.start()?);
+            }
+            match proto.heartbeat {
+                Some(ref heartbeat) => Ok(transport.heartbeat(heartbeat.start()?)),
+                None => Ok(transport),
+            }
+        }))
+    }
+}
+
+#[test]
+fn serialize() {
+    use bincode;
+    use tokio_core::io::Codec as TokioCodec;
+
+    const MSG: (u64, (char, char, char)) = (4, ('a', 'b', 'c'));
+    let mut buf = EasyBuf::new();
+    let mut vec = Vec::new();
+
+    // Serialize twice to check for idempotence.
+    for _ in 0..2 {
+        let mut codec: Codec<(char, char, char), (char, char, char)> = Codec::new(2_000_000);
+        codec.encode(MSG, &mut vec).unwrap();
+        buf.get_mut().append(&mut vec);
+        let actual: Result<Option<(u64,
+                                   Result<(char, char, char), DecodeError<bincode::Error>>)>,
+                           io::Error> = codec.decode(&mut buf);
+
+        match actual {
+            Ok(Some((id, ref v))) if id == MSG.0 && *v.as_ref().unwrap() == MSG.1 => {}
+            bad => panic!("Expected {:?}, but got {:?}", Some(MSG), bad),
+        }
+
+        assert!(buf.get_mut().is_empty(),
+                "Expected empty buf but got {:?}",
+                *buf.get_mut());
+    }
+}
+
+#[test]
+fn deserialize_big() {
+    use tokio_core::io::Codec as TokioCodec;
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(24);
+
+    let mut vec = Vec::new();
+    assert_eq!(codec.encode((0, vec![0; 24]), &mut vec).err().unwrap().kind(),
+               io::ErrorKind::InvalidData);
+
+    let mut buf = EasyBuf::new();
+    // Header
+    buf.get_mut().append(&mut vec![0; 8]);
+    // Len
+    buf.get_mut().append(&mut vec![0, 0, 0, 0, 0, 0, 0, 25]);
+    assert_eq!(codec.decode(&mut buf).err().unwrap().kind(),
+               io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn encode_payload() {
+    use bincode;
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::with_checksum(24);
+    let mut encoded = Vec::new();
+    codec.encode((1, vec![1, 2, 3]), &mut encoded).unwrap();
+    let payload = bincode::serialize(&vec![1u8, 2, 3], bincode::Infinite).unwrap();
+    let mut relayed = Vec::new();
+    codec.encode_payload(1, &payload, &mut relayed).unwrap();
+    assert_eq!(relayed, encoded);
+    match codec.decode(&mut EasyBuf::from(relayed)) {
+        Ok(Some((1, Ok(ref v)))) if *v == vec![1, 2, 3] => {}
+        bad => panic!("Expected Some((1, Ok([1, 2, 3]))), but got {:?}", bad),
+    }
+
+    let mut vec = Vec::new();
+    let e = codec.encode_payload(2, &[0; 25], &mut vec).err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    assert!(vec.is_empty());
+}
+
+#[test]
+fn fragmentation() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::with_checksum(24).fragmentation(1000);
+    let mut vec = Vec::new();
+    codec.encode((1, vec![7; 100]), &mut vec).unwrap();
+    // 108 bytes of payload in fragments of 16, each with an 8-byte fragment header.
+    assert_eq!(vec.len(), 7 * (8 + 1 + 8 + 4) + 108 + 7 * 8);
+    codec.encode((2, vec![8]), &mut vec).unwrap();
+    let decoded: Vec<_> = codec.decode_all(&vec)
+        .map(|decoded| {
+            let (id, message) = decoded.unwrap();
+            (id, message.unwrap())
+        })
+        .collect();
+    assert_eq!(decoded, vec![(1, vec![7; 100]), (2, vec![8])]);
+
+    // The peer gives up on the first payload once it has received more than 50 bytes of it.
+    let mut peer: Codec<Vec<u8>, Vec<u8>> =
+        Codec::with_checksum(24).fragmentation(50).skip_too_big(true);
+    let mut buf = EasyBuf::from(vec);
+    match peer.decode(&mut buf) {
+        Ok(Some((1, Err(DecodeError::PayloadTooLarge { len: 64, max: 50 })))) => {}
+        bad => panic!("Expected PayloadTooLarge, but got {:?}", bad),
+    }
+    match peer.decode(&mut buf) {
+        Ok(Some((2, Ok(ref v)))) if *v == vec![8] => {}
+        bad => panic!("Expected Some((2, Ok([8]))), but got {:?}", bad),
+    }
+
+    let mut vec = Vec::new();
+    assert_eq!(codec.encode((3, vec![0; 1000]), &mut vec).err().unwrap().kind(),
+               io::ErrorKind::InvalidData);
+    assert!(vec.is_empty());
+}
+
+#[test]
+fn unnegotiated_flags() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut codec: Codec<Vec<u8>, Vec<u8>> =
+        Codec::new(24).frame_metadata(true).fragmentation(1000);
+    let mut vec = Vec::new();
+    codec.encode((1, vec![7; 100]), &mut vec).unwrap();
+
+    // Both frame with a flags byte, but the peer didn't enable fragmentation.
+    let mut peer: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000).frame_metadata(true);
+    let e = peer.decode(&mut EasyBuf::from(vec)).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn max_reassembly_time() {
+    use std::thread;
+    use tokio_core::io::Codec as TokioCodec;
+
+    struct Abandoned(Mutex<Vec<(RequestId, u32, u32)>>);
+
+    impl CodecMetrics for Abandoned {
+        fn on_abandoned_reassembly(&self, id: RequestId, received: u32, total: u32) {
+            self.0.lock().unwrap().push((id, received, total));
+        }
+    }
+
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::with_checksum(24).fragmentation(1000);
+    let mut vec = Vec::new();
+    codec.encode((1, vec![7; 100]), &mut vec).unwrap();
+    codec.encode((2, vec![8]), &mut vec).unwrap();
+
+    let abandoned = Arc::new(Abandoned(Mutex::new(Vec::new())));
+    let mut peer: Codec<Vec<u8>, Vec<u8>> = Codec::with_checksum(24)
+        .fragmentation(1000)
+        .max_reassembly_time(Duration::from_millis(20))
+        .metrics(abandoned.clone());
+    // The first two fragments, of 45 bytes each, arrive promptly; the rest don't.
+    let mut buf = EasyBuf::new();
+    buf.get_mut().extend_from_slice(&vec[..90]);
+    assert!(peer.decode(&mut buf).unwrap().is_none());
+    thread::sleep(Duration::from_millis(30));
+    buf.get_mut().extend_from_slice(&vec[90..]);
+    match peer.decode(&mut buf) {
+        Ok(Some((1, Err(DecodeError::ReassemblyTimedOut { .. })))) => {}
+        bad => panic!("Expected ReassemblyTimedOut, but got {:?}", bad),
+    }
+    // The late fragments are skipped.
+    match peer.decode(&mut buf) {
+        Ok(Some((2, Ok(ref v)))) if *v == vec![8] => {}
+        bad => panic!("Expected Some((2, Ok([8]))), but got {:?}", bad),
+    }
+    assert_eq!(*abandoned.0.lock().unwrap(), vec![(1, 2, 7)]);
+}
+
+#[test]
+fn payload_limits() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let limits = PayloadLimits::new();
+    let mut codec: Codec<Vec<u8>, Vec<u8>> =
+        Codec::new(24).payload_limits(limits.clone()).skip_too_big(true);
+    // Two frames with 16-byte payloads.
+    let mut vec = Vec::new();
+    codec.encode((1, vec![0; 8]), &mut vec).unwrap();
+    codec.encode((2, vec![0; 8]), &mut vec).unwrap();
+    assert_eq!(vec.len(), 64);
+
+    // The first frame's length has been read when the limit drops, so it is still received.
+    let mut buf = EasyBuf::new();
+    buf.get_mut().extend_from_slice(&vec[..20]);
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+    limits.set(10);
+    assert_eq!(codec.max_outbound(), 10);
+    assert_eq!(codec.max_inbound(), 10);
+    buf.get_mut().extend_from_slice(&vec[20..]);
+    match codec.decode(&mut buf) {
+        Ok(Some((1, Ok(ref v)))) if *v == vec![0; 8] => {}
+        bad => panic!("Expected Some((1, Ok([0; 8]))), but got {:?}", bad),
+    }
+    match codec.decode(&mut buf) {
+        Ok(Some((2, Err(DecodeError::PayloadTooLarge { len: 16, max: 10 })))) => {}
+        bad => panic!("Expected PayloadTooLarge, but got {:?}", bad),
+    }
+
+    let mut vec = Vec::new();
+    assert_eq!(codec.encode((3, vec![0; 8]), &mut vec).err().unwrap().kind(),
+               io::ErrorKind::InvalidData);
+
+    // The limits never loosen the codec's own.
+    limits.set(1000);
+    assert_eq!(codec.max_outbound(), 24);
+    limits.clear();
+    codec.encode((3, vec![0; 8]), &mut vec).unwrap();
+}
+
+#[test]
+fn request_ids() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let next = AtomicUsize::new(100);
+    let mut client: Codec<u8, u8> = Codec::new(2_000_000)
+        .request_ids(move || next.fetch_add(1, Ordering::SeqCst) as RequestId);
+    let mut vec = Vec::new();
+    client.encode((0, 7), &mut vec).unwrap();
+    client.encode((1, 8), &mut vec).unwrap();
+    let mut server: Codec<u8, u8> = Codec::new(2_000_000);
+    let requests: Vec<_> = server.decode_all(&vec)
+        .map(|decoded| {
+            let (id, request) = decoded.unwrap();
+            (id, request.unwrap())
+        })
+        .collect();
+    assert_eq!(requests, vec![(100, 7), (101, 8)]);
+
+    let mut vec = Vec::new();
+    server.encode((101, 9), &mut vec).unwrap();
+    server.encode((100, 10), &mut vec).unwrap();
+    server.encode((5, 11), &mut vec).unwrap();
+    let responses: Vec<_> = client.decode_all(&vec)
+        .map(|decoded| {
+            let (id, response) = decoded.unwrap();
+            (id, response.unwrap())
+        })
+        .collect();
+    // A frame with an id the client never sent is discarded.
+    assert_eq!(responses, vec![(1, 9), (0, 10)]);
+}
+
+#[test]
+fn request_ids_freed() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let next = AtomicUsize::new(100);
+    let mut client: Codec<Vec<u8>, u8> = Codec::new(16)
+        .request_ids(move || next.fetch_add(1, Ordering::SeqCst) as RequestId);
+    let mut vec = Vec::new();
+    // The id of a request that fails to encode is freed at once.
+    client.encode((0, vec![0; 32]), &mut vec).unwrap_err();
+    assert!(client.wire_ids.is_empty());
+
+    // The id of a request that timed out is freed, and its late response discarded.
+    client.encode((1, vec![1]), &mut vec).unwrap();
+    assert!(client.abandon_request(1));
+    assert!(client.wire_ids.is_empty());
+    assert!(!client.abandon_request(1));
+    let mut server: Codec<u8, Vec<u8>> = Codec::new(16);
+    let mut vec = Vec::new();
+    server.encode((101, 9), &mut vec).unwrap();
+    assert!(client.decode_all(&vec).next().is_none());
+}
+
+#[test]
+fn request_ids_exhausted() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    // Wraps after 3 ids, and returns a reserved one in between.
+    let next = AtomicUsize::new(0);
+    let mut client: Codec<u8, u8> = Codec::new(2_000_000).request_ids(move || {
+        match next.fetch_add(1, Ordering::SeqCst) % 4 {
+            3 => HEARTBEAT_ID,
+            n => n as RequestId,
+        }
+    });
+    let mut vec = Vec::new();
+    for id in 10..13 {
+        client.encode((id, 7), &mut vec).unwrap();
+    }
+    let len = vec.len();
+    let e = client.encode((13, 8), &mut vec).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::Other);
+    assert_eq!(e.get_ref().unwrap().downcast_ref::<IdSpaceExhaustedError>(),
+               Some(&IdSpaceExhaustedError {
+                   in_flight: 3,
+                   attempts: 11,
+               }));
+    assert_eq!(vec.len(), len);
+
+    let mut server: Codec<u8, u8> = Codec::new(2_000_000);
+    let requests: Vec<_> = server.decode_all(&vec).map(|decoded| decoded.unwrap().0).collect();
+    assert_eq!(requests, vec![0, 1, 2]);
+
+    // Once 1 is answered, it is the only free id.
+    let mut vec = Vec::new();
+    server.encode((1, 9), &mut vec).unwrap();
+    assert_eq!(client.decode_all(&vec).map(|decoded| decoded.unwrap().0).collect::<Vec<_>>(),
+               vec![11]);
+    let mut vec = Vec::new();
+    client.encode((14, 8), &mut vec).unwrap();
+    assert_eq!(server.decode_all(&vec).map(|decoded| decoded.unwrap().0).collect::<Vec<_>>(),
+               vec![1]);
+}
+
+#[test]
+fn encode_failure_leaves_buf_unchanged() {
+    use serde::ser::{Error, Serialize, Serializer};
+    use tokio_core::io::Codec as TokioCodec;
+
+    struct Unserializable;
+
+    impl Serialize for Unserializable {
+        fn serialize<S: Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+            Err(S::Error::custom("Unserializable can't be serialized"))
+        }
+    }
+
+    let mut vec = Vec::new();
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(24);
+    codec.encode((0, vec![0; 4]), &mut vec).unwrap();
+    let expected = vec.clone();
+
+    assert_eq!(codec.encode((1, vec![0; 24]), &mut vec).err().unwrap().kind(),
+               io::ErrorKind::InvalidData);
+    assert_eq!(vec, expected);
+
+    let mut codec: Codec<Unserializable, ()> = Codec::new(24);
+    assert_eq!(codec.encode((2, Unserializable), &mut vec).err().unwrap().kind(),
+               io::ErrorKind::InvalidInput);
+    assert_eq!(vec, expected);
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn encode_to_preserves_write_errors() {
+    /// Takes `remaining` bytes, then fails as if the connection had closed.
+    struct Closing {
+        remaining: usize,
+    }
+
+    impl io::Write for Closing {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.remaining == 0 {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"));
+            }
+            let n = cmp::min(buf.len(), self.remaining);
+            self.remaining -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // The 16-byte header is written, and the connection closes while the payload is streamed.
+    let mut codec: JsonCodec<Vec<u8>, Vec<u8>> = JsonCodec::new(1024);
+    let mut w = Closing { remaining: 20 };
+    assert_eq!(codec.encode_to((0, vec![0; 12]), &mut w).err().unwrap().kind(),
+               io::ErrorKind::BrokenPipe);
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn serialize_json() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    const MSG: (u64, (char, char, char)) = (4, ('a', 'b', 'c'));
+    let mut buf = EasyBuf::new();
+    let mut vec = Vec::new();
+
+    let mut codec: JsonCodec<(char, char, char), (char, char, char)> = JsonCodec::new(2_000_000);
+    codec.encode(MSG, &mut vec).unwrap();
+    // The payload follows the 16-byte header as plain JSON.
+    assert_eq!(&vec[16..], br#"["a","b","c"]"#);
+    buf.get_mut().append(&mut vec);
+
+    match codec.decode(&mut buf) {
+        Ok(Some((id, ref v))) if id == MSG.0 && *v.as_ref().unwrap() == MSG.1 => {}
+        bad => panic!("Expected {:?}, but got {:?}", Some(MSG), bad),
+    }
+    assert!(buf.get_mut().is_empty(),
+            "Expected empty buf but got {:?}",
+            *buf.get_mut());
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn deserialize_json_errors() {
+    use tokio_core::io::Codec as TokioCodec;
+    let mut codec: JsonCodec<Vec<u8>, Vec<u8>> = JsonCodec::new(24);
+
+    let mut vec = Vec::new();
+    // `[0,0,0,0,0,0,0,0,0,0,0,0]` is 25 bytes of JSON.
+    assert_eq!(codec.encode((0, vec![0; 12]), &mut vec).err().unwrap().kind(),
+               io::ErrorKind::InvalidData);
+
+    let mut buf = EasyBuf::new();
+    // Header
+    buf.get_mut().append(&mut vec![0; 8]);
+    // Len
+    buf.get_mut().append(&mut vec![0, 0, 0, 0, 0, 0, 0, 25]);
+    assert_eq!(codec.decode(&mut buf).err().unwrap().kind(),
+               io::ErrorKind::InvalidData);
+
+    // A well-framed payload that isn't valid JSON surfaces as the `Err` arm.
+    let mut buf = EasyBuf::new();
+    buf.get_mut().append(&mut vec![0, 0, 0, 0, 0, 0, 0, 1]);
+    buf.get_mut().append(&mut vec![0, 0, 0, 0, 0, 0, 0, 1]);
+    buf.get_mut().push(b'{');
+    match codec.decode(&mut buf) {
+        Ok(Some((1, Err(DecodeError::Deserialize(_))))) => {}
+        bad => panic!("Expected a deserialization error, but got {:?}", bad),
+    }
+}
+
+#[test]
+#[cfg(feature = "msgpack")]
+fn serialize_msgpack() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    const MSG: (u64, (char, char, char)) = (4, ('a', 'b', 'c'));
+    let mut buf = EasyBuf::new();
+    let mut vec = Vec::new();
+
+    let mut codec: MsgPackCodec<(char, char, char), (char, char, char)> =
+        MsgPackCodec::new(2_000_000);
+    codec.encode(MSG, &mut vec).unwrap();
+    buf.get_mut().append(&mut vec);
+
+    match codec.decode(&mut buf) {
+        Ok(Some((id, ref v))) if id == MSG.0 && *v.as_ref().unwrap() == MSG.1 => {}
+        bad => panic!("Expected {:?}, but got {:?}", Some(MSG), bad),
+    }
+    assert!(buf.get_mut().is_empty(),
+            "Expected empty buf but got {:?}",
+            *buf.get_mut());
+}
+
+#[test]
+#[cfg(feature = "msgpack")]
+fn serialize_msgpack_big() {
+    use tokio_core::io::Codec as TokioCodec;
+    let mut codec: MsgPackCodec<Vec<u8>, Vec<u8>> = MsgPackCodec::new(24);
+
+    let mut vec = Vec::new();
+    // A 24-element array needs a 3-byte array header in MessagePack.
+    assert_eq!(codec.encode((0, vec![0; 24]), &mut vec).err().unwrap().kind(),
+               io::ErrorKind::InvalidData);
+    assert!(vec.is_empty(), "Expected empty vec but got {:?}", vec);
+}
+
+#[test]
+#[cfg(feature = "cbor")]
+fn serialize_cbor() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    #[derive(Serialize)]
+    struct V2 {
+        name: String,
+        nickname: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct V1 {
+        name: String,
+    }
+
+    let mut buf = EasyBuf::new();
+    let mut vec = Vec::new();
+
+    // CBOR payloads carry field names, so a peer that doesn't know about `nickname` can still
+    // decode the message.
+    let mut codec: CborCodec<V2, V1> = CborCodec::new(2_000_000);
+    let msg = V2 {
+        name: "Tim".to_string(),
+        nickname: Some("tikue".to_string()),
+    };
+    codec.encode((4, msg), &mut vec).unwrap();
+    buf.get_mut().append(&mut vec);
+
+    match codec.decode(&mut buf) {
+        Ok(Some((4, Ok(ref v)))) if *v == V1 { name: "Tim".to_string() } => {}
+        bad => panic!("Expected V1 {{ name: \"Tim\" }}, but got {:?}", bad),
+    }
+    assert!(buf.get_mut().is_empty(),
+            "Expected empty buf but got {:?}",
+            *buf.get_mut());
+}
+
+#[test]
+#[cfg(feature = "cbor")]
+fn deserialize_cbor_big() {
+    use tokio_core::io::Codec as TokioCodec;
+    let mut codec: CborCodec<Vec<u8>, Vec<u8>> = CborCodec::new(24);
+
+    let mut vec = Vec::new();
+    assert_eq!(codec.encode((0, vec![0; 24]), &mut vec).err().unwrap().kind(),
+               io::ErrorKind::InvalidData);
+
+    let mut buf = EasyBuf::new();
+    // Header
+    buf.get_mut().append(&mut vec![0; 8]);
+    // Len
+    buf.get_mut().append(&mut vec![0, 0, 0, 0, 0, 0, 0, 25]);
+    assert_eq!(codec.decode(&mut buf).err().unwrap().kind(),
+               io::ErrorKind::InvalidData);
+}
+
+#[test]
+#[cfg(feature = "zstd")]
+fn compression() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let options = CompressionOptions::new(Compression::Zstd).threshold(16);
+    // `max_payload_size` applies to the compressed size, so a compressible payload may be
+    // larger than it.
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(100).compression(options);
+    let mut vec = Vec::new();
+    codec.encode((1, vec![7; 1000]), &mut vec).unwrap();
+    // The flags byte follows the 8-byte id.
+    assert_eq!(vec[8], FLAG_COMPRESSED);
+    let compressed_len = vec.len();
+    assert!(compressed_len < 117, "Expected a small frame but got {:?}", vec);
+
+    // Payloads under the threshold are sent as-is.
+    codec.encode((2, vec![7; 4]), &mut vec).unwrap();
+    assert_eq!(vec[compressed_len + 8], 0);
+
+    let mut buf = EasyBuf::new();
+    buf.get_mut().append(&mut vec);
+    match codec.decode(&mut buf) {
+        Ok(Some((1, Ok(ref v)))) if *v == vec![7; 1000] => {}
+        bad => panic!("Expected the compressed payload, but got {:?}", bad),
+    }
+    match codec.decode(&mut buf) {
+        Ok(Some((2, Ok(ref v)))) if *v == vec![7; 4] => {}
+        bad => panic!("Expected the uncompressed payload, but got {:?}", bad),
+    }
+    assert!(buf.get_mut().is_empty(),
+            "Expected empty buf but got {:?}",
+            *buf.get_mut());
+}
+
+#[test]
+#[cfg(feature = "zstd")]
+fn asymmetric_compression() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    // The client sends its requests as-is, and the server compresses its responses.
+    let options = CompressionOptions::new(Compression::Zstd).threshold(16);
+    let mut client: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000).decompression(options);
+    let mut server: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000).compression(options);
+    let mut vec = Vec::new();
+    client.encode((1, vec![7; 1000]), &mut vec).unwrap();
+    assert_eq!(vec[8], 0);
+    let mut buf = EasyBuf::from(vec);
+    assert_eq!(server.decode(&mut buf).unwrap().unwrap().1.unwrap(), vec![7; 1000]);
+
+    let mut vec = Vec::new();
+    server.encode((1, vec![7; 1000]), &mut vec).unwrap();
+    assert_eq!(vec[8], FLAG_COMPRESSED);
+    let mut buf = EasyBuf::from(vec);
+    assert_eq!(client.decode(&mut buf).unwrap().unwrap().1.unwrap(), vec![7; 1000]);
+
+    // Over a connection, each side learns in the handshake what the other decompresses.
+    let mut core = reactor::Core::new().unwrap();
+    let (client_io, server_io) = in_memory();
+    let server_proto: Proto<Vec<u8>, Vec<u8>> = Proto::new(2_000_000).compression(options);
+    let io = ServerProto::bind_transport(&server_proto, server_io);
+    let client_proto: Proto<Vec<u8>, Vec<u8>> = Proto::new(2_000_000).accept_compression(options);
+    let client = ClientProto::bind_transport(&client_proto, client_io);
+    let (server, client) = core.run(io.join(client)).unwrap();
+    assert_eq!(server.codec().frame.compression.map(|c| c.compression()),
+               Some(Compression::Zstd));
+    assert!(server.codec().frame.decompression.is_none());
+    assert!(client.codec().frame.compression.is_none());
+    assert_eq!(client.codec().frame.decompression.map(|c| c.compression()),
+               Some(Compression::Zstd));
+}
+
+#[test]
+#[cfg(feature = "zstd")]
+fn decompression_bomb() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let options = CompressionOptions::new(Compression::Zstd).threshold(0);
+    let mut sender: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000).compression(options);
+    let mut vec = Vec::new();
+    sender.encode((1, vec![0; 100_000]), &mut vec).unwrap();
+
+    let options = options.max_decompressed_size(1_000);
+    let mut receiver: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000).compression(options);
+    let mut buf = EasyBuf::new();
+    buf.get_mut().append(&mut vec);
+    assert_eq!(receiver.decode(&mut buf).err().unwrap().kind(),
+               io::ErrorKind::InvalidData);
+
+    // Nor will a codec send a payload that it wouldn't inflate itself.
+    assert_eq!(receiver.encode((2, vec![0; 100_000]), &mut vec).err().unwrap().kind(),
+               io::ErrorKind::InvalidData);
+    assert!(vec.is_empty(), "Expected empty vec but got {:?}", vec);
+}
+
+#[test]
+#[cfg(feature = "snappy")]
+fn snappy() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let options = CompressionOptions::new(Compression::Snappy).threshold(16);
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(100).compression(options);
+    let mut vec = Vec::new();
+    codec.encode((1, vec![7; 1000]), &mut vec).unwrap();
+    assert_eq!(vec[8], FLAG_COMPRESSED);
+    assert!(vec.len() < 117, "Expected a small frame but got {:?}", vec);
+
+    let mut buf = EasyBuf::from(vec);
+    match codec.decode(&mut buf) {
+        Ok(Some((1, Ok(ref v)))) if *v == vec![7; 1000] => {}
+        bad => panic!("Expected the compressed payload, but got {:?}", bad),
+    }
+
+    // The decompressed size limit still applies.
+    let mut sender: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000).compression(options);
+    let mut vec = Vec::new();
+    sender.encode((2, vec![0; 100_000]), &mut vec).unwrap();
+    let options = options.max_decompressed_size(1_000);
+    let mut receiver: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000).compression(options);
+    let mut buf = EasyBuf::from(vec);
+    assert_eq!(receiver.decode(&mut buf).err().unwrap().kind(),
+               io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn checksum() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::with_checksum(2_000_000);
+    let mut vec = Vec::new();
+    codec.encode((1, vec![1, 2, 3]), &mut vec).unwrap();
+    // id + len + 11 bytes of payload + crc
+    assert_eq!(vec.len(), 8 + 8 + 11 + 4);
+
+    let mut buf = EasyBuf::new();
+    buf.get_mut().extend_from_slice(&vec);
+    match codec.decode(&mut buf) {
+        Ok(Some((1, Ok(ref v)))) if *v == vec![1, 2, 3] => {}
+        bad => panic!("Expected Some((1, Ok([1, 2, 3]))), but got {:?}", bad),
+    }
+
+    // Corrupt the last byte of the payload.
+    vec[8 + 8 + 10] ^= 0xff;
+    let mut buf = EasyBuf::new();
+    buf.get_mut().append(&mut vec);
+    match codec.decode(&mut buf) {
+        Ok(Some((1, Err(DecodeError::ChecksumMismatch { .. })))) => {}
+        bad => panic!("Expected ChecksumMismatch, but got {:?}", bad),
+    }
+    assert!(buf.get_mut().is_empty(),
+            "Expected empty buf but got {:?}",
+            *buf.get_mut());
+}
+
+#[test]
+fn len_width_u32() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000).len_width(LenWidth::U32);
+    let mut vec = Vec::new();
+    codec.encode((1, vec![1, 2, 3]), &mut vec).unwrap();
+    // id + 4-byte len + 11 bytes of payload
+    assert_eq!(vec.len(), 8 + 4 + 11);
+    assert_eq!(&vec[8..12], &[0, 0, 0, 11]);
+
+    let mut buf = EasyBuf::new();
+    buf.get_mut().append(&mut vec);
+    match codec.decode(&mut buf) {
+        Ok(Some((1, Ok(ref v)))) if *v == vec![1, 2, 3] => {}
+        bad => panic!("Expected Some((1, Ok([1, 2, 3]))), but got {:?}", bad),
+    }
+    assert!(buf.get_mut().is_empty(),
+            "Expected empty buf but got {:?}",
+            *buf.get_mut());
+}
+
+#[test]
+fn per_direction_limits() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut big_responses: Codec<Vec<u8>, Vec<u8>> = Codec::with_limits(2_000_000, 24);
+    let mut small_responses: Codec<Vec<u8>, Vec<u8>> = Codec::with_limits(24, 2_000_000);
+
+    let mut vec = Vec::new();
+    big_responses.encode((0, vec![0; 24]), &mut vec).unwrap();
+    assert_eq!(small_responses.encode((0, vec![0; 24]), &mut Vec::new()).err().unwrap().kind(),
+               io::ErrorKind::InvalidData);
+
+    let mut buf = EasyBuf::from(vec.clone());
+    assert_eq!(small_responses.decode(&mut buf).unwrap().unwrap().1.unwrap(), vec![0; 24]);
+    let mut buf = EasyBuf::from(vec);
+    assert_eq!(big_responses.decode(&mut buf).err().unwrap().kind(),
+               io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn skip_too_big() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut sender: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    let mut receiver: Codec<Vec<u8>, Vec<u8>> = Codec::new(24).skip_too_big(true);
+    let mut vec = Vec::new();
+    sender.encode((1, vec![0; 100]), &mut vec).unwrap();
+    sender.encode((2, vec![1, 2, 3]), &mut vec).unwrap();
+
+    // Feed the frames in a byte at a time; the rejected payload is skipped as it arrives, and
+    // the frame after it is unaffected.
+    let mut buf = EasyBuf::new();
+    let mut decoded = vec![];
+    for byte in vec {
+        buf.get_mut().push(byte);
+        if let Some((id, result)) = receiver.decode(&mut buf).unwrap() {
+            decoded.push((id, result.ok()));
+        }
+    }
+    assert_eq!(decoded, vec![(1, None), (2, Some(vec![1, 2, 3]))]);
+
+    // By default, a payload that is too big closes the connection.
+    let mut receiver: Codec<Vec<u8>, Vec<u8>> = Codec::new(24);
+    let mut vec = Vec::new();
+    sender.encode((1, vec![0; 100]), &mut vec).unwrap();
+    let mut buf = EasyBuf::from(vec);
+    let e = receiver.decode(&mut buf).err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(e.get_ref().unwrap().downcast_ref::<PayloadTooLargeError>(),
+               Some(&PayloadTooLargeError {
+                   id: Some(1),
+                   len: 108,
+                   max: 24,
+               }));
+
+    // Sending a payload that is too big fails the same way.
+    let e = receiver.encode((3, vec![0; 100]), &mut vec![]).err().unwrap();
+    assert_eq!(e.get_ref().unwrap().downcast_ref::<PayloadTooLargeError>(),
+               Some(&PayloadTooLargeError {
+                   id: Some(3),
+                   len: 108,
+                   max: 24,
+               }));
+}
+
+#[test]
+fn isolate_payload_errors() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut sender: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000)
+        .frame_metadata(true)
+        .metadata(|_: &Vec<u8>| {
+            let mut metadata = Metadata::new();
+            metadata.insert("trace-id".to_string(), "1".to_string());
+            metadata
+        });
+    let mut vec = Vec::new();
+    sender.encode((1, vec![0; 10]), &mut vec).unwrap();
+    // After the id, flags, and length, the payload starts with the length of the metadata.
+    for byte in &mut vec[17..21] {
+        *byte = 0xff;
+    }
+    // A single byte doesn't deserialize as a `Vec<u8>`.
+    Codec::<u8, u8>::new(2_000_000).frame_metadata(true).encode((2, 5), &mut vec).unwrap();
+    sender.encode((3, vec![1, 2, 3]), &mut vec).unwrap();
+
+    let mut receiver: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000)
+        .frame_metadata(true)
+        .isolate_payload_errors(true);
+    let mut buf = EasyBuf::from(vec.clone());
+    match receiver.decode(&mut buf) {
+        Ok(Some((1, Err(DecodeError::MalformedPayload(ref e))))) => {
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData)
+        }
+        bad => panic!("Expected MalformedPayload, but got {:?}", bad),
+    }
+    match receiver.decode(&mut buf) {
+        Ok(Some((2, Err(DecodeError::Deserialize(_))))) => {}
+        bad => panic!("Expected a Deserialize error, but got {:?}", bad),
+    }
+    match receiver.decode(&mut buf) {
+        Ok(Some((3, Ok(ref v)))) if *v == vec![1, 2, 3] => {}
+        bad => panic!("Expected Some((3, Ok([1, 2, 3]))), but got {:?}", bad),
+    }
+
+    // By default, malformed metadata closes the connection.
+    let mut receiver: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000).frame_metadata(true);
+    let mut buf = EasyBuf::from(vec);
+    assert_eq!(receiver.decode(&mut buf).err().unwrap().kind(),
+               io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn metrics() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio_core::io::Codec as TokioCodec;
+
+    #[derive(Default)]
+    struct Counts {
+        encoded: AtomicUsize,
+        decoded: AtomicUsize,
+        rejected: AtomicUsize,
+    }
+
+    impl CodecMetrics for Counts {
+        fn on_encode(&self, _: RequestId, _: u64) {
+            self.encoded.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_decode(&self, _: RequestId, _: u64) {
+            self.decoded.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_reject(&self, _: u64, _: u64) {
+            self.rejected.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let counts = Arc::new(Counts::default());
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(24).metrics(counts.clone());
+    let mut vec = Vec::new();
+    codec.encode((1, vec![1, 2, 3]), &mut vec).unwrap();
+    assert!(codec.encode((2, vec![0; 24]), &mut Vec::new()).is_err());
+
+    let mut sender: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    sender.encode((3, vec![0; 24]), &mut vec).unwrap();
+    let mut buf = EasyBuf::from(vec);
+    codec.decode(&mut buf).unwrap().unwrap();
+    codec.decode(&mut buf).unwrap().unwrap();
+
+    assert_eq!(counts.encoded.load(Ordering::SeqCst), 1);
+    assert_eq!(counts.decoded.load(Ordering::SeqCst), 1);
+    assert_eq!(counts.rejected.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn little_endian() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::with_checksum(2_000_000)
+        .endianness(Endianness::Little);
+    let mut vec = Vec::new();
+    codec.encode((1, vec![1, 2, 3]), &mut vec).unwrap();
+    assert_eq!(&vec[..16], &[1, 0, 0, 0, 0, 0, 0, 0, 11, 0, 0, 0, 0, 0, 0, 0]);
+
+    let mut buf = EasyBuf::new();
+    buf.get_mut().append(&mut vec);
+    match codec.decode(&mut buf) {
+        Ok(Some((1, Ok(ref v)))) if *v == vec![1, 2, 3] => {}
+        bad => panic!("Expected Some((1, Ok([1, 2, 3]))), but got {:?}", bad),
+    }
+    assert!(buf.get_mut().is_empty(),
+            "Expected empty buf but got {:?}",
+            *buf.get_mut());
+}
+
+#[test]
+fn len_width_varint() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::with_varint_len(2_000_000);
+    let mut vec = Vec::new();
+    codec.encode((1, vec![1, 2, 3]), &mut vec).unwrap();
+    // id + 1-byte len + 11 bytes of payload
+    assert_eq!(vec.len(), 8 + 1 + 11);
+    assert_eq!(vec[8], 11);
+    codec.encode((2, vec![0; 300]), &mut vec).unwrap();
+    // 308 = 0b10_0110100
+    assert_eq!(&vec[28..30], &[0b1011_0100, 0b10]);
+
+    // Feed the frames in a byte at a time to exercise the partial varint.
+    let mut buf = EasyBuf::new();
+    let mut decoded = vec![];
+    for byte in vec {
+        buf.get_mut().push(byte);
+        if let Some((id, result)) = codec.decode(&mut buf).unwrap() {
+            decoded.push((id, result.unwrap().len()));
+        }
+    }
+    assert_eq!(decoded, vec![(1, 3), (2, 300)]);
+
+    let mut receiver: Codec<Vec<u8>, Vec<u8>> = Codec::with_varint_len(24);
+    let mut buf = EasyBuf::new();
+    buf.get_mut().append(&mut vec![0; 8]);
+    buf.get_mut().append(&mut vec![0b1011_0100, 0b10]);
+    assert_eq!(receiver.decode(&mut buf).err().unwrap().kind(),
+               io::ErrorKind::InvalidData);
+
+    let mut receiver: Codec<Vec<u8>, Vec<u8>> = Codec::with_varint_len(24);
+    let mut buf = EasyBuf::new();
+    buf.get_mut().append(&mut vec![0; 8]);
+    buf.get_mut().append(&mut vec![0xff; 10]);
+    assert_eq!(receiver.decode(&mut buf).err().unwrap().kind(),
+               io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn resync() {
+    use self::frame::FRAME_MARKER;
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000).frame_markers(true);
+    let mut vec = Vec::new();
+    for id in 1..4 {
+        codec.encode((id, vec![1, 2, 3]), &mut vec).unwrap();
+    }
+    // marker + id + len + 11 bytes of payload
+    assert_eq!(vec.len(), 3 * (4 + 8 + 8 + 11));
+    assert_eq!(&vec[..4], FRAME_MARKER);
+
+    // Overstate the length of frame 1 so that it reads into frame 2.
+    vec[4 + 8 + 7] += 16;
+    let mut buf = EasyBuf::from(vec);
+    match codec.decode(&mut buf) {
+        Ok(Some((1, Ok(ref v)))) if *v == vec![1, 2, 3] => {}
+        bad => panic!("Expected Some((1, Ok([1, 2, 3]))), but got {:?}", bad),
+    }
+    assert_eq!(codec.decode(&mut buf).err().unwrap().kind(),
+               io::ErrorKind::InvalidData);
+
+    // Frame 2 is lost, but frame 3 is found.
+    codec.resync().unwrap();
+    match codec.decode(&mut buf) {
+        Ok(Some((3, Ok(ref v)))) if *v == vec![1, 2, 3] => {}
+        bad => panic!("Expected Some((3, Ok([1, 2, 3]))), but got {:?}", bad),
+    }
+    assert!(buf.get_mut().is_empty(),
+            "Expected empty buf but got {:?}",
+            *buf.get_mut());
+
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    assert_eq!(codec.resync().err().unwrap().kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+#[cfg(feature = "zstd")]
+fn single_pass() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut two_pass: Codec<Vec<u8>, Vec<u8>> = Codec::with_checksum(24);
+    let mut single_pass: Codec<Vec<u8>, Vec<u8>> = Codec::with_checksum(24).single_pass(true);
+    let mut expected = Vec::new();
+    let mut vec = Vec::new();
+    for len in 0..3 {
+        two_pass.encode((len, vec![len as u8; len as usize]), &mut expected).unwrap();
+        single_pass.encode((len, vec![len as u8; len as usize]), &mut vec).unwrap();
+    }
+    assert_eq!(vec, expected);
+
+    // Too-big payloads are still rejected, without leaving anything behind.
+    assert_eq!(single_pass.encode((3, vec![0; 24]), &mut vec).err().unwrap().kind(),
+               io::ErrorKind::InvalidData);
+    assert_eq!(vec, expected);
+
+    let options = CompressionOptions::new(Compression::Zstd).threshold(16);
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(100)
+        .compression(options)
+        .single_pass(true);
+    let mut vec = Vec::new();
+    codec.encode((1, vec![7; 1000]), &mut vec).unwrap();
+    codec.encode((2, vec![7; 4]), &mut vec).unwrap();
+    let mut buf = EasyBuf::from(vec);
+    assert_eq!(codec.decode(&mut buf).unwrap().unwrap().1.unwrap(), vec![7; 1000]);
+    assert_eq!(codec.decode(&mut buf).unwrap().unwrap().1.unwrap(), vec![7; 4]);
+}
+
+#[test]
+fn deadlines() {
+    use byteorder::{BigEndian, ByteOrder};
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut client: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000)
+        .deadlines(true)
+        .request_timeout(Duration::from_secs(3600));
+    let mut server: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000).deadlines(true);
+    let mut vec = Vec::new();
+    client.encode((1, vec![1, 2, 3]), &mut vec).unwrap();
+    // id + deadline + len + 11 bytes of payload
+    assert_eq!(vec.len(), 8 + 8 + 8 + 11);
+    let now = frame::unix_millis(SystemTime::now());
+    assert!(BigEndian::read_u64(&vec[8..16]) > now);
+
+    // A deadline of 0 means none.
+    server.encode((2, vec![1, 2, 3]), &mut vec).unwrap();
+    assert_eq!(BigEndian::read_u64(&vec[35 + 8..35 + 16]), 0);
+
+    client.encode((3, vec![1, 2, 3]), &mut vec).unwrap();
+    BigEndian::write_u64(&mut vec[70 + 8..70 + 16], 1);
+    let mut buf = EasyBuf::from(vec);
+    for id in 1..3 {
+        match server.decode(&mut buf) {
+            Ok(Some((decoded, Ok(ref v)))) if decoded == id && *v == vec![1, 2, 3] => {}
+            bad => panic!("Expected Some(({}, Ok([1, 2, 3]))), but got {:?}", id, bad),
+        }
+    }
+    match server.decode(&mut buf) {
+        Ok(Some((3, Err(DecodeError::DeadlineExceeded { deadline: 1 })))) => {}
+        bad => panic!("Expected DeadlineExceeded, but got {:?}", bad),
+    }
+    assert!(buf.get_mut().is_empty(),
+            "Expected empty buf but got {:?}",
+            *buf.get_mut());
+}
+
+#[test]
+fn max_header_wait() {
+    use std::thread;
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut vec = Vec::new();
+    Codec::<Vec<u8>, Vec<u8>>::new(2_000_000).encode((1, vec![1, 2, 3]), &mut vec).unwrap();
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000)
+        .max_header_wait(Duration::from_millis(20));
+
+    // A frame that arrives promptly is decoded.
+    let mut buf = EasyBuf::from(vec.clone());
+    assert!(codec.decode(&mut buf).unwrap().is_some());
+
+    // A header that dribbles in isn't.
+    let mut buf = EasyBuf::new();
+    buf.get_mut().extend_from_slice(&vec[..4]);
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+    thread::sleep(Duration::from_millis(30));
+    buf.get_mut().push(vec[4]);
+    assert_eq!(codec.decode(&mut buf).err().unwrap().kind(),
+               io::ErrorKind::TimedOut);
+}
+
+#[test]
+fn bincode_limit() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut unlimited: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    let mut limited: Codec<Vec<u8>, Vec<u8>> =
+        Codec::with_serializer(2_000_000, BincodeSerializer::new().limit(16));
+
+    let mut vec = Vec::new();
+    unlimited.encode((1, vec![0; 32]), &mut vec).unwrap();
+    let mut buf = EasyBuf::from(vec);
+    match limited.decode(&mut buf).unwrap() {
+        Some((1, Err(DecodeError::Deserialize(_)))) => {}
+        bad => panic!("Expected a Deserialize error, but got {:?}", bad),
+    }
+
+    assert!(limited.encode((2, vec![0; 32]), &mut vec![]).is_err());
+    let mut vec = Vec::new();
+    limited.encode((3, vec![0; 4]), &mut vec).unwrap();
+    let mut buf = EasyBuf::from(vec);
+    assert_eq!(unlimited.decode(&mut buf).unwrap().unwrap().1.unwrap(), vec![0; 4]);
+}
+
+#[test]
+fn decode_progress() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut vec = Vec::new();
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    codec.encode((7, vec![1, 2, 3]), &mut vec).unwrap();
+    assert_eq!(codec.state(), DecodeProgress::WaitingForId);
+
+    // id + len + 11 bytes of payload
+    let mut buf = EasyBuf::new();
+    buf.get_mut().extend_from_slice(&vec[..12]);
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+    assert_eq!(codec.state(), DecodeProgress::WaitingForLen { id: 7 });
+
+    buf.get_mut().extend_from_slice(&vec[12..20]);
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+    assert_eq!(codec.state(),
+               DecodeProgress::WaitingForPayload { id: 7, needed: 11 });
+
+    buf.get_mut().extend_from_slice(&vec[20..25]);
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+    assert_eq!(codec.state(),
+               DecodeProgress::WaitingForPayload { id: 7, needed: 6 });
+
+    buf.get_mut().extend_from_slice(&vec[25..]);
+    assert!(codec.decode(&mut buf).unwrap().is_some());
+    assert_eq!(codec.state(), DecodeProgress::WaitingForId);
+}
+
+#[test]
+#[cfg(all(feature = "json", feature = "msgpack"))]
+fn negotiated_format() {
+    use futures::future;
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+    use tokio_service::Service;
+
+    struct Double;
+
+    impl Service for Double {
+        type Request = Result<u32, DecodeError<FormatError>>;
+        type Response = u32;
+        type Error = io::Error;
+        type Future = future::FutureResult<u32, io::Error>;
+
+        fn call(&self, request: Self::Request) -> Self::Future {
+            future::result(request.map(|n| n * 2).map_err(DecodeError::into_io))
+        }
+    }
+
+    let mut core = Core::new().unwrap();
+    let server: NegotiatedProto<u32, u32> = Proto::with_serializer(2_000_000, Format::Bincode)
+        .accept_formats(vec![Format::Json, Format::MsgPack]);
+    for &format in &[Format::Bincode, Format::Json, Format::MsgPack] {
+        let (client_io, server_io) = in_memory();
+        server.bind_server(&core.handle(), server_io, Double);
+        let client_proto: NegotiatedProto<u32, u32> = Proto::with_serializer(2_000_000, format);
+        let client = Client::new(&core.handle(), client_io, &client_proto);
+        assert_eq!(core.run(client.call(21)).unwrap(), 42);
+    }
+}
+
+#[test]
+#[cfg(feature = "lz4")]
+fn lz4() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let options = CompressionOptions::new(Compression::Lz4).threshold(16);
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(100).compression(options);
+    let mut vec = Vec::new();
+    codec.encode((1, vec![7; 1000]), &mut vec).unwrap();
+    assert_eq!(vec[8], FLAG_COMPRESSED);
+    assert!(vec.len() < 117, "Expected a small frame but got {:?}", vec);
+
+    let mut buf = EasyBuf::from(vec);
+    match codec.decode(&mut buf) {
+        Ok(Some((1, Ok(ref v)))) if *v == vec![7; 1000] => {}
+        bad => panic!("Expected the compressed payload, but got {:?}", bad),
+    }
+
+    // The decompressed size limit still applies.
+    let mut sender: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000).compression(options);
+    let mut vec = Vec::new();
+    sender.encode((2, vec![0; 100_000]), &mut vec).unwrap();
+    let options = options.max_decompressed_size(1_000);
+    let mut receiver: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000).compression(options);
+    let mut buf = EasyBuf::from(vec);
+    assert_eq!(receiver.decode(&mut buf).err().unwrap().kind(),
+               io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn encoded_len() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let codecs: Vec<Codec<String, String>> =
+        vec![Codec::new(2_000_000),
+             Codec::with_checksum(2_000_000).frame_markers(true),
+             Codec::with_varint_len(2_000_000).deadlines(true),
+             Codec::new(2_000_000).len_width(LenWidth::U32)];
+    for mut codec in codecs {
+        for message in &["".to_string(), "a".repeat(200)] {
+            let mut vec = Vec::new();
+            codec.encode((1, message.clone()), &mut vec).unwrap();
+            assert_eq!(codec.encoded_len(message), vec.len() as u64);
+        }
+    }
+}
+
+#[test]
+fn decode_eof_mid_frame() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut encoder: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    let mut frame = vec![];
+    encoder.encode((1, vec![1; 100]), &mut frame).unwrap();
+
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    let err = codec.decode_eof(&mut EasyBuf::from(frame[..12].to_vec())).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    assert!(err.to_string().contains("WaitingForLen { id: 1 }"), "{}", err);
+
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    let err = codec.decode_eof(&mut EasyBuf::from(frame[..50].to_vec())).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    assert!(err.to_string().contains("WaitingForPayload { id: 1, needed: 66 }"), "{}", err);
+}
+
+#[test]
+fn checksum_threshold() {
+    use self::frame::FLAG_CHECKSUM;
+    use tokio_core::io::Codec as TokioCodec;
+
+    for &single_pass in &[false, true] {
+        let mut codec: Codec<Vec<u8>, Vec<u8>> =
+            Codec::new(2_000_000).checksum_threshold(64).single_pass(single_pass);
+        let mut small = Vec::new();
+        codec.encode((1, vec![1; 4]), &mut small).unwrap();
+        assert_eq!(small[8], 0);
+        assert_eq!(small.len() as u64, codec.encoded_len(&vec![1; 4]));
+        let mut big = Vec::new();
+        codec.encode((2, vec![2; 100]), &mut big).unwrap();
+        assert_eq!(big[8], FLAG_CHECKSUM);
+        assert_eq!(big.len() as u64, codec.encoded_len(&vec![2; 100]));
+
+        let mut buf = EasyBuf::from(small.iter().chain(&big).cloned().collect::<Vec<_>>());
+        match codec.decode(&mut buf) {
+            Ok(Some((1, Ok(ref v)))) if *v == vec![1; 4] => {}
+            bad => panic!("Expected the small payload, but got {:?}", bad),
+        }
+        match codec.decode(&mut buf) {
+            Ok(Some((2, Ok(ref v)))) if *v == vec![2; 100] => {}
+            bad => panic!("Expected the big payload, but got {:?}", bad),
+        }
+
+        big[20] ^= 1;
+        match codec.decode(&mut EasyBuf::from(big)) {
+            Ok(Some((2, Err(DecodeError::ChecksumMismatch { .. })))) => {}
+            bad => panic!("Expected a checksum mismatch, but got {:?}", bad),
+        }
+    }
+}
+
+#[test]
+fn priorities() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut client: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000)
+        .priorities(true)
+        .priority(|request: &Vec<u8>| request[0]);
+    let mut vec = Vec::new();
+    client.encode((1, vec![5]), &mut vec).unwrap();
+    assert_eq!(vec[8], 5);
+    assert_eq!(vec.len() as u64, client.encoded_len(&vec![5]));
+
+    // The response takes the priority of its request.
+    let mut server: Codec<Vec<u8>, Vec<u8>> =
+        Codec::new(2_000_000).priorities(true).inherit_priorities();
+    match server.decode(&mut EasyBuf::from(vec)) {
+        Ok(Some((1, Ok(ref v)))) if *v == vec![5] => {}
+        bad => panic!("Expected request id = 1, but got {:?}", bad),
+    }
+    let mut vec = Vec::new();
+    server.encode((1, vec![0]), &mut vec).unwrap();
+    assert_eq!(vec[8], 5);
+    assert!(server.priorities.is_empty());
+}
+
+#[test]
+fn unbounded_single_pass() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut bounded: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    let mut unbounded: Codec<Vec<u8>, Vec<u8>> = Codec::new(u64::MAX);
+    let mut expected = Vec::new();
+    let mut vec = Vec::new();
+    for len in 0..3 {
+        bounded.encode((len, vec![len as u8; len as usize]), &mut expected).unwrap();
+        unbounded.encode((len, vec![len as u8; len as usize]), &mut vec).unwrap();
+    }
+    assert_eq!(vec, expected);
+    // Only a single pass estimates the size of the next payload.
+    assert_eq!(bounded.size_estimate, 0);
+    assert_eq!(unbounded.size_estimate, 2);
+
+    let mut unbounded: Codec<Vec<u8>, Vec<u8>> = Codec::unbounded();
+    assert_eq!(unbounded.max_outbound(), u64::MAX);
+    assert_eq!(unbounded.max_inbound(), u64::MAX);
+    let mut vec = Vec::new();
+    unbounded.encode((2, vec![2; 2]), &mut vec).unwrap();
+    assert_eq!(vec, &expected[expected.len() - vec.len()..]);
+}
+
+#[test]
+fn hostile_length() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    // A payload as long as the length field allows, which no read buffer can hold with its
+    // checksum.
+    let mut codec: Codec<Vec<u8>, Vec<u8>> =
+        Codec::unbounded().checksum(true).len_width(LenWidth::U64);
+    let mut buf = EasyBuf::from(vec![0, 0, 0, 0, 0, 0, 0, 1, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+                                     0xff, 0xff]);
+    let e = codec.decode(&mut buf).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn metadata() {
+    use std::sync::Mutex;
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut client: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000)
+        .frame_metadata(true)
+        .metadata(|request: &Vec<u8>| {
+            let mut metadata = Metadata::new();
+            if !request.is_empty() {
+                metadata.insert("trace-id".to_string(), request[0].to_string());
+            }
+            metadata
+        });
+    let mut vec = Vec::new();
+    client.encode((1, vec![5]), &mut vec).unwrap();
+    assert_eq!(vec.len() as u64, client.encoded_len(&vec![5]));
+    // A message without metadata is sent as before.
+    client.encode((2, vec![]), &mut vec).unwrap();
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let hook_received = received.clone();
+    let mut server: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000)
+        .frame_metadata(true)
+        .on_metadata(move |id, metadata| {
+            hook_received.lock().unwrap().push((id, metadata.clone()));
+        });
+    let mut buf = EasyBuf::from(vec);
+    match server.decode(&mut buf) {
+        Ok(Some((1, Ok(ref v)))) if *v == vec![5] => {}
+        bad => panic!("Expected request id = 1, but got {:?}", bad),
+    }
+    match server.decode(&mut buf) {
+        Ok(Some((2, Ok(ref v)))) if v.is_empty() => {}
+        bad => panic!("Expected request id = 2, but got {:?}", bad),
+    }
+    let received = received.lock().unwrap();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].0, 1);
+    assert_eq!(received[0].1.get("trace-id").map(String::as_str), Some("5"));
+}
+
+#[test]
+fn sequence_numbers() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    struct Gaps(Mutex<Vec<(RequestId, u64, u64)>>);
+
+    impl CodecMetrics for Gaps {
+        fn on_sequence_gap(&self, id: RequestId, expected: u64, received: u64) {
+            self.0.lock().unwrap().push((id, expected, received));
+        }
+    }
+
+    let mut client: Codec<u8, u8> = Codec::new(2_000_000)
+        .frame_metadata(true)
+        .sequence_numbers(true);
+    let frames: Vec<_> = (0..4)
+        .map(|id| {
+            let mut vec = Vec::new();
+            client.encode((id, id as u8), &mut vec).unwrap();
+            vec
+        })
+        .collect();
+
+    let gaps = Arc::new(Gaps(Mutex::new(Vec::new())));
+    let mut server: Codec<u8, u8> = Codec::new(2_000_000)
+        .frame_metadata(true)
+        .metrics(gaps.clone());
+    let mut vec = Vec::new();
+    for &i in &[0, 2, 1, 3] {
+        vec.extend_from_slice(&frames[i]);
+    }
+    let requests: Vec<_> = server.decode_all(&vec)
+        .map(|decoded| {
+            let (id, request) = decoded.unwrap();
+            (id, request.unwrap())
+        })
+        .collect();
+    // Dispatch is unaffected.
+    assert_eq!(requests, vec![(0, 0), (2, 2), (1, 1), (3, 3)]);
+    assert_eq!(*gaps.0.lock().unwrap(), vec![(2, 1, 2), (1, 3, 1), (3, 2, 3)]);
+}
+
+#[test]
+fn screen_requests() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut client: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000)
+        .frame_metadata(true)
+        .metadata(|request: &Vec<u8>| {
+            let mut metadata = Metadata::new();
+            metadata.insert("tenant".to_string(), request.len().to_string());
+            metadata
+        });
+    let mut vec = Vec::new();
+    client.encode((1, vec![1]), &mut vec).unwrap();
+    client.encode((2, vec![1, 2, 3]), &mut vec).unwrap();
+
+    let mut server: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000)
+        .frame_metadata(true)
+        .screen_requests(|id, len, metadata| match metadata.get("tenant") {
+            Some(tenant) if tenant == "1" => Err(format!("Tenant of {} ({} bytes)", id, len)),
+            _ => Ok(()),
+        });
+    let mut buf = EasyBuf::from(vec);
+    let reason = match server.decode(&mut buf) {
+        Ok(Some((1, Err(DecodeError::Rejected { reason })))) => reason,
+        bad => panic!("Expected request id = 1 to be rejected, but got {:?}", bad),
+    };
+    assert!(reason.starts_with("Tenant of 1 ("));
+    assert_eq!(server.take_screened(1), Some(reason));
+    // The next frame starts where the rejected one ended.
+    match server.decode(&mut buf) {
+        Ok(Some((2, Ok(ref v)))) if *v == vec![1, 2, 3] => {}
+        bad => panic!("Expected request id = 2, but got {:?}", bad),
+    }
+    assert_eq!(server.take_screened(2), None);
+}
+
+#[test]
+fn screen_requests_by_header() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut client: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    let mut vec = Vec::new();
+    client.encode((1, vec![0; 100]), &mut vec).unwrap();
+    client.encode((2, vec![1, 2, 3]), &mut vec).unwrap();
+
+    let screened = Arc::new(AtomicUsize::new(0));
+    let calls = screened.clone();
+    let mut server: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000)
+        .screen_requests(move |_, len, _| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            if len > 100 {
+                Err(format!("{} bytes", len))
+            } else {
+                Ok(())
+            }
+        });
+    // Without metadata, the request is rejected as soon as its header arrives.
+    let mut buf = EasyBuf::from(vec[..16].to_vec());
+    match server.decode(&mut buf) {
+        Ok(Some((1, Err(DecodeError::Rejected { ref reason })))) if reason == "108 bytes" => {}
+        bad => panic!("Expected request id = 1 to be rejected, but got {:?}", bad),
+    }
+    assert_eq!(server.take_screened(1), Some("108 bytes".to_string()));
+    // Its payload is skipped as it arrives, and the next request is screened only once.
+    buf.get_mut().extend_from_slice(&vec[16..]);
+    match server.decode(&mut buf) {
+        Ok(Some((2, Ok(ref v)))) if *v == vec![1, 2, 3] => {}
+        bad => panic!("Expected request id = 2, but got {:?}", bad),
+    }
+    assert_eq!(screened.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn zero_length_payload() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    #[derive(Debug, Deserialize)]
+    struct Point {
+        x: u32,
+        y: u32,
+    }
+
+    let mut vec = Vec::new();
+    Codec::<(), ()>::new(2_000_000).encode((1, ()), &mut vec).unwrap();
+    // The id, then a length of 0.
+    assert_eq!(vec.len(), 8 + 8);
+
+    let mut unit: Codec<(), ()> = Codec::new(2_000_000);
+    match unit.decode(&mut EasyBuf::from(vec.clone())) {
+        Ok(Some((1, Ok(())))) => {}
+        bad => panic!("Expected request id = 1, but got {:?}", bad),
+    }
+
+    let mut point: Codec<(), Point> = Codec::new(2_000_000);
+    match point.decode(&mut EasyBuf::from(vec)) {
+        Ok(Some((1, Err(DecodeError::EmptyPayload(_))))) => {}
+        bad => panic!("Expected an empty payload error, but got {:?}", bad),
+    }
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn encryption() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let key = Arc::new(EncryptionKey::new(&[7; 32]));
+    let mut client: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000).encryption(key.clone());
+    let mut vec = Vec::new();
+    client.encode((1, vec![5; 16]), &mut vec).unwrap();
+    assert_eq!(vec.len() as u64, client.encoded_len(&vec![5; 16]));
+    // The plaintext doesn't appear on the wire.
+    assert!(!vec.windows(16).any(|window| window == &[5; 16][..]));
+
+    let mut server: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000).encryption(key);
+    match server.decode(&mut EasyBuf::from(vec.clone())) {
+        Ok(Some((1, Ok(ref v)))) if *v == vec![5; 16] => {}
+        bad => panic!("Expected request id = 1, but got {:?}", bad),
+    }
+
+    // A flipped bit in the payload.
+    let mut tampered = vec.clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    match server.decode(&mut EasyBuf::from(tampered)) {
+        Ok(Some((1, Err(DecodeError::Unauthenticated)))) => {}
+        bad => panic!("Expected an authentication failure, but got {:?}", bad),
+    }
+
+    // The payload moved to another id.
+    let mut moved = vec.clone();
+    moved[7] = 2;
+    match server.decode(&mut EasyBuf::from(moved)) {
+        Ok(Some((_, Err(DecodeError::Unauthenticated)))) => {}
+        bad => panic!("Expected an authentication failure, but got {:?}", bad),
+    }
+
+    // Another key.
+    let mut other: Codec<Vec<u8>, Vec<u8>> =
+        Codec::new(2_000_000).encryption(Arc::new(EncryptionKey::new(&[8; 32])));
+    match other.decode(&mut EasyBuf::from(vec)) {
+        Ok(Some((1, Err(DecodeError::Unauthenticated)))) => {}
+        bad => panic!("Expected an authentication failure, but got {:?}", bad),
+    }
+}
+
+#[test]
+fn decode_all() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    let mut vec = Vec::new();
+    for id in 1..4 {
+        codec.encode((id, vec![id as u8]), &mut vec).unwrap();
+    }
+    let ids: Vec<_> = codec.decode_all(&vec).map(|frame| frame.unwrap().0).collect();
+    assert_eq!(ids, vec![1, 2, 3]);
+
+    // A truncated frame ends the frames with an error.
+    let frames: Vec<_> = codec.decode_all(&vec[..vec.len() - 1]).collect();
+    assert_eq!(frames.len(), 3);
+    assert_eq!(frames[2].as_ref().err().unwrap().kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[cfg(feature = "capture")]
+#[test]
+fn capture() {
+    use tokio_core::io::Codec as TokioCodec;
+
+    /// A sink whose bytes the test can read while the codec owns it.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut vec = Vec::new();
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    for id in 1..4 {
+        codec.encode((id, vec![id as u8; 10]), &mut vec).unwrap();
+    }
+
+    // The bytes arrive in pieces that split frames, and each is copied once.
+    let sink = Shared::default();
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000).capture(sink.clone());
+    let mut buf = EasyBuf::new();
+    let mut ids = vec![];
+    for chunk in vec.chunks(15) {
+        buf.get_mut().extend_from_slice(chunk);
+        while let Some((id, _)) = codec.decode(&mut buf).unwrap() {
+            ids.push(id);
+        }
+    }
+    assert_eq!(ids, vec![1, 2, 3]);
+    let captured = sink.0.lock().unwrap().clone();
+    assert_eq!(captured, vec);
+
+    let mut replayed: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    let frames: Vec<_> = replayed.replay(&captured[..])
+        .unwrap()
+        .map(|frame| {
+            let (id, message) = frame.unwrap();
+            (id, message.unwrap())
+        })
+        .collect();
+    assert_eq!(frames, vec![(1, vec![1; 10]), (2, vec![2; 10]), (3, vec![3; 10])]);
+}
+
+#[test]
+fn default_response_timeout() {
+    use tokio_core::reactor::Core;
+
+    let core = Core::new().unwrap();
+    let timeout = |proto: Proto<u32, u32>| {
+        proto.default_timeouts(&core.handle()).response_timeout.map(|options| options.timeout)
+    };
+    assert_eq!(timeout(Proto::new(1024)), Some(Duration::from_secs(30)));
+    assert_eq!(timeout(Proto::new(1024).response_timeout(&core.handle(), Duration::from_secs(1))),
+               Some(Duration::from_secs(1)));
+    assert_eq!(timeout(Proto::new(1024).no_response_timeout()), None);
+}
@@ -0,0 +1,525 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+use futures::sync::oneshot;
+use futures::task::{self, Task};
+use serde;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error as StdError;
+use std::{fmt, io, mem};
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use super::{BincodeSerializer, DecodeError, PayloadSerializer, handshake};
+use super::frame::{CodecState, Frame, FrameOptions, MIN_CONTROL_ID};
+use super::handshake::HandshakeOptions;
+use tokio_core::io::{Codec, EasyBuf, Framed, Io};
+use tokio_core::reactor;
+use tokio_proto::streaming::multiplex::RequestId;
+use tokio_service::Service;
+
+/// Set in the id of a frame that answers a request, so that the ids of the requests each side
+/// originates are their own: a response carries the id of the request it answers, which was
+/// assigned by the side receiving the response. Request ids must be below this bit.
+pub const RESPONSE_BIT: RequestId = 1 << 62;
+
+/// What a frame of a `PeerCodec` holds.
+#[derive(Debug)]
+pub enum PeerMessage<Req, Resp> {
+    /// A request originated by the side that sent the frame.
+    Request(Req),
+    /// The response to a request originated by the side that received the frame.
+    Response(Resp),
+}
+
+/// A tokio `Codec` for connections on which both sides make requests and answer them, framed
+/// like the payloads of a `Codec`.
+///
+/// Requests and responses share the id space, so a response is told apart from a request with
+/// the same id by `RESPONSE_BIT`. Compression isn't supported.
+pub struct PeerCodec<Req, Resp, S = BincodeSerializer> {
+    max_payload_size: u64,
+    frame: FrameOptions,
+    serializer: S,
+    state: CodecState,
+    _phantom_data: PhantomData<(Req, Resp)>,
+}
+
+impl<Req, Resp, S> PeerCodec<Req, Resp, S>
+    where S: PayloadSerializer + Default
+{
+    /// Returns a new `PeerCodec` that rejects payloads larger than `max_payload_size` bytes.
+    pub fn new(max_payload_size: u64) -> Self {
+        PeerCodec::with_serializer(max_payload_size, S::default())
+    }
+}
+
+impl<Req, Resp, S> PeerCodec<Req, Resp, S>
+    where S: PayloadSerializer
+{
+    /// Returns a new `PeerCodec` that uses `serializer` for payloads, rejecting payloads
+    /// larger than `max_payload_size` bytes.
+    pub fn with_serializer(max_payload_size: u64, serializer: S) -> Self {
+        PeerCodec {
+            max_payload_size: max_payload_size,
+            frame: FrameOptions::default(),
+            serializer: serializer,
+            state: CodecState::Id,
+            _phantom_data: PhantomData,
+        }
+    }
+
+    /// Set whether payloads are followed by their CRC32. The peer must use the same setting.
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.frame.checksum = checksum;
+        self
+    }
+
+    /// Appends a frame with id `id`, holding `message`, to `buf`.
+    fn encode_payload<M>(&self, id: RequestId, message: &M, buf: &mut Vec<u8>) -> io::Result<()>
+        where M: serde::Serialize
+    {
+        let payload_size = self.serializer.serialized_size(message);
+        if payload_size > self.max_payload_size {
+            return Err(super::too_big(Some(id), payload_size, self.max_payload_size));
+        }
+        let frame_start = buf.len();
+        self.frame.write_header(buf, id, 0, 0, 0, payload_size);
+        let payload_start = buf.len();
+        if let Err(e) = self.serializer.serialize_into(buf, message) {
+            buf.truncate(frame_start);
+            return Err(e);
+        }
+        self.frame.write_trailer(buf, payload_start);
+        Ok(())
+    }
+
+    /// Deserializes the payload of `frame`, if it was read.
+    fn deserialize<T>(&self,
+                      frame: Result<Frame, DecodeError<S::Error>>)
+                      -> Result<T, DecodeError<S::Error>>
+        where T: serde::Deserialize
+    {
+        let frame = frame?;
+        match self.serializer.deserialize_slice(&frame.payload) {
+            Ok(message) => Ok(message),
+            Err(e) if frame.payload.is_empty() => Err(DecodeError::EmptyPayload(e)),
+            Err(e) => Err(DecodeError::Deserialize(e)),
+        }
+    }
+}
+
+impl<Req, Resp, S> Codec for PeerCodec<Req, Resp, S>
+    where Req: serde::Serialize + serde::Deserialize,
+          Resp: serde::Serialize + serde::Deserialize,
+          S: PayloadSerializer
+{
+    type Out = (RequestId, PeerMessage<Req, Resp>);
+    type In = (RequestId,
+               PeerMessage<Result<Req, DecodeError<S::Error>>,
+                           Result<Resp, DecodeError<S::Error>>>);
+
+    fn encode(&mut self, (id, message): Self::Out, buf: &mut Vec<u8>) -> io::Result<()> {
+        if id >= RESPONSE_BIT {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("Request id {} is too large for a peer \
+                                               connection (max is {})",
+                                              id,
+                                              RESPONSE_BIT - 1)));
+        }
+        match message {
+            PeerMessage::Request(request) => self.encode_payload(id, &request, buf),
+            PeerMessage::Response(response) => {
+                self.encode_payload(id | RESPONSE_BIT, &response, buf)
+            }
+        }
+    }
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Self::In>> {
+        loop {
+            let (id, frame) = match self.state.decode(&self.frame, self.max_payload_size, buf)? {
+                Some(decoded) => decoded,
+                None => return Ok(None),
+            };
+            if id >= MIN_CONTROL_ID {
+                // Heartbeats and the other control frames of a `Codec`'s transport aren't sent
+                // by peers.
+                trace!("--> Ignoring control frame {}.", id);
+                continue;
+            }
+            let message = if id & RESPONSE_BIT != 0 {
+                PeerMessage::Response(self.deserialize(frame))
+            } else {
+                PeerMessage::Request(self.deserialize(frame))
+            };
+            return Ok(Some((id & !RESPONSE_BIT, message)));
+        }
+    }
+}
+
+/// The calls made with a `PeerClient` that its connection hasn't sent yet.
+struct CallState<Req, Resp, E> {
+    queued: Vec<(Req, oneshot::Sender<Result<Resp, DecodeError<E>>>)>,
+    /// The task of the connection, woken to send the calls.
+    task: Option<Task>,
+    /// Set once the connection has closed, after which calls fail.
+    closed: bool,
+}
+
+/// Shared by a `PeerClient` and its connection.
+type Calls<Req, Resp, E> = Arc<Mutex<CallState<Req, Resp, E>>>;
+
+/// Binds connections on which each side is both a client and a server, such as between the
+/// members of a mesh, using a `PeerCodec`.
+///
+/// Each side answers the requests of the other with its service, while making requests of its
+/// own with the `PeerClient` returned by `bind_peer`. Requests are answered in whatever order
+/// their responses are ready.
+pub struct PeerProto<Req, Resp, S = BincodeSerializer> {
+    max_payload_size: u64,
+    checksum: bool,
+    handshake: HandshakeOptions,
+    serializer: S,
+    _phantom_data: PhantomData<(Req, Resp)>,
+}
+
+impl<Req, Resp, S> PeerProto<Req, Resp, S>
+    where S: PayloadSerializer + Default
+{
+    /// Returns a new `PeerProto` that rejects payloads larger than `max_payload_size` bytes.
+    pub fn new(max_payload_size: u64) -> Self {
+        PeerProto::with_serializer(max_payload_size, S::default())
+    }
+}
+
+impl<Req, Resp, S> PeerProto<Req, Resp, S>
+    where S: PayloadSerializer
+{
+    /// Returns a new `PeerProto` whose codecs serialize payloads with `serializer`, rejecting
+    /// payloads larger than `max_payload_size` bytes.
+    pub fn with_serializer(max_payload_size: u64, serializer: S) -> Self {
+        PeerProto {
+            max_payload_size: max_payload_size,
+            checksum: false,
+            handshake: HandshakeOptions::default(),
+            serializer: serializer,
+            _phantom_data: PhantomData,
+        }
+    }
+
+    /// Set whether payloads are followed by their CRC32. The peer must use the same setting.
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
+}
+
+impl<Req, Resp, S> PeerProto<Req, Resp, S>
+    where Req: serde::Serialize + serde::Deserialize + 'static,
+          Resp: serde::Serialize + serde::Deserialize + 'static,
+          S: PayloadSerializer + Clone + 'static,
+          S::Error: 'static
+{
+    fn codec(&self) -> PeerCodec<Req, Resp, S> {
+        PeerCodec::with_serializer(self.max_payload_size, self.serializer.clone())
+            .checksum(self.checksum)
+    }
+
+    /// Binds `io` on the reactor of `handle`, answering the requests of the other side with
+    /// `service` and returning the client to make requests of it with. `initiator` says whether
+    /// this side opened the connection, which decides its part in the handshake; exactly one
+    /// side must be the initiator. The handshake runs in the background; if it fails, so do the
+    /// calls.
+    ///
+    /// If the service fails, the connection is closed.
+    pub fn bind_peer<T, Svc>(&self,
+                             handle: &reactor::Handle,
+                             io: T,
+                             service: Svc,
+                             initiator: bool)
+                             -> PeerClient<Req, Resp, S::Error>
+        where T: Io + 'static,
+              Svc: Service<Request = Result<Req, DecodeError<S::Error>>,
+                           Response = Resp,
+                           Error = io::Error> + 'static,
+              Svc::Future: 'static
+    {
+        let calls = Arc::new(Mutex::new(CallState {
+            queued: vec![],
+            task: None,
+            closed: false,
+        }));
+        let handshake = if initiator {
+            handshake::client(io, self.handshake.clone())
+        } else {
+            handshake::server(io, self.handshake.clone())
+        };
+        let codec = self.codec();
+        let task_calls = calls.clone();
+        let closed_calls = calls.clone();
+        handle.spawn(handshake.and_then(move |(io, _)| {
+                PeerTask {
+                    transport: io.framed(codec),
+                    service: service,
+                    calls: task_calls,
+                    next_id: 0,
+                    awaiting: HashMap::new(),
+                    in_flight: vec![],
+                    outbound: VecDeque::new(),
+                    eof: false,
+                }
+            })
+            .then(move |result| {
+                let mut calls = closed_calls.lock().unwrap();
+                calls.closed = true;
+                calls.queued.clear();
+                calls.task = None;
+                if let Err(e) = result {
+                    warn!("Peer connection failed: {}", e);
+                }
+                Ok(())
+            }));
+        PeerClient { calls: calls }
+    }
+}
+
+impl<Req, Resp, S> Clone for PeerProto<Req, Resp, S>
+    where S: Clone
+{
+    fn clone(&self) -> Self {
+        PeerProto {
+            max_payload_size: self.max_payload_size,
+            checksum: self.checksum,
+            handshake: self.handshake.clone(),
+            serializer: self.serializer.clone(),
+            _phantom_data: PhantomData,
+        }
+    }
+}
+
+/// Drives a connection bound with `PeerProto::bind_peer`, until the other side closes it.
+struct PeerTask<T, Req, Resp, S, Svc>
+    where S: PayloadSerializer,
+          Svc: Service
+{
+    transport: Framed<T, PeerCodec<Req, Resp, S>>,
+    service: Svc,
+    calls: Calls<Req, Resp, S::Error>,
+    /// The id of the next request this side originates.
+    next_id: RequestId,
+    /// The callers waiting for the responses to the requests this side sent, by id.
+    awaiting: HashMap<RequestId, oneshot::Sender<Result<Resp, DecodeError<S::Error>>>>,
+    /// The responses being produced to the requests of the other side.
+    in_flight: Vec<(RequestId, Svc::Future)>,
+    /// The frames waiting for the transport to accept them.
+    outbound: VecDeque<(RequestId, PeerMessage<Req, Resp>)>,
+    /// Set once the other side closed its end, after which the responses in flight are still
+    /// sent.
+    eof: bool,
+}
+
+impl<T, Req, Resp, S, Svc> Future for PeerTask<T, Req, Resp, S, Svc>
+    where T: Io,
+          Req: serde::Serialize + serde::Deserialize,
+          Resp: serde::Serialize + serde::Deserialize,
+          S: PayloadSerializer,
+          Svc: Service<Request = Result<Req, DecodeError<S::Error>>,
+                       Response = Resp,
+                       Error = io::Error>
+{
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        while !self.eof {
+            match self.transport.poll()? {
+                Async::Ready(Some((id, PeerMessage::Request(request)))) => {
+                    let response = self.service.call(request);
+                    self.in_flight.push((id, response));
+                }
+                Async::Ready(Some((id, PeerMessage::Response(response)))) => {
+                    match self.awaiting.remove(&id) {
+                        // The caller may have stopped waiting.
+                        Some(tx) => {
+                            let _ = tx.send(response);
+                        }
+                        None => warn!("Ignoring a response to request {}, which wasn't sent.", id),
+                    }
+                }
+                Async::Ready(None) => {
+                    trace!("The peer closed the connection.");
+                    self.eof = true;
+                    // No responses will arrive for the requests still awaiting one.
+                    self.awaiting.clear();
+                }
+                Async::NotReady => break,
+            }
+        }
+
+        let mut i = 0;
+        while i < self.in_flight.len() {
+            match self.in_flight[i].1.poll()? {
+                Async::Ready(response) => {
+                    let (id, _) = self.in_flight.swap_remove(i);
+                    self.outbound.push_back((id, PeerMessage::Response(response)));
+                }
+                Async::NotReady => i += 1,
+            }
+        }
+
+        if !self.eof {
+            let queued = {
+                let mut calls = self.calls.lock().unwrap();
+                calls.task = Some(task::park());
+                mem::replace(&mut calls.queued, vec![])
+            };
+            for (request, tx) in queued {
+                // The ids wrap, skipping those of calls still awaiting their response.
+                let mut id = self.next_id;
+                while self.awaiting.contains_key(&id) {
+                    id = (id + 1) % RESPONSE_BIT;
+                }
+                self.next_id = (id + 1) % RESPONSE_BIT;
+                self.awaiting.insert(id, tx);
+                self.outbound.push_back((id, PeerMessage::Request(request)));
+            }
+        }
+
+        while let Some(frame) = self.outbound.pop_front() {
+            if let AsyncSink::NotReady(frame) = self.transport.start_send(frame)? {
+                self.outbound.push_front(frame);
+                break;
+            }
+        }
+        let flushed = self.transport.poll_complete()?.is_ready();
+        if self.eof && flushed && self.in_flight.is_empty() && self.outbound.is_empty() {
+            return Ok(Async::Ready(()));
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+/// Makes requests of the other side of a connection bound with `PeerProto::bind_peer`. Cloning
+/// a `PeerClient` shares the connection.
+pub struct PeerClient<Req, Resp, E> {
+    calls: Calls<Req, Resp, E>,
+}
+
+impl<Req, Resp, E> PeerClient<Req, Resp, E> {
+    /// Sends `request`, returning a future of the response.
+    pub fn call(&self, request: Req) -> PeerResponse<Resp, E> {
+        let (tx, rx) = oneshot::channel();
+        let mut calls = self.calls.lock().unwrap();
+        if !calls.closed {
+            calls.queued.push((request, tx));
+            if let Some(task) = calls.task.take() {
+                task.unpark();
+            }
+        }
+        PeerResponse { inner: rx }
+    }
+}
+
+impl<Req, Resp, E> Clone for PeerClient<Req, Resp, E> {
+    fn clone(&self) -> Self {
+        PeerClient { calls: self.calls.clone() }
+    }
+}
+
+impl<Req, Resp, E> fmt::Debug for PeerClient<Req, Resp, E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PeerClient {{ .. }}")
+    }
+}
+
+/// The response to a `PeerClient::call`.
+///
+/// A response that can't be decoded fails with the `DecodeError` converted by
+/// `DecodeError::into_io`.
+pub struct PeerResponse<Resp, E> {
+    inner: oneshot::Receiver<Result<Resp, DecodeError<E>>>,
+}
+
+impl<Resp, E> Future for PeerResponse<Resp, E>
+    where E: StdError + Send + Sync + 'static
+{
+    type Item = Resp;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Resp, io::Error> {
+        let response = match self.inner.poll() {
+            Ok(Async::Ready(response)) => response,
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Err(_) => {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                          "The connection to the peer closed before the \
+                                           response arrived"))
+            }
+        };
+        response.map(Async::Ready).map_err(DecodeError::into_io)
+    }
+}
+
+impl<Resp, E> fmt::Debug for PeerResponse<Resp, E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PeerResponse {{ .. }}")
+    }
+}
+
+#[test]
+fn codec_round_trip() {
+    let mut codec: PeerCodec<u32, String> = PeerCodec::new(2_000_000);
+    let mut vec = Vec::new();
+    codec.encode((3, PeerMessage::Request(7)), &mut vec).unwrap();
+    codec.encode((3, PeerMessage::Response("seven".to_string())), &mut vec).unwrap();
+    assert_eq!(codec.encode((RESPONSE_BIT, PeerMessage::Request(0)), &mut vec)
+                   .err()
+                   .unwrap()
+                   .kind(),
+               io::ErrorKind::InvalidInput);
+
+    let mut buf = EasyBuf::from(vec);
+    match codec.decode(&mut buf) {
+        Ok(Some((3, PeerMessage::Request(Ok(7))))) => {}
+        bad => panic!("Expected request 3, but got {:?}", bad),
+    }
+    match codec.decode(&mut buf) {
+        Ok(Some((3, PeerMessage::Response(Ok(ref s))))) if s == "seven" => {}
+        bad => panic!("Expected the response to request 3, but got {:?}", bad),
+    }
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+}
+
+#[test]
+fn calls_both_ways() {
+    use bincode;
+    use futures::future;
+    use super::in_memory;
+    use tokio_core::reactor::Core;
+
+    /// Answers with `n` plus the amount.
+    struct Add(u32);
+
+    impl Service for Add {
+        type Request = Result<u32, DecodeError<bincode::Error>>;
+        type Response = u32;
+        type Error = io::Error;
+        type Future = future::FutureResult<u32, io::Error>;
+
+        fn call(&self, request: Self::Request) -> Self::Future {
+            future::result(request.map(|n| n + self.0).map_err(DecodeError::into_io))
+        }
+    }
+
+    let mut core = Core::new().unwrap();
+    let (a_io, b_io) = in_memory();
+    let proto: PeerProto<u32, u32> = PeerProto::new(2_000_000).checksum(true);
+    let a = proto.bind_peer(&core.handle(), a_io, Add(1), true);
+    let b = proto.bind_peer(&core.handle(), b_io, Add(100), false);
+    // Both sides start with request id 0, so the ids collide without the response bit.
+    let calls = future::join_all((0..5).map(|n| a.call(n).join(b.call(n))).collect::<Vec<_>>());
+    let responses = core.run(calls).unwrap();
+    assert_eq!(responses, (0..5).map(|n| (n + 100, n + 1)).collect::<Vec<_>>());
+}
@@ -0,0 +1,142 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use futures::{Future, Poll};
+use futures::sync::oneshot;
+use futures::task::{self, Task};
+use std::{fmt, io, mem};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// What a `Client` and the codec of its connection share about pings.
+#[derive(Default)]
+pub struct PingState {
+    /// The senders of the `PingFuture`s whose ping hasn't been sent yet.
+    requested: Vec<oneshot::Sender<Duration>>,
+    /// The task of the connection's transport, woken to send a ping.
+    task: Option<Task>,
+    /// The round-trip time measured last, by a ping or a heartbeat.
+    rtt: Option<Duration>,
+    /// Set once the connection has closed, after which no pings are sent.
+    closed: bool,
+}
+
+/// Shared by a `Client` and the codec of its connection.
+pub type Pings = Arc<Mutex<PingState>>;
+
+/// Asks the connection of `pings` to send a ping, returning a future of its round-trip time.
+pub fn request(pings: &Pings) -> PingFuture {
+    let (tx, rx) = oneshot::channel();
+    let mut state = pings.lock().unwrap();
+    if state.closed {
+        return PingFuture { inner: rx };
+    }
+    state.requested.push(tx);
+    if let Some(task) = state.task.take() {
+        task.unpark();
+    }
+    PingFuture { inner: rx }
+}
+
+/// The round-trip time measured last on the connection of `pings`, if any.
+pub fn rtt(pings: &Pings) -> Option<Duration> {
+    pings.lock().unwrap().rtt
+}
+
+/// The codec's end of `Pings`. Dropping it fails the pings never answered.
+pub struct PingHandle {
+    pings: Pings,
+    /// The timestamps of pings are microseconds since this instant.
+    epoch: Instant,
+    /// The senders of the `PingFuture`s whose ping was sent, completed by the next pong.
+    awaiting: Vec<oneshot::Sender<Duration>>,
+}
+
+impl PingHandle {
+    /// Returns the codec's end of `pings`.
+    pub fn new(pings: Pings) -> Self {
+        PingHandle {
+            pings: pings,
+            epoch: Instant::now(),
+            awaiting: vec![],
+        }
+    }
+
+    /// Returns the timestamp to send in a ping if the client asked for one, registering the
+    /// current task to be woken when it does. If `supported` is false, because the peer
+    /// predates pings, the pings asked for fail instead.
+    pub fn poll_requested(&mut self, supported: bool) -> Option<u64> {
+        let mut state = self.pings.lock().unwrap();
+        state.task = Some(task::park());
+        if state.requested.is_empty() {
+            return None;
+        }
+        let requested = mem::replace(&mut state.requested, vec![]);
+        if !supported {
+            return None;
+        }
+        self.awaiting.extend(requested);
+        Some(micros(self.epoch.elapsed()))
+    }
+
+    /// Completes the pings awaiting a pong with the time since `timestamp`, which the server
+    /// echoed.
+    pub fn pong(&mut self, timestamp: u64) {
+        let now = micros(self.epoch.elapsed());
+        let rtt = micros_duration(now.saturating_sub(timestamp));
+        self.record(rtt);
+        for ping in self.awaiting.drain(..) {
+            // The client may have stopped waiting.
+            let _ = ping.send(rtt);
+        }
+    }
+
+    /// Records a round-trip time measured some other way, such as by a heartbeat.
+    pub fn record(&self, rtt: Duration) {
+        trace!("Measured a round-trip time of {:?}", rtt);
+        self.pings.lock().unwrap().rtt = Some(rtt);
+    }
+}
+
+impl Drop for PingHandle {
+    fn drop(&mut self) {
+        let mut state = self.pings.lock().unwrap();
+        state.requested.clear();
+        state.task = None;
+        state.closed = true;
+    }
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + (duration.subsec_nanos() / 1_000) as u64
+}
+
+fn micros_duration(micros: u64) -> Duration {
+    Duration::new(micros / 1_000_000, (micros % 1_000_000) as u32 * 1_000)
+}
+
+/// A future of the round-trip time of a ping sent with `Client::ping`.
+pub struct PingFuture {
+    inner: oneshot::Receiver<Duration>,
+}
+
+impl Future for PingFuture {
+    type Item = Duration;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Duration, io::Error> {
+        self.inner.poll().map_err(|_| {
+            io::Error::new(io::ErrorKind::Other,
+                           "The ping was not answered: the connection closed, or the server \
+                            predates pings")
+        })
+    }
+}
+
+impl fmt::Debug for PingFuture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PingFuture {{ .. }}")
+    }
+}
@@ -0,0 +1,457 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use crc::crc32;
+use futures::Future;
+use serde;
+use std::{cmp, io, mem};
+use std::marker::PhantomData;
+use super::{BincodeSerializer, DecodeError, PayloadSerializer, handshake};
+#[cfg(feature = "zstd")]
+use super::Compression;
+use super::compression::CompressionContext;
+use super::handshake::HandshakeOptions;
+use tokio_core::io::{Codec, EasyBuf, Framed, Io};
+use tokio_proto::pipeline::{ClientProto, ServerProto};
+
+/// A tokio `Codec` for pipelined protocols, in which responses are sent in the order of their
+/// requests, so frames carry no request id.
+///
+/// Every frame is an 8-byte big-endian length followed by the payload, and its CRC32 if
+/// checksums are enabled. That's 8 bytes less per frame than a `Codec`, for services that
+/// answer requests strictly in order. There are no heartbeats, and payloads are only
+/// compressed with a context for the whole connection; see `stream_compression`.
+pub struct PipelineCodec<Encode, Decode, S = BincodeSerializer> {
+    max_payload_size: u64,
+    checksum: bool,
+    /// Compresses every payload, if set.
+    compression: Option<CompressionContext>,
+    serializer: S,
+    state: State,
+    _phantom_data: PhantomData<(Encode, Decode)>,
+}
+
+/// What a `PipelineCodec` is reading.
+#[derive(Clone, Copy, Debug)]
+enum State {
+    Len,
+    Payload { len: u64 },
+    /// Discarding the rest of a frame whose payload was too large.
+    Skip { remaining: u64 },
+}
+
+impl<Encode, Decode, S> PipelineCodec<Encode, Decode, S>
+    where S: PayloadSerializer + Default
+{
+    /// Returns a new `PipelineCodec` that rejects payloads larger than `max_payload_size` bytes.
+    pub fn new(max_payload_size: u64) -> Self {
+        PipelineCodec::with_serializer(max_payload_size, S::default())
+    }
+}
+
+impl<Encode, Decode, S> PipelineCodec<Encode, Decode, S>
+    where S: PayloadSerializer
+{
+    /// Returns a new `PipelineCodec` that uses `serializer` for payloads, rejecting payloads
+    /// larger than `max_payload_size` bytes.
+    pub fn with_serializer(max_payload_size: u64, serializer: S) -> Self {
+        PipelineCodec {
+            max_payload_size: max_payload_size,
+            checksum: false,
+            compression: None,
+            serializer: serializer,
+            state: State::Len,
+            _phantom_data: PhantomData,
+        }
+    }
+
+    /// Set whether payloads are followed by their CRC32. The peer must use the same setting.
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Compress every payload with zstd, using the last `window` bytes of the payloads sent
+    /// before it as a dictionary, and decompress payloads with those received, failing on a
+    /// payload that inflates to more than `max_decompressed_size` bytes. Messages that repeat
+    /// the strings of earlier ones then compress far better than they do on their own, which
+    /// pays off for streams of small, similar messages that per-payload compression barely
+    /// shrinks. The peer must use the same window. Only built with the `zstd` feature.
+    ///
+    /// This only works because a pipeline sends and receives payloads strictly in order. A
+    /// payload that can't be used fails the connection, since the dictionaries would no longer
+    /// match: one that is too large, fails its checksum, or doesn't decompress. The max
+    /// payload size bounds the compressed payload.
+    #[cfg(feature = "zstd")]
+    pub fn stream_compression(mut self, window: usize, max_decompressed_size: u64) -> Self {
+        self.compression = Some(CompressionContext::new(window, max_decompressed_size));
+        self
+    }
+
+    /// The number of bytes that follow a payload.
+    fn trailer_len(&self) -> u64 {
+        if self.checksum {
+            mem::size_of::<u32>() as u64
+        } else {
+            0
+        }
+    }
+}
+
+impl<Encode, Decode, S> Codec for PipelineCodec<Encode, Decode, S>
+    where Encode: serde::Serialize,
+          Decode: serde::Deserialize,
+          S: PayloadSerializer
+{
+    type Out = Encode;
+    type In = Result<Decode, DecodeError<S::Error>>;
+
+    fn encode(&mut self, message: Encode, buf: &mut Vec<u8>) -> io::Result<()> {
+        if let Some(ref mut compression) = self.compression {
+            let mut payload = Vec::new();
+            self.serializer.serialize_into(&mut payload, &message)?;
+            let payload = compression.compress(&payload)?;
+            if payload.len() as u64 > self.max_payload_size {
+                return Err(super::too_big(None, payload.len() as u64, self.max_payload_size));
+            }
+            buf.write_u64::<BigEndian>(payload.len() as u64).unwrap();
+            buf.extend_from_slice(&payload);
+            if self.checksum {
+                buf.write_u32::<BigEndian>(crc32::checksum_ieee(&payload)).unwrap();
+            }
+            return Ok(());
+        }
+        let payload_size = self.serializer.serialized_size(&message);
+        if payload_size > self.max_payload_size {
+            return Err(super::too_big(None, payload_size, self.max_payload_size));
+        }
+        let frame_start = buf.len();
+        buf.write_u64::<BigEndian>(payload_size).unwrap();
+        let payload_start = buf.len();
+        if let Err(e) = self.serializer.serialize_into(buf, &message) {
+            buf.truncate(frame_start);
+            return Err(e);
+        }
+        if self.checksum {
+            let checksum = crc32::checksum_ieee(&buf[payload_start..]);
+            buf.write_u32::<BigEndian>(checksum).unwrap();
+        }
+        Ok(())
+    }
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Self::In>> {
+        loop {
+            match self.state {
+                State::Len => {
+                    if buf.len() < mem::size_of::<u64>() {
+                        return Ok(None);
+                    }
+                    let len = BigEndian::read_u64(buf.drain_to(mem::size_of::<u64>()).as_slice());
+                    trace!("--> Parsed payload length = {}", len);
+                    if len > self.max_payload_size {
+                        warn!("Rejecting too-big packet of size {} (max is {})",
+                              len,
+                              self.max_payload_size);
+                        if self.compression.is_some() {
+                            return Err(super::too_big(None, len, self.max_payload_size));
+                        }
+                        self.state = State::Skip {
+                            remaining: len.saturating_add(self.trailer_len()),
+                        };
+                        return Ok(Some(Err(DecodeError::PayloadTooLarge {
+                            len: len,
+                            max: self.max_payload_size,
+                        })));
+                    }
+                    self.state = State::Payload { len: len };
+                }
+                State::Payload { len } => {
+                    if (buf.len() as u64) < len + self.trailer_len() {
+                        return Ok(None);
+                    }
+                    self.state = State::Len;
+                    let payload = buf.drain_to(len as usize);
+                    if self.checksum {
+                        let checksum = buf.drain_to(mem::size_of::<u32>());
+                        let expected = BigEndian::read_u32(checksum.as_slice());
+                        let actual = crc32::checksum_ieee(payload.as_slice());
+                        if actual != expected {
+                            if self.compression.is_some() {
+                                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                          format!("Checksum mismatch on a \
+                                                                   compressed stream: \
+                                                                   expected {:#x}, got {:#x}",
+                                                                  expected,
+                                                                  actual)));
+                            }
+                            return Ok(Some(Err(DecodeError::ChecksumMismatch {
+                                expected: expected,
+                                actual: actual,
+                            })));
+                        }
+                    }
+                    let payload = match self.compression {
+                        Some(ref mut compression) => {
+                            EasyBuf::from(compression.decompress(payload.as_slice())?)
+                        }
+                        None => payload,
+                    };
+                    return Ok(Some(self.serializer
+                        .deserialize_slice(&payload)
+                        .map_err(DecodeError::Deserialize)));
+                }
+                State::Skip { remaining } => {
+                    let skipped = cmp::min(remaining, buf.len() as u64);
+                    buf.drain_to(skipped as usize);
+                    if skipped < remaining {
+                        self.state = State::Skip { remaining: remaining - skipped };
+                        return Ok(None);
+                    }
+                    self.state = State::Len;
+                }
+            }
+        }
+    }
+}
+
+/// Implements the `pipeline::ServerProto` and `pipeline::ClientProto` traits using a
+/// `PipelineCodec`.
+///
+/// tokio-proto answers pipelined requests one at a time, in order, so a slow request delays
+/// the ones behind it; a `Proto` is better suited to services with requests of mixed latency.
+pub struct PipelineProto<Encode, Decode, S = BincodeSerializer> {
+    max_payload_size: u64,
+    checksum: bool,
+    /// The window and max decompressed size of stream compression, if enabled.
+    stream_compression: Option<(usize, u64)>,
+    handshake: HandshakeOptions,
+    serializer: S,
+    _phantom_data: PhantomData<(Encode, Decode)>,
+}
+
+impl<Encode, Decode, S> PipelineProto<Encode, Decode, S>
+    where S: PayloadSerializer + Default
+{
+    /// Returns a new `PipelineProto` that rejects payloads larger than `max_payload_size` bytes.
+    pub fn new(max_payload_size: u64) -> Self {
+        PipelineProto::with_serializer(max_payload_size, S::default())
+    }
+}
+
+impl<Encode, Decode, S> PipelineProto<Encode, Decode, S>
+    where S: PayloadSerializer
+{
+    /// Returns a new `PipelineProto` whose codecs serialize payloads with `serializer`,
+    /// rejecting payloads larger than `max_payload_size` bytes.
+    pub fn with_serializer(max_payload_size: u64, serializer: S) -> Self {
+        PipelineProto {
+            max_payload_size: max_payload_size,
+            checksum: false,
+            stream_compression: None,
+            handshake: HandshakeOptions::default(),
+            serializer: serializer,
+            _phantom_data: PhantomData,
+        }
+    }
+
+    /// Set whether payloads are followed by their CRC32. The peer must use the same setting.
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Compress every payload with a zstd context kept for the whole connection; see
+    /// `PipelineCodec::stream_compression`. The handshake fails unless the peer enables it too.
+    /// Only built with the `zstd` feature.
+    #[cfg(feature = "zstd")]
+    pub fn stream_compression(mut self, window: usize, max_decompressed_size: u64) -> Self {
+        self.stream_compression = Some((window, max_decompressed_size));
+        self.handshake.compression = Some(Compression::Zstd);
+        self.handshake.require_compression = true;
+        self
+    }
+}
+
+impl<Encode, Decode, S> PipelineProto<Encode, Decode, S>
+    where S: PayloadSerializer + Clone
+{
+    fn codec(&self) -> PipelineCodec<Encode, Decode, S> {
+        let codec = PipelineCodec::with_serializer(self.max_payload_size, self.serializer.clone())
+            .checksum(self.checksum);
+        #[cfg(feature = "zstd")]
+        let codec = match self.stream_compression {
+            Some((window, max_decompressed_size)) => {
+                codec.stream_compression(window, max_decompressed_size)
+            }
+            None => codec,
+        };
+        codec
+    }
+}
+
+impl<Encode, Decode, S> Clone for PipelineProto<Encode, Decode, S>
+    where S: Clone
+{
+    fn clone(&self) -> Self {
+        PipelineProto {
+            max_payload_size: self.max_payload_size,
+            checksum: self.checksum,
+            stream_compression: self.stream_compression,
+            handshake: self.handshake.clone(),
+            serializer: self.serializer.clone(),
+            _phantom_data: PhantomData,
+        }
+    }
+}
+
+impl<T, Encode, Decode, S> ServerProto<T> for PipelineProto<Encode, Decode, S>
+    where T: Io + 'static,
+          Encode: serde::Serialize + 'static,
+          Decode: serde::Deserialize + 'static,
+          S: PayloadSerializer + Clone + 'static,
+          S::Error: 'static
+{
+    type Request = Result<Decode, DecodeError<S::Error>>;
+    type Response = Encode;
+    type Transport = Framed<T, PipelineCodec<Encode, Decode, S>>;
+    type BindTransport = Box<Future<Item = Self::Transport, Error = io::Error>>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        let codec = self.codec();
+        Box::new(handshake::server(io, self.handshake.clone()).map(move |(io, _)| io.framed(codec)))
+    }
+}
+
+impl<T, Encode, Decode, S> ClientProto<T> for PipelineProto<Encode, Decode, S>
+    where T: Io + 'static,
+          Encode: serde::Serialize + 'static,
+          Decode: serde::Deserialize + 'static,
+          S: PayloadSerializer + Clone + 'static,
+          S::Error: 'static
+{
+    type Request = Encode;
+    type Response = Result<Decode, DecodeError<S::Error>>;
+    type Transport = Framed<T, PipelineCodec<Encode, Decode, S>>;
+    type BindTransport = Box<Future<Item = Self::Transport, Error = io::Error>>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        let codec = self.codec();
+        Box::new(handshake::client(io, self.handshake.clone()).map(move |(io, _)| io.framed(codec)))
+    }
+}
+
+#[test]
+fn round_trip() {
+    let mut codec: PipelineCodec<Vec<u8>, Vec<u8>> = PipelineCodec::new(24).checksum(true);
+    let mut vec = Vec::new();
+    codec.encode(vec![1, 2, 3], &mut vec).unwrap();
+    // len + bincode's length of the vec + its bytes + crc
+    assert_eq!(vec.len(), 8 + 8 + 3 + 4);
+
+    let mut sender: PipelineCodec<Vec<u8>, Vec<u8>> = PipelineCodec::new(2_000_000)
+        .checksum(true);
+    sender.encode(vec![0; 25], &mut vec).unwrap();
+    codec.encode(vec![4], &mut vec).unwrap();
+    let mut buf = EasyBuf::from(vec);
+    match codec.decode(&mut buf) {
+        Ok(Some(Ok(ref v))) if *v == vec![1, 2, 3] => {}
+        bad => panic!("Expected Some(Ok([1, 2, 3])), but got {:?}", bad),
+    }
+    // The payload that is too large still takes its turn, so later responses stay in order.
+    match codec.decode(&mut buf) {
+        Ok(Some(Err(DecodeError::PayloadTooLarge { len: 33, max: 24 }))) => {}
+        bad => panic!("Expected PayloadTooLarge, but got {:?}", bad),
+    }
+    match codec.decode(&mut buf) {
+        Ok(Some(Ok(ref v))) if *v == vec![4] => {}
+        bad => panic!("Expected Some(Ok([4])), but got {:?}", bad),
+    }
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+}
+
+#[test]
+#[cfg(feature = "zstd")]
+fn stream_compression() {
+    let mut sender: PipelineCodec<String, String> = PipelineCodec::new(2_000_000)
+        .checksum(true)
+        .stream_compression(4096, 1 << 20);
+    let lines: Vec<_> = (0..10)
+        .map(|i| {
+            format!("2017-03-01T12:00:{:02}Z INFO request {} served in 3 ms by worker 3 of pool \
+                     frontend-east, cache hit",
+                    i,
+                    i)
+        })
+        .collect();
+    let mut vec = Vec::new();
+    let mut sizes = Vec::new();
+    for line in &lines {
+        let start = vec.len();
+        sender.encode(line.clone(), &mut vec).unwrap();
+        sizes.push(vec.len() - start);
+    }
+    // Once the first line is in the window, the rest mostly refer back to it.
+    assert!(sizes[1..].iter().all(|&size| size < sizes[0] / 2),
+            "Frame sizes: {:?}",
+            sizes);
+
+    let mut receiver: PipelineCodec<String, String> = PipelineCodec::new(2_000_000)
+        .checksum(true)
+        .stream_compression(4096, 1 << 20);
+    let mut buf = EasyBuf::from(vec.clone());
+    for line in &lines {
+        match receiver.decode(&mut buf) {
+            Ok(Some(Ok(ref decoded))) if decoded == line => {}
+            bad => panic!("Expected Some(Ok({:?})), but got {:?}", line, bad),
+        }
+    }
+
+    // Without the earlier payloads, a later one can't be decompressed.
+    let mut receiver: PipelineCodec<String, String> = PipelineCodec::new(2_000_000)
+        .checksum(true)
+        .stream_compression(4096, 1 << 20);
+    let mut buf = EasyBuf::from(vec[sizes[0]..].to_vec());
+    match receiver.decode(&mut buf) {
+        Ok(Some(Ok(ref decoded))) if *decoded == lines[1] => {
+            panic!("Decompressed {:?} without its dictionary", decoded)
+        }
+        _ => {}
+    }
+}
+
+#[test]
+fn pipelined_calls() {
+    use futures::future;
+    use super::in_memory;
+    use tokio_core::reactor::Core;
+    use tokio_proto::{BindClient, BindServer};
+    use tokio_proto::pipeline::Pipeline;
+    use tokio_service::Service;
+
+    struct Increment;
+
+    impl Service for Increment {
+        type Request = Result<u32, DecodeError<::bincode::Error>>;
+        type Response = u32;
+        type Error = io::Error;
+        type Future = future::FutureResult<u32, io::Error>;
+
+        fn call(&self, request: Self::Request) -> Self::Future {
+            future::result(request.map(|n| n + 1).map_err(DecodeError::into_io))
+        }
+    }
+
+    let mut core = Core::new().unwrap();
+    let (client_io, server_io) = in_memory();
+    let proto: PipelineProto<u32, u32> = PipelineProto::new(2_000_000);
+    BindServer::<Pipeline, _>::bind_server(&proto, &core.handle(), server_io, Increment);
+    let client = BindClient::<Pipeline, _>::bind_client(&proto, &core.handle(), client_io);
+    let calls = client.call(1).join(client.call(2));
+    match core.run(calls).unwrap() {
+        (Ok(2), Ok(3)) => {}
+        bad => panic!("Expected (Ok(2), Ok(3)), but got {:?}", bad),
+    }
+}
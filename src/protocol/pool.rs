@@ -0,0 +1,375 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use futures::{Future, Poll, future};
+use futures::future::Shared;
+use serde;
+use std::cell::RefCell;
+use std::error::Error as StdError;
+use std::{fmt, io};
+use std::rc::Rc;
+use super::{BincodeSerializer, Client, DecodeError, PayloadSerializer, Proto};
+use tokio_core::io::Io;
+use tokio_core::reactor;
+
+/// A connection being established, or established, shared by the calls made on it.
+type Connection<T, Encode, Decode, S> = Shared<Box<Future<Item = Client<T, Encode, Decode, S>,
+                                                          Error = io::Error>>>;
+
+/// Opens a new connection for the pool.
+type Connector<T> = Rc<Fn() -> Box<Future<Item = T, Error = io::Error>>>;
+
+/// Returns true if a request can be sent again after its connection failed.
+type Idempotent<Encode> = Rc<Fn(&Encode) -> bool>;
+
+/// One of the connections of a pool.
+struct Slot<T, Encode, Decode, S>
+    where T: Io + 'static,
+          Encode: serde::Serialize + 'static,
+          Decode: serde::Deserialize + 'static,
+          S: PayloadSerializer + Clone + 'static,
+          S::Error: 'static
+{
+    /// `None` until the first call, and again once the connection fails.
+    connection: Option<Connection<T, Encode, Decode, S>>,
+    /// Counts the connections opened for the slot, so a failure is only acted on once.
+    generation: u64,
+    /// The calls waiting on the slot's connections.
+    in_flight: usize,
+}
+
+struct State<T, Encode, Decode, S>
+    where T: Io + 'static,
+          Encode: serde::Serialize + 'static,
+          Decode: serde::Deserialize + 'static,
+          S: PayloadSerializer + Clone + 'static,
+          S::Error: 'static
+{
+    handle: reactor::Handle,
+    proto: Proto<Encode, Decode, S>,
+    connect: Connector<T>,
+    slots: Vec<Slot<T, Encode, Decode, S>>,
+    max_retries: u32,
+    idempotent: Option<Idempotent<Encode>>,
+}
+
+impl<T, Encode, Decode, S> State<T, Encode, Decode, S>
+    where T: Io + 'static,
+          Encode: serde::Serialize + 'static,
+          Decode: serde::Deserialize + 'static,
+          S: PayloadSerializer + Clone + 'static,
+          S::Error: 'static
+{
+    /// Picks the slot with the fewest calls in flight for a new call, connecting it if it isn't.
+    /// Returns its index, its generation, and its connection.
+    fn checkout(&mut self) -> (usize, u64, Connection<T, Encode, Decode, S>) {
+        let index = (0..self.slots.len())
+            .min_by_key(|&index| self.slots[index].in_flight)
+            .expect("A pool has at least one connection");
+        if self.slots[index].connection.is_none() {
+            self.connect(index);
+        }
+        let slot = &mut self.slots[index];
+        slot.in_flight += 1;
+        (index, slot.generation, slot.connection.clone().unwrap())
+    }
+
+    /// Opens a new connection for slot `index`.
+    fn connect(&mut self, index: usize) {
+        let handle = self.handle.clone();
+        let proto = self.proto.clone();
+        let connection: Box<Future<Item = _, Error = _>> =
+            Box::new((self.connect)().map(move |io| Client::new(&handle, io, &proto)));
+        let slot = &mut self.slots[index];
+        slot.generation += 1;
+        debug!("Opening connection {} of pool slot {}.", slot.generation, index);
+        slot.connection = Some(connection.shared());
+    }
+
+    /// Retires a call made on slot `index`, dropping the slot's connection if the call found it
+    /// broken and it hasn't been replaced already. The next call on the slot reconnects.
+    fn checkin(&mut self, index: usize, generation: u64, failed: bool) {
+        let slot = &mut self.slots[index];
+        slot.in_flight -= 1;
+        if failed && slot.generation == generation {
+            warn!("Connection {} of pool slot {} failed.", generation, index);
+            slot.connection = None;
+        }
+    }
+}
+
+/// A fixed number of connections to a server, bound with a `Proto`, over which calls are
+/// spread.
+///
+/// Each call is made on the connection with the fewest calls in flight, multiplexed with the
+/// others on it. Connections are opened when first needed, with the function given to `new`,
+/// and a connection that fails is replaced on the next call made on it. Calls that fail because
+/// their connection did aren't retried, unless `retry_idempotent` says they can be. Cloning a
+/// `ClientPool` shares its connections.
+pub struct ClientPool<T, Encode, Decode, S = BincodeSerializer>
+    where T: Io + 'static,
+          Encode: serde::Serialize + 'static,
+          Decode: serde::Deserialize + 'static,
+          S: PayloadSerializer + Clone + 'static,
+          S::Error: 'static
+{
+    state: Rc<RefCell<State<T, Encode, Decode, S>>>,
+}
+
+impl<T, Encode, Decode, S> ClientPool<T, Encode, Decode, S>
+    where T: Io + 'static,
+          Encode: serde::Serialize + 'static,
+          Decode: serde::Deserialize + 'static,
+          S: PayloadSerializer + Clone + 'static,
+          S::Error: 'static
+{
+    /// Returns a pool of `size` connections on the reactor of `handle`, each bound with `proto`
+    /// to a stream opened by `connect`, such as a `TcpStream::connect`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is 0.
+    pub fn new<F>(handle: &reactor::Handle,
+                  proto: &Proto<Encode, Decode, S>,
+                  size: usize,
+                  connect: F)
+                  -> Self
+        where F: Fn() -> Box<Future<Item = T, Error = io::Error>> + 'static
+    {
+        assert!(size > 0, "A pool needs at least one connection");
+        let slots = (0..size)
+            .map(|_| {
+                Slot {
+                    connection: None,
+                    generation: 0,
+                    in_flight: 0,
+                }
+            })
+            .collect();
+        ClientPool {
+            state: Rc::new(RefCell::new(State {
+                handle: handle.clone(),
+                proto: proto.clone(),
+                connect: Rc::new(connect),
+                slots: slots,
+                max_retries: 0,
+                idempotent: None,
+            })),
+        }
+    }
+
+    /// Resend a call whose connection failed before its response arrived, on another
+    /// connection, up to `max_retries` times, if `is_idempotent` returns true for its request.
+    /// A request that isn't idempotent might be handled twice, so by default no call is
+    /// retried.
+    pub fn retry_idempotent<F>(self, max_retries: u32, is_idempotent: F) -> Self
+        where F: Fn(&Encode) -> bool + 'static
+    {
+        {
+            let mut state = self.state.borrow_mut();
+            state.max_retries = max_retries;
+            state.idempotent = Some(Rc::new(is_idempotent));
+        }
+        self
+    }
+
+    /// The number of connections in the pool.
+    pub fn size(&self) -> usize {
+        self.state.borrow().slots.len()
+    }
+
+    /// The number of calls waiting for a response, over every connection.
+    pub fn in_flight(&self) -> usize {
+        self.state.borrow().slots.iter().map(|slot| slot.in_flight).sum()
+    }
+}
+
+impl<T, Encode, Decode, S> ClientPool<T, Encode, Decode, S>
+    where T: Io + 'static,
+          Encode: serde::Serialize + Clone + 'static,
+          Decode: serde::Deserialize + 'static,
+          S: PayloadSerializer + Clone + 'static,
+          S::Error: StdError + Send + Sync + 'static
+{
+    /// Sends `request` on the least-loaded connection, returning a future of the response.
+    pub fn call(&self, request: Encode) -> PoolFuture<Decode> {
+        let retries = {
+            let state = self.state.borrow();
+            match state.idempotent {
+                Some(ref idempotent) if idempotent(&request) => state.max_retries,
+                _ => 0,
+            }
+        };
+        PoolFuture { inner: attempt(self.state.clone(), request, retries) }
+    }
+}
+
+impl<T, Encode, Decode, S> Clone for ClientPool<T, Encode, Decode, S>
+    where T: Io + 'static,
+          Encode: serde::Serialize + 'static,
+          Decode: serde::Deserialize + 'static,
+          S: PayloadSerializer + Clone + 'static,
+          S::Error: 'static
+{
+    fn clone(&self) -> Self {
+        ClientPool { state: self.state.clone() }
+    }
+}
+
+impl<T, Encode, Decode, S> fmt::Debug for ClientPool<T, Encode, Decode, S>
+    where T: Io + 'static,
+          Encode: serde::Serialize + 'static,
+          Decode: serde::Deserialize + 'static,
+          S: PayloadSerializer + Clone + 'static,
+          S::Error: 'static
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ClientPool {{ size: {}, .. }}", self.size())
+    }
+}
+
+/// Makes a call on the least-loaded connection of the pool, trying again on another up to
+/// `retries` times if the connection fails.
+fn attempt<T, Encode, Decode, S>(state: Rc<RefCell<State<T, Encode, Decode, S>>>,
+                                 request: Encode,
+                                 retries: u32)
+                                 -> Box<Future<Item = Decode, Error = io::Error>>
+    where T: Io + 'static,
+          Encode: serde::Serialize + Clone + 'static,
+          Decode: serde::Deserialize + 'static,
+          S: PayloadSerializer + Clone + 'static,
+          S::Error: StdError + Send + Sync + 'static
+{
+    let (index, generation, connection) = state.borrow_mut().checkout();
+    let retry = if retries > 0 {
+        Some(request.clone())
+    } else {
+        None
+    };
+    Box::new(connection.map_err(|e| io::Error::new(e.kind(), e.to_string()))
+        .and_then(move |client| client.call(request))
+        .then(move |result| {
+            let failed = match result {
+                Ok(_) => false,
+                Err(ref e) => connection_failed::<S::Error>(e),
+            };
+            state.borrow_mut().checkin(index, generation, failed);
+            if failed {
+                if let Some(request) = retry {
+                    debug!("Retrying a call whose connection failed; {} retries left.",
+                           retries - 1);
+                    return attempt(state, request, retries - 1);
+                }
+            }
+            Box::new(future::result(result)) as Box<Future<Item = Decode, Error = io::Error>>
+        }))
+}
+
+/// True if `e` means the call's connection failed, rather than the call alone: `e` wasn't made
+/// from a `DecodeError`, or it says the server is closing the connection.
+fn connection_failed<E>(e: &io::Error) -> bool
+    where E: StdError + Send + Sync + 'static
+{
+    e.kind() == io::ErrorKind::ConnectionAborted ||
+    e.get_ref().map_or(true, |inner| !inner.is::<DecodeError<E>>())
+}
+
+/// A future that resolves to the response to a `ClientPool::call`.
+pub struct PoolFuture<Decode> {
+    inner: Box<Future<Item = Decode, Error = io::Error>>,
+}
+
+impl<Decode> Future for PoolFuture<Decode> {
+    type Item = Decode;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Decode, io::Error> {
+        self.inner.poll()
+    }
+}
+
+impl<Decode> fmt::Debug for PoolFuture<Decode> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PoolFuture {{ .. }}")
+    }
+}
+
+#[cfg(test)]
+struct Double;
+
+#[cfg(test)]
+impl ::tokio_service::Service for Double {
+    type Request = Result<u32, DecodeError<::bincode::Error>>;
+    type Response = u32;
+    type Error = io::Error;
+    type Future = future::FutureResult<u32, io::Error>;
+
+    fn call(&self, request: Self::Request) -> Self::Future {
+        future::result(request.map(|n| n * 2).map_err(DecodeError::into_io))
+    }
+}
+
+/// Returns a pool of `size` connections to `Double` servers, and the number of connections it
+/// has opened. The first connection is broken: its server end is dropped.
+#[cfg(test)]
+fn double_pool(handle: &reactor::Handle,
+               size: usize)
+               -> (ClientPool<super::MemoryIo, u32, u32>, Rc<::std::cell::Cell<usize>>) {
+    use std::cell::Cell;
+    use super::in_memory;
+    use tokio_proto::BindServer;
+
+    let proto: Proto<u32, u32> = Proto::new(2_000_000);
+    let connections = Rc::new(Cell::new(0));
+    let opened = connections.clone();
+    let server_handle = handle.clone();
+    let server_proto = proto.clone();
+    let pool = ClientPool::new(handle, &proto, size, move || {
+        opened.set(opened.get() + 1);
+        let (client_io, server_io) = in_memory();
+        if opened.get() > 1 {
+            server_proto.bind_server(&server_handle, server_io, Double);
+        }
+        Box::new(future::ok(client_io)) as Box<Future<Item = _, Error = io::Error>>
+    });
+    (pool, connections)
+}
+
+#[test]
+fn least_loaded() {
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let (pool, connections) = double_pool(&core.handle(), 2);
+
+    // The calls alternate between the connections; those on the broken one fail, and it is
+    // replaced by the next call made on it.
+    let calls = (0..4).map(|n| pool.call(n).then(Ok::<_, ()>)).collect::<Vec<_>>();
+    assert_eq!(pool.in_flight(), 4);
+    let responses = core.run(future::join_all(calls)).unwrap();
+    assert_eq!(connections.get(), 2);
+    assert_eq!(responses.iter().filter(|response| response.is_err()).count(), 2);
+    assert_eq!(pool.in_flight(), 0);
+
+    let calls = (0..4).map(|n| pool.call(n)).collect::<Vec<_>>();
+    assert_eq!(core.run(future::join_all(calls)).unwrap(), vec![0, 2, 4, 6]);
+    assert_eq!(connections.get(), 3);
+}
+
+#[test]
+fn retry_idempotent() {
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let (pool, connections) = double_pool(&core.handle(), 1);
+    let pool = pool.retry_idempotent(1, |n| n % 2 == 0);
+    assert_eq!(core.run(pool.call(2)).unwrap(), 4);
+    assert_eq!(connections.get(), 2);
+
+    let (pool, _) = double_pool(&core.handle(), 1);
+    let pool = pool.retry_idempotent(1, |n| n % 2 == 0);
+    assert!(core.run(pool.call(1)).is_err());
+    assert_eq!(core.run(pool.call(1)).unwrap(), 2);
+}
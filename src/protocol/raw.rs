@@ -0,0 +1,119 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use super::{DecodeError, Endianness, LenWidth};
+use super::frame::{CodecState, FrameOptions, HEARTBEAT_ID};
+use std::{cmp, io};
+use tokio_core::io::{Codec, EasyBuf};
+use tokio_proto::streaming::multiplex::RequestId;
+use util::Never;
+
+/// A tokio `Codec` whose payloads are opaque bytes, framed like the payloads of a `Codec`.
+///
+/// Nothing is serialized: `encode` writes the id, the length, and the bytes as given, and
+/// `decode` returns the bytes of each frame. This is useful for carrying messages serialized
+/// by some other system. Compression isn't supported; the peer must not enable it.
+pub struct RawCodec {
+    max_outbound: u64,
+    max_inbound: u64,
+    frame: FrameOptions,
+    state: CodecState,
+}
+
+impl RawCodec {
+    /// Returns a new `RawCodec` that rejects payloads larger than `max_payload_size` bytes.
+    pub fn new(max_payload_size: u64) -> Self {
+        RawCodec::with_limits(max_payload_size, max_payload_size)
+    }
+
+    /// Returns a new `RawCodec` that refuses to send payloads larger than `max_outbound` bytes
+    /// and rejects received payloads larger than `max_inbound` bytes.
+    pub fn with_limits(max_outbound: u64, max_inbound: u64) -> Self {
+        RawCodec {
+            max_outbound: max_outbound,
+            max_inbound: max_inbound,
+            frame: FrameOptions::default(),
+            state: CodecState::Id,
+        }
+    }
+
+    /// Set whether payloads are followed by their CRC32. The peer must use the same setting.
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.frame.checksum = checksum;
+        self
+    }
+
+    /// Set the width of the length prefix. The peer must use the same width.
+    pub fn len_width(mut self, width: LenWidth) -> Self {
+        self.frame.len_width = width;
+        self
+    }
+
+    /// Set the byte order of the id, length, and checksum fields. The peer must use the same
+    /// byte order.
+    pub fn endianness(mut self, endianness: Endianness) -> Self {
+        self.frame.endianness = endianness;
+        self
+    }
+
+    /// The largest payload that can be sent, taking the width of the length prefix into account.
+    fn max_outbound(&self) -> u64 {
+        cmp::min(self.max_outbound, self.frame.len_width.max_len())
+    }
+}
+
+impl Codec for RawCodec {
+    type Out = (RequestId, Vec<u8>);
+    type In = (RequestId, Result<Vec<u8>, DecodeError<Never>>);
+
+    fn encode(&mut self, (id, payload): Self::Out, buf: &mut Vec<u8>) -> io::Result<()> {
+        let payload_size = payload.len() as u64;
+        if payload_size > self.max_outbound() {
+            return Err(super::too_big(Some(id), payload_size, self.max_outbound()));
+        }
+        self.frame.write_header(buf, id, 0, 0, 0, payload_size);
+        let payload_start = buf.len();
+        buf.extend_from_slice(&payload);
+        self.frame.write_trailer(buf, payload_start);
+        Ok(())
+    }
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Self::In>> {
+        loop {
+            match self.state.decode(&self.frame, self.max_inbound, buf)? {
+                // The sender expects heartbeats to be echoed by a transport, not a codec.
+                Some((HEARTBEAT_ID, _)) => trace!("--> Ignoring heartbeat."),
+                Some((id, frame)) => {
+                    return Ok(Some((id, frame.map(|frame| frame.payload.as_slice().to_vec()))))
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+#[test]
+fn round_trip() {
+    let mut codec = RawCodec::new(24).checksum(true);
+    let mut vec = Vec::new();
+    codec.encode((1, b"opaque".to_vec()), &mut vec).unwrap();
+    // id + len + the bytes as given + crc
+    assert_eq!(vec.len(), 8 + 8 + 6 + 4);
+    assert_eq!(&vec[16..22], b"opaque");
+    assert_eq!(codec.encode((2, vec![0; 25]), &mut Vec::new()).err().unwrap().kind(),
+               io::ErrorKind::InvalidData);
+
+    let mut sender = RawCodec::new(2_000_000).checksum(true);
+    sender.encode((3, vec![0; 25]), &mut vec).unwrap();
+    let mut buf = EasyBuf::from(vec);
+    match codec.decode(&mut buf) {
+        Ok(Some((1, Ok(ref payload)))) if payload == b"opaque" => {}
+        bad => panic!("Expected Some((1, Ok(b\"opaque\"))), but got {:?}", bad),
+    }
+    match codec.decode(&mut buf) {
+        Ok(Some((3, Err(DecodeError::PayloadTooLarge { len: 25, max: 24 })))) => {}
+        bad => panic!("Expected PayloadTooLarge, but got {:?}", bad),
+    }
+}
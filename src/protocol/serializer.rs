@@ -0,0 +1,504 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+#[cfg(feature = "msgpack")]
+use rmp_serde;
+#[cfg(feature = "cbor")]
+use serde_cbor;
+#[cfg(feature = "json")]
+use serde_json;
+use bincode::{self, Bounded, Infinite};
+use serde::{Deserialize, Serialize};
+use std::{error, fmt};
+use std::io::{self, Cursor, Write};
+use tokio_core::io::EasyBuf;
+
+/// Serializes and deserializes the payload of a frame.
+///
+/// `Codec` handles the id and length framing; a `PayloadSerializer` is only responsible for the
+/// bytes in between.
+///
+/// serde 0.9's serializers don't say whether their format is human-readable, so a type is
+/// serialized the same way by every `PayloadSerializer`: bincode, MessagePack, and CBOR get the
+/// same calls as JSON. A type that should look different in a text format, such as a timestamp
+/// sent as a string rather than an integer, needs a separate type for the services that use
+/// `JsonSerializer`.
+pub trait PayloadSerializer {
+    /// The error produced when a payload can't be deserialized.
+    type Error;
+
+    /// Appends the serialized form of `msg` to `w`. A value the format can't represent fails
+    /// with an error of kind `InvalidInput`.
+    fn serialize_into<T: Serialize>(&self, w: &mut Vec<u8>, msg: &T) -> io::Result<()>;
+
+    /// Writes the serialized form of `msg` to `w` as it is serialized, so that a large payload
+    /// needn't be held in memory. Formats that can serialize to any writer should override it;
+    /// by default, `msg` is serialized into a `Vec` first. An error writing to `w` is returned
+    /// as it is, so that it can be told apart from a value that can't be serialized, which
+    /// fails with an error of kind `InvalidInput`.
+    fn serialize_to<W: Write, T: Serialize>(&self, w: &mut W, msg: &T) -> io::Result<()> {
+        let mut payload = Vec::new();
+        self.serialize_into(&mut payload, msg)?;
+        w.write_all(&payload)
+    }
+
+    /// Returns the number of bytes `serialize_into` will append for `msg`.
+    fn serialized_size<T: Serialize>(&self, msg: &T) -> u64;
+
+    /// Deserializes a payload from `r`.
+    fn deserialize_from<T: Deserialize>(&self, r: &mut Cursor<EasyBuf>) -> Result<T, Self::Error>;
+
+    /// Deserializes `payload` in place. This is what `Codec` calls; formats that can read from a
+    /// byte slice should override it to skip the overhead of going through a reader.
+    ///
+    /// The deserialized value can't borrow from `payload`, since serde 0.9's `Deserialize` has
+    /// no lifetime to borrow with, so bytes that end up in the value are still copied once.
+    fn deserialize_slice<T: Deserialize>(&self, payload: &EasyBuf) -> Result<T, Self::Error> {
+        self.deserialize_from(&mut Cursor::new(payload.clone()))
+    }
+
+    /// Checks `payload` before `Codec` deserializes it. An error fails the connection, rather
+    /// than only the payload's request, and is sent to the peer if it wraps a `ProtocolError`.
+    /// By default, every payload passes; `TypeAllowlist` checks the payload's type tag.
+    fn check_payload(&self, _payload: &EasyBuf) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The format to propose in the handshake, if this serializer can switch formats per
+    /// connection. Only `Format` can.
+    fn format(&self) -> Option<Format> {
+        None
+    }
+
+    /// Returns the serializer to use on a connection that negotiated `format`. Only called if
+    /// `format` returned `Some`.
+    fn with_format(self, _format: Format) -> Self
+        where Self: Sized
+    {
+        self
+    }
+}
+
+/// Serializes to `w` with `serialize`. If it fails because `w` did, returns the error `w`
+/// returned; otherwise, the value couldn't be serialized, and the error is of kind
+/// `InvalidInput`.
+fn serialize_with<W, F, E>(w: &mut W, serialize: F) -> io::Result<()>
+    where W: Write,
+          F: FnOnce(&mut WriteErrors<&mut W>) -> Result<(), E>,
+          E: Into<Box<error::Error + Send + Sync>>
+{
+    let mut w = WriteErrors {
+        inner: w,
+        error: None,
+    };
+    let result = serialize(&mut w);
+    result.map_err(|e| {
+        w.error.take().unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, e))
+    })
+}
+
+/// A `Write` that keeps the first error its writer returns, so that a serializer's failure can
+/// be traced to it. The serializer gets an error of the same kind.
+struct WriteErrors<W> {
+    inner: W,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> WriteErrors<W> {
+    /// Keeps `e`, returning an error of the same kind for the serializer.
+    fn keep(&mut self, e: io::Error) -> io::Error {
+        let kind = e.kind();
+        if self.error.is_none() {
+            self.error = Some(e);
+        }
+        io::Error::new(kind, "Writing the payload failed")
+    }
+}
+
+impl<W: Write> Write for WriteErrors<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.inner.write(buf) {
+            Ok(n) => Ok(n),
+            Err(e) => Err(self.keep(e)),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.inner.flush() {
+            Ok(()) => Ok(()),
+            Err(e) => Err(self.keep(e)),
+        }
+    }
+}
+
+/// A `Write` that discards its input, keeping count of the bytes written.
+#[cfg(any(feature = "json", feature = "msgpack", feature = "cbor"))]
+struct ByteCounter(u64);
+
+#[cfg(any(feature = "json", feature = "msgpack", feature = "cbor"))]
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Serializes payloads with bincode. This is the format used by tarpc services.
+///
+/// By default bincode's own size limit is `Infinite`, leaving payload sizes to the `Codec`.
+/// This version of bincode has no other options; its integer encoding is fixed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BincodeSerializer {
+    limit: Option<u64>,
+}
+
+impl BincodeSerializer {
+    /// Returns a new `BincodeSerializer` with no size limit of its own.
+    pub fn new() -> Self {
+        BincodeSerializer::default()
+    }
+
+    /// Set bincode's size limit: messages over `bytes` fail to serialize, and deserializing
+    /// fails before reading more than `bytes`. This bounds what bincode allocates for a
+    /// payload, such as a `Vec` whose encoded length is far larger than the payload itself.
+    pub fn limit(mut self, bytes: u64) -> Self {
+        self.limit = Some(bytes);
+        self
+    }
+}
+
+impl PayloadSerializer for BincodeSerializer {
+    type Error = bincode::Error;
+
+    fn serialize_into<T: Serialize>(&self, w: &mut Vec<u8>, msg: &T) -> io::Result<()> {
+        self.serialize_to(w, msg)
+    }
+
+    fn serialize_to<W: Write, T: Serialize>(&self, w: &mut W, msg: &T) -> io::Result<()> {
+        serialize_with(w, |w| match self.limit {
+            Some(limit) => bincode::serialize_into(w, msg, Bounded(limit)),
+            None => bincode::serialize_into(w, msg, Infinite),
+        })
+    }
+
+    fn serialized_size<T: Serialize>(&self, msg: &T) -> u64 {
+        bincode::serialized_size(msg)
+    }
+
+    fn deserialize_from<T: Deserialize>(&self, r: &mut Cursor<EasyBuf>) -> Result<T, Self::Error> {
+        match self.limit {
+            Some(limit) => bincode::deserialize_from(r, Bounded(limit)),
+            None => bincode::deserialize_from(r, Infinite),
+        }
+    }
+
+    fn deserialize_slice<T: Deserialize>(&self, payload: &EasyBuf) -> Result<T, Self::Error> {
+        match self.limit {
+            Some(limit) => bincode::deserialize_from(&mut payload.as_slice(), Bounded(limit)),
+            None => bincode::deserialize(payload.as_slice()),
+        }
+    }
+}
+
+/// Serializes payloads as JSON, which makes traffic easy to inspect with standard tooling.
+/// Only built with the `json` feature.
+#[cfg(feature = "json")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonSerializer;
+
+#[cfg(feature = "json")]
+impl PayloadSerializer for JsonSerializer {
+    type Error = serde_json::Error;
+
+    fn serialize_into<T: Serialize>(&self, w: &mut Vec<u8>, msg: &T) -> io::Result<()> {
+        self.serialize_to(w, msg)
+    }
+
+    fn serialize_to<W: Write, T: Serialize>(&self, w: &mut W, msg: &T) -> io::Result<()> {
+        serialize_with(w, |w| serde_json::to_writer(w, msg))
+    }
+
+    /// JSON has no size precomputation, so this serializes `msg` into a byte counter. If `msg`
+    /// can't be serialized, the error is reported by `serialize_into` instead.
+    fn serialized_size<T: Serialize>(&self, msg: &T) -> u64 {
+        let mut counter = ByteCounter(0);
+        let _ = serde_json::to_writer(&mut counter, msg);
+        counter.0
+    }
+
+    fn deserialize_from<T: Deserialize>(&self, r: &mut Cursor<EasyBuf>) -> Result<T, Self::Error> {
+        serde_json::from_reader(r)
+    }
+
+    fn deserialize_slice<T: Deserialize>(&self, payload: &EasyBuf) -> Result<T, Self::Error> {
+        serde_json::from_slice(payload.as_slice())
+    }
+}
+
+/// Serializes payloads as MessagePack, which allows clients written in other languages to speak
+/// the tarpc protocol. Only built with the `msgpack` feature.
+#[cfg(feature = "msgpack")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MsgPackSerializer;
+
+#[cfg(feature = "msgpack")]
+impl PayloadSerializer for MsgPackSerializer {
+    type Error = rmp_serde::decode::Error;
+
+    fn serialize_into<T: Serialize>(&self, w: &mut Vec<u8>, msg: &T) -> io::Result<()> {
+        self.serialize_to(w, msg)
+    }
+
+    fn serialize_to<W: Write, T: Serialize>(&self, w: &mut W, msg: &T) -> io::Result<()> {
+        serialize_with(w, |w| rmp_serde::encode::write(w, msg))
+    }
+
+    /// MessagePack has no size precomputation, so this serializes `msg` into a byte counter. If
+    /// `msg` can't be serialized, the error is reported by `serialize_into` instead.
+    fn serialized_size<T: Serialize>(&self, msg: &T) -> u64 {
+        let mut counter = ByteCounter(0);
+        let _ = rmp_serde::encode::write(&mut counter, msg);
+        counter.0
+    }
+
+    fn deserialize_from<T: Deserialize>(&self, r: &mut Cursor<EasyBuf>) -> Result<T, Self::Error> {
+        rmp_serde::decode::from_read(r)
+    }
+
+    fn deserialize_slice<T: Deserialize>(&self, payload: &EasyBuf) -> Result<T, Self::Error> {
+        rmp_serde::decode::from_read(payload.as_slice())
+    }
+}
+
+/// Serializes payloads as CBOR. Unlike bincode's positional encoding, CBOR maps carry their field
+/// names, so peers can add or drop optional fields without breaking each other. Only built
+/// with the `cbor` feature.
+#[cfg(feature = "cbor")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CborSerializer;
+
+#[cfg(feature = "cbor")]
+impl PayloadSerializer for CborSerializer {
+    type Error = serde_cbor::Error;
+
+    fn serialize_into<T: Serialize>(&self, w: &mut Vec<u8>, msg: &T) -> io::Result<()> {
+        self.serialize_to(w, msg)
+    }
+
+    fn serialize_to<W: Write, T: Serialize>(&self, w: &mut W, msg: &T) -> io::Result<()> {
+        serialize_with(w, |w| serde_cbor::to_writer(w, msg))
+    }
+
+    /// CBOR has no size precomputation, so this serializes `msg` into a byte counter. If `msg`
+    /// can't be serialized, the error is reported by `serialize_into` instead.
+    fn serialized_size<T: Serialize>(&self, msg: &T) -> u64 {
+        let mut counter = ByteCounter(0);
+        let _ = serde_cbor::to_writer(&mut counter, msg);
+        counter.0
+    }
+
+    fn deserialize_from<T: Deserialize>(&self, r: &mut Cursor<EasyBuf>) -> Result<T, Self::Error> {
+        serde_cbor::from_reader(r)
+    }
+
+    fn deserialize_slice<T: Deserialize>(&self, payload: &EasyBuf) -> Result<T, Self::Error> {
+        serde_cbor::from_slice(payload.as_slice())
+    }
+}
+
+/// A payload format chosen per connection in the handshake, so that one server can accept
+/// clients that speak different formats. The framing is the same for every format.
+///
+/// The client proposes the format it was created with; the server accepts it if it is the
+/// server's own format or one of the formats given to `Proto::accept_formats`. Every format but
+/// bincode is behind a feature: `json`, `msgpack`, or `cbor`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    /// Serializes payloads with bincode, like `BincodeSerializer`.
+    Bincode,
+    /// Serializes payloads as JSON, like `JsonSerializer`.
+    #[cfg(feature = "json")]
+    Json,
+    /// Serializes payloads as MessagePack, like `MsgPackSerializer`.
+    #[cfg(feature = "msgpack")]
+    MsgPack,
+    /// Serializes payloads as CBOR, like `CborSerializer`.
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+/// The number that stands for `format` in the handshake. It doesn't depend on the features
+/// enabled, so peers built with different formats still understand each other.
+pub fn format_code(format: Format) -> u32 {
+    match format {
+        Format::Bincode => 0,
+        #[cfg(feature = "json")]
+        Format::Json => 1,
+        #[cfg(feature = "msgpack")]
+        Format::MsgPack => 2,
+        #[cfg(feature = "cbor")]
+        Format::Cbor => 3,
+    }
+}
+
+/// The format that `code` stands for, if this build supports it.
+pub fn format_from_code(code: u32) -> Option<Format> {
+    match code {
+        0 => Some(Format::Bincode),
+        #[cfg(feature = "json")]
+        1 => Some(Format::Json),
+        #[cfg(feature = "msgpack")]
+        2 => Some(Format::MsgPack),
+        #[cfg(feature = "cbor")]
+        3 => Some(Format::Cbor),
+        _ => None,
+    }
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format::Bincode
+    }
+}
+
+/// Why a payload couldn't be deserialized in the negotiated `Format`.
+#[derive(Debug)]
+pub enum FormatError {
+    /// The bincode payload couldn't be deserialized.
+    Bincode(bincode::Error),
+    /// The JSON payload couldn't be deserialized.
+    #[cfg(feature = "json")]
+    Json(serde_json::Error),
+    /// The MessagePack payload couldn't be deserialized.
+    #[cfg(feature = "msgpack")]
+    MsgPack(rmp_serde::decode::Error),
+    /// The CBOR payload couldn't be deserialized.
+    #[cfg(feature = "cbor")]
+    Cbor(serde_cbor::Error),
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FormatError::Bincode(ref e) => fmt::Display::fmt(e, f),
+            #[cfg(feature = "json")]
+            FormatError::Json(ref e) => fmt::Display::fmt(e, f),
+            #[cfg(feature = "msgpack")]
+            FormatError::MsgPack(ref e) => fmt::Display::fmt(e, f),
+            #[cfg(feature = "cbor")]
+            FormatError::Cbor(ref e) => fmt::Display::fmt(e, f),
+        }
+    }
+}
+
+impl error::Error for FormatError {
+    fn description(&self) -> &str {
+        match *self {
+            FormatError::Bincode(ref e) => e.description(),
+            #[cfg(feature = "json")]
+            FormatError::Json(ref e) => e.description(),
+            #[cfg(feature = "msgpack")]
+            FormatError::MsgPack(ref e) => e.description(),
+            #[cfg(feature = "cbor")]
+            FormatError::Cbor(ref e) => e.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            FormatError::Bincode(ref e) => Some(e),
+            #[cfg(feature = "json")]
+            FormatError::Json(ref e) => Some(e),
+            #[cfg(feature = "msgpack")]
+            FormatError::MsgPack(ref e) => Some(e),
+            #[cfg(feature = "cbor")]
+            FormatError::Cbor(ref e) => Some(e),
+        }
+    }
+}
+
+impl PayloadSerializer for Format {
+    type Error = FormatError;
+
+    fn serialize_into<T: Serialize>(&self, w: &mut Vec<u8>, msg: &T) -> io::Result<()> {
+        match *self {
+            Format::Bincode => BincodeSerializer::default().serialize_into(w, msg),
+            #[cfg(feature = "json")]
+            Format::Json => JsonSerializer.serialize_into(w, msg),
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack => MsgPackSerializer.serialize_into(w, msg),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => CborSerializer.serialize_into(w, msg),
+        }
+    }
+
+    fn serialize_to<W: Write, T: Serialize>(&self, w: &mut W, msg: &T) -> io::Result<()> {
+        match *self {
+            Format::Bincode => BincodeSerializer::default().serialize_to(w, msg),
+            #[cfg(feature = "json")]
+            Format::Json => JsonSerializer.serialize_to(w, msg),
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack => MsgPackSerializer.serialize_to(w, msg),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => CborSerializer.serialize_to(w, msg),
+        }
+    }
+
+    fn serialized_size<T: Serialize>(&self, msg: &T) -> u64 {
+        match *self {
+            Format::Bincode => BincodeSerializer::default().serialized_size(msg),
+            #[cfg(feature = "json")]
+            Format::Json => JsonSerializer.serialized_size(msg),
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack => MsgPackSerializer.serialized_size(msg),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => CborSerializer.serialized_size(msg),
+        }
+    }
+
+    fn deserialize_from<T: Deserialize>(&self, r: &mut Cursor<EasyBuf>) -> Result<T, Self::Error> {
+        match *self {
+            Format::Bincode => {
+                BincodeSerializer::default().deserialize_from(r).map_err(FormatError::Bincode)
+            }
+            #[cfg(feature = "json")]
+            Format::Json => JsonSerializer.deserialize_from(r).map_err(FormatError::Json),
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack => MsgPackSerializer.deserialize_from(r).map_err(FormatError::MsgPack),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => CborSerializer.deserialize_from(r).map_err(FormatError::Cbor),
+        }
+    }
+
+    fn deserialize_slice<T: Deserialize>(&self, payload: &EasyBuf) -> Result<T, Self::Error> {
+        match *self {
+            Format::Bincode => {
+                BincodeSerializer::default()
+                    .deserialize_slice(payload)
+                    .map_err(FormatError::Bincode)
+            }
+            #[cfg(feature = "json")]
+            Format::Json => JsonSerializer.deserialize_slice(payload).map_err(FormatError::Json),
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack => {
+                MsgPackSerializer.deserialize_slice(payload).map_err(FormatError::MsgPack)
+            }
+            #[cfg(feature = "cbor")]
+            Format::Cbor => CborSerializer.deserialize_slice(payload).map_err(FormatError::Cbor),
+        }
+    }
+
+    fn format(&self) -> Option<Format> {
+        Some(*self)
+    }
+
+    fn with_format(self, format: Format) -> Self {
+        format
+    }
+}
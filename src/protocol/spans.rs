@@ -0,0 +1,152 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::io;
+use super::DecodeError;
+use tokio_proto::streaming::multiplex::RequestId;
+
+cfg_if! {
+    if #[cfg(feature = "spans")] {
+        use std::collections::HashMap;
+        use std::time::Instant;
+
+        /// The `log` target of spans, so that they can be routed apart from the crate's other
+        /// logs, for example to a tracing system through a `log` bridge.
+        const TARGET: &'static str = "tarpc::span";
+
+        /// When a request's span opened, and the size of its payload.
+        struct Span {
+            opened: Instant,
+            request_size: u64,
+        }
+
+        /// Tracks a span for every request a server is handling, logged as `key=value` records
+        /// under the `tarpc::span` target. A span opens when the request is decoded and closes
+        /// when its response is encoded; the close record carries the request and response
+        /// sizes and the microseconds in between.
+        pub struct RequestSpans {
+            spans: Option<HashMap<RequestId, Span>>,
+        }
+
+        impl Default for RequestSpans {
+            fn default() -> Self {
+                RequestSpans { spans: None }
+            }
+        }
+
+        impl RequestSpans {
+            /// Returns a `RequestSpans` that records spans.
+            pub fn enabled() -> Self {
+                RequestSpans { spans: Some(HashMap::new()) }
+            }
+
+            /// Opens the span of request `id`, whose payload is `request_size` bytes.
+            pub fn open(&mut self, id: RequestId, request_size: u64) {
+                if let Some(ref mut spans) = self.spans {
+                    debug!(target: TARGET, "span=open id={} request_size={}", id, request_size);
+                    spans.insert(id, Span {
+                        opened: Instant::now(),
+                        request_size: request_size,
+                    });
+                }
+            }
+
+            /// Records why request `id` was rejected, opening its span if it wasn't yet.
+            pub fn rejected<E>(&mut self, id: RequestId, error: &DecodeError<E>) {
+                let opened = match self.spans {
+                    Some(ref spans) => spans.contains_key(&id),
+                    None => return,
+                };
+                if !opened {
+                    let request_size = match *error {
+                        DecodeError::PayloadTooLarge { len, .. } => len,
+                        _ => 0,
+                    };
+                    self.open(id, request_size);
+                }
+                let event = match *error {
+                    DecodeError::PayloadTooLarge { len, max } => {
+                        format!("request payload too large len={} max={}", len, max)
+                    }
+                    DecodeError::ChecksumMismatch { expected, actual } => {
+                        format!("request checksum mismatch expected={} actual={}",
+                                expected,
+                                actual)
+                    }
+                    DecodeError::DeadlineExceeded { deadline } => {
+                        format!("request deadline exceeded deadline={}", deadline)
+                    }
+                    DecodeError::TimedOut { .. } => "request timed out".to_string(),
+                    DecodeError::ReassemblyTimedOut { .. } => {
+                        "request reassembly timed out".to_string()
+                    }
+                    DecodeError::Closing { .. } => "server closing".to_string(),
+                    DecodeError::Rejected { .. } => "request rejected".to_string(),
+                    DecodeError::Unsent(_) => "request unsent".to_string(),
+                    DecodeError::Unauthenticated => "request failed authentication".to_string(),
+                    DecodeError::MalformedPayload(_) => "request payload malformed".to_string(),
+                    DecodeError::Deserialize(_) => "request deserialization failed".to_string(),
+                    DecodeError::EmptyPayload(_) => "request payload empty".to_string(),
+                };
+                warn!(target: TARGET, "span=event id={} event=\"{}\"", id, event);
+            }
+
+            /// Closes the span of request `id` after its response, whose payload is
+            /// `response_size` bytes, was encoded.
+            pub fn close(&mut self, id: RequestId, response_size: u64) {
+                if let Some(span) = self.spans.as_mut().and_then(|spans| spans.remove(&id)) {
+                    debug!(target: TARGET,
+                           "span=close id={} request_size={} response_size={} elapsed_us={}",
+                           id,
+                           span.request_size,
+                           response_size,
+                           micros(span.opened));
+                }
+            }
+
+            /// Closes the span of request `id` after its response failed to encode.
+            pub fn encode_failed(&mut self, id: RequestId, error: &io::Error) {
+                if let Some(span) = self.spans.as_mut().and_then(|spans| spans.remove(&id)) {
+                    warn!(target: TARGET,
+                          "span=close id={} request_size={} elapsed_us={} event=\"response \
+                           failed to encode\" error=\"{}\"",
+                          id,
+                          span.request_size,
+                          micros(span.opened),
+                          error);
+                }
+            }
+        }
+
+        /// The microseconds since `start`.
+        fn micros(start: Instant) -> u64 {
+            let elapsed = start.elapsed();
+            elapsed.as_secs() * 1_000_000 + (elapsed.subsec_nanos() / 1_000) as u64
+        }
+    } else {
+        /// Stands in for request spans when the `spans` feature is disabled. Does nothing.
+        #[derive(Default)]
+        pub struct RequestSpans;
+
+        impl RequestSpans {
+            #[inline]
+            pub fn enabled() -> Self {
+                RequestSpans
+            }
+
+            #[inline]
+            pub fn open(&mut self, _: RequestId, _: u64) {}
+
+            #[inline]
+            pub fn rejected<E>(&mut self, _: RequestId, _: &DecodeError<E>) {}
+
+            #[inline]
+            pub fn close(&mut self, _: RequestId, _: u64) {}
+
+            #[inline]
+            pub fn encode_failed(&mut self, _: RequestId, _: &io::Error) {}
+        }
+    }
+}
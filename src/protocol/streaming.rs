@@ -0,0 +1,494 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use futures::{Async, Future, Poll, Stream};
+use serde;
+use std::error::Error as StdError;
+use std::{fmt, io};
+use std::marker::PhantomData;
+use super::{BincodeSerializer, DecodeError, PayloadSerializer, handshake};
+use super::frame::{CodecState, FLAG_ERROR, FLAG_STREAM_END, FLAG_STREAM_ITEM, FLAG_STREAM_START,
+                   Frame as WireFrame, FrameOptions, HEARTBEAT_ID};
+use super::handshake::HandshakeOptions;
+use tokio_core::io::{Codec, EasyBuf, Framed, Io};
+use tokio_core::reactor;
+use tokio_proto::BindClient;
+use tokio_proto::streaming::{Body, Message};
+use tokio_proto::streaming::multiplex::{ClientProto, Frame, RequestId, ServerProto,
+                                        StreamingMultiplex};
+use tokio_proto::util::client_proxy::{ClientProxy, Response};
+use tokio_service::Service;
+
+/// The kind of protocol a `StreamingProto` is bound as, given the type of its request bodies.
+type Kind<Encode> = StreamingMultiplex<Body<Encode, io::Error>>;
+
+/// A tokio `Codec` for the frames of a streaming multiplex protocol, in which a message may be
+/// followed by a stream of items with the same request id.
+///
+/// Every frame carries a flags byte after its id that records what it holds: a message, an
+/// item, the end of a stream, or an error. Compression isn't supported.
+pub struct StreamingCodec<Encode, Decode, S = BincodeSerializer> {
+    max_payload_size: u64,
+    frame: FrameOptions,
+    serializer: S,
+    state: CodecState,
+    _phantom_data: PhantomData<(Encode, Decode)>,
+}
+
+impl<Encode, Decode, S> StreamingCodec<Encode, Decode, S>
+    where S: PayloadSerializer + Default
+{
+    /// Returns a new `StreamingCodec` that rejects payloads larger than `max_payload_size` bytes.
+    pub fn new(max_payload_size: u64) -> Self {
+        StreamingCodec::with_serializer(max_payload_size, S::default())
+    }
+}
+
+impl<Encode, Decode, S> StreamingCodec<Encode, Decode, S>
+    where S: PayloadSerializer
+{
+    /// Returns a new `StreamingCodec` that uses `serializer` for payloads, rejecting payloads
+    /// larger than `max_payload_size` bytes.
+    pub fn with_serializer(max_payload_size: u64, serializer: S) -> Self {
+        StreamingCodec {
+            max_payload_size: max_payload_size,
+            frame: FrameOptions { streams: true, ..FrameOptions::default() },
+            serializer: serializer,
+            state: CodecState::Id,
+            _phantom_data: PhantomData,
+        }
+    }
+
+    /// Set whether payloads are followed by their CRC32. The peer must use the same setting.
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.frame.checksum = checksum;
+        self
+    }
+
+    /// Appends a frame with the given flags, holding `message`, to `buf`.
+    fn encode_payload<M>(&self,
+                         id: RequestId,
+                         flags: u8,
+                         message: &M,
+                         buf: &mut Vec<u8>)
+                         -> io::Result<()>
+        where M: serde::Serialize
+    {
+        let payload_size = self.serializer.serialized_size(message);
+        if payload_size > self.max_payload_size {
+            return Err(super::too_big(Some(id), payload_size, self.max_payload_size));
+        }
+        let frame_start = buf.len();
+        self.frame.write_header(buf, id, flags, 0, 0, payload_size);
+        let payload_start = buf.len();
+        if let Err(e) = self.serializer.serialize_into(buf, message) {
+            buf.truncate(frame_start);
+            return Err(e);
+        }
+        self.frame.write_trailer(buf, payload_start);
+        Ok(())
+    }
+
+    /// Decodes the next frame that isn't a heartbeat.
+    fn decode_frame(&mut self,
+                    buf: &mut EasyBuf)
+                    -> io::Result<Option<(RequestId, Result<WireFrame, DecodeError<S::Error>>)>> {
+        loop {
+            match self.state.decode(&self.frame, self.max_payload_size, buf)? {
+                // The sender expects heartbeats to be echoed by a transport, not a codec.
+                Some((HEARTBEAT_ID, _)) => trace!("--> Ignoring heartbeat."),
+                decoded => return Ok(decoded),
+            }
+        }
+    }
+
+    /// Deserializes the payload of a message, an item, or an error.
+    fn deserialize<T>(&self, payload: &EasyBuf) -> io::Result<T>
+        where T: serde::Deserialize,
+              S::Error: StdError + Send + Sync + 'static
+    {
+        self.serializer
+            .deserialize_slice(payload)
+            .map_err(|e| DecodeError::Deserialize(e).into_io())
+    }
+}
+
+impl<Encode, Decode, S> Codec for StreamingCodec<Encode, Decode, S>
+    where Encode: serde::Serialize,
+          Decode: serde::Deserialize,
+          S: PayloadSerializer,
+          S::Error: StdError + Send + Sync + 'static
+{
+    type Out = Frame<Encode, Encode, io::Error>;
+    type In = Frame<Decode, Decode, io::Error>;
+
+    fn encode(&mut self, frame: Self::Out, buf: &mut Vec<u8>) -> io::Result<()> {
+        match frame {
+            Frame::Message { id, message, body, .. } => {
+                let flags = if body { FLAG_STREAM_START } else { 0 };
+                self.encode_payload(id, flags, &message, buf)
+            }
+            Frame::Body { id, chunk: Some(item) } => {
+                self.encode_payload(id, FLAG_STREAM_ITEM, &item, buf)
+            }
+            Frame::Body { id, chunk: None } => {
+                self.frame.write_header(buf, id, FLAG_STREAM_END, 0, 0, 0);
+                let payload_start = buf.len();
+                self.frame.write_trailer(buf, payload_start);
+                Ok(())
+            }
+            Frame::Error { id, error } => {
+                self.encode_payload(id, FLAG_ERROR, &error.to_string(), buf)
+            }
+        }
+    }
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Self::In>> {
+        let (id, frame) = match self.decode_frame(buf)? {
+            Some((id, Ok(frame))) => (id, frame),
+            // The frame was well-delimited, so only its own request fails.
+            Some((id, Err(e))) => {
+                return Ok(Some(Frame::Error {
+                    id: id,
+                    error: e.into_io(),
+                }))
+            }
+            None => return Ok(None),
+        };
+        let decoded = if frame.flags & FLAG_STREAM_END != 0 {
+            Ok(Frame::Body {
+                id: id,
+                chunk: None,
+            })
+        } else if frame.flags & FLAG_ERROR != 0 {
+            self.deserialize::<String>(&frame.payload).map(|message| {
+                Frame::Error {
+                    id: id,
+                    error: io::Error::new(io::ErrorKind::Other, message),
+                }
+            })
+        } else if frame.flags & FLAG_STREAM_ITEM != 0 {
+            self.deserialize(&frame.payload).map(|item| {
+                Frame::Body {
+                    id: id,
+                    chunk: Some(item),
+                }
+            })
+        } else {
+            let body = frame.flags & FLAG_STREAM_START != 0;
+            self.deserialize(&frame.payload).map(|message| {
+                Frame::Message {
+                    id: id,
+                    message: message,
+                    body: body,
+                    solo: false,
+                }
+            })
+        };
+        Ok(Some(decoded.unwrap_or_else(|e| {
+            Frame::Error {
+                id: id,
+                error: e,
+            }
+        })))
+    }
+}
+
+/// Implements the streaming `multiplex::ServerProto` and `multiplex::ClientProto` traits using
+/// a `StreamingCodec`.
+///
+/// A server can answer a request with `Message::WithBody`, streaming the items of the body to
+/// the client as they are produced. tokio-proto stops reading from the connection while a body
+/// has items that haven't been consumed, so a slow consumer slows down the peer rather than
+/// making the reader buffer.
+pub struct StreamingProto<Encode, Decode, S = BincodeSerializer> {
+    max_payload_size: u64,
+    checksum: bool,
+    handshake: HandshakeOptions,
+    serializer: S,
+    _phantom_data: PhantomData<(Encode, Decode)>,
+}
+
+impl<Encode, Decode, S> StreamingProto<Encode, Decode, S>
+    where S: PayloadSerializer + Default
+{
+    /// Returns a new `StreamingProto` that rejects payloads larger than `max_payload_size` bytes.
+    pub fn new(max_payload_size: u64) -> Self {
+        StreamingProto::with_serializer(max_payload_size, S::default())
+    }
+}
+
+impl<Encode, Decode, S> StreamingProto<Encode, Decode, S>
+    where S: PayloadSerializer
+{
+    /// Returns a new `StreamingProto` whose codecs serialize payloads with `serializer`,
+    /// rejecting payloads larger than `max_payload_size` bytes.
+    pub fn with_serializer(max_payload_size: u64, serializer: S) -> Self {
+        StreamingProto {
+            max_payload_size: max_payload_size,
+            checksum: false,
+            handshake: HandshakeOptions::default(),
+            serializer: serializer,
+            _phantom_data: PhantomData,
+        }
+    }
+
+    /// Set whether payloads are followed by their CRC32. The peer must use the same setting.
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
+}
+
+impl<Encode, Decode, S> StreamingProto<Encode, Decode, S>
+    where S: PayloadSerializer + Clone
+{
+    fn codec(&self) -> StreamingCodec<Encode, Decode, S> {
+        StreamingCodec::with_serializer(self.max_payload_size, self.serializer.clone())
+            .checksum(self.checksum)
+    }
+}
+
+impl<Encode, Decode, S> Clone for StreamingProto<Encode, Decode, S>
+    where S: Clone
+{
+    fn clone(&self) -> Self {
+        StreamingProto {
+            max_payload_size: self.max_payload_size,
+            checksum: self.checksum,
+            handshake: self.handshake.clone(),
+            serializer: self.serializer.clone(),
+            _phantom_data: PhantomData,
+        }
+    }
+}
+
+impl<T, Encode, Decode, S> ServerProto<T> for StreamingProto<Encode, Decode, S>
+    where T: Io + 'static,
+          Encode: serde::Serialize + 'static,
+          Decode: serde::Deserialize + 'static,
+          S: PayloadSerializer + Clone + 'static,
+          S::Error: StdError + Send + Sync + 'static
+{
+    type Request = Decode;
+    type RequestBody = Decode;
+    type Response = Encode;
+    type ResponseBody = Encode;
+    type Error = io::Error;
+    type Transport = Framed<T, StreamingCodec<Encode, Decode, S>>;
+    type BindTransport = Box<Future<Item = Self::Transport, Error = io::Error>>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        let codec = self.codec();
+        Box::new(handshake::server(io, self.handshake.clone()).map(move |(io, _)| io.framed(codec)))
+    }
+}
+
+impl<T, Encode, Decode, S> ClientProto<T> for StreamingProto<Encode, Decode, S>
+    where T: Io + 'static,
+          Encode: serde::Serialize + 'static,
+          Decode: serde::Deserialize + 'static,
+          S: PayloadSerializer + Clone + 'static,
+          S::Error: StdError + Send + Sync + 'static
+{
+    type Request = Encode;
+    type RequestBody = Encode;
+    type Response = Decode;
+    type ResponseBody = Decode;
+    type Error = io::Error;
+    type Transport = Framed<T, StreamingCodec<Encode, Decode, S>>;
+    type BindTransport = Box<Future<Item = Self::Transport, Error = io::Error>>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        let codec = self.codec();
+        Box::new(handshake::client(io, self.handshake.clone()).map(move |(io, _)| io.framed(codec)))
+    }
+}
+
+/// A connection to a server bound with a `StreamingProto`, whose responses are streams.
+pub struct StreamingClient<Encode, Decode>
+    where Encode: 'static,
+          Decode: 'static
+{
+    inner: ClientProxy<Message<Encode, Body<Encode, io::Error>>,
+                       Message<Decode, Body<Decode, io::Error>>,
+                       io::Error>,
+}
+
+impl<Encode, Decode> StreamingClient<Encode, Decode>
+    where Encode: serde::Serialize + 'static,
+          Decode: serde::Deserialize + 'static
+{
+    /// Binds `proto` to `io` on the reactor of `handle`.
+    pub fn new<T, S>(handle: &reactor::Handle,
+                     io: T,
+                     proto: &StreamingProto<Encode, Decode, S>)
+                     -> Self
+        where T: Io + 'static,
+              S: PayloadSerializer + Clone + 'static,
+              S::Error: StdError + Send + Sync + 'static
+    {
+        StreamingClient { inner: BindClient::<Kind<Encode>, T>::bind_client(proto, handle, io) }
+    }
+
+    /// Sends `request`, returning the stream of the response: the message the server answered
+    /// with, followed by the items of its body, if any.
+    pub fn call(&self, request: Encode) -> ResponseStream<Decode> {
+        ResponseStream {
+            state: ResponseState::Waiting(self.inner.call(Message::WithoutBody(request))),
+        }
+    }
+}
+
+impl<Encode, Decode> Clone for StreamingClient<Encode, Decode>
+    where Encode: 'static,
+          Decode: 'static
+{
+    fn clone(&self) -> Self {
+        StreamingClient { inner: self.inner.clone() }
+    }
+}
+
+impl<Encode, Decode> fmt::Debug for StreamingClient<Encode, Decode>
+    where Encode: 'static,
+          Decode: 'static
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StreamingClient {{ .. }}")
+    }
+}
+
+/// The response to a `StreamingClient::call`.
+pub struct ResponseStream<Decode>
+    where Decode: 'static
+{
+    state: ResponseState<Decode>,
+}
+
+enum ResponseState<Decode>
+    where Decode: 'static
+{
+    /// Waiting for the message that starts the response.
+    Waiting(Response<Message<Decode, Body<Decode, io::Error>>, io::Error>),
+    /// Yielding the items of the body.
+    Streaming(Body<Decode, io::Error>),
+    Done,
+}
+
+impl<Decode> Stream for ResponseStream<Decode>
+    where Decode: 'static
+{
+    type Item = Decode;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Decode>, io::Error> {
+        let message = match self.state {
+            ResponseState::Waiting(ref mut response) => {
+                match response.poll()? {
+                    Async::Ready(message) => message,
+                    Async::NotReady => return Ok(Async::NotReady),
+                }
+            }
+            ResponseState::Streaming(ref mut body) => return body.poll(),
+            ResponseState::Done => return Ok(Async::Ready(None)),
+        };
+        match message {
+            Message::WithoutBody(head) => {
+                self.state = ResponseState::Done;
+                Ok(Async::Ready(Some(head)))
+            }
+            Message::WithBody(head, body) => {
+                self.state = ResponseState::Streaming(body);
+                Ok(Async::Ready(Some(head)))
+            }
+        }
+    }
+}
+
+#[test]
+fn codec_round_trip() {
+    let mut codec: StreamingCodec<u32, u32> = StreamingCodec::new(2_000_000);
+    let mut vec = Vec::new();
+    let frames = vec![Frame::Message {
+                          id: 1,
+                          message: 0,
+                          body: true,
+                          solo: false,
+                      },
+                      Frame::Body {
+                          id: 1,
+                          chunk: Some(7),
+                      },
+                      Frame::Body {
+                          id: 1,
+                          chunk: None,
+                      },
+                      Frame::Error {
+                          id: 2,
+                          error: io::Error::new(io::ErrorKind::Other, "oops"),
+                      }];
+    for frame in frames {
+        codec.encode(frame, &mut vec).unwrap();
+    }
+    // The flags byte follows the 8-byte id.
+    assert_eq!(vec[8], FLAG_STREAM_START);
+
+    let mut buf = EasyBuf::from(vec);
+    match codec.decode(&mut buf) {
+        Ok(Some(Frame::Message { id: 1, message: 0, body: true, .. })) => {}
+        bad => panic!("Expected the start of a stream, but got {:?}", bad),
+    }
+    match codec.decode(&mut buf) {
+        Ok(Some(Frame::Body { id: 1, chunk: Some(7) })) => {}
+        bad => panic!("Expected an item, but got {:?}", bad),
+    }
+    match codec.decode(&mut buf) {
+        Ok(Some(Frame::Body { id: 1, chunk: None })) => {}
+        bad => panic!("Expected the end of the stream, but got {:?}", bad),
+    }
+    match codec.decode(&mut buf) {
+        Ok(Some(Frame::Error { id: 2, ref error })) if error.to_string() == "oops" => {}
+        bad => panic!("Expected an error, but got {:?}", bad),
+    }
+}
+
+#[test]
+fn stream_response() {
+    use futures::{Sink, future, stream};
+    use super::in_memory;
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+
+    /// Answers `n` with 0, followed by a stream of 1 through `n`.
+    struct Count {
+        handle: reactor::Handle,
+    }
+
+    impl Service for Count {
+        type Request = Message<u32, Body<u32, io::Error>>;
+        type Response = Message<u32, Body<u32, io::Error>>;
+        type Error = io::Error;
+        type Future = future::FutureResult<Self::Response, io::Error>;
+
+        fn call(&self, request: Self::Request) -> Self::Future {
+            let n = match request {
+                Message::WithoutBody(n) |
+                Message::WithBody(n, _) => n,
+            };
+            let (tx, body) = Body::pair();
+            let items = stream::iter((1..n + 1).map(|i| Ok(Ok(i))));
+            self.handle.spawn(tx.send_all(items).map(|_| ()).map_err(|_| ()));
+            future::ok(Message::WithBody(0, body))
+        }
+    }
+
+    let mut core = Core::new().unwrap();
+    let (client_io, server_io) = in_memory();
+    let proto: StreamingProto<u32, u32> = StreamingProto::new(2_000_000);
+    let count = Count { handle: core.handle() };
+    BindServer::<Kind<u32>, _>::bind_server(&proto, &core.handle(), server_io, count);
+    let client = StreamingClient::new(&core.handle(), client_io, &proto);
+    assert_eq!(core.run(client.call(3).collect()).unwrap(), vec![0, 1, 2, 3]);
+}
@@ -0,0 +1,2448 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use serde;
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream, task};
+use super::{Codec, DecodeError, Drain, PayloadSerializer, ProtocolError, ProtocolErrorCode};
+use super::drain::Registration;
+use super::error;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+use tokio_core::io::{EasyBuf, Io};
+use tokio_core::reactor::{Handle, Interval, Remote, Timeout};
+use tokio_proto::streaming::multiplex::RequestId;
+
+/// Once this many encoded bytes are waiting to be written, `start_send` stops accepting frames,
+/// unless the transport is given a high-water mark of its own.
+const BACKPRESSURE_BOUNDARY: usize = 8 * 1024;
+
+/// The most bytes read at once by a transport with a max backlog, which counts the frames
+/// buffered after each read.
+const BACKLOG_CHUNK: usize = 4 * 1024;
+
+/// Configures the heartbeats a client sends to detect a dead server.
+#[derive(Clone)]
+pub struct HeartbeatOptions {
+    /// The reactor that runs the heartbeat timers.
+    pub remote: Remote,
+    /// How often a heartbeat is sent.
+    pub interval: Duration,
+    /// How long to wait for the server to echo a heartbeat.
+    pub timeout: Duration,
+}
+
+impl HeartbeatOptions {
+    /// Starts the heartbeat timers. Must be called on the reactor's thread.
+    pub fn start(&self) -> io::Result<Heartbeat> {
+        let handle = match self.remote.handle() {
+            Some(handle) => handle,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                          "Heartbeats must be started on the thread running \
+                                           their reactor"))
+            }
+        };
+        Ok(Heartbeat {
+            interval: Interval::new(self.interval, &handle)?,
+            timeout: self.timeout,
+            handle: handle,
+            deadline: None,
+            sent: None,
+        })
+    }
+}
+
+/// The heartbeat timers of a connection.
+pub struct Heartbeat {
+    interval: Interval,
+    timeout: Duration,
+    handle: Handle,
+    /// Set while waiting for a heartbeat to be echoed.
+    deadline: Option<Timeout>,
+    /// When the heartbeat being waited for was sent, to measure the round-trip time.
+    sent: Option<Instant>,
+}
+
+/// Configures how long a connection may go without making progress on a frame it has started
+/// to read or write.
+#[derive(Clone)]
+pub struct IdleTimeoutOptions {
+    /// The reactor that runs the timers.
+    pub remote: Remote,
+    /// How long to wait for more of a partially received frame.
+    pub read: Duration,
+    /// How long to wait for the peer to accept more of the frames being sent.
+    pub write: Duration,
+}
+
+impl IdleTimeoutOptions {
+    /// Returns the timers. Must be called on the reactor's thread.
+    pub fn start(&self) -> io::Result<IdleTimeouts> {
+        let handle = match self.remote.handle() {
+            Some(handle) => handle,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                          "Idle timeouts must be started on the thread running \
+                                           their reactor"))
+            }
+        };
+        Ok(IdleTimeouts {
+            read: IdleTimer::new(self.read, "read"),
+            write: IdleTimer::new(self.write, "write"),
+            handle: handle,
+        })
+    }
+}
+
+/// The idle timers of a connection.
+pub struct IdleTimeouts {
+    read: IdleTimer,
+    write: IdleTimer,
+    handle: Handle,
+}
+
+/// Fails once a transport has waited too long without making progress.
+struct IdleTimer {
+    timeout: Duration,
+    /// What the transport is waiting to do, for the error message.
+    operation: &'static str,
+    /// Set while the transport is waiting.
+    deadline: Option<Timeout>,
+}
+
+impl IdleTimer {
+    fn new(timeout: Duration, operation: &'static str) -> Self {
+        IdleTimer {
+            timeout: timeout,
+            operation: operation,
+            deadline: None,
+        }
+    }
+
+    /// Called when the transport made progress or stopped waiting.
+    fn reset(&mut self) {
+        self.deadline = None;
+    }
+
+    /// Called when the transport is waiting. Fails if it has waited longer than the timeout.
+    fn poll(&mut self, handle: &Handle) -> io::Result<()> {
+        let mut deadline = match self.deadline.take() {
+            Some(deadline) => deadline,
+            None => Timeout::new(self.timeout, handle)?,
+        };
+        // Polling registers the task to be woken at the deadline.
+        let expired = deadline.poll()?;
+        self.deadline = Some(deadline);
+        if let Async::Ready(()) = expired {
+            warn!("No progress made on a {} within {:?}; closing the connection.",
+                  self.operation,
+                  self.timeout);
+            return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                      format!("No progress made on a {} within {:?}",
+                                              self.operation,
+                                              self.timeout)));
+        }
+        Ok(())
+    }
+}
+
+/// Configures how long a server keeps a connection open without any frames sent or received.
+#[derive(Clone)]
+pub struct IdleReaperOptions {
+    /// The reactor that runs the timer.
+    pub remote: Remote,
+    /// How long a connection may go without a frame.
+    pub timeout: Duration,
+}
+
+impl IdleReaperOptions {
+    /// Returns the reaper. Must be called on the reactor's thread.
+    pub fn start(&self) -> io::Result<IdleReaper> {
+        let handle = match self.remote.handle() {
+            Some(handle) => handle,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                          "Idle connections must be reaped on the thread \
+                                           running their reactor"))
+            }
+        };
+        Ok(IdleReaper {
+            timeout: self.timeout,
+            handle: handle,
+            last_active: Instant::now(),
+            deadline: None,
+        })
+    }
+}
+
+/// Closes a connection once no frames have been sent or received on it for too long.
+pub struct IdleReaper {
+    timeout: Duration,
+    handle: Handle,
+    /// When the last frame was sent or received.
+    last_active: Instant,
+    /// Set while waiting for the connection to go idle.
+    deadline: Option<Timeout>,
+}
+
+impl IdleReaper {
+    /// Called when a frame is sent or received.
+    fn active(&mut self) {
+        self.last_active = Instant::now();
+    }
+
+    /// True if the connection has gone without frames for too long. Otherwise, arranges for the
+    /// task to be woken when it would have. Rather than resetting the timer for every frame, a
+    /// timer that fires early is replaced by one for the rest of the timeout.
+    fn poll_idle(&mut self) -> io::Result<bool> {
+        let now = Instant::now();
+        let idle_at = self.last_active + self.timeout;
+        if now >= idle_at {
+            return Ok(true);
+        }
+        let mut deadline = match self.deadline.take() {
+            Some(deadline) => deadline,
+            None => Timeout::new(idle_at - now, &self.handle)?,
+        };
+        // Polling registers the task to be woken at the deadline.
+        if let Async::Ready(()) = deadline.poll()? {
+            deadline = Timeout::new(idle_at - now, &self.handle)?;
+            deadline.poll()?;
+        }
+        self.deadline = Some(deadline);
+        Ok(false)
+    }
+}
+
+/// Configures how long a transport holds frames back before writing them, so that small frames
+/// sent in quick succession go out in one write.
+#[derive(Clone)]
+pub struct FlushDelayOptions {
+    /// The reactor that runs the timer.
+    pub remote: Remote,
+    /// How long the frames buffered may wait to be written.
+    pub delay: Duration,
+    /// Once this many bytes are buffered, they are written without waiting.
+    pub threshold: usize,
+}
+
+impl FlushDelayOptions {
+    /// Returns the timer. Must be called on the reactor's thread.
+    pub fn start(&self) -> io::Result<FlushDelay> {
+        let handle = match self.remote.handle() {
+            Some(handle) => handle,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                          "Flush delays must be started on the thread running \
+                                           their reactor"))
+            }
+        };
+        Ok(FlushDelay {
+            delay: self.delay,
+            threshold: self.threshold,
+            handle: handle,
+            deadline: None,
+        })
+    }
+}
+
+/// Holds the frames a transport buffers back until they are due or there are enough of them.
+pub struct FlushDelay {
+    delay: Duration,
+    threshold: usize,
+    handle: Handle,
+    /// Set while frames are held back, to wake the task when they are due.
+    deadline: Option<Timeout>,
+}
+
+impl FlushDelay {
+    /// True if the `buffered` bytes waiting to be written should be held back a while longer,
+    /// in which case the task is woken when they are due.
+    fn poll_hold(&mut self, buffered: usize) -> io::Result<bool> {
+        if buffered == 0 || buffered >= self.threshold {
+            self.deadline = None;
+            return Ok(false);
+        }
+        let mut deadline = match self.deadline.take() {
+            Some(deadline) => deadline,
+            None => Timeout::new(self.delay, &self.handle)?,
+        };
+        // Polling registers the task to be woken at the deadline.
+        if let Async::Ready(()) = deadline.poll()? {
+            return Ok(false);
+        }
+        self.deadline = Some(deadline);
+        Ok(true)
+    }
+}
+
+/// Configures how long a client waits for the response to each request.
+#[derive(Clone)]
+pub struct ResponseTimeoutOptions {
+    /// The reactor that runs the timers.
+    pub remote: Remote,
+    /// How long to wait for a response.
+    pub timeout: Duration,
+}
+
+impl ResponseTimeoutOptions {
+    /// Returns the timers. Must be called on the reactor's thread.
+    pub fn start(&self) -> io::Result<ResponseTimeouts> {
+        let handle = match self.remote.handle() {
+            Some(handle) => handle,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                          "Response timeouts must be started on the thread \
+                                           running their reactor"))
+            }
+        };
+        Ok(ResponseTimeouts {
+            timeout: self.timeout,
+            handle: handle,
+            pending: HashMap::new(),
+            abandoned: HashSet::new(),
+        })
+    }
+}
+
+/// The timers of the requests a client is awaiting responses to.
+pub struct ResponseTimeouts {
+    timeout: Duration,
+    handle: Handle,
+    /// The deadline of every request sent and not yet answered.
+    pending: HashMap<RequestId, Timeout>,
+    /// Requests that timed out, whose responses are discarded if they arrive after all.
+    abandoned: HashSet<RequestId>,
+}
+
+impl ResponseTimeouts {
+    /// Starts timing request `id`, which was just sent.
+    fn start(&mut self, id: RequestId) -> io::Result<()> {
+        let mut deadline = Timeout::new(self.timeout, &self.handle)?;
+        // Polling registers the task to be woken at the deadline. If it has already passed, the
+        // next `poll_expired` will say so.
+        deadline.poll()?;
+        self.pending.insert(id, deadline);
+        Ok(())
+    }
+
+    /// Returns a request whose response didn't arrive in time, if any, and stops timing it.
+    fn poll_expired(&mut self) -> io::Result<Option<RequestId>> {
+        let mut expired = None;
+        for (&id, deadline) in &mut self.pending {
+            if let Async::Ready(()) = deadline.poll()? {
+                expired = Some(id);
+                break;
+            }
+        }
+        if let Some(id) = expired {
+            self.pending.remove(&id);
+        }
+        Ok(expired)
+    }
+
+    /// Discards the response to request `id`, which timed out, if it arrives after all.
+    fn abandon(&mut self, id: RequestId) {
+        self.abandoned.insert(id);
+    }
+
+    /// Stops timing request `id`, whose response arrived. Returns false if the request had
+    /// already timed out, in which case the response must be discarded.
+    fn finish(&mut self, id: RequestId) -> bool {
+        if self.abandoned.remove(&id) {
+            return false;
+        }
+        self.pending.remove(&id);
+        true
+    }
+}
+
+/// Configures how many requests per second a server reads from each connection.
+#[derive(Clone)]
+pub struct RateLimitOptions {
+    /// The reactor that runs the timers.
+    pub remote: Remote,
+    /// How many requests may be read per second, on average.
+    pub per_second: u32,
+    /// How many requests may be read at once after the connection was quiet.
+    pub burst: u32,
+}
+
+impl RateLimitOptions {
+    /// Returns a full token bucket. Must be called on the reactor's thread.
+    pub fn start(&self) -> io::Result<RateLimiter> {
+        let handle = match self.remote.handle() {
+            Some(handle) => handle,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                          "Rate limits must be started on the thread running \
+                                           their reactor"))
+            }
+        };
+        Ok(RateLimiter {
+            per_second: self.per_second as f64,
+            burst: self.burst as f64,
+            tokens: self.burst as f64,
+            refilled: Instant::now(),
+            handle: handle,
+            wakeup: None,
+        })
+    }
+}
+
+/// A token bucket holding a token for each request, or frame, a connection may read.
+pub struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    tokens: f64,
+    /// When tokens were last added.
+    refilled: Instant,
+    handle: Handle,
+    /// Set while waiting for a token.
+    wakeup: Option<Timeout>,
+}
+
+impl RateLimiter {
+    /// True if a request may be read. Otherwise, arranges for the current task to be woken when
+    /// one may.
+    pub fn poll_ready(&mut self) -> io::Result<bool> {
+        let now = Instant::now();
+        let elapsed = now - self.refilled;
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.burst);
+        self.refilled = now;
+        if self.tokens >= 1. {
+            self.wakeup = None;
+            return Ok(true);
+        }
+        let wait = (1. - self.tokens) / self.per_second;
+        let wait = Duration::new(wait as u64, (wait.fract() * 1e9) as u32);
+        let mut wakeup = Timeout::new(wait, &self.handle)?;
+        // Polling registers the task to be woken at the deadline.
+        if let Async::Ready(()) = wakeup.poll()? {
+            task::park().unpark();
+        }
+        self.wakeup = Some(wakeup);
+        Ok(false)
+    }
+
+    /// Takes the token of a request that was read.
+    pub fn take(&mut self) {
+        self.tokens -= 1.;
+    }
+}
+
+/// Frames messages on a connection with a `Codec`, and handles the frames that never reach the
+/// service: heartbeats and pings are echoed by servers, and sent and awaited by clients, which
+/// time them to measure the round-trip time.
+///
+/// A server transport can also stop reading requests while too many are awaiting a response or
+/// while it has read too many too quickly, and can be drained: told to stop reading requests
+/// and close once it has responded to the ones it read, after telling the client it is closing
+/// with a goodbye frame. A client that receives a goodbye fails the requests made after it with
+/// `DecodeError::Closing`, rather than sending them. Either side can time out a frame that
+/// makes no progress, and a client can stop waiting for responses that take too long. A server
+/// whose codec persists requests acknowledges each once it is persisted. A side whose connection
+/// fails with an error that has a `ProtocolErrorCode`, such as a payload too large to skip, tells
+/// the other why with a protocol error frame, and the other fails with the same `ProtocolError`.
+///
+/// Frames sent while the connection is busy are encoded one after another into a single buffer,
+/// which `poll_complete` writes with as few calls as the connection allows. A frame that fails
+/// to encode, such as one that is too big, is left out of the buffer and dropped, without
+/// disturbing the frames around it; a client waiting for the response to a dropped request
+/// learns of it through its response timeout. With `Codec::write_through`, the buffer is
+/// written out whenever it fills up while a frame is encoded, so a large frame is never
+/// buffered in full, and a frame whose connection breaks partway through stops being
+/// serialized. Once reading or writing the connection fails, the buffered frames are freed and
+/// no more are encoded.
+pub struct Transport<T, C> {
+    upstream: T,
+    codec: C,
+    eof: bool,
+    is_readable: bool,
+    rd: EasyBuf,
+    wr: Vec<u8>,
+    /// Encoded frames waiting to be moved to `wr`, by priority, if frames carry priorities.
+    queued: BTreeMap<u8, VecDeque<Vec<u8>>>,
+    /// The number of bytes in `queued`.
+    queued_bytes: usize,
+    /// The read buffer is grown to hold at least this many bytes before every read.
+    read_capacity: usize,
+    /// If set, the read buffer is grown to hold the rest of the payload being read, up to this
+    /// many bytes of it, before every read.
+    read_whole_payloads: Option<usize>,
+    /// Once more encoded bytes than this are waiting to be written, frames aren't accepted.
+    high_water_mark: usize,
+    /// Once this many frames are read without yielding, the task yields to let others run.
+    max_frames_per_poll: Option<usize>,
+    /// Frames read since the task last yielded.
+    frames_read: usize,
+    /// Once the read buffer holds this many complete frames, no more bytes are read.
+    max_backlog: Option<usize>,
+    /// Once the read buffer and the payloads of the requests in flight hold this many bytes, no
+    /// more are read.
+    max_buffered: Option<usize>,
+    /// The payload sizes of the requests in flight, if `max_buffered` is set.
+    held: HashMap<RequestId, usize>,
+    /// The sum of `held`.
+    held_bytes: usize,
+    max_in_flight: Option<usize>,
+    /// Requests that have been read but not yet responded to. Only tracked if `max_in_flight`,
+    /// `drain`, a reaper, or `reject_duplicates` is set.
+    in_flight: HashSet<RequestId>,
+    /// If true, requests reusing the id of a request in flight are rejected.
+    reject_duplicates: bool,
+    heartbeat: Option<Heartbeat>,
+    drain: Option<Registration>,
+    timeouts: Option<IdleTimeouts>,
+    reaper: Option<IdleReaper>,
+    flush_delay: Option<FlushDelay>,
+    response_timeouts: Option<ResponseTimeouts>,
+    rate_limit: Option<RateLimiter>,
+    /// Set if this server has told its client it is closing.
+    said_goodbye: bool,
+    /// The reason given by the server, once this client has been told it is closing.
+    goodbye: Option<String>,
+    /// Requests not sent because the server is closing, which are yet to be failed.
+    refused: Vec<RequestId>,
+    /// Requests that failed to encode, which are yet to be failed.
+    unsent: Vec<(RequestId, io::Error)>,
+    /// Whether the frames sent are requests, rather than responses.
+    client: bool,
+    /// The kind of the error that broke the connection, once reading or writing it failed.
+    /// Frames sent after that aren't encoded.
+    failed: Option<io::ErrorKind>,
+    /// The requests being persisted, which are acknowledged once they are.
+    persisting: Vec<(RequestId, Box<Future<Item = (), Error = io::Error>>)>,
+}
+
+impl<T, C> Transport<T, C> {
+    /// Returns a transport that frames messages on `upstream` with `codec`.
+    pub fn new(upstream: T, codec: C) -> Self {
+        Transport::with_capacity(upstream, codec, 0, BACKPRESSURE_BOUNDARY)
+    }
+
+    /// Like `new`, but reserves room for `read` bytes in the read buffer and `write` bytes in
+    /// the write buffer up front, so that connections carrying large payloads don't repeatedly
+    /// reallocate them. The read buffer is kept at `read` bytes or more as frames are split off
+    /// it.
+    pub fn with_capacity(upstream: T, codec: C, read: usize, write: usize) -> Self {
+        Transport {
+            upstream: upstream,
+            codec: codec,
+            eof: false,
+            is_readable: false,
+            rd: EasyBuf::new(),
+            wr: Vec::with_capacity(write),
+            queued: BTreeMap::new(),
+            queued_bytes: 0,
+            read_capacity: read,
+            read_whole_payloads: None,
+            high_water_mark: BACKPRESSURE_BOUNDARY,
+            max_frames_per_poll: None,
+            frames_read: 0,
+            max_backlog: None,
+            max_buffered: None,
+            held: HashMap::new(),
+            held_bytes: 0,
+            max_in_flight: None,
+            in_flight: HashSet::new(),
+            reject_duplicates: false,
+            heartbeat: None,
+            drain: None,
+            timeouts: None,
+            reaper: None,
+            flush_delay: None,
+            response_timeouts: None,
+            rate_limit: None,
+            said_goodbye: false,
+            goodbye: None,
+            refused: vec![],
+            unsent: vec![],
+            client: false,
+            failed: None,
+            persisting: vec![],
+        }
+    }
+
+    /// The codec framing the connection, such as to check the payload size limits negotiated
+    /// with the peer.
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    /// The number of encoded bytes waiting to be written to the connection.
+    pub fn buffered_outbound(&self) -> usize {
+        self.wr.len() + self.queued_bytes
+    }
+
+    /// Records that the connection broke with `e`, freeing the frames that will never be
+    /// written, and returns `e`.
+    fn fail(&mut self, e: io::Error) -> io::Error {
+        debug!("Connection failed: {}", e);
+        self.failed = Some(e.kind());
+        self.wr = Vec::new();
+        self.queued.clear();
+        self.queued_bytes = 0;
+        e
+    }
+
+    /// Stop accepting frames while more than `bytes` encoded bytes are waiting to be written,
+    /// if set, instead of the default of 8 KiB.
+    pub fn high_water_mark(mut self, bytes: Option<usize>) -> Self {
+        self.high_water_mark = bytes.unwrap_or(BACKPRESSURE_BOUNDARY);
+        self
+    }
+
+    /// Read at most `frames` frames before yielding to the other tasks on the reactor, if set, so
+    /// that a peer that sends a large batch at once doesn't hold up the other connections. The
+    /// rest of the batch is read once the task runs again. By default there is no limit.
+    pub fn max_frames_per_poll(mut self, frames: Option<usize>) -> Self {
+        self.max_frames_per_poll = frames;
+        self
+    }
+
+    /// Stop reading once the read buffer holds `frames` complete frames that haven't been
+    /// passed on, if set, reading `BACKLOG_CHUNK` bytes at a time to find out.
+    pub fn max_backlog(mut self, frames: Option<usize>) -> Self {
+        self.max_backlog = frames;
+        self
+    }
+
+    /// Hold at most `bytes` bytes read from the connection, if set: those in the read buffer,
+    /// plus, on a server, the payloads of the requests awaiting a response. Once the limit is
+    /// reached, reading stops until a response frees some of it; if nothing is in flight, the
+    /// frame being read can never fit, and the connection fails with an error of kind
+    /// `InvalidData`. Unlike the max payload size, this bounds the memory a peer can tie up with
+    /// many large requests at once. By default there is no limit.
+    pub fn max_buffered_bytes(mut self, bytes: Option<usize>) -> Self {
+        self.max_buffered = bytes;
+        self
+    }
+
+    /// Once the header of a frame is read, grow the read buffer to hold the rest of its payload,
+    /// up to `bytes` of it, if set, so that the payload is read in as few calls as the
+    /// connection allows. With `max_backlog`, reads are made in chunks of that size rather than
+    /// `BACKLOG_CHUNK` while a payload is read. By default the buffer grows as bytes are read.
+    pub fn read_whole_payloads(mut self, bytes: Option<usize>) -> Self {
+        self.read_whole_payloads = bytes;
+        self
+    }
+
+    /// The number of bytes that can still be read before reaching `max_buffered`, if set.
+    fn read_budget(&self) -> Option<usize> {
+        self.max_buffered.map(|max| max.saturating_sub(self.rd.len() + self.held_bytes))
+    }
+
+    /// Answer a request that reuses the id of a request still in flight with a rejection frame,
+    /// rather than passing it on to the service, if `reject` is true. The client fails the
+    /// request with `DecodeError::Rejected`. Only applies to servers.
+    pub fn reject_duplicate_ids(mut self, reject: bool) -> Self {
+        self.reject_duplicates = reject;
+        self
+    }
+
+    /// Allow at most `max_in_flight` outstanding requests, if set.
+    pub fn max_in_flight(mut self, max_in_flight: Option<usize>) -> Self {
+        self.max_in_flight = max_in_flight;
+        self
+    }
+
+    /// Send heartbeats, failing if they aren't echoed in time.
+    pub fn heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = Some(heartbeat);
+        self
+    }
+
+    /// Fail if a frame that is partly read or written makes no progress in time.
+    pub fn idle_timeouts(mut self, timeouts: IdleTimeouts) -> Self {
+        self.timeouts = Some(timeouts);
+        self
+    }
+
+    /// Close the connection once `reaper` finds it idle, with no requests in flight.
+    pub fn reap_idle(mut self, reaper: IdleReaper) -> Self {
+        self.reaper = Some(reaper);
+        self
+    }
+
+    /// Hold frames back for up to the delay of `delay` before writing them, so that frames sent
+    /// in quick succession go out in one write, unless its threshold of bytes is buffered. By
+    /// default, frames are written as soon as they are sent, trading throughput for latency.
+    pub fn flush_delay(mut self, delay: FlushDelay) -> Self {
+        self.flush_delay = Some(delay);
+        self
+    }
+
+    /// Send requests rather than responses, so that a request that fails to encode is failed
+    /// rather than rejected to the peer. Set on clients.
+    pub fn client(mut self) -> Self {
+        self.client = true;
+        self
+    }
+
+    /// Fail requests whose responses don't arrive in time. Their request ids are retired, and
+    /// their responses discarded if they arrive later.
+    pub fn response_timeouts(mut self, timeouts: ResponseTimeouts) -> Self {
+        self.response_timeouts = Some(timeouts);
+        self
+    }
+
+    /// Stop reading requests while `limiter` has no tokens. Responses are still sent.
+    pub fn rate_limit(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limit = Some(limiter);
+        self
+    }
+
+    /// Close gracefully when `drain` starts, if set.
+    pub fn drain(mut self, drain: Option<Drain>) -> Self {
+        self.drain = drain.map(|drain| drain.register());
+        self
+    }
+
+    /// Returns a reference to the underlying I/O stream.
+    pub fn get_ref(&self) -> &T {
+        &self.upstream
+    }
+
+    /// Called when bytes were read, or reading stopped for a reason other than the peer.
+    fn reset_read_timeout(&mut self) {
+        if let Some(ref mut timeouts) = self.timeouts {
+            timeouts.read.reset();
+        }
+    }
+
+    /// Called when writing would block. Fails if no bytes could be written for too long.
+    fn poll_write_timeout(&mut self) -> io::Result<()> {
+        match self.timeouts {
+            Some(ref mut timeouts) => timeouts.write.poll(&timeouts.handle),
+            None => Ok(()),
+        }
+    }
+
+    /// True if the task has read its share of frames, in which case it is woken to read the rest
+    /// after the other tasks that are ready have run.
+    fn yield_turn(&mut self) -> bool {
+        match self.max_frames_per_poll {
+            Some(max) if self.frames_read >= max => {
+                trace!("Read {} frames; yielding.", self.frames_read);
+                self.frames_read = 0;
+                task::park().unpark();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn tracks_in_flight(&self) -> bool {
+        self.max_in_flight.is_some() || self.drain.is_some() || self.reaper.is_some() ||
+        self.reject_duplicates
+    }
+
+    /// Called when a frame was sent or received.
+    fn active(&mut self) {
+        if let Some(ref mut reaper) = self.reaper {
+            reaper.active();
+        }
+    }
+
+    /// Adds an encoded frame to the queue of frames of `priority`.
+    fn enqueue(&mut self, priority: u8, frame: Vec<u8>) {
+        if frame.is_empty() {
+            return;
+        }
+        self.queued_bytes += frame.len();
+        self.queued.entry(priority).or_insert_with(VecDeque::new).push_back(frame);
+    }
+
+    /// Moves queued frames to the write buffer, highest priority first, until it holds a full
+    /// write's worth. Frames are only moved once the buffer is empty, so that a frame queued
+    /// meanwhile can still overtake those of lower priority.
+    fn dequeue(&mut self) {
+        while self.wr.len() < BACKPRESSURE_BOUNDARY {
+            let priority = match self.queued.keys().next_back() {
+                Some(&priority) => priority,
+                None => return,
+            };
+            let (frame, emptied) = {
+                let frames = self.queued.get_mut(&priority).expect("queue of a listed priority");
+                let frame = frames.pop_front().expect("priority with no frames");
+                (frame, frames.is_empty())
+            };
+            if emptied {
+                self.queued.remove(&priority);
+            }
+            self.queued_bytes -= frame.len();
+            self.wr.extend_from_slice(&frame);
+        }
+    }
+
+    fn draining(&self) -> bool {
+        self.drain.as_ref().map_or(false, Registration::is_draining)
+    }
+
+    /// True if the rate limit allows reading a request, or there is none. Otherwise, the task is
+    /// woken when it does.
+    fn poll_rate_limit(&mut self) -> io::Result<bool> {
+        match self.rate_limit {
+            Some(ref mut limiter) => limiter.poll_ready(),
+            None => Ok(true),
+        }
+    }
+
+    fn at_capacity(&self) -> bool {
+        match self.max_in_flight {
+            Some(max_in_flight) => self.in_flight.len() >= max_in_flight,
+            None => false,
+        }
+    }
+}
+
+/// Appends to a transport's write buffer, writing the buffer to the socket whenever it holds
+/// `BACKPRESSURE_BOUNDARY` bytes or more, until the socket would block.
+struct WriteThrough<'a, T: 'a> {
+    upstream: &'a mut T,
+    wr: &'a mut Vec<u8>,
+    /// Where the frame being encoded starts in `wr`, until part of it is written to the socket.
+    frame_start: Option<usize>,
+    /// Set once the socket would block; the rest of the frame is only buffered.
+    blocked: bool,
+    /// The error writing to the socket, once it fails, which aborts the rest of the frame.
+    failed: Option<io::Error>,
+}
+
+impl<'a, T: Write> WriteThrough<'a, T> {
+    fn write_out(&mut self) -> io::Result<()> {
+        while !self.wr.is_empty() {
+            let n = match self.upstream.write(&self.wr[..]) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                              "failed to write frame to transport"))
+                }
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.blocked = true;
+                    return Ok(());
+                }
+                Err(e) => {
+                    let abort = io::Error::new(e.kind(), "failed to write frame to transport");
+                    self.failed = Some(e);
+                    return Err(abort);
+                }
+            };
+            self.wr.drain(..n);
+            self.frame_start = match self.frame_start {
+                Some(start) if start >= n => Some(start - n),
+                _ => None,
+            };
+        }
+        Ok(())
+    }
+}
+
+impl<'a, T: Write> Write for WriteThrough<'a, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.wr.extend_from_slice(buf);
+        if !self.blocked && self.wr.len() >= BACKPRESSURE_BOUNDARY {
+            self.write_out()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<T, Encode, Decode, S> Transport<T, Codec<Encode, Decode, S>>
+    where T: Io,
+          Encode: serde::Serialize,
+          Decode: serde::Deserialize,
+          S: PayloadSerializer
+{
+    /// Encodes `message` into the write buffer with `Codec::encode_to`, writing the buffer to the
+    /// socket whenever it fills up. A frame that fails to encode before any of it was written is
+    /// left out of the buffer, like one that fails in `Codec::encode`, and the error is returned
+    /// in the inner result. Once part of it was written, the stream can't be framed any further,
+    /// so the error is returned in the outer result, closing the connection. So is an error
+    /// writing to the socket, which stops the frame from being serialized any further.
+    fn encode_through(&mut self, message: (RequestId, Encode)) -> io::Result<io::Result<()>> {
+        let (encoded, unsent, failed) = {
+            let mut writer = WriteThrough {
+                upstream: &mut self.upstream,
+                frame_start: Some(self.wr.len()),
+                wr: &mut self.wr,
+                blocked: false,
+                failed: None,
+            };
+            let encoded = self.codec.encode_to(message, &mut writer);
+            (encoded, writer.frame_start, writer.failed)
+        };
+        match (encoded, unsent, failed) {
+            (Ok(()), _, _) => Ok(Ok(())),
+            (Err(_), _, Some(e)) => Err(self.fail(e)),
+            (Err(e), Some(frame_start), None) => {
+                self.wr.truncate(frame_start);
+                Ok(Err(e))
+            }
+            (Err(e), None, None) => Err(self.fail(e)),
+        }
+    }
+
+    /// Returns `e`, the error the connection is failing with, first telling the peer why with a
+    /// protocol error frame if `e` has a `ProtocolErrorCode`. The frame is written, after the
+    /// frames ahead of it, as far as the socket takes it without blocking, since the connection
+    /// closes right after.
+    fn refuse(&mut self, e: io::Error) -> io::Error {
+        let reason = match error::protocol_error(&e) {
+            Some(reason) => reason,
+            None => return e,
+        };
+        if self.codec.encode_protocol_error(&reason, &mut self.wr) {
+            debug!("Closing the connection with {:?}: {}", reason.code, reason.message);
+            let mut written = 0;
+            while written < self.wr.len() {
+                match self.upstream.write(&self.wr[written..]) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => written += n,
+                }
+            }
+            self.wr.drain(..written);
+            let _ = self.upstream.flush();
+        }
+        e
+    }
+
+    /// Fails the connection with `error`, first telling the peer why with a protocol error
+    /// frame, and returns the `io::Error` the connection failed with.
+    pub fn close_with(&mut self, error: ProtocolError) -> io::Error {
+        let e = self.refuse(error.into_io());
+        self.fail(e)
+    }
+
+    /// Decodes the next message in the read buffer, answering any heartbeats in front of it.
+    fn decode(&mut self)
+              -> io::Result<Option<(RequestId, Result<Decode, DecodeError<S::Error>>)>> {
+        use tokio_core::io::Codec as TokioCodec;
+
+        let message = match self.codec.decode(&mut self.rd) {
+            Ok(message) => message,
+            Err(e) => return Err(self.refuse(e)),
+        };
+        // Unlike `decode_eof`, this accepts a stream that ends with a heartbeat or goodbye. The
+        // frames held back by the codec's frame rate aren't cut off.
+        if message.is_none() && self.eof && !self.codec.throttled {
+            if let Some(e) = self.codec.truncated() {
+                return Err(e);
+            }
+        }
+        if let Some(reason) = self.codec.take_goodbye() {
+            debug!("Server said goodbye: {:?}; not sending new requests.", reason);
+            self.goodbye = Some(reason);
+        }
+        let heartbeats = self.codec.take_heartbeats();
+        let pongs = self.codec.take_pongs();
+        let queries = self.codec.take_queries();
+        if message.is_some() || heartbeats > 0 || !pongs.is_empty() || queries > 0 {
+            self.active();
+        }
+        if !pongs.is_empty() {
+            trace!("Echoing {} pings.", pongs.len());
+            for timestamp in pongs {
+                self.codec.encode_ping(timestamp, &mut self.wr);
+            }
+            self.poll_complete()?;
+        }
+        if queries > 0 {
+            trace!("Answering {} stats queries.", queries);
+            for _ in 0..queries {
+                self.codec.encode_stats(&mut self.wr);
+            }
+            self.poll_complete()?;
+        }
+        if heartbeats == 0 {
+            return Ok(message);
+        }
+        if let Some(ref mut heartbeat) = self.heartbeat {
+            trace!("Heartbeat echoed.");
+            heartbeat.deadline = None;
+            if let Some(sent) = heartbeat.sent.take() {
+                self.codec.record_rtt(sent.elapsed());
+            }
+            return Ok(message);
+        }
+        trace!("Echoing {} heartbeats.", heartbeats);
+        for _ in 0..heartbeats {
+            self.codec.encode_heartbeat(&mut self.wr);
+        }
+        self.poll_complete()?;
+        Ok(message)
+    }
+
+    /// Counts the complete frames in the read buffer, if the backlog is limited, and reports
+    /// them to the metrics. True if there is room for more.
+    fn room_in_backlog(&self) -> bool {
+        let max = match self.max_backlog {
+            Some(max) => max,
+            None => return false,
+        };
+        let backlog = self.codec.complete_frames(&self.rd);
+        trace!("{} complete frames buffered (max is {}).", backlog, max);
+        if let Some(ref metrics) = self.codec.metrics {
+            metrics.on_backlog(backlog);
+        }
+        backlog < max
+    }
+
+    /// Called when reading would block. Fails if the frame being read has stalled for too long.
+    fn poll_read_timeout(&mut self) -> io::Result<()> {
+        let mid_frame = self.rd.len() > 0 || self.codec.mid_frame();
+        match self.timeouts {
+            Some(ref mut timeouts) if mid_frame => timeouts.read.poll(&timeouts.handle),
+            Some(ref mut timeouts) => {
+                // Waiting between frames is just an idle connection.
+                timeouts.read.reset();
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// True if the connection has gone without frames for too long, and has no requests in
+    /// flight; a request that takes longer than the timeout to handle doesn't make its
+    /// connection idle.
+    fn poll_reaper(&mut self) -> io::Result<bool> {
+        let timeout = match self.reaper {
+            Some(ref mut reaper) => {
+                if !reaper.poll_idle()? {
+                    return Ok(false);
+                }
+                reaper.timeout
+            }
+            None => return Ok(false),
+        };
+        if !self.in_flight.is_empty() {
+            return Ok(false);
+        }
+        debug!("No frames sent or received within {:?}; closing the idle connection.", timeout);
+        if let Some(ref metrics) = self.codec.metrics {
+            metrics.on_reap(timeout);
+        }
+        Ok(true)
+    }
+
+    /// Returns the timeout error for a request whose response didn't arrive in time, if any.
+    fn poll_timed_out(&mut self)
+                      -> io::Result<Option<(RequestId, Result<Decode, DecodeError<S::Error>>)>> {
+        let timeouts = match self.response_timeouts {
+            Some(ref mut timeouts) => timeouts,
+            None => return Ok(None),
+        };
+        match timeouts.poll_expired()? {
+            Some(id) => {
+                warn!("No response to request id = {} within {:?}.", id, timeouts.timeout);
+                // A codec that sent the request with an id of its own discards the response.
+                if !self.codec.abandon_request(id) {
+                    timeouts.abandon(id);
+                }
+                Ok(Some((id, Err(DecodeError::TimedOut { timeout: timeouts.timeout }))))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Tells the client that request `id` was rejected for reusing the id of a request still in
+    /// flight. Dispatching it would route both responses to whichever request the client is
+    /// waiting on, so it is never passed on to the service.
+    fn reject_duplicate(&mut self, id: RequestId) -> io::Result<()> {
+        warn!("Rejecting request id = {}, which reuses the id of a request in flight.", id);
+        if let Some(ref metrics) = self.codec.metrics {
+            metrics.on_duplicate_id(id);
+        }
+        self.codec.unanswered();
+        let reason = format!("Request id {} is already in flight", id);
+        if self.codec.encode_rejection(id, &reason, &mut self.wr) {
+            self.poll_complete()?;
+        }
+        Ok(())
+    }
+
+    /// Fails the frame `id`, which failed to encode with `e`: a client fails its request with
+    /// `DecodeError::Unsent`, and a server tells its client that the response was rejected, so
+    /// that neither waits for a frame that was never sent.
+    fn fail_unencoded(&mut self, id: RequestId, e: io::Error) {
+        if self.client {
+            warn!("Failing request id = {}, which failed to encode: {}", id, e);
+            self.unsent.push((id, e));
+            // Wake the task to fail the request; see `poll`.
+            task::park().unpark();
+            return;
+        }
+        warn!("Rejecting the response to request id = {}, which failed to encode: {}", id, e);
+        let reason = format!("The response failed to encode: {}", e);
+        // Written by `poll_complete`, which follows every `start_send`.
+        if !self.codec.encode_rejection(id, &reason, &mut self.wr) {
+            debug!("Dropping the response to request id = {}; the client predates rejections.",
+                   id);
+        }
+    }
+
+    /// Tells the client that request `id` was rejected for `reason` by the codec's
+    /// `screen_requests`, without passing it on to the service.
+    fn reject_screened(&mut self, id: RequestId, reason: &str) -> io::Result<()> {
+        self.codec.unanswered();
+        if self.codec.encode_rejection(id, reason, &mut self.wr) {
+            self.poll_complete()?;
+        }
+        Ok(())
+    }
+
+    /// Answers request `id`, a retry of a request already answered, with the cached `response`
+    /// to it, without passing it on to the service.
+    fn answer_retry(&mut self, id: RequestId, response: &[u8]) -> io::Result<()> {
+        debug!("Answering request id = {} with the cached response to its idempotency key.",
+               id);
+        match self.codec.encode_payload(id, response, &mut self.wr) {
+            Ok(()) => {
+                self.poll_complete()?;
+            }
+            Err(e) => warn!("Dropping the cached response to request id = {}: {}", id, e),
+        }
+        Ok(())
+    }
+
+    /// Acknowledges the requests that have finished persisting. A request that fails to persist
+    /// isn't acknowledged, so that the client retries it.
+    fn poll_persisted(&mut self) -> io::Result<()> {
+        let mut acked = false;
+        let mut i = 0;
+        while i < self.persisting.len() {
+            let polled = self.persisting[i].1.poll();
+            match polled {
+                Ok(Async::NotReady) => i += 1,
+                Ok(Async::Ready(())) => {
+                    let (id, _) = self.persisting.swap_remove(i);
+                    trace!("Acknowledging request id = {}.", id);
+                    acked |= self.codec.encode_ack(id, &mut self.wr);
+                }
+                Err(e) => {
+                    let (id, _) = self.persisting.swap_remove(i);
+                    warn!("Not acknowledging request id = {}, which failed to persist: {}",
+                          id,
+                          e);
+                }
+            }
+        }
+        if acked {
+            self.poll_complete()?;
+        }
+        Ok(())
+    }
+
+    /// Tells the client, once, that the connection is closing, so that it stops sending requests.
+    fn say_goodbye(&mut self) -> io::Result<()> {
+        if self.said_goodbye {
+            return Ok(());
+        }
+        self.said_goodbye = true;
+        let reason = self.drain.as_ref().map(Registration::reason).unwrap_or_default();
+        if self.codec.encode_goodbye(&reason, &mut self.wr) {
+            debug!("Saying goodbye: {:?}", reason);
+            self.poll_complete()?;
+        }
+        Ok(())
+    }
+
+    /// Sends a heartbeat every interval, and fails if the last one wasn't echoed in time.
+    fn poll_heartbeat(&mut self) -> io::Result<()> {
+        let send = match self.heartbeat {
+            None => return Ok(()),
+            Some(ref mut heartbeat) => {
+                if let Some(ref mut deadline) = heartbeat.deadline {
+                    if let Async::Ready(()) = deadline.poll()? {
+                        warn!("Heartbeat not echoed within {:?}; closing the connection.",
+                              heartbeat.timeout);
+                        return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                                  format!("Heartbeat not echoed within {:?}",
+                                                          heartbeat.timeout)));
+                    }
+                }
+                let mut send = false;
+                while let Async::Ready(Some(())) = heartbeat.interval.poll()? {
+                    send = true;
+                }
+                // While a heartbeat is unanswered there's no point sending another.
+                if send && heartbeat.deadline.is_none() {
+                    let mut deadline = Timeout::new(heartbeat.timeout, &heartbeat.handle)?;
+                    // Polling registers the task to be woken at the deadline.
+                    if let Async::Ready(()) = deadline.poll()? {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                                  "Heartbeat timeout elapsed immediately"));
+                    }
+                    heartbeat.deadline = Some(deadline);
+                    heartbeat.sent = Some(Instant::now());
+                    true
+                } else {
+                    false
+                }
+            }
+        };
+        if send {
+            trace!("Sending heartbeat.");
+            self.codec.encode_heartbeat(&mut self.wr);
+            self.poll_complete()?;
+        }
+        Ok(())
+    }
+
+    /// Sends a ping if the client asked for one.
+    fn poll_ping(&mut self) -> io::Result<()> {
+        if self.codec.poll_ping(&mut self.wr) {
+            trace!("Sending ping.");
+            self.poll_complete()?;
+        }
+        Ok(())
+    }
+
+    /// Sends the stats queries the client asked for.
+    fn poll_stats_queries(&mut self) -> io::Result<()> {
+        if self.codec.poll_stats_queries(&mut self.wr) {
+            trace!("Sending stats queries.");
+            self.poll_complete()?;
+        }
+        Ok(())
+    }
+}
+
+impl<T, Encode, Decode, S> Stream for Transport<T, Codec<Encode, Decode, S>>
+    where T: Io,
+          Encode: serde::Serialize,
+          Decode: serde::Deserialize,
+          S: PayloadSerializer
+{
+    type Item = (RequestId, Result<Decode, DecodeError<S::Error>>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        self.poll_heartbeat()?;
+        self.poll_ping()?;
+        self.poll_stats_queries()?;
+        self.poll_persisted()?;
+        if self.poll_reaper()? {
+            return Ok(Async::Ready(None));
+        }
+        if let Some(timed_out) = self.poll_timed_out()? {
+            return Ok(Async::Ready(Some(timed_out)));
+        }
+        if let Some(id) = self.refused.pop() {
+            let reason = self.goodbye.clone().unwrap_or_default();
+            return Ok(Async::Ready(Some((id, Err(DecodeError::Closing { reason: reason })))));
+        }
+        if let Some((id, e)) = self.unsent.pop() {
+            return Ok(Async::Ready(Some((id, Err(DecodeError::Unsent(e))))));
+        }
+        if self.drain.as_ref().map_or(false, Registration::poll_draining) {
+            self.say_goodbye()?;
+            // A request still persisting is acknowledged before closing.
+            if self.in_flight.is_empty() && self.persisting.is_empty() {
+                debug!("Drained; closing the connection.");
+                return Ok(Async::Ready(None));
+            }
+            // The task is woken when the last response is sent; see `start_send`.
+            trace!("Draining; waiting for {} requests in flight.", self.in_flight.len());
+            self.reset_read_timeout();
+            return Ok(Async::NotReady);
+        }
+        if self.at_capacity() {
+            // The task is woken when a response retires a request; see `start_send`.
+            trace!("{} requests in flight; not reading until one completes.",
+                   self.in_flight.len());
+            self.reset_read_timeout();
+            return Ok(Async::NotReady);
+        }
+        if !self.poll_rate_limit()? {
+            trace!("Rate limited; not reading until a request is allowed.");
+            self.reset_read_timeout();
+            return Ok(Async::NotReady);
+        }
+        loop {
+            if self.is_readable {
+                if self.eof && self.rd.len() == 0 && !self.codec.mid_frame() {
+                    return Ok(Async::Ready(None));
+                }
+                if self.yield_turn() {
+                    return Ok(Async::NotReady);
+                }
+                if let Some(message) = self.decode()? {
+                    let awaited = match self.response_timeouts {
+                        Some(ref mut timeouts) => timeouts.finish(message.0),
+                        None => true,
+                    };
+                    if !awaited {
+                        debug!("Discarding the late response to request id = {}.", message.0);
+                        continue;
+                    }
+                    if let Some(reason) = self.codec.take_screened(message.0) {
+                        self.reject_screened(message.0, &reason)?;
+                        continue;
+                    }
+                    if self.reject_duplicates && self.in_flight.contains(&message.0) {
+                        self.reject_duplicate(message.0)?;
+                        continue;
+                    }
+                    if let Some(response) = self.codec.take_cached_response(message.0) {
+                        self.answer_retry(message.0, &response)?;
+                        continue;
+                    }
+                    if self.tracks_in_flight() {
+                        self.in_flight.insert(message.0);
+                        if self.max_buffered.is_some() {
+                            let size = self.codec.decoded_size as usize;
+                            self.held.insert(message.0, size);
+                            self.held_bytes += size;
+                        }
+                    }
+                    let persisting = match (self.codec.persist.as_ref(), &message.1) {
+                        (Some(persist), &Ok(ref request)) => Some(persist(request)),
+                        _ => None,
+                    };
+                    if let Some(persisting) = persisting {
+                        // Polled on the next call, which registers the task to be woken.
+                        self.persisting.push((message.0, persisting));
+                    }
+                    if let Some(ref mut limiter) = self.rate_limit {
+                        limiter.take();
+                    }
+                    self.frames_read += 1;
+                    return Ok(Async::Ready(Some(message)));
+                }
+                if self.codec.throttled {
+                    // The codec's limiter wakes the task once another frame may be decoded.
+                    trace!("Decoded too many frames too quickly; not reading until another is \
+                            allowed.");
+                    self.reset_read_timeout();
+                    return Ok(Async::NotReady);
+                }
+                if self.eof {
+                    // Nothing but bytes skipped while resyncing was left.
+                    return Ok(Async::Ready(None));
+                }
+                self.is_readable = false;
+            }
+            let budget = self.read_budget();
+            if budget == Some(0) {
+                if self.held_bytes > 0 {
+                    // The task is woken when a response frees some; see `start_send`.
+                    trace!("Holding {} bytes of requests in flight; not reading until one \
+                            completes.",
+                           self.held_bytes);
+                    self.reset_read_timeout();
+                    return Ok(Async::NotReady);
+                }
+                let message = format!("Buffered {} bytes without completing a frame (max is {})",
+                                      self.rd.len(),
+                                      self.max_buffered.unwrap_or_default());
+                let error = ProtocolError::new(ProtocolErrorCode::PayloadTooLarge, message);
+                return Err(self.refuse(error.into_io()));
+            }
+            // Only a hint: the bytes of the payload still to come, up to `read_whole_payloads`.
+            let needed = match (self.read_whole_payloads, self.codec.bytes_needed()) {
+                (Some(max), Some(needed)) => cmp::min(needed, max as u64) as usize,
+                _ => 0,
+            };
+            let chunk = cmp::max(BACKLOG_CHUNK, needed);
+            let limit = match (budget, self.max_backlog) {
+                (Some(budget), Some(_)) => Some(cmp::min(budget, chunk)),
+                (None, Some(_)) => Some(chunk),
+                (budget, None) => budget,
+            };
+            let before = self.rd.len();
+            let read = {
+                let mut rd = self.rd.get_mut();
+                let additional = cmp::max(self.read_capacity.saturating_sub(rd.len()), needed);
+                if additional > 0 {
+                    rd.reserve(additional);
+                }
+                match limit {
+                    Some(limit) => (&mut self.upstream).take(limit as u64).read_to_end(&mut rd),
+                    None => self.upstream.read_to_end(&mut rd),
+                }
+            };
+            let mut more = false;
+            match read {
+                // Reading stopped at the limit rather than the end of the stream.
+                Ok(n) if Some(n) == limit => more = true,
+                Ok(_) => self.eof = true,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if self.rd.len() == before {
+                        self.frames_read = 0;
+                        self.poll_read_timeout()?;
+                        return Ok(Async::NotReady);
+                    }
+                }
+                Err(e) => return Err(self.fail(e)),
+            }
+            self.reset_read_timeout();
+            if self.room_in_backlog() && more && self.read_budget() != Some(0) {
+                // Read the rest of the burst, up to the max backlog, before passing it on.
+                continue;
+            }
+            self.is_readable = true;
+        }
+    }
+}
+
+impl<T, Encode, Decode, S> Sink for Transport<T, Codec<Encode, Decode, S>>
+    where T: Io,
+          Encode: serde::Serialize,
+          Decode: serde::Deserialize,
+          S: PayloadSerializer
+{
+    type SinkItem = (RequestId, Encode);
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, message: Self::SinkItem) -> StartSend<Self::SinkItem, io::Error> {
+        use tokio_core::io::Codec as TokioCodec;
+
+        if let Some(kind) = self.failed {
+            debug!("Not encoding frame id = {}; the connection failed.", message.0);
+            return Err(io::Error::new(kind, "The connection failed"));
+        }
+        if self.buffered_outbound() > self.high_water_mark {
+            self.poll_complete()?;
+            if self.buffered_outbound() > self.high_water_mark {
+                return Ok(AsyncSink::NotReady(message));
+            }
+        }
+        let id = message.0;
+        if self.goodbye.is_some() {
+            debug!("Not sending request id = {}; the server is closing.", id);
+            // The request won't be acknowledged either, but takes its place in the order the
+            // codec expects requests in.
+            self.codec.await_ack(id);
+            self.codec.awaiting_acks.remove(&id);
+            self.refused.push(id);
+            // Wake the task to fail the request; see `poll`.
+            task::park().unpark();
+            return Ok(AsyncSink::Ready);
+        }
+        let encoded = if self.codec.frame.priorities {
+            // Queued by priority rather than written in order; see `dequeue`.
+            let priority = self.codec.priority(id, &message.1);
+            let mut frame = Vec::new();
+            let encoded = self.codec.encode(message, &mut frame);
+            self.enqueue(priority, frame);
+            encoded
+        } else if self.codec.write_through {
+            self.encode_through(message)?
+        } else {
+            self.codec.encode(message, &mut self.wr)
+        };
+        match encoded {
+            Ok(()) => {
+                self.active();
+                if let Some(ref mut timeouts) = self.response_timeouts {
+                    timeouts.start(id)?;
+                }
+            }
+            // The codec leaves nothing of the frame behind, so the frames before and after it
+            // are unaffected.
+            Err(e) => self.fail_unencoded(id, e),
+        }
+
+        let was_at_capacity = self.at_capacity() || self.read_budget() == Some(0);
+        if let Some(size) = self.held.remove(&id) {
+            self.held_bytes -= size;
+        }
+        let retired = self.in_flight.remove(&id);
+        let drained = retired && self.in_flight.is_empty() && self.draining();
+        let at_capacity = self.at_capacity() || self.read_budget() == Some(0);
+        if was_at_capacity && !at_capacity || drained {
+            // Reading stopped without anything to wake the task when it can resume, or close.
+            task::park().unpark();
+        }
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        if self.wr.is_empty() {
+            self.dequeue();
+        }
+        if let Some(ref mut delay) = self.flush_delay {
+            let buffered = self.wr.len() + self.queued_bytes;
+            if delay.poll_hold(buffered)? {
+                trace!("Holding {} bytes back to write with the frames that follow.", buffered);
+                return Ok(Async::NotReady);
+            }
+        }
+        while !self.wr.is_empty() {
+            let n = match self.upstream.write(&self.wr) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.poll_write_timeout()?;
+                    return Ok(Async::NotReady);
+                }
+                Err(e) => return Err(self.fail(e)),
+            };
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                          "failed to write frame to transport"));
+            }
+            self.wr.drain(..n);
+            if let Some(ref mut timeouts) = self.timeouts {
+                timeouts.write.reset();
+            }
+            if self.wr.is_empty() {
+                self.dequeue();
+            }
+        }
+        match self.upstream.flush() {
+            Ok(()) => Ok(Async::Ready(())),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.poll_write_timeout()?;
+                Ok(Async::NotReady)
+            }
+            Err(e) => Err(self.fail(e)),
+        }
+    }
+}
+
+#[test]
+fn max_in_flight() {
+    use futures::future;
+    use super::handshake::MockIo;
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    let mut vec = Vec::new();
+    for id in 1..4 {
+        codec.encode((id, vec![id as u8]), &mut vec).unwrap();
+    }
+    let mut transport = Transport::new(MockIo::new(vec), codec).max_in_flight(Some(2));
+
+    // Polls outside of a task panic, so run each step in one.
+    fn poll_id<S: Stream<Item = (RequestId, R), Error = io::Error>, R>(transport: &mut S)
+                                                                      -> Option<RequestId> {
+        future::lazy(|| {
+                Ok::<_, io::Error>(match transport.poll() {
+                    Ok(Async::Ready(Some((id, _)))) => Some(id),
+                    Ok(Async::Ready(None)) => panic!("Unexpected end of stream"),
+                    Ok(Async::NotReady) => None,
+                    Err(e) => panic!("Unexpected error: {}", e),
+                })
+            })
+            .wait()
+            .unwrap()
+    }
+    assert_eq!(poll_id(&mut transport), Some(1));
+    assert_eq!(poll_id(&mut transport), Some(2));
+    assert_eq!(poll_id(&mut transport), None);
+
+    future::lazy(|| transport.start_send((1, vec![])))
+        .wait()
+        .unwrap();
+    assert_eq!(poll_id(&mut transport), Some(3));
+}
+
+#[test]
+fn heartbeat() {
+    use super::in_memory;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let options = HeartbeatOptions {
+        remote: core.remote(),
+        interval: Duration::from_millis(10),
+        timeout: Duration::from_millis(50),
+    };
+
+    // Heartbeats that are echoed keep the connection open.
+    let (client_io, server_io) = in_memory();
+    let client: Transport<_, Codec<(), ()>> = Transport::new(client_io, Codec::new(1024))
+        .heartbeat(options.start().unwrap());
+    let server: Transport<_, Codec<(), ()>> = Transport::new(server_io, Codec::new(1024));
+    core.handle().spawn(server.for_each(|_| Ok(())).map_err(|e| panic!("{}", e)));
+    let quiet = Timeout::new(Duration::from_millis(200), &core.handle()).unwrap();
+    let stayed_open = client.into_future()
+        .map(|_| false)
+        .map_err(|(e, _)| e)
+        .select(quiet.map(|()| true))
+        .map(|(stayed_open, _)| stayed_open)
+        .map_err(|(e, _)| e);
+    assert!(core.run(stayed_open).unwrap());
+
+    // A peer that never answers is detected.
+    let (client_io, _server_io) = in_memory();
+    let client: Transport<_, Codec<(), ()>> = Transport::new(client_io, Codec::new(1024))
+        .heartbeat(options.start().unwrap());
+    let err = core.run(client.into_future()).err().unwrap().0;
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+}
+
+#[test]
+fn drain() {
+    use futures::future;
+    use super::handshake::MockIo;
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    let mut vec = Vec::new();
+    for id in 1..3 {
+        codec.encode((id, vec![id as u8]), &mut vec).unwrap();
+    }
+    let drain = Drain::new();
+    let mut transport = Transport::new(MockIo::new(vec), codec).drain(Some(drain.clone()));
+
+    let mut drained = future::lazy(|| {
+            match transport.poll() {
+                Ok(Async::Ready(Some((1, _)))) => {}
+                bad => panic!("Expected request 1, but got {:?}", bad),
+            }
+            let mut drained = drain.start();
+            assert_eq!(drained.poll(), Ok(Async::NotReady));
+            // Request 2 is never read, and the transport stays open until 1 is answered.
+            match transport.poll() {
+                Ok(Async::NotReady) => {}
+                bad => panic!("Expected NotReady, but got {:?}", bad),
+            }
+            transport.start_send((1, vec![])).unwrap();
+            match transport.poll() {
+                Ok(Async::Ready(None)) => {}
+                bad => panic!("Expected the end of the stream, but got {:?}", bad),
+            }
+            Ok::<_, ()>(drained)
+        })
+        .wait()
+        .unwrap();
+
+    drop(transport);
+    assert_eq!(future::lazy(|| drained.poll()).wait(), Ok(Async::Ready(())));
+}
+
+#[test]
+fn idle_timeouts() {
+    use super::in_memory;
+    use tokio_core::io::Codec as TokioCodec;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let options = IdleTimeoutOptions {
+        remote: core.remote(),
+        read: Duration::from_millis(50),
+        write: Duration::from_millis(50),
+    };
+
+    // An idle connection stays open.
+    let (_client_io, server_io) = in_memory();
+    let server: Transport<_, Codec<Vec<u8>, Vec<u8>>> =
+        Transport::new(server_io, Codec::new(1024)).idle_timeouts(options.start().unwrap());
+    let quiet = Timeout::new(Duration::from_millis(200), &core.handle()).unwrap();
+    let stayed_open = server.into_future()
+        .map(|_| false)
+        .map_err(|(e, _)| e)
+        .select(quiet.map(|()| true))
+        .map(|(stayed_open, _)| stayed_open)
+        .map_err(|(e, _)| e);
+    assert!(core.run(stayed_open).unwrap());
+
+    // A peer that stalls mid-payload is timed out.
+    let (mut client_io, server_io) = in_memory();
+    let mut frame = Vec::new();
+    Codec::<Vec<u8>, Vec<u8>>::new(1024).encode((1, vec![0; 100]), &mut frame).unwrap();
+    client_io.write_all(&frame[..50]).unwrap();
+    let server: Transport<_, Codec<Vec<u8>, Vec<u8>>> =
+        Transport::new(server_io, Codec::new(1024)).idle_timeouts(options.start().unwrap());
+    let err = core.run(server.into_future()).err().unwrap().0;
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+}
+
+#[test]
+fn response_timeouts() {
+    use super::in_memory;
+    use tokio_core::io::Codec as TokioCodec;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let options = ResponseTimeoutOptions {
+        remote: core.remote(),
+        timeout: Duration::from_millis(20),
+    };
+    let (client_io, mut server_io) = in_memory();
+    let client: Transport<_, Codec<u8, u8>> =
+        Transport::new(client_io, Codec::new(1024)).response_timeouts(options.start().unwrap());
+
+    // The server doesn't answer request 0 in time.
+    let client = core.run(client.send((0, 0))).unwrap();
+    let (response, client) = core.run(client.into_future()).map_err(|(e, _)| e).unwrap();
+    match response {
+        Some((0, Err(DecodeError::TimedOut { .. }))) => {}
+        bad => panic!("Expected Some((0, Err(TimedOut))), but got {:?}", bad),
+    }
+
+    // Its late response is discarded.
+    let mut responses = Vec::new();
+    let mut codec: Codec<u8, u8> = Codec::new(1024);
+    codec.encode((0, 0), &mut responses).unwrap();
+    codec.encode((1, 1), &mut responses).unwrap();
+    server_io.write_all(&responses).unwrap();
+    let client = core.run(client.send((1, 1))).unwrap();
+    let (response, _client) = core.run(client.into_future()).map_err(|(e, _)| e).unwrap();
+    match response {
+        Some((1, Ok(1))) => {}
+        bad => panic!("Expected Some((1, Ok(1))), but got {:?}", bad),
+    }
+}
+
+#[test]
+fn rate_limit() {
+    use super::handshake::MockIo;
+    use tokio_core::io::Codec as TokioCodec;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let options = RateLimitOptions {
+        remote: core.remote(),
+        per_second: 10,
+        burst: 2,
+    };
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    let mut vec = Vec::new();
+    for id in 1..4 {
+        codec.encode((id, vec![id as u8]), &mut vec).unwrap();
+    }
+    let transport = Transport::new(MockIo::new(vec), codec).rate_limit(options.start().unwrap());
+
+    // The burst is read right away, and the next request once a token is added.
+    let start = Instant::now();
+    let ids = core.run(transport.map(|(id, _)| id).collect()).unwrap();
+    assert_eq!(ids, vec![1, 2, 3]);
+    assert!(start.elapsed() >= Duration::from_millis(90),
+            "Read 3 requests in {:?}",
+            start.elapsed());
+}
+
+#[test]
+fn frame_rate() {
+    use super::handshake::MockIo;
+    use tokio_core::io::Codec as TokioCodec;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let options = RateLimitOptions {
+        remote: core.remote(),
+        per_second: 10,
+        burst: 2,
+    };
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    let mut vec = Vec::new();
+    codec.encode((1, vec![1]), &mut vec).unwrap();
+    codec.encode_heartbeat(&mut vec);
+    codec.encode((2, vec![2]), &mut vec).unwrap();
+    let codec = codec.frame_rate(options.start().unwrap());
+    let transport = Transport::new(MockIo::new(vec), codec);
+
+    // The first request and the heartbeat use up the burst, so the second request waits for a
+    // token before it is decoded.
+    let start = Instant::now();
+    let ids = core.run(transport.map(|(id, _)| id).collect()).unwrap();
+    assert_eq!(ids, vec![1, 2]);
+    assert!(start.elapsed() >= Duration::from_millis(90),
+            "Decoded 3 frames in {:?}",
+            start.elapsed());
+}
+
+#[test]
+fn batched_writes() {
+    use futures::future;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use tokio_core::io::Codec as TokioCodec;
+
+    /// Records each call to `write`.
+    struct CountingIo {
+        writes: Rc<RefCell<Vec<Vec<u8>>>>,
+    }
+
+    impl Read for CountingIo {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::WouldBlock, "nothing to read"))
+        }
+    }
+
+    impl Write for CountingIo {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes.borrow_mut().push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Io for CountingIo {}
+
+    let writes = Rc::new(RefCell::new(vec![]));
+    let io = CountingIo { writes: writes.clone() };
+    let mut transport: Transport<_, Codec<Vec<u8>, Vec<u8>>> = Transport::new(io,
+                                                                              Codec::new(16));
+    future::lazy(|| {
+            transport.start_send((1, vec![1; 4]))?;
+            // Too big: rejected without affecting the other frames.
+            transport.start_send((2, vec![2; 32]))?;
+            transport.start_send((3, vec![3; 4]))?;
+            transport.poll_complete()
+        })
+        .wait()
+        .unwrap();
+
+    let writes = writes.borrow();
+    assert_eq!(writes.len(), 1);
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(16);
+    let mut buf = EasyBuf::from(writes[0].clone());
+    assert_eq!(codec.decode(&mut buf).unwrap().unwrap().0, 1);
+    match codec.decode(&mut buf).unwrap() {
+        Some((2, Err(DecodeError::Rejected { .. }))) => {}
+        bad => panic!("Expected a rejection of request id = 2, but got {:?}", bad),
+    }
+    assert_eq!(codec.decode(&mut buf).unwrap().unwrap().0, 3);
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+}
+
+#[test]
+fn goodbye() {
+    use futures::future;
+    use super::handshake::MockIo;
+
+    let drain = Drain::new();
+    let server_io = MockIo::new(vec![]);
+    let said = server_io.written.clone();
+    let mut server: Transport<_, Codec<Vec<u8>, Vec<u8>>> =
+        Transport::new(server_io, Codec::new(2_000_000)).drain(Some(drain.clone()));
+    let _drained = drain.start_with_reason("Restarting");
+    match future::lazy(|| server.poll()).wait() {
+        Ok(Async::Ready(None)) => {}
+        bad => panic!("Expected the end of the stream, but got {:?}", bad),
+    }
+
+    // The client reads the goodbye, then fails the requests made after it without sending them.
+    let client_io = MockIo::new(said.borrow().clone());
+    let sent = client_io.written.clone();
+    let mut client: Transport<_, Codec<Vec<u8>, Vec<u8>>> = Transport::new(client_io,
+                                                                            Codec::new(2_000_000));
+    future::lazy(|| {
+            match client.poll() {
+                Ok(Async::Ready(None)) => {}
+                bad => panic!("Expected the end of the stream, but got {:?}", bad),
+            }
+            client.start_send((1, vec![1])).unwrap();
+            match client.poll() {
+                Ok(Async::Ready(Some((1, Err(DecodeError::Closing { ref reason })))))
+                    if reason == "Restarting" => {}
+                bad => panic!("Expected a Closing error, but got {:?}", bad),
+            }
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap();
+    assert!(sent.borrow().is_empty());
+}
+
+#[test]
+fn high_water_mark() {
+    use futures::future;
+
+    /// A connection whose peer never reads.
+    struct StalledIo;
+
+    impl Read for StalledIo {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::WouldBlock, "nothing to read"))
+        }
+    }
+
+    impl Write for StalledIo {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::WouldBlock, "peer isn't reading"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Io for StalledIo {}
+
+    let mut transport: Transport<_, Codec<Vec<u8>, Vec<u8>>> =
+        Transport::new(StalledIo, Codec::new(2_000_000)).high_water_mark(Some(16));
+    future::lazy(|| {
+            assert!(transport.start_send((1, vec![1; 4])).unwrap().is_ready());
+            assert_eq!(transport.buffered_outbound(), 8 + 8 + 12);
+            assert!(transport.start_send((2, vec![2; 4])).unwrap().is_not_ready());
+            assert_eq!(transport.buffered_outbound(), 8 + 8 + 12);
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap();
+}
+
+#[test]
+fn buffer_capacity() {
+    use futures::future;
+    use super::handshake::MockIo;
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    let mut frames = vec![];
+    codec.encode((1, vec![1; 100]), &mut frames).unwrap();
+    let mut transport: Transport<_, Codec<Vec<u8>, Vec<u8>>> =
+        Transport::with_capacity(MockIo::new(frames), Codec::new(2_000_000), 4096, 1024);
+    assert!(transport.wr.capacity() >= 1024);
+    match future::lazy(|| transport.poll()).wait() {
+        Ok(Async::Ready(Some((1, Ok(_))))) => {}
+        bad => panic!("Expected request id = 1, but got {:?}", bad),
+    }
+    assert!(transport.rd.get_mut().capacity() >= 4096);
+}
+
+#[test]
+fn closed_mid_frame() {
+    use futures::future;
+    use super::handshake::MockIo;
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    let mut frame = vec![];
+    codec.encode((1, vec![1; 100]), &mut frame).unwrap();
+    // Cut off partway through the length, right after the header, and partway through the
+    // payload.
+    for &len in &[12, 16, 50] {
+        let io = MockIo::new(frame[..len].to_vec());
+        let mut transport: Transport<_, Codec<Vec<u8>, Vec<u8>>> =
+            Transport::new(io, Codec::new(2_000_000));
+        match future::lazy(|| transport.poll()).wait() {
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+            bad => panic!("Expected an UnexpectedEof error, but got {:?}", bad),
+        }
+    }
+}
+
+#[test]
+fn priorities() {
+    use futures::future;
+    use super::handshake::MockIo;
+    use tokio_core::io::Codec as TokioCodec;
+
+    let io = MockIo::new(vec![]);
+    let written = io.written.clone();
+    let mut codec: Codec<Vec<u8>, Vec<u8>> =
+        Codec::new(2_000_000).priorities(true).inherit_priorities();
+    // As if request 2 had been sent with priority 9, and the others with the default.
+    codec.priorities.insert(2, 9);
+    let mut transport = Transport::new(io, codec);
+    future::lazy(|| {
+            for id in 1..4 {
+                assert!(transport.start_send((id, vec![id as u8]))?.is_ready());
+            }
+            transport.poll_complete()
+        })
+        .wait()
+        .unwrap();
+
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000).priorities(true);
+    let mut buf = EasyBuf::from(written.borrow().clone());
+    let ids = (0..3).map(|_| codec.decode(&mut buf).unwrap().unwrap().0).collect::<Vec<_>>();
+    assert_eq!(ids, vec![2, 1, 3]);
+}
+
+#[test]
+fn write_through() {
+    use futures::future;
+    use super::handshake::MockIo;
+    use tokio_core::io::Codec as TokioCodec;
+
+    let io = MockIo::new(vec![]);
+    let written = io.written.clone();
+    let codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(64 * 1024).write_through(true);
+    let mut transport = Transport::new(io, codec);
+    let large = vec![1; 4 * BACKPRESSURE_BOUNDARY];
+    future::lazy(|| {
+            transport.start_send((1, large.clone()))?;
+            // The frame reached the socket as it was serialized, leaving the tail buffered.
+            assert!(!written.borrow().is_empty());
+            assert!(transport.wr.len() < BACKPRESSURE_BOUNDARY);
+
+            // Too big: rejected before anything of it is written.
+            let sent = written.borrow().len();
+            transport.start_send((2, vec![2; 100 * 1024]))?;
+            assert_eq!(written.borrow().len(), sent);
+            transport.start_send((3, vec![3; 4]))?;
+            transport.poll_complete()
+        })
+        .wait()
+        .unwrap();
+
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(64 * 1024);
+    let mut buf = EasyBuf::from(written.borrow().clone());
+    match codec.decode(&mut buf).unwrap() {
+        Some((1, Ok(ref payload))) if *payload == large => {}
+        bad => panic!("Expected request id = 1, but got {:?}", bad),
+    }
+    match codec.decode(&mut buf).unwrap() {
+        Some((2, Err(DecodeError::Rejected { ref reason }))) => {
+            assert!(reason.starts_with("The response failed to encode"));
+        }
+        bad => panic!("Expected a rejection of request id = 2, but got {:?}", bad),
+    }
+    assert_eq!(codec.decode(&mut buf).unwrap().unwrap().0, 3);
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+}
+
+#[test]
+fn fail_unencoded_requests() {
+    use futures::future;
+    use super::handshake::MockIo;
+
+    let io = MockIo::new(vec![]);
+    let written = io.written.clone();
+    let codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(16);
+    let mut transport = Transport::new(io, codec).client();
+    future::lazy(|| {
+            transport.start_send((1, vec![1; 32]))?;
+            transport.poll_complete()?;
+            match transport.poll()? {
+                Async::Ready(Some((1, Err(DecodeError::Unsent(_))))) => {}
+                bad => panic!("Expected request id = 1 to fail, but got {:?}", bad),
+            }
+            Ok::<_, io::Error>(())
+        })
+        .wait()
+        .unwrap();
+    // Nothing was sent to the server.
+    assert!(written.borrow().is_empty());
+}
+
+#[test]
+fn abort_on_failure() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    struct BrokenPipe {
+        writes: Rc<Cell<usize>>,
+    }
+
+    impl io::Read for BrokenPipe {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::WouldBlock, "not ready"))
+        }
+    }
+
+    impl io::Write for BrokenPipe {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            self.writes.set(self.writes.get() + 1);
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Io for BrokenPipe {}
+
+    let writes = Rc::new(Cell::new(0));
+    let codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(1 << 20).write_through(true);
+    let mut transport = Transport::new(BrokenPipe { writes: writes.clone() }, codec);
+
+    // The first write fails, so the rest of the frame is neither serialized nor buffered.
+    let err = transport.start_send((1, vec![1; 16 * BACKPRESSURE_BOUNDARY])).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    assert_eq!(writes.get(), 1);
+    assert_eq!(transport.wr.capacity(), 0);
+
+    // Later frames aren't encoded at all.
+    let err = transport.start_send((2, vec![2; 4])).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    assert_eq!(writes.get(), 1);
+    assert_eq!(transport.buffered_outbound(), 0);
+}
+
+#[test]
+fn reap_idle() {
+    use futures::future;
+    use super::{CodecMetrics, in_memory};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use tokio_core::io::Codec as TokioCodec;
+    use tokio_core::reactor::Core;
+
+    #[derive(Default)]
+    struct Reaped(AtomicUsize);
+
+    impl CodecMetrics for Reaped {
+        fn on_reap(&self, _: Duration) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let mut core = Core::new().unwrap();
+    let options = IdleReaperOptions {
+        remote: core.remote(),
+        timeout: Duration::from_millis(50),
+    };
+    let reaped = Arc::new(Reaped::default());
+    let (mut client_io, server_io) = in_memory();
+    let mut frame = Vec::new();
+    Codec::<Vec<u8>, Vec<u8>>::new(1024).encode((1, vec![0; 4]), &mut frame).unwrap();
+    client_io.write_all(&frame).unwrap();
+    let server: Transport<_, Codec<Vec<u8>, Vec<u8>>> =
+        Transport::new(server_io, Codec::new(1024).metrics(reaped.clone()))
+            .reap_idle(options.start().unwrap());
+    let (request, mut server) = core.run(server.into_future()).map_err(|(e, _)| e).unwrap();
+    assert_eq!(request.unwrap().0, 1);
+
+    // A connection with a request in flight isn't idle.
+    thread::sleep(Duration::from_millis(100));
+    core.run(future::lazy(|| {
+            assert!(server.poll()?.is_not_ready());
+            server.start_send((1, vec![]))?;
+            server.poll_complete()
+        }))
+        .unwrap();
+
+    // Once answered, it's closed after going quiet for the timeout.
+    let start = Instant::now();
+    let (closed, _) = core.run(server.into_future()).map_err(|(e, _)| e).unwrap();
+    assert!(closed.is_none());
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    assert_eq!(reaped.0.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn max_frames_per_poll() {
+    use futures::future;
+    use super::handshake::MockIo;
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    let mut vec = Vec::new();
+    for id in 1..6 {
+        codec.encode((id, vec![id as u8]), &mut vec).unwrap();
+    }
+    let mut transport = Transport::new(MockIo::new(vec), codec).max_frames_per_poll(Some(2));
+    let polls = future::lazy(|| {
+            let mut polls = vec![];
+            loop {
+                match transport.poll()? {
+                    Async::Ready(Some((id, _))) => polls.push(Some(id)),
+                    Async::Ready(None) => return Ok::<_, io::Error>(polls),
+                    Async::NotReady => polls.push(None),
+                }
+            }
+        })
+        .wait()
+        .unwrap();
+    assert_eq!(polls, vec![Some(1), Some(2), None, Some(3), Some(4), None, Some(5)]);
+}
+
+#[test]
+fn max_buffered_bytes() {
+    use futures::future;
+    use super::handshake::MockIo;
+    use tokio_core::io::Codec as TokioCodec;
+
+    // Frames of 8 + 8 + 108 bytes, with payloads of 108.
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    let mut vec = Vec::new();
+    for id in 1..4 {
+        codec.encode((id, vec![id as u8; 100]), &mut vec).unwrap();
+    }
+    let frames = vec.clone();
+    let mut transport = Transport::new(MockIo::new(vec), codec)
+        .reject_duplicate_ids(true)
+        .max_buffered_bytes(Some(250));
+    future::lazy(|| {
+            match transport.poll()? {
+                Async::Ready(Some((1, _))) => {}
+                _ => panic!("Expected request id = 1"),
+            }
+            match transport.poll()? {
+                Async::Ready(Some((2, _))) => {}
+                _ => panic!("Expected request id = 2"),
+            }
+            // Two payloads in flight and the start of the third frame use up the budget.
+            assert!(transport.poll()?.is_not_ready());
+            transport.start_send((1, vec![]))?;
+            match transport.poll()? {
+                Async::Ready(Some((3, _))) => {}
+                _ => panic!("Expected request id = 3"),
+            }
+            Ok::<_, io::Error>(())
+        })
+        .wait()
+        .unwrap();
+
+    // With nothing in flight, a frame that can't fit fails the connection.
+    let codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    let mut transport = Transport::new(MockIo::new(frames), codec).max_buffered_bytes(Some(100));
+    let err = future::lazy(|| transport.poll()).wait().err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn reject_duplicate_ids() {
+    use futures::future;
+    use super::handshake::MockIo;
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    let mut vec = Vec::new();
+    for &id in &[1, 1, 2] {
+        codec.encode((id, vec![id as u8]), &mut vec).unwrap();
+    }
+    let io = MockIo::new(vec);
+    let written = io.written.clone();
+    let mut transport = Transport::new(io, codec).reject_duplicate_ids(true);
+    let ids = future::lazy(|| {
+            let mut ids = vec![];
+            while let Async::Ready(Some((id, _))) = transport.poll()? {
+                ids.push(id);
+            }
+            Ok::<_, io::Error>(ids)
+        })
+        .wait()
+        .unwrap();
+    assert_eq!(ids, vec![1, 2]);
+
+    let mut client: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    let mut buf = EasyBuf::from(written.borrow().clone());
+    match client.decode(&mut buf).unwrap() {
+        Some((1, Err(DecodeError::Rejected { .. }))) => {}
+        bad => panic!("Expected a rejection of request id = 1, but got {:?}", bad),
+    }
+    assert!(client.decode(&mut buf).unwrap().is_none());
+}
+
+#[test]
+fn answer_retries() {
+    use futures::future;
+    use super::{IDEMPOTENCY_KEY, Metadata, ResponseCache};
+    use super::handshake::MockIo;
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut client: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000)
+        .frame_metadata(true)
+        .metadata(|request: &Vec<u8>| {
+            let mut metadata = Metadata::new();
+            metadata.insert(IDEMPOTENCY_KEY.to_string(), format!("key-{}", request[0]));
+            metadata
+        });
+    // Request 2 retries request 1, and request 3 is a different request.
+    let mut vec = Vec::new();
+    for &(id, request) in &[(1, 7), (2, 7), (3, 8)] {
+        client.encode((id, vec![request]), &mut vec).unwrap();
+    }
+    let io = MockIo::new(vec);
+    let written = io.written.clone();
+    let cache = ResponseCache::new(10, Duration::from_secs(60));
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000).frame_metadata(true);
+    codec.response_cache = Some(cache.clone());
+    let mut transport = Transport::new(io, codec);
+    let ids = future::lazy(|| {
+            let mut ids = vec![];
+            while let Async::Ready(Some((id, request))) = transport.poll()? {
+                ids.push(id);
+                transport.start_send((id, vec![request.unwrap()[0] * 2]))?;
+                transport.poll_complete()?;
+            }
+            Ok::<_, io::Error>(ids)
+        })
+        .wait()
+        .unwrap();
+    assert_eq!(ids, vec![1, 3]);
+    assert_eq!(cache.len(), 2);
+
+    let responses: Vec<_> = client.decode_all(&written.borrow())
+        .map(|decoded| {
+            let (id, response) = decoded.unwrap();
+            (id, response.unwrap())
+        })
+        .collect();
+    assert_eq!(responses, vec![(1, vec![14]), (2, vec![14]), (3, vec![16])]);
+}
+
+#[test]
+fn max_backlog() {
+    use futures::future;
+    use super::CodecMetrics;
+    use super::handshake::MockIo;
+    use std::sync::{Arc, Mutex};
+    use tokio_core::io::Codec as TokioCodec;
+
+    #[derive(Default)]
+    struct Backlogs(Mutex<Vec<usize>>);
+
+    impl CodecMetrics for Backlogs {
+        fn on_backlog(&self, frames: usize) {
+            self.0.lock().unwrap().push(frames);
+        }
+    }
+
+    // Frames of 8 + 8 + 1008 bytes, so that each chunk read holds 4.
+    let backlogs = Arc::new(Backlogs::default());
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000).metrics(backlogs.clone());
+    let mut vec = Vec::new();
+    for id in 1..11 {
+        codec.encode((id, vec![id as u8; 1000]), &mut vec).unwrap();
+    }
+    let mut transport = Transport::new(MockIo::new(vec), codec).max_backlog(Some(2));
+    let ids = future::lazy(|| {
+            let mut ids = vec![];
+            loop {
+                match transport.poll()? {
+                    Async::Ready(Some((id, _))) => ids.push(id),
+                    Async::Ready(None) => return Ok::<_, io::Error>(ids),
+                    Async::NotReady => panic!("Expected a frame"),
+                }
+            }
+        })
+        .wait()
+        .unwrap();
+    assert_eq!(ids, (1..11).collect::<Vec<_>>());
+    // Reading stops at each chunk, which already exceeds the max.
+    assert_eq!(*backlogs.0.lock().unwrap(), vec![4, 4, 2]);
+}
+
+#[test]
+fn protocol_error() {
+    use futures::future;
+    use super::handshake::MockIo;
+    use tokio_core::io::Codec as TokioCodec;
+
+    let mut client: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    let mut frame = Vec::new();
+    client.encode((1, vec![0; 100]), &mut frame).unwrap();
+    let server_io = MockIo::new(frame);
+    let written = server_io.written.clone();
+    let codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(24).skip_too_big(false);
+    let mut server = Transport::new(server_io, codec);
+    let err = future::lazy(|| server.poll()).wait().err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    // The client learns why the server closed the connection.
+    let mut buf = EasyBuf::from(written.borrow().clone());
+    let err = client.decode(&mut buf).err().unwrap();
+    assert_eq!(ProtocolError::from_io(&err).unwrap().code,
+               ProtocolErrorCode::PayloadTooLarge);
+}
+
+#[test]
+fn flush_delay() {
+    use futures::future;
+    use super::handshake::MockIo;
+    use std::thread;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let options = FlushDelayOptions {
+        remote: core.remote(),
+        delay: Duration::from_millis(50),
+        threshold: 64,
+    };
+    let io = MockIo::new(vec![]);
+    let written = io.written.clone();
+    let codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    let mut transport = Transport::new(io, codec).flush_delay(options.start().unwrap());
+
+    // Frames of 8 + 8 + 9 bytes are held back until the delay is up.
+    core.run(future::lazy(|| {
+            transport.start_send((1, vec![1]))?;
+            transport.start_send((2, vec![2]))?;
+            assert!(transport.poll_complete()?.is_not_ready());
+            Ok::<_, io::Error>(())
+        }))
+        .unwrap();
+    assert!(written.borrow().is_empty());
+    thread::sleep(Duration::from_millis(60));
+    core.run(future::lazy(|| transport.poll_complete().map(|ready| assert!(ready.is_ready()))))
+        .unwrap();
+    assert_eq!(written.borrow().len(), 50);
+
+    // Once the threshold is buffered, frames are written right away.
+    core.run(future::lazy(|| {
+            transport.start_send((3, vec![3; 64]))?;
+            assert!(transport.poll_complete()?.is_ready());
+            Ok::<_, io::Error>(())
+        }))
+        .unwrap();
+    assert_eq!(written.borrow().len(), 50 + 88);
+}
+
+#[test]
+fn read_whole_payloads() {
+    use futures::future;
+    use tokio_core::io::Codec as TokioCodec;
+
+    /// Makes `available` bytes readable at a time, counting the calls to `read`.
+    struct Trickle {
+        read: io::Cursor<Vec<u8>>,
+        available: usize,
+        reads: usize,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            if self.available == 0 {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "Nothing to read"));
+            }
+            let len = cmp::min(buf.len(), self.available);
+            let n = self.read.read(&mut buf[..len])?;
+            self.available -= n;
+            Ok(n)
+        }
+    }
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Io for Trickle {}
+
+    let mut codec: Codec<Vec<u8>, Vec<u8>> = Codec::new(2_000_000);
+    let mut frame = vec![];
+    codec.encode((1, vec![1; 100_000]), &mut frame).unwrap();
+    let reads = |bytes| {
+        let io = Trickle {
+            read: io::Cursor::new(frame.clone()),
+            available: 20,
+            reads: 0,
+        };
+        let mut transport: Transport<_, Codec<Vec<u8>, Vec<u8>>> =
+            Transport::new(io, Codec::new(2_000_000)).read_whole_payloads(bytes);
+        // The header, then the payload once the rest of it arrives.
+        assert!(future::lazy(|| transport.poll()).wait().unwrap().is_not_ready());
+        assert!(transport.codec.bytes_needed().unwrap() > 99_000);
+        transport.upstream.available = frame.len() - 20;
+        transport.upstream.reads = 0;
+        match future::lazy(|| transport.poll()).wait() {
+            Ok(Async::Ready(Some((1, Ok(ref payload))))) if payload.len() == 100_000 => {}
+            bad => panic!("Expected request id = 1, but got {:?}", bad),
+        }
+        transport.upstream.reads
+    };
+    // A read of the payload, and one that finds nothing more.
+    assert_eq!(reads(Some(1_000_000)), 2);
+    assert!(reads(None) > 2);
+}
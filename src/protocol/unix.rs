@@ -0,0 +1,100 @@
+// Copyright 2017 Google Inc. All Rights Reserved.
+//
+// Licensed under the MIT License, <LICENSE or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use futures::{Future, Stream};
+use serde;
+use std::io;
+use std::path::Path;
+use super::{Client, DecodeError, PayloadSerializer, Proto};
+use tokio_core::reactor;
+use tokio_proto::BindServer;
+use tokio_service::NewService;
+use tokio_uds::{UnixListener, UnixStream};
+
+/// Binds a Unix socket at `path`, returning a future that serves each connection accepted on
+/// it with `proto` and a service made by `new_service`. The future runs until accepting fails,
+/// and must be spawned or run on the reactor of `handle`. The socket file isn't removed
+/// afterwards; binding fails if it already exists.
+///
+/// For processes on the same host, a Unix socket skips the TCP/IP stack, with its checksums,
+/// congestion control, and loopback routing, so frames cost less latency and CPU than over
+/// loopback TCP. The bytes exchanged are exactly those exchanged over TCP: the handshake,
+/// framing, and options are the same.
+pub fn serve_unix<P, N, Encode, Decode, S>(path: P,
+                                           handle: &reactor::Handle,
+                                           proto: Proto<Encode, Decode, S>,
+                                           new_service: N)
+                                           -> io::Result<Box<Future<Item = (), Error = io::Error>>>
+    where P: AsRef<Path>,
+          N: NewService<Request = Result<Decode, DecodeError<S::Error>>,
+                        Response = Encode,
+                        Error = io::Error> + 'static,
+          N::Instance: 'static,
+          Encode: serde::Serialize + 'static,
+          Decode: serde::Deserialize + 'static,
+          S: PayloadSerializer + Clone + 'static,
+          S::Error: 'static
+{
+    let listener = UnixListener::bind(path, handle)?;
+    let handle = handle.clone();
+    Ok(Box::new(listener.incoming().for_each(move |(io, _)| {
+        proto.bind_server(&handle, io, new_service.new_service()?);
+        Ok(())
+    })))
+}
+
+/// Connects to the Unix socket at `path`, returning a `Client` that makes calls with `proto`
+/// on the reactor of `handle`.
+pub fn connect_unix<P, Encode, Decode, S>(path: P,
+                                          handle: &reactor::Handle,
+                                          proto: &Proto<Encode, Decode, S>)
+                                          -> io::Result<Client<UnixStream, Encode, Decode, S>>
+    where P: AsRef<Path>,
+          Encode: serde::Serialize + 'static,
+          Decode: serde::Deserialize + 'static,
+          S: PayloadSerializer + Clone + 'static,
+          S::Error: 'static
+{
+    let io = UnixStream::connect(path, handle)?;
+    Ok(Client::new(handle, io, proto))
+}
+
+#[test]
+fn round_trip() {
+    use bincode;
+    use futures::future;
+    use std::{env, fs};
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tokio_core::reactor::Core;
+    use tokio_service::Service;
+
+    #[derive(Clone)]
+    struct Double;
+
+    impl Service for Double {
+        type Request = Result<u32, DecodeError<bincode::Error>>;
+        type Response = u32;
+        type Error = io::Error;
+        type Future = future::FutureResult<u32, io::Error>;
+
+        fn call(&self, request: Self::Request) -> Self::Future {
+            future::result(request.map(|n| n * 2).map_err(DecodeError::into_io))
+        }
+    }
+
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    let path = env::temp_dir().join(format!("tarpc-unix-{}.sock", nanos));
+    let _ = fs::remove_file(&path);
+    let mut core = Core::new().unwrap();
+    let proto: Proto<u32, u32> = Proto::new(2_000_000).checksum(true);
+    let server = serve_unix(&path, &core.handle(), proto.clone(), || Ok(Double)).unwrap();
+    core.handle().spawn(server.map_err(|_| ()));
+
+    let client = connect_unix(&path, &core.handle(), &proto).unwrap();
+    let calls = (0..10).map(|n| client.call(n)).collect::<Vec<_>>();
+    let responses = core.run(future::join_all(calls)).unwrap();
+    assert_eq!(responses, (0..10).map(|n| n * 2).collect::<Vec<_>>());
+    fs::remove_file(&path).unwrap();
+}
@@ -1,5 +1,6 @@
 use {bincode, future};
 use future::server::{Response, Shutdown};
+use protocol::{DecodeError, Handshake};
 use futures::Future;
 use serde::{Deserialize, Serialize};
 use std::io;
@@ -22,6 +23,40 @@ impl Options {
         self
     }
 
+    /// Stop reading requests from a client while `requests` of them are awaiting a response,
+    /// resuming as responses are sent. By default there is no limit.
+    pub fn max_in_flight(mut self, requests: usize) -> Self {
+        self.opts = self.opts.max_in_flight(requests);
+        self
+    }
+
+    /// Handle at most `requests` requests at once, across every connection. By default there is
+    /// no limit.
+    pub fn max_concurrent(mut self, requests: usize) -> Self {
+        self.opts = self.opts.max_concurrent(requests);
+        self
+    }
+
+    /// Call `hook` with the address of every new connection, before anything is read from it,
+    /// e.g. to only accept clients on an allowlist. If `hook` returns an error, the connection is
+    /// closed at once.
+    pub fn on_accept<F>(mut self, hook: F) -> Self
+        where F: Fn(&SocketAddr) -> io::Result<()> + Send + Sync + 'static
+    {
+        self.opts = self.opts.on_accept(hook);
+        self
+    }
+
+    /// Call `hook` with the parameters negotiated for every new connection, e.g. to log the
+    /// protocol version a client speaks. If `hook` returns an error, the client is told why and
+    /// the connection is closed.
+    pub fn on_handshake<F>(mut self, hook: F) -> Self
+        where F: Fn(&Handshake) -> io::Result<()> + Send + Sync + 'static
+    {
+        self.opts = self.opts.on_handshake(hook);
+        self
+    }
+
     /// Set the `TlsAcceptor`
     #[cfg(feature = "tls")]
     pub fn tls(mut self, tls_acceptor: TlsAcceptor) -> Self {
@@ -44,7 +79,7 @@ impl Handle {
                                    addr: SocketAddr,
                                    options: Options)
                                    -> io::Result<Self>
-        where S: NewService<Request = Result<Req, bincode::Error>,
+        where S: NewService<Request = Result<Req, DecodeError<bincode::Error>>,
                             Response = Response<Resp, E>,
                             Error = io::Error> + 'static,
               Req: Deserialize + 'static,
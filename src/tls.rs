@@ -1,6 +1,11 @@
 /// TLS-specific functionality for clients.
 pub mod client {
+    use errors::native_to_io;
+    use futures::Future;
     use native_tls::{Error, TlsConnector};
+    use std::io;
+    use tokio_core::net::TcpStream;
+    use tokio_tls::{TlsConnectorExt, TlsStream};
 
     /// TLS context for client
     pub struct Context {
@@ -35,5 +40,31 @@ pub mod client {
             }
         }
     }
+
+    /// Performs the client side of a TLS handshake on `socket`. The resulting stream can be
+    /// passed to `BindClient::bind_client`, e.g. with a `protocol::Proto`, which starts framing
+    /// only after the handshake completes.
+    pub fn connect(socket: TcpStream,
+                   context: &Context)
+                   -> Box<Future<Item = TlsStream<TcpStream>, Error = io::Error>> {
+        Box::new(context.tls_connector.connect_async(&context.domain, socket).map_err(native_to_io))
+    }
 }
 
+/// TLS-specific functionality for servers.
+pub mod server {
+    use errors::native_to_io;
+    use futures::Future;
+    use native_tls::TlsAcceptor;
+    use std::io;
+    use tokio_core::net::TcpStream;
+    use tokio_tls::{TlsAcceptorExt, TlsStream};
+
+    /// Performs the server side of a TLS handshake on `socket`. The resulting stream can be
+    /// passed to `BindServer::bind_server`, e.g. with a `protocol::Proto`.
+    pub fn accept(socket: TcpStream,
+                  acceptor: &TlsAcceptor)
+                  -> Box<Future<Item = TlsStream<TcpStream>, Error = io::Error>> {
+        Box::new(acceptor.accept_async(socket).map_err(native_to_io))
+    }
+}